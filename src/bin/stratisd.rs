@@ -8,13 +8,15 @@
 extern crate log;
 
 use std::cell::RefCell;
+use std::cmp;
 use std::env;
 use std::fs::{File, OpenOptions};
 use std::io::{ErrorKind, Read, Write};
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::rc::Rc;
+use std::time::Instant;
 
 use chrono::Duration;
 use clap::{App, Arg, ArgMatches};
@@ -31,14 +33,14 @@ use uuid::Uuid;
 #[cfg(feature = "dbus_enabled")]
 use dbus::Connection;
 
-use devicemapper::Device;
+use devicemapper::{Bytes, Device, IEC};
 #[cfg(feature = "dbus_enabled")]
 use libstratis::dbus_api::{consts, prop_changed_dispatch, DbusConnectionData};
 #[cfg(feature = "dbus_enabled")]
+use libstratis::engine::MaybeDbusPath;
 use libstratis::engine::{
-    get_engine_listener_list_mut, EngineEvent, EngineListener, MaybeDbusPath,
+    get_engine_listener_list_mut, Engine, EngineEvent, EngineListener, Pool, SimEngine, StratEngine,
 };
-use libstratis::engine::{Engine, Pool, SimEngine, StratEngine};
 use libstratis::stratis::buff_log;
 use libstratis::stratis::{StratisError, StratisResult, VERSION};
 
@@ -60,6 +62,16 @@ fn log_engine_state(engine: &dyn Engine) {
     debug!("Engine state: \n{:#?}", engine);
 }
 
+/// Logs every engine event, whether or not there is a D-Bus interface.
+#[derive(Debug)]
+struct EventLogger;
+
+impl EngineListener for EventLogger {
+    fn notify(&self, event: &EngineEvent) {
+        debug!("Engine event: {:?}", event);
+    }
+}
+
 /// Configure the env_logger as necessary in order to allow the buffered
 /// logger to work correctly. Return a Handle to the underlying env_logger.
 pub fn from_env_logger(
@@ -267,6 +279,16 @@ impl EngineListener for EventHandler {
                     });
                 }
             }
+            // The D-Bus objects are added and removed by the methods that
+            // create and destroy them, a change in space state is sent
+            // as a change of the pool's property, and jobs have no D-Bus
+            // objects.
+            EngineEvent::FilesystemCreated { .. }
+            | EngineEvent::FilesystemDestroyed { .. }
+            | EngineEvent::JobFinished { .. }
+            | EngineEvent::PoolCreated { .. }
+            | EngineEvent::PoolDestroyed { .. }
+            | EngineEvent::SpaceLow { .. } => {}
         }
     }
 }
@@ -470,6 +492,92 @@ fn process_poll(poll_timeout: i32, fds: &mut Vec<libc::pollfd>) -> StratisResult
     Ok(())
 }
 
+/// Reduce the poll timeout, if necessary, so that poll returns no later than
+/// ms milliseconds from now.
+fn reduce_timeout(timeout: &mut i32, ms: u64) {
+    // Wake at least once a minute; the timeout is recomputed each time.
+    let ms = cmp::min(ms, 60_000) as i32;
+    if *timeout < 0 || ms < *timeout {
+        *timeout = ms;
+    }
+}
+
+/// The number of milliseconds from now until deadline, rounded up.
+fn ms_until(deadline: Instant, now: Instant) -> u64 {
+    let remaining = deadline - now;
+    remaining.as_secs() * 1000 + u64::from(remaining.subsec_millis()) + 1
+}
+
+/// Resume every pool that has been quiesced for longer than its timeout.
+/// Return the poll timeout, reduced if necessary, so that the pools that
+/// remain quiesced are resumed in time.
+fn resume_expired_pools(engine: &mut dyn Engine, poll_timeout: i32) -> i32 {
+    let now = Instant::now();
+    let mut timeout = poll_timeout;
+    for (pool_name, pool_uuid, pool) in engine.pools_mut() {
+        match pool.quiesce_deadline() {
+            Some(deadline) if deadline <= now => {
+                warn!(
+                    "quiesce timeout expired for pool {} with UUID {}, resuming it",
+                    pool_name,
+                    pool_uuid.to_simple_ref()
+                );
+                if let Err(err) = pool.unquiesce() {
+                    error!("failed to resume pool {}: {:?}", pool_name, err);
+                    // Try again in a little while.
+                    reduce_timeout(&mut timeout, 1000);
+                }
+            }
+            Some(deadline) => reduce_timeout(&mut timeout, ms_until(deadline, now)),
+            None => (),
+        }
+    }
+    timeout
+}
+
+/// Do a pass of the trim of every filesystem which is due to be trimmed.
+/// Each pass is bounded, so a large filesystem remains due until its last
+/// pass. Filesystems in pools which are quiesced or read-only are passed
+/// over until the pool is writable again. Return the poll timeout, reduced
+/// if necessary, so that the next pass or trim is not late.
+fn trim_due_filesystems(engine: &mut dyn Engine, poll_timeout: i32) -> i32 {
+    let now = Instant::now();
+    let mut timeout = poll_timeout;
+    for (pool_name, _, pool) in engine.pools_mut() {
+        if pool.quiesce_deadline().is_some() || pool.read_only_reason().is_some() {
+            continue;
+        }
+        for (fs_name, _, fs) in pool.filesystems_mut() {
+            if let Some(deadline) = fs.trim_deadline() {
+                if deadline <= now {
+                    // A failed trim is not retried until the next interval.
+                    match fs.trim() {
+                        Ok(Some(trimmed)) => info!(
+                            "trimmed {} from filesystem {} in pool {}",
+                            trimmed, fs_name, pool_name
+                        ),
+                        Ok(None) => (),
+                        Err(err) => error!(
+                            "failed to trim filesystem {} in pool {}: {:?}",
+                            fs_name, pool_name, err
+                        ),
+                    }
+                }
+            }
+            // The last pass of a trim reschedules the filesystem's next
+            // trim; until then, the next pass follows at once.
+            if let Some(deadline) = fs.trim_deadline() {
+                if deadline > now {
+                    reduce_timeout(&mut timeout, ms_until(deadline, now));
+                } else {
+                    reduce_timeout(&mut timeout, 0);
+                }
+            }
+        }
+    }
+    timeout
+}
+
 /// Set up all sorts of signal and event handling mechanisms.
 /// Initialize the engine and keep it running until a signal is received
 /// or a fatal error is encountered. Dump log entries on specified signal
@@ -480,6 +588,8 @@ fn run(matches: &ArgMatches, buff_log: &buff_log::Handle<env_logger::Logger>) ->
 
     let mut dbus_support = MaybeDbusSupport::new();
 
+    get_engine_listener_list_mut().register_listener(Box::new(EventLogger));
+
     // Setup a udev listener before initializing the engine. A device may
     // appear after the engine has processed the udev db, but before it has
     // completed initialization. Unless the udev event has been recorded, the
@@ -488,12 +598,56 @@ fn run(matches: &ArgMatches, buff_log: &buff_log::Handle<env_logger::Logger>) ->
     let context = libudev::Context::new()?;
     let mut udev_monitor = UdevMonitor::create(&context)?;
 
+    // The simulator, if its state is to be saved across restarts.
+    let mut sim_engine: Option<Rc<RefCell<SimEngine>>> = None;
+
     let engine: Rc<RefCell<dyn Engine>> = {
         if matches.is_present("sim") {
             info!("Using SimEngine");
-            Rc::new(RefCell::new(SimEngine::default()))
+            if let Some(state_file) = matches.value_of("sim_state") {
+                let engine = Rc::new(RefCell::new(SimEngine::with_state_file(Path::new(
+                    state_file,
+                ))?));
+                sim_engine = Some(Rc::clone(&engine));
+                engine
+            } else {
+                Rc::new(RefCell::new(SimEngine::default()))
+            }
         } else {
             info!("Using StratEngine");
+            if let Some(size) = matches.value_of("min_dev_size") {
+                let size = size
+                    .parse::<u64>()
+                    .ok()
+                    .and_then(|size| size.checked_mul(IEC::Mi))
+                    .ok_or_else(|| {
+                        StratisError::Error(format!(
+                            "Invalid value \"{}\" for minimum device size",
+                            size
+                        ))
+                    })?;
+                StratEngine::set_min_dev_size(Bytes(size))?;
+            }
+            if let Some(copies) = matches.value_of("metadata_redundancy") {
+                let copies = copies.parse::<usize>().map_err(|_| {
+                    StratisError::Error(format!(
+                        "Invalid value \"{}\" for metadata redundancy",
+                        copies
+                    ))
+                })?;
+                StratEngine::set_metadata_redundancy(copies)?;
+            }
+            if let (Some(warn_pct), Some(crit_pct)) = (
+                matches.value_of("space_warn_pct"),
+                matches.value_of("space_crit_pct"),
+            ) {
+                let parse_pct = |pct: &str| {
+                    pct.parse::<u8>().map_err(|_| {
+                        StratisError::Error(format!("Invalid value \"{}\" for percentage", pct))
+                    })
+                };
+                StratEngine::set_space_thresholds(parse_pct(warn_pct)?, parse_pct(crit_pct)?)?;
+            }
             Rc::new(RefCell::new(StratEngine::initialize()?))
         }
     };
@@ -615,7 +769,23 @@ fn run(matches: &ArgMatches, buff_log: &buff_log::Handle<env_logger::Logger>) ->
 
         dbus_support.process(&engine, &mut fds, dbus_client_index_start);
 
-        process_poll(dbus_support.poll_timeout(), &mut fds)?;
+        let poll_timeout =
+            resume_expired_pools(&mut *engine.borrow_mut(), dbus_support.poll_timeout());
+        let poll_timeout = trim_due_filesystems(&mut *engine.borrow_mut(), poll_timeout);
+        // While a job is running, poll only for what is ready, so that the
+        // job's next step follows at once. Each step is bounded in time, so
+        // what becomes ready meanwhile waits no longer than that.
+        let poll_timeout = if engine.borrow_mut().step_jobs() {
+            0
+        } else {
+            poll_timeout
+        };
+        if let Some(ref sim_engine) = sim_engine {
+            if let Err(err) = sim_engine.borrow_mut().save_state() {
+                warn!("Failed to save simulator state: {}", err);
+            }
+        }
+        process_poll(poll_timeout, &mut fds)?;
     }
 }
 
@@ -633,6 +803,44 @@ fn main() {
                 .long("sim")
                 .help("Use simulator engine"),
         )
+        .arg(
+            Arg::with_name("sim_state")
+                .long("sim-state")
+                .value_name("FILE")
+                .takes_value(true)
+                .requires("sim")
+                .help("File to which the simulator's state is saved, restored at startup"),
+        )
+        .arg(
+            Arg::with_name("min_dev_size")
+                .long("min-dev-size")
+                .value_name("MiB")
+                .takes_value(true)
+                .help("Minimum size of a pool member, for development and testing only"),
+        )
+        .arg(
+            Arg::with_name("metadata_redundancy")
+                .long("metadata-redundancy")
+                .value_name("N")
+                .takes_value(true)
+                .help("Number of members of each pool to which pool metadata is written"),
+        )
+        .arg(
+            Arg::with_name("space_warn_pct")
+                .long("space-warn-pct")
+                .value_name("PCT")
+                .takes_value(true)
+                .requires("space_crit_pct")
+                .help("Percentage of a pool's data space in use at which to warn"),
+        )
+        .arg(
+            Arg::with_name("space_crit_pct")
+                .long("space-crit-pct")
+                .value_name("PCT")
+                .takes_value(true)
+                .requires("space_warn_pct")
+                .help("Percentage of a pool's data space in use at which space is critical"),
+        )
         .get_matches();
 
     // Using a let-expression here so that the scope of the lock file
@@ -5,6 +5,7 @@
 use std::cell::RefCell;
 use std::path::Path;
 use std::rc::Rc;
+use std::time::Duration;
 use std::vec::Vec;
 
 use dbus;
@@ -15,9 +16,14 @@ use dbus::tree::{
 use dbus::{BusType, Connection, ConnectionItem, Message, NameFlag};
 use libc;
 
+use devicemapper::Bytes;
+
 use crate::dbus_api::consts;
-use crate::engine::{Engine, Pool, PoolUuid};
-use crate::stratis::VERSION;
+use crate::engine::{
+    run_once, AllocationStrategy, ChunkSize, Engine, Pool, PoolUuid, SimDevProperties,
+    SimFailureModel, SimOperation,
+};
+use crate::stratis::{ErrorEnum, VERSION};
 
 use crate::dbus_api::blockdev::create_dbus_blockdev;
 use crate::dbus_api::filesystem::create_dbus_filesystem;
@@ -28,19 +34,48 @@ use crate::dbus_api::util::{
 };
 
 fn create_pool(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
+    create_pool_shared(m, false)
+}
+
+fn create_pool_with_request_id(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
+    create_pool_shared(m, true)
+}
+
+/// Create a pool. If with_request_id is true, the method takes a request ID
+/// as its last argument, and a request made again with the same ID is given
+/// the result of the first.
+fn create_pool_shared(m: &MethodInfo<MTFn<TData>, TData>, with_request_id: bool) -> MethodResult {
     let message: &Message = m.msg;
     let mut iter = message.iter_init();
 
     let name: &str = get_next_arg(&mut iter, 0)?;
     let redundancy: (bool, u16) = get_next_arg(&mut iter, 1)?;
     let devs: Array<&str, _> = get_next_arg(&mut iter, 3)?;
+    let request_id: (bool, &str) = if with_request_id {
+        get_next_arg(&mut iter, 4)?
+    } else {
+        (false, "")
+    };
 
     let blockdevs = devs.map(|x| Path::new(x)).collect::<Vec<&Path>>();
 
     let object_path = m.path.get_name();
     let dbus_context = m.tree.get_data();
     let mut engine = dbus_context.engine.borrow_mut();
-    let result = engine.create_pool(name, &blockdevs, tuple_to_option(redundancy));
+    let result = run_once(
+        &mut *engine,
+        tuple_to_option(request_id),
+        "create_pool",
+        |engine| {
+            engine.create_pool(
+                name,
+                &blockdevs,
+                tuple_to_option(redundancy),
+                ChunkSize::Auto,
+                AllocationStrategy::FillFirst,
+            )
+        },
+    );
 
     let return_message = message.method_return();
 
@@ -50,16 +85,31 @@ fn create_pool(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
         Ok(pool_uuid) => {
             let (_, pool) = get_mut_pool!(engine; pool_uuid; default_return; return_message);
 
-            let pool_object_path: dbus::Path =
-                create_dbus_pool(dbus_context, object_path.clone(), pool_uuid, pool);
-
-            let bd_object_paths = pool
-                .blockdevs_mut()
-                .into_iter()
-                .map(|(uuid, bd)| {
-                    create_dbus_blockdev(dbus_context, pool_object_path.clone(), uuid, bd)
-                })
-                .collect::<Vec<_>>();
+            // A replayed request is given the objects made for the pool
+            // by the first one.
+            let (pool_object_path, bd_object_paths) = match pool.get_dbus_path().0.clone() {
+                Some(pool_object_path) => {
+                    let bd_object_paths = pool
+                        .blockdevs()
+                        .into_iter()
+                        .filter_map(|(_, bd)| bd.get_dbus_path().0.clone())
+                        .collect::<Vec<_>>();
+                    (pool_object_path, bd_object_paths)
+                }
+                None => {
+                    let pool_object_path: dbus::Path =
+                        create_dbus_pool(dbus_context, object_path.clone(), pool_uuid, pool);
+
+                    let bd_object_paths = pool
+                        .blockdevs_mut()
+                        .into_iter()
+                        .map(|(uuid, bd)| {
+                            create_dbus_blockdev(dbus_context, pool_object_path.clone(), uuid, bd)
+                        })
+                        .collect::<Vec<_>>();
+                    (pool_object_path, bd_object_paths)
+                }
+            };
 
             return_message.append3(
                 (pool_object_path, bd_object_paths),
@@ -76,33 +126,57 @@ fn create_pool(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
 }
 
 fn destroy_pool(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
+    destroy_pool_shared(m, false)
+}
+
+fn destroy_pool_with_request_id(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
+    destroy_pool_shared(m, true)
+}
+
+/// Destroy a pool. If with_request_id is true, the method takes a request
+/// ID as its last argument, and a request made again with the same ID is
+/// given the result of the first.
+fn destroy_pool_shared(m: &MethodInfo<MTFn<TData>, TData>, with_request_id: bool) -> MethodResult {
     let message: &Message = m.msg;
     let mut iter = message.iter_init();
 
     let object_path: dbus::Path<'static> = get_next_arg(&mut iter, 0)?;
+    let request_id: (bool, &str) = if with_request_id {
+        get_next_arg(&mut iter, 1)?
+    } else {
+        (false, "")
+    };
 
     let dbus_context = m.tree.get_data();
 
     let default_return = false;
     let return_message = message.method_return();
 
+    // The pool's object is gone if this is a replay of a request that
+    // destroyed it, but the result of that request is still returned.
     let pool_uuid = match m.tree.get(&object_path) {
-        Some(pool_path) => get_data!(pool_path; default_return; return_message).uuid,
-        None => {
-            return Ok(vec![return_message.append3(
-                default_return,
-                msg_code_ok(),
-                msg_string_ok(),
-            )]);
-        }
+        Some(pool_path) => Some(get_data!(pool_path; default_return; return_message).uuid),
+        None => None,
     };
 
-    let msg = match dbus_context.engine.borrow_mut().destroy_pool(pool_uuid) {
+    let result = run_once(
+        &mut *dbus_context.engine.borrow_mut(),
+        tuple_to_option(request_id),
+        "destroy_pool",
+        |engine| match pool_uuid {
+            Some(pool_uuid) => engine.destroy_pool(pool_uuid),
+            None => Ok(false),
+        },
+    );
+
+    let msg = match result {
         Ok(action) => {
-            dbus_context
-                .actions
-                .borrow_mut()
-                .push_remove(&object_path, m.tree);
+            if pool_uuid.is_some() {
+                dbus_context
+                    .actions
+                    .borrow_mut()
+                    .push_remove(&object_path, m.tree);
+            }
             return_message.append3(action, msg_code_ok(), msg_string_ok())
         }
         Err(err) => {
@@ -142,6 +216,118 @@ fn configure_simulator(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
     Ok(vec![msg])
 }
 
+/// The simulator operation with the given name, which is that of the
+/// corresponding engine method.
+fn sim_operation(name: &str) -> Option<SimOperation> {
+    match name {
+        "CreatePool" => Some(SimOperation::CreatePool),
+        "DestroyPool" => Some(SimOperation::DestroyPool),
+        "RenamePool" => Some(SimOperation::RenamePool),
+        "AddBlockdevs" => Some(SimOperation::AddBlockdevs),
+        "CreateFilesystems" => Some(SimOperation::CreateFilesystems),
+        "DestroyFilesystems" => Some(SimOperation::DestroyFilesystems),
+        "RenameFilesystem" => Some(SimOperation::RenameFilesystem),
+        "SnapshotFilesystem" => Some(SimOperation::SnapshotFilesystem),
+        _ => None,
+    }
+}
+
+/// The outcome of a scripted attempt, given as the return code the attempt
+/// should give: None for OK, otherwise the error to fail with.
+fn sim_outcome(code: u16) -> Option<Option<ErrorEnum>> {
+    match code {
+        x if x == DbusErrorEnum::OK as u16 => Some(None),
+        x if x == DbusErrorEnum::ERROR as u16 => Some(Some(ErrorEnum::Error)),
+        x if x == DbusErrorEnum::ALREADY_EXISTS as u16 => Some(Some(ErrorEnum::AlreadyExists)),
+        x if x == DbusErrorEnum::BUSY as u16 => Some(Some(ErrorEnum::Busy)),
+        x if x == DbusErrorEnum::NOTFOUND as u16 => Some(Some(ErrorEnum::NotFound)),
+        _ => None,
+    }
+}
+
+fn configure_simulator_operation(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
+    let message = m.msg;
+    let mut iter = message.iter_init();
+
+    let operation: &str = get_next_arg(&mut iter, 0)?;
+    let denominator: u32 = get_next_arg(&mut iter, 1)?;
+    let script: Array<u16, _> = get_next_arg(&mut iter, 2)?;
+
+    let return_message = message.method_return();
+
+    let operation = match sim_operation(operation) {
+        Some(operation) => operation,
+        None => {
+            let error_message = format!("no simulator operation named {}", operation);
+            let (rc, rs) = (DbusErrorEnum::ERROR as u16, error_message);
+            return Ok(vec![return_message.append2(rc, rs)]);
+        }
+    };
+
+    // An empty script means that the operation fails at random.
+    let script = match script.map(sim_outcome).collect::<Option<Vec<_>>>() {
+        Some(script) => script,
+        None => {
+            let error_message = "script contains an unknown return code";
+            let (rc, rs) = (DbusErrorEnum::ERROR as u16, error_message);
+            return Ok(vec![return_message.append2(rc, rs)]);
+        }
+    };
+    let model = if script.is_empty() {
+        SimFailureModel::Probability(denominator)
+    } else {
+        SimFailureModel::Script(script)
+    };
+
+    let dbus_context = m.tree.get_data();
+    let result = dbus_context
+        .engine
+        .borrow_mut()
+        .configure_simulator_operation(operation, model);
+
+    let msg = match result {
+        Ok(_) => return_message.append2(msg_code_ok(), msg_string_ok()),
+        Err(err) => {
+            let (rc, rs) = engine_to_dbus_err_tuple(&err);
+            return_message.append2(rc, rs)
+        }
+    };
+    Ok(vec![msg])
+}
+
+fn configure_simulator_device(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
+    let message = m.msg;
+    let mut iter = message.iter_init();
+
+    let devnode: &str = get_next_arg(&mut iter, 0)?;
+    let size: (bool, u64) = get_next_arg(&mut iter, 1)?;
+    let rotational: bool = get_next_arg(&mut iter, 2)?;
+    let latency_ms: u32 = get_next_arg(&mut iter, 3)?;
+
+    let return_message = message.method_return();
+
+    let properties = SimDevProperties {
+        size: tuple_to_option(size).map(|size| Bytes(size).sectors()),
+        rotational,
+        latency: Duration::from_millis(u64::from(latency_ms)),
+    };
+
+    let dbus_context = m.tree.get_data();
+    let result = dbus_context
+        .engine
+        .borrow_mut()
+        .configure_simulator_device(Path::new(devnode), properties);
+
+    let msg = match result {
+        Ok(_) => return_message.append2(msg_code_ok(), msg_string_ok()),
+        Err(err) => {
+            let (rc, rs) = engine_to_dbus_err_tuple(&err);
+            return_message.append2(rc, rs)
+        }
+    };
+    Ok(vec![msg])
+}
+
 fn get_base_tree<'a>(dbus_context: DbusContext) -> (Tree<MTFn<TData>, TData>, dbus::Path<'a>) {
     let f = Factory::new_fn();
 
@@ -156,6 +342,16 @@ fn get_base_tree<'a>(dbus_context: DbusContext) -> (Tree<MTFn<TData>, TData>, db
         .out_arg(("return_code", "q"))
         .out_arg(("return_string", "s"));
 
+    let create_pool_with_request_id_method = f
+        .method("CreatePoolWithRequestId", (), create_pool_with_request_id)
+        .in_arg(("name", "s"))
+        .in_arg(("redundancy", "(bq)"))
+        .in_arg(("devices", "as"))
+        .in_arg(("request_id", "(bs)"))
+        .out_arg(("result", "(oao)"))
+        .out_arg(("return_code", "q"))
+        .out_arg(("return_string", "s"));
+
     let destroy_pool_method = f
         .method("DestroyPool", (), destroy_pool)
         .in_arg(("pool", "o"))
@@ -163,12 +359,41 @@ fn get_base_tree<'a>(dbus_context: DbusContext) -> (Tree<MTFn<TData>, TData>, db
         .out_arg(("return_code", "q"))
         .out_arg(("return_string", "s"));
 
+    let destroy_pool_with_request_id_method = f
+        .method("DestroyPoolWithRequestId", (), destroy_pool_with_request_id)
+        .in_arg(("pool", "o"))
+        .in_arg(("request_id", "(bs)"))
+        .out_arg(("action", "b"))
+        .out_arg(("return_code", "q"))
+        .out_arg(("return_string", "s"));
+
     let configure_simulator_method = f
         .method("ConfigureSimulator", (), configure_simulator)
         .in_arg(("denominator", "u"))
         .out_arg(("return_code", "q"))
         .out_arg(("return_string", "s"));
 
+    let configure_simulator_operation_method = f
+        .method(
+            "ConfigureSimulatorOperation",
+            (),
+            configure_simulator_operation,
+        )
+        .in_arg(("operation", "s"))
+        .in_arg(("denominator", "u"))
+        .in_arg(("script", "aq"))
+        .out_arg(("return_code", "q"))
+        .out_arg(("return_string", "s"));
+
+    let configure_simulator_device_method = f
+        .method("ConfigureSimulatorDevice", (), configure_simulator_device)
+        .in_arg(("devnode", "s"))
+        .in_arg(("size", "(bt)"))
+        .in_arg(("rotational", "b"))
+        .in_arg(("latency_ms", "u"))
+        .out_arg(("return_code", "q"))
+        .out_arg(("return_string", "s"));
+
     let version_property = f
         .property::<&str, _>("Version", ())
         .access(Access::Read)
@@ -182,8 +407,12 @@ fn get_base_tree<'a>(dbus_context: DbusContext) -> (Tree<MTFn<TData>, TData>, db
         .add(
             f.interface(consts::MANAGER_INTERFACE_NAME, ())
                 .add_m(create_pool_method)
+                .add_m(create_pool_with_request_id_method)
                 .add_m(destroy_pool_method)
+                .add_m(destroy_pool_with_request_id_method)
                 .add_m(configure_simulator_method)
+                .add_m(configure_simulator_operation_method)
+                .add_m(configure_simulator_device_method)
                 .add_p(version_property),
         );
 
@@ -41,6 +41,12 @@ pub fn create_dbus_blockdev<'a>(
         .emits_changed(EmitsChangedSignal::Const)
         .on_get(get_blockdev_devnode);
 
+    let persistent_devnode_property = f
+        .property::<&str, _>("PersistentDevnode", ())
+        .access(Access::Read)
+        .emits_changed(EmitsChangedSignal::Const)
+        .on_get(get_blockdev_persistent_devnode);
+
     let hardware_info_property = f
         .property::<&str, _>("HardwareInfo", ())
         .access(Access::Read)
@@ -99,6 +105,7 @@ pub fn create_dbus_blockdev<'a>(
                 .add_m(set_userid_method)
                 .add_p(devnode_property)
                 .add_p(hardware_info_property)
+                .add_p(persistent_devnode_property)
                 .add_p(initialization_time_property)
                 .add_p(total_physical_size_property)
                 .add_p(pool_property)
@@ -210,6 +217,17 @@ fn get_blockdev_devnode(
     get_blockdev_property(i, p, |_, p| Ok(format!("{}", p.devnode().display())))
 }
 
+/// Get a path to the device which is stable across reboots for an object
+/// path.
+fn get_blockdev_persistent_devnode(
+    i: &mut IterAppend,
+    p: &PropInfo<MTFn<TData>, TData>,
+) -> Result<(), MethodErr> {
+    get_blockdev_property(i, p, |_, p| {
+        Ok(format!("{}", p.persistent_devnode().display()))
+    })
+}
+
 fn get_blockdev_hardware_info(
     i: &mut IterAppend,
     p: &PropInfo<MTFn<TData>, TData>,
@@ -178,17 +178,19 @@ fn snapshot_filesystem(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
     let mut engine = dbus_context.engine.borrow_mut();
     let (pool_name, pool) = get_mut_pool!(engine; pool_uuid; default_return; return_message);
 
-    let msg = match pool.snapshot_filesystem(pool_uuid, &pool_name, fs_uuid, snapshot_name) {
-        Ok((uuid, fs)) => {
-            let fs_object_path: dbus::Path =
-                create_dbus_filesystem(dbus_context, object_path.clone(), uuid, fs);
-            return_message.append3(fs_object_path, msg_code_ok(), msg_string_ok())
-        }
-        Err(err) => {
-            let (rc, rs) = engine_to_dbus_err_tuple(&err);
-            return_message.append3(default_return, rc, rs)
-        }
-    };
+    let msg =
+        match pool.snapshot_filesystem(pool_uuid, &pool_name, fs_uuid, snapshot_name, false, false)
+        {
+            Ok((uuid, fs)) => {
+                let fs_object_path: dbus::Path =
+                    create_dbus_filesystem(dbus_context, object_path.clone(), uuid, fs);
+                return_message.append3(fs_object_path, msg_code_ok(), msg_string_ok())
+            }
+            Err(err) => {
+                let (rc, rs) = engine_to_dbus_err_tuple(&err);
+                return_message.append3(default_return, rc, rs)
+            }
+        };
 
     Ok(vec![msg])
 }
@@ -60,6 +60,7 @@ pub fn engine_to_dbus_err_tuple(err: &StratisError) -> (u16, String) {
             ErrorEnum::Busy => DbusErrorEnum::BUSY,
             ErrorEnum::Invalid => DbusErrorEnum::ERROR,
             ErrorEnum::NotFound => DbusErrorEnum::NOTFOUND,
+            ErrorEnum::Corrupt => DbusErrorEnum::ERROR,
         },
         StratisError::Io(_) => DbusErrorEnum::ERROR,
         StratisError::Nix(_) => DbusErrorEnum::ERROR,
@@ -0,0 +1,132 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Consistency checks: a comparison of what an engine holds in memory with
+// what is actually on the system, i.e., the metadata on the blockdevs, the
+// active DM devices, and the links in DEV_PATH. Each difference found is
+// reported as a discrepancy. Those discrepancies that can be put right
+// without risk to data, by doing again what the engine would have done in
+// the first place, may be repaired.
+
+use std::path::PathBuf;
+
+use crate::engine::{DevUuid, PoolUuid};
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Discrepancy {
+    /// A directory or link in DEV_PATH that belongs to no pool or
+    /// filesystem.
+    StaleLink { path: PathBuf },
+    /// A filesystem's link in DEV_PATH is absent, or does not point to the
+    /// filesystem's device.
+    MissingLink { path: PathBuf, target: PathBuf },
+    /// A DM device of the pool is not active.
+    MissingDmDevice { pool_uuid: PoolUuid, name: String },
+    /// A DM device of the pool is active, but with a table other than the
+    /// one the pool set up.
+    DmTableMismatch { pool_uuid: PoolUuid, name: String },
+    /// A DM device named for a pool that the engine does not know of.
+    OrphanedDmDevice { name: String },
+    /// The BDA on a blockdev could not be read, or does not identify the
+    /// blockdev as a member of its pool.
+    BdaMismatch {
+        pool_uuid: PoolUuid,
+        dev_uuid: DevUuid,
+        devnode: PathBuf,
+        reason: String,
+    },
+    /// The sequence number of the most recent metadata on a blockdev is not
+    /// the one the pool last wrote to it.
+    MetadataSequenceMismatch {
+        pool_uuid: PoolUuid,
+        dev_uuid: DevUuid,
+        devnode: PathBuf,
+        on_disk: Option<u64>,
+        in_memory: Option<u64>,
+    },
+    /// The thin metadata of a pool failed thin_check and could not be
+    /// repaired, so the pool is not set up.
+    CorruptThinMetadata { pool_uuid: PoolUuid, reason: String },
+}
+
+impl Discrepancy {
+    /// Whether the discrepancy may be repaired without risk to data. A
+    /// blockdev whose metadata is newer than the pool's, or which has a
+    /// damaged or foreign BDA, might hold something that the pool does not
+    /// know of, and is left alone; so is a DM device whose table differs.
+    pub fn repairable(&self) -> bool {
+        match *self {
+            Discrepancy::StaleLink { .. }
+            | Discrepancy::MissingLink { .. }
+            | Discrepancy::OrphanedDmDevice { .. } => true,
+            Discrepancy::MetadataSequenceMismatch {
+                on_disk, in_memory, ..
+            } => on_disk < in_memory,
+            Discrepancy::MissingDmDevice { .. }
+            | Discrepancy::DmTableMismatch { .. }
+            | Discrepancy::BdaMismatch { .. }
+            | Discrepancy::CorruptThinMetadata { .. } => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CheckFinding {
+    pub discrepancy: Discrepancy,
+    /// True if a repair was made and the discrepancy is no longer found.
+    pub repaired: bool,
+}
+
+/// Make findings of the discrepancies found before any repair. If repairs
+/// were made, after holds the discrepancies found afterwards, and each of
+/// before not among them was repaired.
+pub fn findings(before: Vec<Discrepancy>, after: Option<&[Discrepancy]>) -> Vec<CheckFinding> {
+    before
+        .into_iter()
+        .map(|discrepancy| {
+            let repaired = match after {
+                Some(after) => !after.contains(&discrepancy),
+                None => false,
+            };
+            CheckFinding {
+                discrepancy,
+                repaired,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    /// A discrepancy is repaired only if a repair was made and the
+    /// discrepancy is not found afterwards.
+    fn repaired_findings() {
+        let stale = Discrepancy::StaleLink {
+            path: PathBuf::from("/dev/stratis/gone"),
+        };
+        let missing = Discrepancy::MissingDmDevice {
+            pool_uuid: Uuid::new_v4(),
+            name: "stratis-1-private".into(),
+        };
+        assert!(stale.repairable());
+        assert!(!missing.repairable());
+
+        let before = vec![stale.clone(), missing.clone()];
+        assert!(findings(before.clone(), None)
+            .iter()
+            .all(|finding| !finding.repaired));
+        assert_eq!(
+            findings(before, Some(&[missing]))
+                .into_iter()
+                .map(|finding| finding.repaired)
+                .collect::<Vec<_>>(),
+            vec![true, false]
+        );
+    }
+}
@@ -8,14 +8,23 @@ use std::os::unix::fs::symlink;
 use std::path::{Path, PathBuf};
 use std::{fs, str};
 
+use crate::engine::check::Discrepancy;
 use crate::engine::Pool;
-use crate::stratis::StratisResult;
+use crate::stratis::{ErrorEnum, StratisError, StratisResult};
 
 use crate::engine::engine::DEV_PATH;
-use crate::engine::types::{Name, PoolUuid};
+use crate::engine::types::{FilesystemUuid, Name, PoolUuid};
 
-/// Set up the root Stratis directory, where dev links as well as temporary
-/// MDV mounts will be created. This must occur before any pools are setup.
+/// The fstab options which make the mount of a Stratis filesystem wait for
+/// stratisd, which sets up the filesystem's device.
+pub const FSTAB_OPTIONS: &str = "defaults,x-systemd.requires=stratisd.service";
+
+/// The root of the tree of links kept by versions of stratisd before it was
+/// moved to DEV_PATH.
+const LEGACY_DEV_PATH: &str = "/stratis";
+
+/// Set up the root Stratis directory, where dev links will be created. This
+/// must occur before any pools are setup.
 pub fn setup_dev_path() -> StratisResult<()> {
     if let Err(err) = fs::create_dir(DEV_PATH) {
         if err.kind() != ErrorKind::AlreadyExists {
@@ -23,10 +32,49 @@ pub fn setup_dev_path() -> StratisResult<()> {
         }
     }
 
+    if let Err(err) = migrate_legacy_dev_path(Path::new(LEGACY_DEV_PATH), Path::new(DEV_PATH)) {
+        warn!(
+            "unable to replace the links in {} with a link to {}, reason {:?}",
+            LEGACY_DEV_PATH, DEV_PATH, err
+        );
+    }
+
+    Ok(())
+}
+
+/// Remove the tree of pool directories and filesystem links at legacy, left
+/// by an earlier version of stratisd, and put a symlink to dev_path in its
+/// place, so that paths in the old tree, e.g., in fstab, still resolve.
+/// Only symlinks, and the directories that held them, are removed, so a
+/// directory holding anything else, e.g., a mounted MDV, is left as it is,
+/// and no symlink is made.
+fn migrate_legacy_dev_path(legacy: &Path, dev_path: &Path) -> StratisResult<()> {
+    match fs::symlink_metadata(legacy) {
+        Ok(ref metadata) if metadata.is_dir() => (),
+        Ok(_) => return Ok(()),
+        Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(From::from(err)),
+    }
+
+    for entry in fs::read_dir(legacy)? {
+        let pool_path = entry?.path();
+        if fs::symlink_metadata(&pool_path)?.is_dir() {
+            for entry in fs::read_dir(&pool_path)? {
+                let path = entry?.path();
+                if fs::symlink_metadata(&path)?.file_type().is_symlink() {
+                    fs::remove_file(&path)?;
+                }
+            }
+            fs::remove_dir(&pool_path)?;
+        }
+    }
+    fs::remove_dir(legacy)?;
+    symlink(dev_path, legacy)?;
+
     Ok(())
 }
 
-/// Setup the pool directory and the symlinks in /stratis for the specified pool and filesystems
+/// Setup the pool directory and the symlinks in DEV_PATH for the specified pool and filesystems
 /// it contains.
 // Don't just remove and recreate everything in case there are processes
 // (e.g. user shells) with the current working directory within the tree.
@@ -56,13 +104,14 @@ pub fn setup_pool_devlinks(pool_name: &str, pool: &dyn Pool) {
         Ok(())
     }() {
         warn!(
-            "setup_pool_devlinks failed for /stratis/{}, reason {:?}",
-            pool_name, err
+            "setup_pool_devlinks failed for {:?}, reason {:?}",
+            pool_directory(pool_name),
+            err
         );
     };
 }
 
-/// Clean up directories and symlinks under /stratis based on current
+/// Clean up directories and symlinks under DEV_PATH based on current
 /// config. Clear out any directory or file that doesn't correspond to a pool.
 // Don't just remove everything in case there are processes
 // (e.g. user shells) with the current working directory within the tree.
@@ -88,6 +137,54 @@ pub fn cleanup_devlinks<'a, I: Iterator<Item = &'a (Name, PoolUuid, &'a dyn Pool
     }
 }
 
+/// Compare the directories and symlinks under DEV_PATH with pools and their
+/// filesystems, returning the differences. cleanup_devlinks() and
+/// setup_pool_devlinks() repair them.
+pub fn check_devlinks(pools: &[(Name, PoolUuid, &dyn Pool)]) -> StratisResult<Vec<Discrepancy>> {
+    let mut found = Vec::new();
+
+    for entry in fs::read_dir(DEV_PATH)? {
+        let dir_name = entry?.file_name().into_string().expect("Unix is utf-8");
+        if !pools
+            .iter()
+            .any(|(pool_name, _, _)| **pool_name == dir_name)
+        {
+            found.push(Discrepancy::StaleLink {
+                path: pool_directory(dir_name),
+            });
+        }
+    }
+
+    for (pool_name, _, pool) in pools {
+        let mut existing_files = match fs::read_dir(pool_directory(&**pool_name)) {
+            Ok(entries) => entries
+                .map(|dir_e| {
+                    dir_e.and_then(|d| Ok(d.file_name().into_string().expect("Unix is utf-8")))
+                })
+                .collect::<Result<HashSet<_>, _>>()?,
+            Err(ref err) if err.kind() == ErrorKind::NotFound => HashSet::new(),
+            Err(err) => return Err(From::from(err)),
+        };
+
+        for (fs_name, _, fs) in pool.filesystems() {
+            existing_files.remove(&fs_name.to_owned());
+            let path = filesystem_mount_path(&**pool_name, &*fs_name);
+            let target = fs.devnode();
+            if fs::read_link(&path).ok().as_ref() != Some(&target) {
+                found.push(Discrepancy::MissingLink { path, target });
+            }
+        }
+
+        for leftover in existing_files {
+            found.push(Discrepancy::StaleLink {
+                path: filesystem_mount_path(&**pool_name, leftover.as_str()),
+            });
+        }
+    }
+
+    Ok(found)
+}
+
 /// Create a directory when a pool is added.
 pub fn pool_added(pool: &str) {
     let p = pool_directory(pool);
@@ -167,3 +264,134 @@ pub fn filesystem_mount_path<T: AsRef<str>>(pool_name: T, fs_name: T) -> PathBuf
         .iter()
         .collect()
 }
+
+/// An fstab entry which mounts the filesystem of pool with the given UUID
+/// on mount_point. The filesystem is identified by its UUID, which is also
+/// the UUID of its XFS filesystem, rather than by its link in DEV_PATH,
+/// because systemd can wait for udev to create a UUID link, but not a
+/// DEV_PATH link.
+/// Returns an error if the filesystem shares its XFS UUID with another
+/// filesystem, because one is a snapshot of the other that kept its XFS
+/// UUID, since then the UUID does not identify the filesystem.
+pub fn fstab_entry(
+    pool: &dyn Pool,
+    fs_uuid: FilesystemUuid,
+    mount_point: &Path,
+) -> StratisResult<String> {
+    let fs = match pool.get_filesystem(fs_uuid) {
+        Some((_, fs)) => fs,
+        None => {
+            return Err(StratisError::Engine(
+                ErrorEnum::NotFound,
+                fs_uuid.to_string(),
+            ))
+        }
+    };
+    if fs.shared_xfs_uuid().is_some()
+        || pool
+            .filesystems()
+            .iter()
+            .any(|&(_, _, fs)| fs.shared_xfs_uuid() == Some(fs_uuid))
+    {
+        let err_msg = format!(
+            "filesystem {} shares its XFS UUID with a snapshot or origin; it can not be identified by UUID in fstab",
+            fs_uuid
+        );
+        return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+    }
+
+    // Whitespace in an fstab field must be written as an octal escape.
+    let mount_point = mount_point
+        .display()
+        .to_string()
+        .replace('\\', "\\134")
+        .replace(' ', "\\040")
+        .replace('\t', "\\011");
+    Ok(format!(
+        "UUID={} {} xfs {} 0 0",
+        fs_uuid, mount_point, FSTAB_OPTIONS
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile;
+
+    use crate::engine::engine::create_pool_with_defaults;
+    use crate::engine::{Engine, SimEngine};
+
+    use super::*;
+
+    #[test]
+    /// The links in the legacy tree are replaced by a link to the new
+    /// tree, unless the legacy tree holds something other than links.
+    fn test_migrate_legacy_dev_path() {
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("stratis_testing")
+            .tempdir()
+            .unwrap();
+        let legacy = tmp_dir.path().join("stratis");
+        let dev_path = tmp_dir.path().join("dev_stratis");
+        fs::create_dir(&dev_path).unwrap();
+
+        migrate_legacy_dev_path(&legacy, &dev_path).unwrap();
+        assert!(fs::symlink_metadata(&legacy).is_err());
+
+        fs::create_dir_all(legacy.join("pool")).unwrap();
+        symlink("/dev/dm-0", legacy.join("pool").join("fs")).unwrap();
+        fs::create_dir(legacy.join("other")).unwrap();
+        fs::write(legacy.join("other").join("file"), b"").unwrap();
+        assert!(migrate_legacy_dev_path(&legacy, &dev_path).is_err());
+        assert!(legacy.join("other").join("file").exists());
+        assert!(!fs::symlink_metadata(&legacy)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+
+        fs::remove_file(legacy.join("other").join("file")).unwrap();
+        migrate_legacy_dev_path(&legacy, &dev_path).unwrap();
+        assert_eq!(fs::read_link(&legacy).unwrap(), dev_path);
+
+        // Once replaced, the link is left alone.
+        migrate_legacy_dev_path(&legacy, &dev_path).unwrap();
+        assert_eq!(fs::read_link(&legacy).unwrap(), dev_path);
+    }
+
+    #[test]
+    /// An fstab entry escapes whitespace in the mount point and requires
+    /// stratisd. There is no entry for a snapshot that kept its origin's
+    /// XFS UUID, nor for the origin.
+    fn test_fstab_entry() {
+        let mut engine = SimEngine::default();
+        let pool_uuid = create_pool_with_defaults(&mut engine, "pool", &[], None).unwrap();
+        let pool = engine.get_mut_pool(pool_uuid).unwrap().1;
+        let fs_uuid = pool
+            .create_filesystems(pool_uuid, "pool", &[("fs", None)])
+            .unwrap()[0]
+            .1;
+        let copy_uuid = pool
+            .snapshot_filesystem(pool_uuid, "pool", fs_uuid, "copy", false, false)
+            .unwrap()
+            .0;
+
+        assert_eq!(
+            fstab_entry(pool, fs_uuid, Path::new("/mnt/my data")).unwrap(),
+            format!(
+                "UUID={} /mnt/my\\040data xfs defaults,x-systemd.requires=stratisd.service 0 0",
+                fs_uuid
+            )
+        );
+        assert!(fstab_entry(pool, copy_uuid, Path::new("/mnt/copy")).is_ok());
+
+        let snapshot_uuid = pool
+            .snapshot_filesystem(pool_uuid, "pool", fs_uuid, "snap", true, false)
+            .unwrap()
+            .0;
+        for uuid in &[fs_uuid, snapshot_uuid] {
+            assert_matches!(
+                fstab_entry(pool, *uuid, Path::new("/mnt/snap")),
+                Err(StratisError::Engine(ErrorEnum::Invalid, _))
+            );
+        }
+    }
+}
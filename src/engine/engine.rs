@@ -5,21 +5,31 @@
 use std::fmt::Debug;
 use std::os::unix::io::RawFd;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 use chrono::{DateTime, Utc};
+use serde_json::Value;
 use uuid::Uuid;
 
-use devicemapper::{Bytes, Device, Sectors};
+use devicemapper::{Bytes, Device, MetaBlocks, Sectors};
 
 use crate::engine::{
-    BlockDevState, BlockDevTier, DevUuid, FilesystemUuid, MaybeDbusPath, Name, PoolUuid,
-    RenameAction,
+    AllocationStrategy, BlockDevState, BlockDevTier, ChunkSize, DevUuid, FilesystemTree,
+    FilesystemUsage, FilesystemUuid, MaybeDbusPath, Name, OverprovisionPolicy, PoolId, PoolPlan,
+    PoolSpaceReport, PoolUuid, RenameAction, SimDevProperties, SimFailureModel, SimOperation,
+    ThinRepairSummary,
 };
 use crate::stratis::StratisResult;
 
+use crate::engine::check::CheckFinding;
+use crate::engine::jobs::{JobKind, JobStatus, JobUuid};
+use crate::engine::reconcile::{PoolSpec, ReconcileAction};
+use crate::engine::records::{BlockDevRecord, FilesystemRecord, PoolRecord};
 use crate::engine::types::{FreeSpaceState, PoolExtendState, PoolState};
 
-pub const DEV_PATH: &str = "/stratis";
+/// The root of the tree of stable device paths for pools and filesystems,
+/// laid out as DEV_PATH/<pool name>/<filesystem name>.
+pub const DEV_PATH: &str = "/dev/stratis";
 
 pub trait Filesystem: Debug {
     /// path of the device node
@@ -28,9 +38,63 @@ pub trait Filesystem: Debug {
     /// When the filesystem was created.
     fn created(&self) -> DateTime<Utc>;
 
+    /// The UUID of the filesystem this filesystem is a snapshot of, if it
+    /// is a snapshot. If the origin was destroyed, or was a snapshot merged
+    /// into its own origin, this is the origin of the origin, if any.
+    fn origin(&self) -> Option<FilesystemUuid>;
+
+    /// The UUID of the filesystem's XFS filesystem, if it is not the
+    /// filesystem's own UUID because the filesystem is a snapshot that kept
+    /// the XFS UUID of its origin. Other filesystems may have the same XFS
+    /// UUID.
+    fn shared_xfs_uuid(&self) -> Option<FilesystemUuid>;
+
+    /// The size of the filesystem when it was created, whether requested or
+    /// the default. None if the filesystem was created before its initial
+    /// size was recorded.
+    fn initial_size(&self) -> Option<Sectors>;
+
+    /// The size beyond which the filesystem is not extended, if any.
+    fn size_limit(&self) -> Option<Sectors>;
+
+    /// The most snapshots that may be taken of the filesystem, if it has a
+    /// limit of its own rather than following the pool's default.
+    fn snapshot_limit(&self) -> Option<u64>;
+
+    /// Get the user-settable string associated with this filesystem.
+    fn user_info(&self) -> Option<&str>;
+
+    /// Whether the filesystem is marked read-only.
+    fn read_only(&self) -> bool;
+
+    /// The interval at which the filesystem is trimmed while it is mounted,
+    /// if it is trimmed periodically.
+    fn trim_interval(&self) -> Option<Duration>;
+
+    /// If the filesystem is trimmed periodically, the time at which it is
+    /// next due to be trimmed.
+    fn trim_deadline(&self) -> Option<Instant>;
+
+    /// Discard the blocks the filesystem is not using, returning their space
+    /// to the pool. A call trims only the next part of a large filesystem,
+    /// so that it does not take long; the filesystem remains due to be
+    /// trimmed, by trim_deadline(), until the last part has been. Then the
+    /// next periodic trim is scheduled.
+    /// Returns the number of bytes discarded, or None if the filesystem is
+    /// not mounted or is read-only, in which case nothing is done.
+    fn trim(&mut self) -> StratisResult<Option<Bytes>>;
+
+    /// The smallest size to which the filesystem's device can be shrunk
+    /// without cutting off any of the filesystem on it.
+    fn min_size(&self) -> StratisResult<Sectors>;
+
     /// The amount of data stored on the filesystem, including overhead.
     fn used(&self) -> StratisResult<Bytes>;
 
+    /// The size of the filesystem, the space allocated to it, and, if it is
+    /// mounted, the space the filesystem itself reports.
+    fn usage(&self) -> StratisResult<FilesystemUsage>;
+
     /// Set dbus path associated with the Pool.
     fn set_dbus_path(&mut self, path: MaybeDbusPath) -> ();
 
@@ -42,6 +106,11 @@ pub trait BlockDev: Debug {
     /// Get the path of the device node for this device.
     fn devnode(&self) -> PathBuf;
 
+    /// Get a path to this device which is stable across reboots, e.g., a
+    /// /dev/disk/by-id link, for reporting purposes. If there is no such
+    /// path, the path of the device node.
+    fn persistent_devnode(&self) -> PathBuf;
+
     /// Get the user-settable string associated with this blockdev.
     fn user_info(&self) -> Option<&str>;
 
@@ -58,6 +127,12 @@ pub trait BlockDev: Debug {
     /// The current state of the blockdev.
     fn state(&self) -> BlockDevState;
 
+    /// Whether the blockdev has been taken offline for maintenance.
+    fn in_maintenance(&self) -> bool;
+
+    /// Whether the blockdev is rotational, None if this is not known.
+    fn rotational(&self) -> Option<bool>;
+
     /// Set dbus path associated with the BlockDev.
     fn set_dbus_path(&mut self, path: MaybeDbusPath) -> ();
 
@@ -78,6 +153,20 @@ pub trait Pool: Debug {
         specs: &[(&'b str, Option<Sectors>)],
     ) -> StratisResult<Vec<(&'b str, FilesystemUuid)>>;
 
+    /// Creates the filesystems specified by specs, in order, each
+    /// independently of the others, so that a failure to create one does
+    /// not prevent the creation of the rest.
+    /// Returns, for each item of specs, its name and either the UUID of the
+    /// filesystem created or the reason it was not created, e.g., that its
+    /// name is already in use, possibly by an earlier item.
+    /// Returns an error only if the pool can not create filesystems at all.
+    fn create_filesystems_each<'b>(
+        &mut self,
+        pool_uuid: PoolUuid,
+        pool_name: &str,
+        specs: &[(&'b str, Option<Sectors>)],
+    ) -> StratisResult<Vec<(&'b str, StratisResult<FilesystemUuid>)>>;
+
     /// Adds blockdevs specified by paths to pool.
     /// Returns a list of uuids corresponding to devices actually added.
     /// Returns an error if a blockdev can not be added because it is owned
@@ -98,7 +187,8 @@ pub trait Pool: Debug {
     /// Ensures that all designated filesystems are gone from pool.
     /// Returns a list of the filesystems found, and actually destroyed.
     /// This list will be a subset of the uuids passed in fs_uuids.
-    /// Precondition: All filesystems given must be unmounted.
+    /// Returns an error, without destroying it or any filesystem after it,
+    /// on reaching a filesystem that is mounted.
     fn destroy_filesystems<'a>(
         &'a mut self,
         pool_name: &str,
@@ -119,14 +209,133 @@ pub trait Pool: Debug {
 
     /// Snapshot filesystem
     /// Create a CoW snapshot of the origin
+    /// The snapshot's XFS filesystem is given a new UUID, so that it can be
+    /// mounted alongside the origin, unless keep_xfs_uuid is true.
+    /// A mounted origin is always frozen while the snapshot is taken, as
+    /// suspending its device freezes it, so the snapshot is clean. If freeze
+    /// is true, the origin is frozen before its device is suspended, and the
+    /// snapshot fails if that freeze does not complete within a time limit,
+    /// rather than waiting on the suspend for as long as it takes.
     fn snapshot_filesystem(
         &mut self,
         pool_uuid: PoolUuid,
         pool_name: &str,
         origin_uuid: FilesystemUuid,
         snapshot_name: &str,
+        keep_xfs_uuid: bool,
+        freeze: bool,
     ) -> StratisResult<(FilesystemUuid, &mut dyn Filesystem)>;
 
+    /// Revert the filesystem with the given UUID to the contents of the
+    /// snapshot with snapshot_uuid. The filesystem keeps its name and UUID.
+    /// Returns an error if the snapshot is not a snapshot of the filesystem,
+    /// if either is mounted, or if the filesystem is read-only.
+    fn revert_filesystem(
+        &mut self,
+        pool_uuid: PoolUuid,
+        pool_name: &str,
+        uuid: FilesystemUuid,
+        snapshot_uuid: FilesystemUuid,
+    ) -> StratisResult<()>;
+
+    /// Merge the snapshot with snapshot_uuid back into the filesystem it is
+    /// a snapshot of, discarding the changes made to that filesystem since
+    /// the snapshot was taken. The filesystem keeps its name and UUID, and
+    /// the snapshot is removed. Snapshots of the snapshot become snapshots
+    /// of the filesystem.
+    /// Returns the UUID of the filesystem merged into.
+    /// Returns an error if the snapshot has no origin, if either it or its
+    /// origin is mounted, or if the origin is read-only.
+    fn merge_snapshot(
+        &mut self,
+        pool_uuid: PoolUuid,
+        pool_name: &str,
+        snapshot_uuid: FilesystemUuid,
+    ) -> StratisResult<FilesystemUuid>;
+
+    /// Extend the filesystem with the given UUID by extend_size. The XFS
+    /// filesystem is grown to fill the extended device, being mounted
+    /// temporarily to do so if it is not mounted.
+    /// Returns an error if the filesystem is read-only, or if its size limit
+    /// or the pool's overprovisioning limit would be exceeded.
+    fn extend_filesystem(
+        &mut self,
+        pool_name: &str,
+        uuid: FilesystemUuid,
+        extend_size: Sectors,
+    ) -> StratisResult<()>;
+
+    /// Set the user-settable string associated with the filesystem specified
+    /// by the uuid.
+    /// Returns true if it changed, otherwise false.
+    fn set_filesystem_user_info(
+        &mut self,
+        pool_name: &str,
+        uuid: FilesystemUuid,
+        user_info: Option<&str>,
+    ) -> StratisResult<bool>;
+
+    /// Shrink the device of the filesystem with the given UUID to new_size,
+    /// which no longer counts against the pool's overprovisioning limit.
+    /// The filesystem on the device is not shrunk, so new_size must be at
+    /// least the filesystem's minimum size.
+    /// Returns an error if new_size is too small or not less than the
+    /// current size, or if the filesystem is mounted or read-only.
+    fn shrink_filesystem(
+        &mut self,
+        pool_name: &str,
+        uuid: FilesystemUuid,
+        new_size: Sectors,
+    ) -> StratisResult<()>;
+
+    /// Mark the filesystem with the given UUID read-only, or writable again.
+    /// The thin device of a read-only filesystem is read-only, so it can
+    /// only be mounted read-only, and it is neither extended nor reverted.
+    /// Returns true if the flag changed, otherwise false.
+    /// Returns an error if the filesystem is mounted when it is to be made
+    /// read-only.
+    fn set_filesystem_read_only(
+        &mut self,
+        pool_name: &str,
+        uuid: FilesystemUuid,
+        read_only: bool,
+    ) -> StratisResult<bool>;
+
+    /// Set the size beyond which the filesystem with the given UUID is not
+    /// extended as it fills. If limit is None, the filesystem may be
+    /// extended without limit.
+    /// Returns true if the limit changed, otherwise false.
+    /// Returns an error if the limit is less than the filesystem's size.
+    fn set_filesystem_size_limit(
+        &mut self,
+        pool_name: &str,
+        uuid: FilesystemUuid,
+        limit: Option<Sectors>,
+    ) -> StratisResult<bool>;
+
+    /// Set the most snapshots that may be taken of the filesystem with the
+    /// given UUID. If limit is None, the pool's default limit applies.
+    /// Snapshots already taken are kept; the limit applies only to new ones.
+    /// Returns true if the limit changed, otherwise false.
+    fn set_filesystem_snapshot_limit(
+        &mut self,
+        pool_name: &str,
+        uuid: FilesystemUuid,
+        limit: Option<u64>,
+    ) -> StratisResult<bool>;
+
+    /// Set the interval at which the filesystem with the given UUID is
+    /// trimmed while it is mounted. If interval is None, the filesystem is
+    /// not trimmed periodically.
+    /// Returns true if the interval changed, otherwise false.
+    /// Returns an error if the interval is 0.
+    fn set_filesystem_trim_interval(
+        &mut self,
+        pool_name: &str,
+        uuid: FilesystemUuid,
+        interval: Option<Duration>,
+    ) -> StratisResult<bool>;
+
     /// The total number of Sectors belonging to this pool.
     /// There are no exclusions, so this number includes overhead sectors
     /// of all sorts, sectors allocated for every sort of metadata by
@@ -140,6 +349,13 @@ pub trait Pool: Debug {
     /// or to reserve for some other purpose.
     fn total_physical_used(&self) -> StratisResult<Sectors>;
 
+    /// A detailed account of the use of the space in this pool.
+    fn space_report(&self) -> StratisResult<PoolSpaceReport>;
+
+    /// The filesystems belonging to this pool, arranged in trees that show
+    /// which filesystems are snapshots of which.
+    fn filesystem_trees(&self) -> Vec<FilesystemTree>;
+
     /// Get all the filesystems belonging to this pool.
     fn filesystems(&self) -> Vec<(Name, FilesystemUuid, &dyn Filesystem)>;
 
@@ -174,6 +390,57 @@ pub trait Pool: Debug {
         user_info: Option<&str>,
     ) -> StratisResult<bool>;
 
+    /// Take the blockdev specified by the uuid offline for maintenance, or
+    /// bring it back online. While offline, no metadata is written to the
+    /// blockdev; when it is brought back online, it is resynchronized with
+    /// the current pool metadata.
+    /// Returns an error if the blockdev holds data that can not be
+    /// redirected elsewhere given the pool's redundancy.
+    /// Returns true if the maintenance state was changed, otherwise false.
+    fn set_blockdev_maintenance(
+        &mut self,
+        pool_name: &str,
+        uuid: DevUuid,
+        maintenance: bool,
+    ) -> StratisResult<bool>;
+
+    /// Move all the data allocated from the data tier blockdev specified by
+    /// the uuid onto the pool's other data tier blockdevs. The blockdev
+    /// remains in the pool, but holds no data, e.g., so that it may be taken
+    /// out of service. I/O to the pool is suspended while data is copied.
+    /// Returns true if any data was moved, otherwise false.
+    /// Returns an error if the blockdev is not in the data tier or if the
+    /// other blockdevs do not have space for its data.
+    fn drain_blockdev(
+        &mut self,
+        pool_uuid: PoolUuid,
+        pool_name: &str,
+        uuid: DevUuid,
+    ) -> StratisResult<bool>;
+
+    /// Reserve a snapshot of the pool's thin provisioning metadata, so that
+    /// external tools, e.g., thin_dump, can read a consistent view of it
+    /// while the pool is in use.
+    /// Returns the devnode of the device on which the metadata is stored and
+    /// the block at which the root of the snapshot is located.
+    /// If a snapshot is already reserved, returns that snapshot.
+    fn reserve_metadata_snapshot(&mut self) -> StratisResult<(PathBuf, MetaBlocks)>;
+
+    /// Release the pool's reserved metadata snapshot.
+    /// Returns true if a snapshot was released, false if none was reserved.
+    fn release_metadata_snapshot(&mut self) -> StratisResult<bool>;
+
+    /// Write a backup of the pool's metadata to a new file, named by the
+    /// pool's UUID and the current time, to the nanosecond, in the
+    /// directory dir.
+    /// Returns the path of the file.
+    fn backup_metadata(
+        &self,
+        pool_uuid: PoolUuid,
+        pool_name: &str,
+        dir: &Path,
+    ) -> StratisResult<PathBuf>;
+
     /// The current state of the Pool.
     fn state(&self) -> PoolState;
 
@@ -183,6 +450,102 @@ pub trait Pool: Debug {
     /// The current space state of the Pool.
     fn free_space_state(&self) -> FreeSpaceState;
 
+    /// The pool's policy for the total size of its filesystems relative to
+    /// its physical capacity.
+    fn overprovision_policy(&self) -> OverprovisionPolicy;
+
+    /// The strategy by which the pool allocates space from its data devices.
+    fn allocation_strategy(&self) -> AllocationStrategy;
+
+    /// Set the strategy by which the pool allocates space from its data
+    /// devices. It applies to all subsequent allocations; space that is
+    /// already allocated is not moved. It is therefore best chosen
+    /// immediately after the pool is created.
+    /// Returns true if the strategy changed, otherwise false.
+    fn set_allocation_strategy(
+        &mut self,
+        pool_name: &str,
+        strategy: AllocationStrategy,
+    ) -> StratisResult<bool>;
+
+    /// Flush and suspend all of the pool's filesystems and devices, so that
+    /// the storage beneath the pool can be captured in a consistent state.
+    /// Operations that would change the pool are refused while it is
+    /// quiesced. The pool must be resumed by the time timeout has elapsed;
+    /// stratisd resumes it then if it has not already been resumed.
+    /// Returns true if the pool was quiesced, false if it already was.
+    fn quiesce(&mut self, timeout: Duration) -> StratisResult<bool>;
+
+    /// Resume a quiesced pool.
+    /// Returns true if the pool was quiesced, otherwise false.
+    fn unquiesce(&mut self) -> StratisResult<bool>;
+
+    /// If the pool is quiesced, the time at which it must be resumed.
+    fn quiesce_deadline(&self) -> Option<Instant>;
+
+    /// If the pool is in read-only mode, the reason it entered it.
+    /// A pool enters read-only mode if its metadata can not be written to
+    /// enough of its devices or if its thin pool reports errors. Operations
+    /// which would change a pool in read-only mode are refused, but the data
+    /// in its filesystems remains accessible.
+    fn read_only_reason(&self) -> Option<&str>;
+
+    /// Take the pool out of read-only mode, e.g., once the problem that
+    /// caused it to enter read-only mode has been repaired.
+    /// Returns true if the pool was in read-only mode, otherwise false.
+    fn clear_read_only(&mut self) -> bool;
+
+    /// Set the pool's overprovisioning policy.
+    /// Returns true if the policy changed, otherwise false.
+    /// Returns an error if the policy specifies a ratio of 0.
+    fn set_overprovision_policy(
+        &mut self,
+        pool_name: &str,
+        policy: OverprovisionPolicy,
+    ) -> StratisResult<bool>;
+
+    /// The chunk size of the pool's thin pool, the unit in which space is
+    /// allocated to the pool's filesystems and shared between snapshots.
+    fn chunk_size(&self) -> Sectors;
+
+    /// The percentage of the pool's usable capacity held in reserve.
+    fn reserved_pct(&self) -> u8;
+
+    /// Set the percentage of the pool's usable capacity held in reserve.
+    /// Space in the reserve is not used for the data of the pool's
+    /// filesystems, but remains available for growth of the thin pool's
+    /// metadata and for recovery. Space already allocated to the thin pool
+    /// is not returned to the reserve, so a reserve set after the pool is
+    /// created is filled from space added to the pool later.
+    /// Returns true if the percentage changed, otherwise false.
+    /// Returns an error if the percentage exceeds 50.
+    fn set_reserved_pct(&mut self, pool_name: &str, pct: u8) -> StratisResult<bool>;
+
+    /// The largest number of filesystems, snapshots included, that the pool
+    /// may hold. Creating a filesystem or snapshot that would exceed it is
+    /// refused.
+    fn filesystem_limit(&self) -> u64;
+
+    /// Set the largest number of filesystems that the pool may hold. If
+    /// limit is None, the pool's default limit is restored, which is computed
+    /// from the space available for the thin pool's metadata.
+    /// Returns true if the limit changed, otherwise false.
+    /// Returns an error if the limit is 0 or less than the number of
+    /// filesystems already in the pool.
+    fn set_filesystem_limit(&mut self, pool_name: &str, limit: Option<u64>) -> StratisResult<bool>;
+
+    /// The most snapshots that may be taken of each filesystem in the pool
+    /// that has no limit of its own, if any. Taking a snapshot that would
+    /// exceed its origin's limit is refused.
+    fn snapshot_limit(&self) -> Option<u64>;
+
+    /// Set the most snapshots that may be taken of each filesystem in the
+    /// pool that has no limit of its own. If limit is None, such
+    /// filesystems may be snapshotted without limit. Snapshots already taken
+    /// are kept; the limit applies only to new ones.
+    /// Returns true if the limit changed, otherwise false.
+    fn set_snapshot_limit(&mut self, pool_name: &str, limit: Option<u64>) -> StratisResult<bool>;
+
     /// Set dbus path associated with the Pool.
     fn set_dbus_path(&mut self, path: MaybeDbusPath) -> ();
 
@@ -191,15 +554,47 @@ pub trait Pool: Debug {
 }
 
 pub trait Engine: Debug {
-    /// Create a Stratis pool.
+    /// Create a Stratis pool, with a chunk size chosen according to
+    /// chunk_size. The chunk size can not be changed later. The pool's
+    /// data is allocated according to allocation from the start; the
+    /// strategy may be changed later with Pool::set_allocation_strategy().
     /// Returns the UUID of the newly created pool.
     /// Returns an error if the redundancy code does not correspond to a
-    /// supported redundancy.
+    /// supported redundancy, or if an explicitly given chunk size is invalid.
     fn create_pool(
         &mut self,
         name: &str,
         blockdev_paths: &[&Path],
         redundancy: Option<u16>,
+        chunk_size: ChunkSize,
+        allocation: AllocationStrategy,
+    ) -> StratisResult<PoolUuid>;
+
+    /// Check whether a pool could be created as create_pool() would create
+    /// it, without writing to any of the devices.
+    /// Returns the layout the pool would have.
+    /// Returns an error in any case in which create_pool() would fail to
+    /// create the pool.
+    fn plan_pool(
+        &self,
+        name: &str,
+        blockdev_paths: &[&Path],
+        redundancy: Option<u16>,
+        chunk_size: ChunkSize,
+    ) -> StratisResult<PoolPlan>;
+
+    /// Create a new pool on blockdev_paths, with its own UUIDs, that holds
+    /// a copy of every filesystem in the pool with the given UUID. The new
+    /// pool has the redundancy and chunk size of the original.
+    /// Returns the UUID of the new pool.
+    /// Returns an error if the original pool does not exist, if any of its
+    /// filesystems is mounted, or if the devices are too small to hold the
+    /// data that the original pool has in use.
+    fn clone_pool(
+        &mut self,
+        uuid: PoolUuid,
+        name: &str,
+        blockdev_paths: &[&Path],
     ) -> StratisResult<PoolUuid>;
 
     /// Evaluate a device node & devicemapper::Device to see if it's a valid
@@ -216,6 +611,76 @@ pub trait Engine: Debug {
     /// Returns true if some action was necessary, otherwise false.
     fn destroy_pool(&mut self, uuid: PoolUuid) -> StratisResult<bool>;
 
+    /// Export a pool, so that its devices can be moved to another host.
+    /// The pool is torn down and marked as exported in its metadata; it is
+    /// not set up again, on this host or any other, until it is imported.
+    /// Returns an error if any of the pool's filesystems is mounted.
+    /// Returns true if the pool was exported, false if there is no such pool.
+    fn export_pool(&mut self, uuid: PoolUuid) -> StratisResult<bool>;
+
+    /// Stop a pool, tearing down its devicemapper devices without changing
+    /// its metadata, so that its devices may be worked on while stratisd
+    /// is running. A stopped pool is not set up again until it is started,
+    /// even if its devices are found again.
+    /// Returns an error if any of the pool's filesystems is mounted.
+    /// Returns true if the pool was stopped, false if there is no such pool.
+    fn stop_pool(&mut self, uuid: PoolUuid) -> StratisResult<bool>;
+
+    /// Start a pool that is not set up, e.g., because it was stopped, or
+    /// because its thin metadata could not be repaired and has since been
+    /// repaired by other means, if all its devices have been found.
+    /// A pool that was last set up on another host, e.g., because this
+    /// host's machine ID has changed since, is not set up unless force is
+    /// true, in which case it is claimed for this host, as by import_pool().
+    /// Returns an error if the pool is not found or can not be set up.
+    /// Returns true if the pool was started, false if it is already set up.
+    fn start_pool(&mut self, uuid: PoolUuid, force: bool) -> StratisResult<bool>;
+
+    /// Remove the record of a device that has been permanently lost from
+    /// the metadata of a pool that can not be set up without it. If the
+    /// pool is then complete, it is set up.
+    /// Returns an error if the device has been found, or if any of the
+    /// pool's data is allocated from the device and would therefore be lost.
+    /// Returns true if the device was forgotten, false if the pool has no
+    /// record of it.
+    fn forget_missing_device(
+        &mut self,
+        pool_uuid: PoolUuid,
+        dev_uuid: DevUuid,
+    ) -> StratisResult<bool>;
+
+    /// Import a pool that has been found but is not set up, e.g., because
+    /// it was exported, and set it up. The pool is claimed for this host
+    /// and is no longer marked as exported.
+    /// Returns an error if the pool is not found, if a name matches more
+    /// than one such pool, or if any of the pool's devices are missing.
+    /// Unless force is true, also returns an error if the pool was not
+    /// exported and was last set up on another host. A pool that was last
+    /// set up on this host before its machine ID changed is also taken to
+    /// belong to another host, and is imported by force.
+    /// Returns the UUID of the imported pool.
+    fn import_pool(&mut self, id: &PoolId, force: bool) -> StratisResult<PoolUuid>;
+
+    /// Restore the metadata of a pool which is not set up from a backup
+    /// written by Pool::backup_metadata(), writing it to all of the pool's
+    /// devices, and then import the pool.
+    /// Returns an error if the pool is already set up or if any of the
+    /// devices recorded in the backup are missing. Unless force is true,
+    /// also returns an error if the metadata on the pool's devices is more
+    /// recent than the backup.
+    /// Returns the UUID of the restored pool.
+    fn restore_pool_metadata(&mut self, backup: &Path, force: bool) -> StratisResult<PoolUuid>;
+
+    /// Repair the thin metadata of a pool which is not set up because its
+    /// thin metadata failed thin_check and could not be repaired when the
+    /// pool was set up, and then set up the pool. thin_repair writes the
+    /// repaired metadata to newly allocated space; the space holding the
+    /// damaged metadata is retained, so that it can be examined.
+    /// Returns an error if there is no such pool, or if the metadata can not
+    /// be repaired, in which case the pool remains as it was.
+    /// Returns a summary of the repair.
+    fn repair_thin_metadata(&mut self, uuid: PoolUuid) -> StratisResult<ThinRepairSummary>;
+
     /// Rename pool with uuid to new_name.
     /// Raises an error if the mapping can't be applied because
     /// new_name is already in use.
@@ -228,16 +693,99 @@ pub trait Engine: Debug {
     /// Get a mutable referent to the pool designated by uuid.
     fn get_mut_pool(&mut self, uuid: PoolUuid) -> Option<(Name, &mut dyn Pool)>;
 
+    /// Find the filesystem with the given UUID in any pool.
+    /// Returns the UUID of its pool and its name, as well as the filesystem.
+    fn get_filesystem(&self, uuid: FilesystemUuid) -> Option<(PoolUuid, Name, &dyn Filesystem)>;
+
+    /// Find the filesystem with the given UUID in any pool.
+    /// Returns the UUID of its pool and its name, as well as the filesystem.
+    fn get_mut_filesystem(
+        &mut self,
+        uuid: FilesystemUuid,
+    ) -> Option<(PoolUuid, Name, &mut dyn Filesystem)>;
+
     /// Configure the simulator, for the real engine, this is a null op.
     /// denominator: the probably of failure is 1/denominator.
     fn configure_simulator(&mut self, denominator: u32) -> StratisResult<()>;
 
+    /// Configure how the simulator decides whether attempts at operation
+    /// fail, so that callers can be tested against failures, including
+    /// transient ones. For the real engine, this is a null op.
+    fn configure_simulator_operation(
+        &mut self,
+        operation: SimOperation,
+        model: SimFailureModel,
+    ) -> StratisResult<()>;
+
+    /// Give the properties of the simulated device at devnode to any device
+    /// at devnode that is added to a pool afterwards. For the real engine,
+    /// this is a null op.
+    fn configure_simulator_device(
+        &mut self,
+        devnode: &Path,
+        properties: SimDevProperties,
+    ) -> StratisResult<()>;
+
     /// Get all pools belonging to this engine.
     fn pools(&self) -> Vec<(Name, PoolUuid, &dyn Pool)>;
 
     /// Get mutable references to all pools belonging to this engine.
     fn pools_mut(&mut self) -> Vec<(Name, PoolUuid, &mut dyn Pool)>;
 
+    /// Get a record of each pool belonging to this engine, ordered by name.
+    fn pool_records(&self) -> Vec<PoolRecord>;
+
+    /// Get a record of each filesystem of the pools belonging to this
+    /// engine, ordered by pool name and filesystem name.
+    fn filesystem_records(&self) -> Vec<FilesystemRecord>;
+
+    /// Get a record of each blockdev of the pools belonging to this engine,
+    /// ordered by pool name and devnode.
+    fn blockdev_records(&self) -> Vec<BlockDevRecord>;
+
+    /// Create whatever pools, blockdevs and filesystems of desired are
+    /// missing, and extend filesystems smaller than desired, returning the
+    /// changes made. Nothing is removed or shrunk. If dry_run is true,
+    /// return the changes that would be made, but make none.
+    fn reconcile(
+        &mut self,
+        desired: &[PoolSpec],
+        dry_run: bool,
+    ) -> StratisResult<Vec<ReconcileAction>>;
+
+    /// Start a job of the given kind on the pool, returning its UUID. The
+    /// job's work is done by step_jobs(). The job is cancelled, and fails,
+    /// if the pool is destroyed, exported or stopped while it runs.
+    /// Returns an error if another job is running on the pool.
+    fn start_job(&mut self, pool_uuid: PoolUuid, kind: JobKind) -> StratisResult<JobUuid>;
+
+    /// The status of the job, None if there is no such job, or if it
+    /// finished so long ago that its status has been discarded.
+    fn job_status(&self, uuid: JobUuid) -> Option<JobStatus>;
+
+    /// The status of every job, in the order in which they were started.
+    fn jobs(&self) -> Vec<JobStatus>;
+
+    /// Do one step of each running job, bounded in time by JOB_STEP_TIME.
+    /// Returns true if any job is still running, in which case it should be
+    /// called again soon.
+    fn step_jobs(&mut self) -> bool;
+
+    /// The result of the request with request_id, if it completed recently.
+    /// Returns an error if the request was for an operation other than
+    /// operation. Use run_once() rather than calling this directly.
+    fn completed_request(&self, request_id: &str, operation: &str) -> StratisResult<Option<Value>>;
+
+    /// Remember that the request with request_id completed with result.
+    fn complete_request(&mut self, request_id: &str, operation: &str, result: Value);
+
+    /// Compare the engine's pools with what is on the system: the metadata
+    /// on their blockdevs, their active DM devices, and their links in
+    /// DEV_PATH. Return a finding for each difference. If repair is true,
+    /// repair those differences that can be repaired safely, and report
+    /// which were.
+    fn check(&mut self, repair: bool) -> StratisResult<Vec<CheckFinding>>;
+
     /// If the engine would like to include an event in the message loop, it
     /// may return an Eventable from this method.
     fn get_eventable(&self) -> Option<&'static dyn Eventable>;
@@ -256,3 +804,21 @@ pub trait Eventable {
     /// Eventable to trigger.
     fn clear_event(&self) -> StratisResult<()>;
 }
+
+/// Create a pool with the default chunk size and allocation strategy, for
+/// tests that do not depend on either.
+#[cfg(test)]
+pub fn create_pool_with_defaults<E: Engine + ?Sized>(
+    engine: &mut E,
+    name: &str,
+    blockdev_paths: &[&Path],
+    redundancy: Option<u16>,
+) -> StratisResult<PoolUuid> {
+    engine.create_pool(
+        name,
+        blockdev_paths,
+        redundancy,
+        ChunkSize::Auto,
+        AllocationStrategy::FillFirst,
+    )
+}
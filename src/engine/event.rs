@@ -5,7 +5,8 @@
 use std::fmt::Debug;
 use std::sync::{Once, ONCE_INIT};
 
-use crate::engine::MaybeDbusPath;
+use crate::engine::jobs::{JobKind, JobState, JobUuid};
+use crate::engine::{FilesystemUuid, MaybeDbusPath, PoolUuid};
 
 use crate::engine::types::{BlockDevState, FreeSpaceState, PoolExtendState, PoolState};
 
@@ -18,11 +19,37 @@ pub enum EngineEvent<'a> {
         dbus_path: &'a MaybeDbusPath,
         state: BlockDevState,
     },
+    FilesystemCreated {
+        pool_name: &'a str,
+        name: &'a str,
+        uuid: FilesystemUuid,
+    },
+    FilesystemDestroyed {
+        dbus_path: &'a MaybeDbusPath,
+        pool_name: &'a str,
+        name: &'a str,
+        uuid: FilesystemUuid,
+    },
     FilesystemRenamed {
         dbus_path: &'a MaybeDbusPath,
         from: &'a str,
         to: &'a str,
     },
+    JobFinished {
+        uuid: JobUuid,
+        pool_uuid: PoolUuid,
+        kind: JobKind,
+        state: &'a JobState,
+    },
+    PoolCreated {
+        name: &'a str,
+        uuid: PoolUuid,
+    },
+    PoolDestroyed {
+        dbus_path: &'a MaybeDbusPath,
+        name: &'a str,
+        uuid: PoolUuid,
+    },
     PoolExtendStateChanged {
         dbus_path: &'a MaybeDbusPath,
         state: PoolExtendState,
@@ -40,6 +67,13 @@ pub enum EngineEvent<'a> {
         dbus_path: &'a MaybeDbusPath,
         state: PoolState,
     },
+    /// The pool's data space usage has reached the warning or the critical
+    /// threshold. Sent with the PoolSpaceStateChanged event for the change.
+    SpaceLow {
+        dbus_path: &'a MaybeDbusPath,
+        state: FreeSpaceState,
+        used_pct: u8,
+    },
 }
 
 pub trait EngineListener: Debug {
@@ -97,3 +131,52 @@ pub fn get_engine_listener_list_mut() -> &'static mut EngineListenerList {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct Recorder {
+        events: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl EngineListener for Recorder {
+        fn notify(&self, event: &EngineEvent) {
+            self.events.borrow_mut().push(format!("{:?}", event));
+        }
+    }
+
+    #[test]
+    /// Every registered listener is notified of every event, in order.
+    fn listeners_notified() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut list = EngineListenerList::new();
+        for _ in 0..2 {
+            list.register_listener(Box::new(Recorder {
+                events: Rc::clone(&events),
+            }));
+        }
+
+        let dbus_path = MaybeDbusPath(None);
+        list.notify(&EngineEvent::PoolCreated {
+            name: "pool",
+            uuid: Uuid::new_v4(),
+        });
+        list.notify(&EngineEvent::SpaceLow {
+            dbus_path: &dbus_path,
+            state: FreeSpaceState::Warn,
+            used_pct: 90,
+        });
+
+        let events = events.borrow();
+        assert_eq!(events.len(), 4);
+        assert!(events[0].starts_with("PoolCreated") && events[1].starts_with("PoolCreated"));
+        assert!(events[2].starts_with("SpaceLow") && events[3].starts_with("SpaceLow"));
+    }
+}
@@ -0,0 +1,361 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Jobs: operations that take too long to be done in a single call. A job
+// is started, and returns its UUID at once; its work is then done a step at
+// a time, each step bounded in time so that the daemon remains responsive
+// in between. The status of a job, including its progress, may be queried
+// while it runs, and for a while after it has finished. When a job
+// finishes, a JobFinished event is sent.
+//
+// Only scrubs are jobs. Creating a pool and moving the data off a
+// blockdev set up or change device-mapper devices, and a pool must not be
+// left half changed between steps, so they are still done in one call.
+
+use std::cmp;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use uuid::Uuid;
+
+use devicemapper::Sectors;
+
+use crate::engine::event::get_engine_listener_list;
+use crate::engine::{EngineEvent, PoolUuid};
+use crate::stratis::{ErrorEnum, StratisError, StratisResult};
+
+pub type JobUuid = Uuid;
+
+/// The amount of a device read at once by a scrub, 1 MiB.
+const SCRUB_READ_SIZE: Sectors = Sectors(2 * 1024);
+
+/// The time that one step of the running jobs may take. Each running job
+/// does at least one read in a step, so a step may take a little longer.
+pub const JOB_STEP_TIME: Duration = Duration::from_millis(50);
+
+/// The number of finished jobs whose status is kept.
+const MAX_FINISHED_JOBS: usize = 16;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum JobKind {
+    /// Read every data and cache device of a pool from start to end, so
+    /// that unreadable regions are found before they are needed.
+    Scrub,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum JobState {
+    Running,
+    Succeeded,
+    Failed(String),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct JobStatus {
+    pub uuid: JobUuid,
+    pub pool_uuid: PoolUuid,
+    pub kind: JobKind,
+    /// What the job is doing now, or did last.
+    pub phase: String,
+    pub done: Sectors,
+    pub total: Sectors,
+    pub state: JobState,
+}
+
+impl JobStatus {
+    /// The percentage of the job's work that is done, rounded down.
+    pub fn progress_pct(&self) -> u8 {
+        if self.total == Sectors(0) {
+            100
+        } else {
+            (*self.done * 100 / *self.total) as u8
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Job {
+    status: JobStatus,
+    devices: Vec<(PathBuf, Sectors)>,
+    device_index: usize,
+    offset: Sectors,
+    errors: Vec<String>,
+}
+
+impl Job {
+    /// Do one step of the job's work, using read to read a range of a
+    /// device, until the deadline has passed or the job has finished; at
+    /// least one range is read.
+    fn step<F>(&mut self, read: &F, deadline: Instant)
+    where
+        F: Fn(&Path, Sectors, Sectors) -> StratisResult<()>,
+    {
+        self.read_next(read);
+        while self.status.state == JobState::Running && Instant::now() < deadline {
+            self.read_next(read);
+        }
+    }
+
+    /// Read the next range. A device that can not be read is passed over,
+    /// and the job fails once it has read the rest.
+    fn read_next<F>(&mut self, read: &F)
+    where
+        F: Fn(&Path, Sectors, Sectors) -> StratisResult<()>,
+    {
+        if let Some(&(ref devnode, size)) = self.devices.get(self.device_index) {
+            let length = cmp::min(SCRUB_READ_SIZE, size - self.offset);
+            self.status.phase = format!("reading {}", devnode.display());
+            match read(devnode, self.offset, length) {
+                Ok(()) => {
+                    self.offset += length;
+                    self.status.done += length;
+                }
+                Err(err) => {
+                    self.errors.push(format!(
+                        "{} at sector {}: {}",
+                        devnode.display(),
+                        *self.offset,
+                        err
+                    ));
+                    // The rest of the device is counted as done.
+                    self.status.done += size - self.offset;
+                    self.offset = size;
+                }
+            }
+            if self.offset >= size {
+                self.device_index += 1;
+                self.offset = Sectors(0);
+            }
+        }
+
+        if self.device_index >= self.devices.len() {
+            self.status.phase = "finished".into();
+            self.status.state = if self.errors.is_empty() {
+                JobState::Succeeded
+            } else {
+                JobState::Failed(format!("unreadable: {}", self.errors.join("; ")))
+            };
+        }
+    }
+}
+
+/// The jobs of an engine, kept in the order in which they were started.
+#[derive(Debug, Default)]
+pub struct JobTable {
+    jobs: Vec<Job>,
+}
+
+impl JobTable {
+    /// Start a scrub of the devices of a pool, each given with its size.
+    /// Returns an error if a job is already running on the pool.
+    pub fn start_scrub(
+        &mut self,
+        pool_uuid: PoolUuid,
+        devices: Vec<(PathBuf, Sectors)>,
+    ) -> StratisResult<JobUuid> {
+        if self
+            .jobs
+            .iter()
+            .any(|job| job.status.pool_uuid == pool_uuid && job.status.state == JobState::Running)
+        {
+            return Err(StratisError::Engine(
+                ErrorEnum::Busy,
+                format!("a job is already running on pool {}", pool_uuid),
+            ));
+        }
+
+        let uuid = Uuid::new_v4();
+        self.jobs.push(Job {
+            status: JobStatus {
+                uuid,
+                pool_uuid,
+                kind: JobKind::Scrub,
+                phase: "starting".into(),
+                done: Sectors(0),
+                total: devices.iter().map(|&(_, size)| size).sum(),
+                state: JobState::Running,
+            },
+            devices,
+            device_index: 0,
+            offset: Sectors(0),
+            errors: Vec::new(),
+        });
+        Ok(uuid)
+    }
+
+    pub fn status(&self, uuid: JobUuid) -> Option<JobStatus> {
+        self.jobs
+            .iter()
+            .find(|job| job.status.uuid == uuid)
+            .map(|job| job.status.clone())
+    }
+
+    pub fn statuses(&self) -> Vec<JobStatus> {
+        self.jobs.iter().map(|job| job.status.clone()).collect()
+    }
+
+    /// Cancel the running jobs of the pool, because the pool has gone away,
+    /// giving reason. A cancelled job has failed, and a JobFinished event
+    /// is sent for it.
+    pub fn cancel(&mut self, pool_uuid: PoolUuid, reason: &str) {
+        for job in self.jobs.iter_mut().filter(|job| {
+            job.status.pool_uuid == pool_uuid && job.status.state == JobState::Running
+        }) {
+            job.status.phase = "cancelled".into();
+            job.status.state = JobState::Failed(format!("cancelled: {}", reason));
+            get_engine_listener_list().notify(&EngineEvent::JobFinished {
+                uuid: job.status.uuid,
+                pool_uuid: job.status.pool_uuid,
+                kind: job.status.kind,
+                state: &job.status.state,
+            });
+        }
+    }
+
+    /// Do one step of each running job, taking about time in all. Returns
+    /// true if any job is still running afterwards.
+    pub fn step<F>(&mut self, read: F, time: Duration) -> bool
+    where
+        F: Fn(&Path, Sectors, Sectors) -> StratisResult<()>,
+    {
+        let deadline = Instant::now() + time;
+        for job in self
+            .jobs
+            .iter_mut()
+            .filter(|job| job.status.state == JobState::Running)
+        {
+            job.step(&read, deadline);
+            if job.status.state != JobState::Running {
+                get_engine_listener_list().notify(&EngineEvent::JobFinished {
+                    uuid: job.status.uuid,
+                    pool_uuid: job.status.pool_uuid,
+                    kind: job.status.kind,
+                    state: &job.status.state,
+                });
+            }
+        }
+
+        let mut finished = self
+            .jobs
+            .iter()
+            .filter(|job| job.status.state != JobState::Running)
+            .count();
+        while finished > MAX_FINISHED_JOBS {
+            let oldest = self
+                .jobs
+                .iter()
+                .position(|job| job.status.state != JobState::Running)
+                .expect("finished > 0");
+            self.jobs.remove(oldest);
+            finished -= 1;
+        }
+
+        self.jobs
+            .iter()
+            .any(|job| job.status.state == JobState::Running)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    #[test]
+    /// A scrub reads each device in turn, at least one read a step, passes
+    /// over a device that can not be read, and fails at the end if any
+    /// could not. Given the time, a step reads more.
+    fn scrub_steps() {
+        let mut jobs = JobTable::default();
+        let pool_uuid = Uuid::new_v4();
+        let uuid = jobs
+            .start_scrub(
+                pool_uuid,
+                vec![
+                    (PathBuf::from("/s/a"), SCRUB_READ_SIZE + Sectors(8)),
+                    (PathBuf::from("/s/bad"), SCRUB_READ_SIZE * 4u64 - Sectors(8)),
+                ],
+            )
+            .unwrap();
+        assert_matches!(
+            jobs.start_scrub(pool_uuid, vec![]),
+            Err(StratisError::Engine(ErrorEnum::Busy, _))
+        );
+
+        let reads = RefCell::new(Vec::new());
+        let read = |devnode: &Path, offset: Sectors, length: Sectors| {
+            reads
+                .borrow_mut()
+                .push((devnode.to_owned(), offset, length));
+            if devnode == Path::new("/s/bad") {
+                Err(StratisError::Error("bad sector".into()))
+            } else {
+                Ok(())
+            }
+        };
+
+        assert!(jobs.step(read, Duration::from_secs(0)));
+        assert_eq!(jobs.status(uuid).unwrap().progress_pct(), 20);
+        assert!(jobs.step(read, Duration::from_secs(0)));
+        assert!(!jobs.step(read, Duration::from_secs(0)));
+
+        assert_eq!(
+            *reads.borrow(),
+            vec![
+                (PathBuf::from("/s/a"), Sectors(0), SCRUB_READ_SIZE),
+                (PathBuf::from("/s/a"), SCRUB_READ_SIZE, Sectors(8)),
+                (PathBuf::from("/s/bad"), Sectors(0), SCRUB_READ_SIZE),
+            ]
+        );
+        let status = jobs.status(uuid).unwrap();
+        assert_eq!(status.progress_pct(), 100);
+        assert_matches!(status.state, JobState::Failed(_));
+
+        // Another job may be started on the pool once the first finished.
+        reads.borrow_mut().clear();
+        let uuid = jobs
+            .start_scrub(
+                pool_uuid,
+                vec![(PathBuf::from("/s/a"), SCRUB_READ_SIZE * 3u64)],
+            )
+            .unwrap();
+        assert!(!jobs.step(read, Duration::from_secs(60)));
+        assert_eq!(reads.borrow().len(), 3);
+        assert_eq!(jobs.status(uuid).unwrap().state, JobState::Succeeded);
+        assert_eq!(jobs.statuses().len(), 2);
+    }
+
+    #[test]
+    /// Cancelling the jobs of a pool fails its running job, which is then
+    /// stepped no more, and leaves the jobs of other pools running.
+    fn cancel_pool_jobs() {
+        let mut jobs = JobTable::default();
+        let pool_uuid = Uuid::new_v4();
+        let other_pool_uuid = Uuid::new_v4();
+        let devices = vec![(PathBuf::from("/s/a"), SCRUB_READ_SIZE * 2u64)];
+        let uuid = jobs.start_scrub(pool_uuid, devices.clone()).unwrap();
+        let other_uuid = jobs.start_scrub(other_pool_uuid, devices).unwrap();
+
+        jobs.cancel(pool_uuid, "the pool was destroyed");
+        let status = jobs.status(uuid).unwrap();
+        assert_eq!(
+            status.state,
+            JobState::Failed("cancelled: the pool was destroyed".into())
+        );
+        assert_eq!(status.done, Sectors(0));
+        assert_eq!(jobs.status(other_uuid).unwrap().state, JobState::Running);
+
+        let reads = RefCell::new(Vec::new());
+        let read = |devnode: &Path, _: Sectors, _: Sectors| {
+            reads.borrow_mut().push(devnode.to_owned());
+            Ok(())
+        };
+        assert!(!jobs.step(read, Duration::from_secs(60)));
+        assert_eq!(reads.borrow().len(), 2);
+        assert_eq!(jobs.status(uuid).unwrap().done, Sectors(0));
+        assert_eq!(jobs.status(other_uuid).unwrap().state, JobState::Succeeded);
+        assert!(jobs.start_scrub(pool_uuid, vec![]).is_ok());
+    }
+}
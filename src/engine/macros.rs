@@ -30,6 +30,30 @@ macro_rules! get_mut_pool {
     };
 }
 
+macro_rules! get_filesystem {
+    ($s:ident; $uuid:ident) => {
+        $s.pools
+            .iter()
+            .filter_map(|(_, pool_uuid, p)| {
+                p.get_filesystem($uuid)
+                    .map(|(name, fs)| (*pool_uuid, name, fs))
+            })
+            .next()
+    };
+}
+
+macro_rules! get_mut_filesystem {
+    ($s:ident; $uuid:ident) => {
+        $s.pools
+            .iter_mut()
+            .filter_map(|(_, pool_uuid, p)| {
+                p.get_mut_filesystem($uuid)
+                    .map(|(name, fs)| (*pool_uuid, name, fs))
+            })
+            .next()
+    };
+}
+
 macro_rules! rename_filesystem_pre {
     ($s:ident; $uuid:ident; $new_name:ident) => {{
         let old_name = match $s.filesystems.get_by_uuid($uuid) {
@@ -72,6 +96,17 @@ macro_rules! rename_pool_pre {
     }};
 }
 
+macro_rules! refuse_if_quiesced {
+    ($pool:ident; $name:ident) => {
+        if $pool.quiesce_deadline().is_some() {
+            return Err(StratisError::Engine(
+                ErrorEnum::Busy,
+                format!("pool {} is quiesced; it must be resumed first", $name),
+            ));
+        }
+    };
+}
+
 macro_rules! set_blockdev_user_info {
     ($s:ident; $info:ident) => {
         if $s.user_info.as_ref().map(|x| &**x) != $info {
@@ -82,3 +117,38 @@ macro_rules! set_blockdev_user_info {
         }
     };
 }
+
+// Compare $dev, a DM device of type $dev_type belonging to the pool with
+// $pool_uuid, with the device that is active. Evaluates to a
+// StratisResult<Option<Discrepancy>>. This is a macro rather than a function
+// because the trait that bounds the tables of DM devices is not exported
+// by devicemapper, so a function generic over the device type can not be
+// written.
+macro_rules! check_dm_device {
+    ($pool_uuid:expr, $dev:expr, $dev_type:ty) => {
+        (|| -> $crate::stratis::StratisResult<Option<$crate::engine::check::Discrepancy>> {
+            let dev: &$dev_type = $dev;
+            let dm = $crate::engine::strat_engine::dm::get_dm();
+            let name = devicemapper::DmDevice::name(dev);
+            if !devicemapper::device_exists(dm, name)? {
+                return Ok(Some($crate::engine::check::Discrepancy::MissingDmDevice {
+                    pool_uuid: $pool_uuid,
+                    name: name.to_string(),
+                }));
+            }
+            let table = <$dev_type as devicemapper::DmDevice<_>>::read_kernel_table(
+                dm,
+                &devicemapper::DevId::Name(name),
+            )?;
+            let expected = devicemapper::DmDevice::table(dev);
+            if <$dev_type as devicemapper::DmDevice<_>>::equivalent_tables(expected, &table)? {
+                Ok(None)
+            } else {
+                Ok(Some($crate::engine::check::Discrepancy::DmTableMismatch {
+                    pool_uuid: $pool_uuid,
+                    name: name.to_string(),
+                }))
+            }
+        })()
+    };
+}
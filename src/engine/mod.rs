@@ -3,34 +3,62 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 pub use self::devlinks::filesystem_mount_path;
+pub use self::devlinks::fstab_entry;
+pub use self::devlinks::FSTAB_OPTIONS;
 
 pub use self::engine::BlockDev;
 pub use self::engine::Engine;
 pub use self::engine::Filesystem;
 pub use self::engine::Pool;
 
+pub use self::check::{CheckFinding, Discrepancy};
+
 pub use self::event::{get_engine_listener_list_mut, EngineEvent, EngineListener};
 
+pub use self::jobs::{JobKind, JobState, JobStatus, JobUuid};
+
+pub use self::reconcile::{FilesystemSpec, PoolSpec, ReconcileAction};
+pub use self::records::{BlockDevRecord, FilesystemRecord, PoolRecord};
+pub use self::requests::run_once;
+
 pub use self::sim_engine::SimEngine;
 pub use self::strat_engine::StratEngine;
 
+pub use self::types::AllocationStrategy;
 pub use self::types::BlockDevState;
 pub use self::types::BlockDevTier;
+pub use self::types::ChunkSize;
 pub use self::types::DevUuid;
+pub use self::types::FilesystemTree;
+pub use self::types::FilesystemUsage;
 pub use self::types::FilesystemUuid;
 pub use self::types::MaybeDbusPath;
 pub use self::types::Name;
+pub use self::types::OverprovisionPolicy;
+pub use self::types::PlannedBlockDev;
+pub use self::types::PoolId;
+pub use self::types::PoolPlan;
+pub use self::types::PoolSpaceReport;
 pub use self::types::PoolUuid;
 pub use self::types::Redundancy;
 pub use self::types::RenameAction;
+pub use self::types::SimDevProperties;
+pub use self::types::SimFailureModel;
+pub use self::types::SimOperation;
+pub use self::types::ThinRepairSummary;
 
 #[macro_use]
 mod macros;
 
+mod check;
 mod devlinks;
 #[allow(clippy::module_inception)]
 mod engine;
 mod event;
+mod jobs;
+mod reconcile;
+mod records;
+mod requests;
 mod sim_engine;
 mod strat_engine;
 mod structures;
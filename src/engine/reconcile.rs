@@ -0,0 +1,230 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Reconciliation of an engine with a description of the pools and
+// filesystems that should exist. The description is compared with the
+// engine's pools, and only what is missing is created: pools, blockdevs
+// and filesystems are added, and filesystems smaller than described are
+// extended. Nothing is ever removed or shrunk, so reconciling with the
+// same description again does nothing.
+
+use std::fs::canonicalize;
+use std::path::{Path, PathBuf};
+
+use devicemapper::Sectors;
+
+use crate::engine::{
+    AllocationStrategy, BlockDevTier, ChunkSize, Engine, FilesystemUuid, PoolUuid,
+};
+use crate::stratis::{ErrorEnum, StratisError, StratisResult};
+
+/// A pool that should exist, with at least these data devices and these
+/// filesystems.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct PoolSpec {
+    pub name: String,
+    pub devices: Vec<PathBuf>,
+    #[serde(default)]
+    pub filesystems: Vec<FilesystemSpec>,
+}
+
+/// A filesystem that should exist, at least size large if a size is given.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct FilesystemSpec {
+    pub name: String,
+    #[serde(default)]
+    pub size: Option<Sectors>,
+}
+
+/// A change made, or to be made, to reconcile an engine with a description.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ReconcileAction {
+    CreatePool {
+        name: String,
+        devices: Vec<PathBuf>,
+    },
+    AddBlockdevs {
+        pool_name: String,
+        devices: Vec<PathBuf>,
+    },
+    CreateFilesystem {
+        pool_name: String,
+        name: String,
+        size: Option<Sectors>,
+    },
+    ExtendFilesystem {
+        pool_name: String,
+        name: String,
+        extend_size: Sectors,
+    },
+}
+
+/// The path by which a device is compared: its canonical path if it has
+/// one, otherwise the path as given.
+fn device_key(path: &Path) -> PathBuf {
+    canonicalize(path).unwrap_or_else(|_| path.to_owned())
+}
+
+/// Find the changes that would reconcile engine with desired, without
+/// making them. The pools of desired must have distinct names.
+pub fn plan(engine: &dyn Engine, desired: &[PoolSpec]) -> StratisResult<Vec<ReconcileAction>> {
+    let mut actions = Vec::new();
+    for (index, pool_spec) in desired.iter().enumerate() {
+        if desired[..index]
+            .iter()
+            .any(|other| other.name == pool_spec.name)
+        {
+            return Err(StratisError::Engine(
+                ErrorEnum::Invalid,
+                format!("pool {} is described more than once", pool_spec.name),
+            ));
+        }
+
+        let pool = engine
+            .pools()
+            .into_iter()
+            .find(|(name, _, _)| **name == pool_spec.name)
+            .map(|(_, _, pool)| pool);
+        let pool = match pool {
+            Some(pool) => pool,
+            None => {
+                actions.push(ReconcileAction::CreatePool {
+                    name: pool_spec.name.clone(),
+                    devices: pool_spec.devices.clone(),
+                });
+                for fs_spec in &pool_spec.filesystems {
+                    actions.push(ReconcileAction::CreateFilesystem {
+                        pool_name: pool_spec.name.clone(),
+                        name: fs_spec.name.clone(),
+                        size: fs_spec.size,
+                    });
+                }
+                continue;
+            }
+        };
+
+        let present = pool
+            .blockdevs()
+            .iter()
+            .map(|(_, bd)| device_key(&bd.devnode()))
+            .collect::<Vec<_>>();
+        let missing = pool_spec
+            .devices
+            .iter()
+            .filter(|path| !present.contains(&device_key(path)))
+            .cloned()
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            actions.push(ReconcileAction::AddBlockdevs {
+                pool_name: pool_spec.name.clone(),
+                devices: missing,
+            });
+        }
+
+        for fs_spec in &pool_spec.filesystems {
+            let filesystem = pool
+                .filesystems()
+                .into_iter()
+                .find(|(name, _, _)| **name == fs_spec.name)
+                .map(|(_, _, filesystem)| filesystem);
+            match (filesystem, fs_spec.size) {
+                (None, size) => actions.push(ReconcileAction::CreateFilesystem {
+                    pool_name: pool_spec.name.clone(),
+                    name: fs_spec.name.clone(),
+                    size,
+                }),
+                (Some(filesystem), Some(size)) => {
+                    let current = filesystem.usage()?.size;
+                    if current < size {
+                        actions.push(ReconcileAction::ExtendFilesystem {
+                            pool_name: pool_spec.name.clone(),
+                            name: fs_spec.name.clone(),
+                            extend_size: size - current,
+                        });
+                    }
+                }
+                (Some(_), None) => {}
+            }
+        }
+    }
+    Ok(actions)
+}
+
+fn pool_uuid(engine: &dyn Engine, pool_name: &str) -> StratisResult<PoolUuid> {
+    engine
+        .pools()
+        .into_iter()
+        .find(|(name, _, _)| **name == *pool_name)
+        .map(|(_, uuid, _)| uuid)
+        .ok_or_else(|| StratisError::Engine(ErrorEnum::NotFound, pool_name.to_owned()))
+}
+
+fn filesystem_uuid(
+    engine: &dyn Engine,
+    pool_uuid: PoolUuid,
+    fs_name: &str,
+) -> StratisResult<FilesystemUuid> {
+    engine
+        .get_pool(pool_uuid)
+        .and_then(|(_, pool)| {
+            pool.filesystems()
+                .into_iter()
+                .find(|(name, _, _)| **name == *fs_name)
+                .map(|(_, uuid, _)| uuid)
+        })
+        .ok_or_else(|| StratisError::Engine(ErrorEnum::NotFound, fs_name.to_owned()))
+}
+
+/// Make the changes that reconcile engine with desired, returning the
+/// changes made. If a change fails, the changes already made are kept, and
+/// reconciling again makes only those that remain.
+pub fn apply(engine: &mut dyn Engine, desired: &[PoolSpec]) -> StratisResult<Vec<ReconcileAction>> {
+    let actions = plan(engine, desired)?;
+    for action in &actions {
+        match *action {
+            ReconcileAction::CreatePool {
+                ref name,
+                ref devices,
+            } => {
+                let devices = devices.iter().map(|p| p.as_path()).collect::<Vec<_>>();
+                engine.create_pool(
+                    name,
+                    &devices,
+                    None,
+                    ChunkSize::Auto,
+                    AllocationStrategy::FillFirst,
+                )?;
+            }
+            ReconcileAction::AddBlockdevs {
+                ref pool_name,
+                ref devices,
+            } => {
+                let uuid = pool_uuid(engine, pool_name)?;
+                let devices = devices.iter().map(|p| p.as_path()).collect::<Vec<_>>();
+                let (_, pool) = engine.get_mut_pool(uuid).expect("found by name");
+                pool.add_blockdevs(uuid, pool_name, &devices, BlockDevTier::Data)?;
+            }
+            ReconcileAction::CreateFilesystem {
+                ref pool_name,
+                ref name,
+                size,
+            } => {
+                let uuid = pool_uuid(engine, pool_name)?;
+                let (_, pool) = engine.get_mut_pool(uuid).expect("found by name");
+                pool.create_filesystems(uuid, pool_name, &[(name, size)])?;
+            }
+            ReconcileAction::ExtendFilesystem {
+                ref pool_name,
+                ref name,
+                extend_size,
+            } => {
+                let uuid = pool_uuid(engine, pool_name)?;
+                let fs_uuid = filesystem_uuid(engine, uuid, name)?;
+                let (_, pool) = engine.get_mut_pool(uuid).expect("found by name");
+                pool.extend_filesystem(pool_name, fs_uuid, extend_size)?;
+            }
+        }
+    }
+    Ok(actions)
+}
@@ -0,0 +1,135 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Records of the objects of an engine, for callers that list them. Each
+// record holds the commonly wanted properties of its object, so that a
+// caller listing pools, filesystems or blockdevs needs no further query
+// for each. The records are built from the engine traits, and are the same
+// for every engine.
+
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+
+use devicemapper::{Bytes, Sectors};
+
+use crate::engine::types::{
+    BlockDevState, BlockDevTier, DevUuid, FilesystemUuid, FreeSpaceState, Name, PoolExtendState,
+    PoolState, PoolUuid,
+};
+use crate::engine::{Pool, PoolSpaceReport};
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PoolRecord {
+    pub uuid: PoolUuid,
+    pub name: String,
+    pub state: PoolState,
+    pub extend_state: PoolExtendState,
+    pub free_space_state: FreeSpaceState,
+    /// The space report of the pool, None if it could not be made.
+    pub space: Option<PoolSpaceReport>,
+    pub filesystem_count: usize,
+    pub blockdev_count: usize,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FilesystemRecord {
+    pub uuid: FilesystemUuid,
+    pub name: String,
+    pub pool_uuid: PoolUuid,
+    pub pool_name: String,
+    pub devnode: PathBuf,
+    pub created: DateTime<Utc>,
+    pub origin: Option<FilesystemUuid>,
+    /// The size of the filesystem's thin device, None if it could not be
+    /// found.
+    pub size: Option<Sectors>,
+    /// The space used by the filesystem, None if it could not be found.
+    pub used: Option<Bytes>,
+    pub read_only: bool,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BlockDevRecord {
+    pub uuid: DevUuid,
+    pub pool_uuid: PoolUuid,
+    pub pool_name: String,
+    pub devnode: PathBuf,
+    pub tier: BlockDevTier,
+    pub size: Sectors,
+    pub state: BlockDevState,
+    pub initialization_time: DateTime<Utc>,
+    pub in_maintenance: bool,
+}
+
+/// Records of pools, ordered by name.
+pub fn pool_records(pools: Vec<(Name, PoolUuid, &dyn Pool)>) -> Vec<PoolRecord> {
+    let mut records = pools
+        .into_iter()
+        .map(|(name, uuid, pool)| PoolRecord {
+            uuid,
+            name: name.to_string(),
+            state: pool.state(),
+            extend_state: pool.extend_state(),
+            free_space_state: pool.free_space_state(),
+            space: pool.space_report().ok(),
+            filesystem_count: pool.filesystems().len(),
+            blockdev_count: pool.blockdevs().len(),
+        })
+        .collect::<Vec<_>>();
+    records.sort_by(|a, b| a.name.cmp(&b.name));
+    records
+}
+
+/// Records of the filesystems of pools, ordered by pool name and then by
+/// name.
+pub fn filesystem_records(pools: Vec<(Name, PoolUuid, &dyn Pool)>) -> Vec<FilesystemRecord> {
+    let mut records = Vec::new();
+    for (pool_name, pool_uuid, pool) in pools {
+        for (name, uuid, filesystem) in pool.filesystems() {
+            let usage = filesystem.usage().ok();
+            records.push(FilesystemRecord {
+                uuid,
+                name: name.to_string(),
+                pool_uuid,
+                pool_name: pool_name.to_string(),
+                devnode: filesystem.devnode(),
+                created: filesystem.created(),
+                origin: filesystem.origin(),
+                size: usage.as_ref().map(|usage| usage.size),
+                used: filesystem.used().ok(),
+                read_only: filesystem.read_only(),
+            });
+        }
+    }
+    records.sort_by(|a, b| (&a.pool_name, &a.name).cmp(&(&b.pool_name, &b.name)));
+    records
+}
+
+/// Records of the blockdevs of pools, ordered by pool name and then by
+/// devnode.
+pub fn blockdev_records(pools: Vec<(Name, PoolUuid, &dyn Pool)>) -> Vec<BlockDevRecord> {
+    let mut records = Vec::new();
+    for (pool_name, pool_uuid, pool) in pools {
+        for (uuid, blockdev) in pool.blockdevs() {
+            let tier = pool
+                .get_blockdev(uuid)
+                .map(|(tier, _)| tier)
+                .unwrap_or(BlockDevTier::Data);
+            records.push(BlockDevRecord {
+                uuid,
+                pool_uuid,
+                pool_name: pool_name.to_string(),
+                devnode: blockdev.devnode(),
+                tier,
+                size: blockdev.size(),
+                state: blockdev.state(),
+                initialization_time: blockdev.initialization_time(),
+                in_maintenance: blockdev.in_maintenance(),
+            });
+        }
+    }
+    records.sort_by(|a, b| (&a.pool_name, &a.devnode).cmp(&(&b.pool_name, &b.devnode)));
+    records
+}
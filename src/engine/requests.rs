@@ -0,0 +1,135 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Request IDs: a client that is not sure whether a mutating call completed,
+// for example because the call timed out, may make it again with the same
+// request ID, and is then given the result of the first call, rather than
+// having the operation done twice. An engine remembers the results of the
+// most recently completed requests. Only requests that succeeded are
+// remembered; a failed request may be retried with the same ID.
+// The D-Bus methods CreatePoolWithRequestId and DestroyPoolWithRequestId
+// take a request ID.
+//
+// The results are kept in memory only. A request made again after stratisd
+// has restarted is not recognized, and its operation is done again.
+
+use std::collections::VecDeque;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{self, Value};
+
+use crate::engine::Engine;
+use crate::stratis::{ErrorEnum, StratisError, StratisResult};
+
+/// The number of completed requests whose results are remembered.
+const MAX_COMPLETED_REQUESTS: usize = 256;
+
+#[derive(Debug)]
+struct CompletedRequest {
+    request_id: String,
+    operation: String,
+    result: Value,
+}
+
+/// The completed requests of an engine, oldest first.
+#[derive(Debug, Default)]
+pub struct CompletedRequests {
+    requests: VecDeque<CompletedRequest>,
+}
+
+impl CompletedRequests {
+    /// The result of the request with request_id, if it is remembered.
+    /// Returns an error if the request was for a different operation.
+    pub fn result(&self, request_id: &str, operation: &str) -> StratisResult<Option<Value>> {
+        match self
+            .requests
+            .iter()
+            .find(|request| request.request_id == request_id)
+        {
+            Some(request) if request.operation == operation => Ok(Some(request.result.clone())),
+            Some(request) => Err(StratisError::Engine(
+                ErrorEnum::Invalid,
+                format!(
+                    "request ID {} was used for {}, not {}",
+                    request_id, request.operation, operation
+                ),
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// Remember the result of the request with request_id, forgetting the
+    /// oldest request if too many are remembered.
+    pub fn insert(&mut self, request_id: &str, operation: &str, result: Value) {
+        self.requests
+            .retain(|request| request.request_id != request_id);
+        if self.requests.len() >= MAX_COMPLETED_REQUESTS {
+            self.requests.pop_front();
+        }
+        self.requests.push_back(CompletedRequest {
+            request_id: request_id.to_owned(),
+            operation: operation.to_owned(),
+            result,
+        });
+    }
+}
+
+/// Call operation, a mutating call on engine named name, at most once for
+/// request_id. If the request completed before, its result
+/// is returned and operation is not called. If there is no request_id,
+/// operation is simply called.
+pub fn run_once<T, F>(
+    engine: &mut dyn Engine,
+    request_id: Option<&str>,
+    name: &str,
+    operation: F,
+) -> StratisResult<T>
+where
+    T: Serialize + DeserializeOwned,
+    F: FnOnce(&mut dyn Engine) -> StratisResult<T>,
+{
+    let request_id = match request_id {
+        Some(request_id) => request_id,
+        None => return operation(engine),
+    };
+
+    if let Some(result) = engine.completed_request(request_id, name)? {
+        return Ok(serde_json::from_value(result)?);
+    }
+
+    let result = operation(engine)?;
+    engine.complete_request(request_id, name, serde_json::to_value(&result)?);
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    /// A request ID is remembered with its operation, and the oldest is
+    /// forgotten once too many are remembered.
+    fn remembered_requests() {
+        let mut requests = CompletedRequests::default();
+        requests.insert("first", "create_pool", json!(1));
+        assert_eq!(
+            requests.result("first", "create_pool").unwrap(),
+            Some(json!(1))
+        );
+        assert_matches!(
+            requests.result("first", "destroy_pool"),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+        assert_eq!(requests.result("second", "create_pool").unwrap(), None);
+
+        for index in 0..MAX_COMPLETED_REQUESTS {
+            requests.insert(&index.to_string(), "create_pool", json!(index));
+        }
+        assert_eq!(requests.result("first", "create_pool").unwrap(), None);
+        assert_eq!(requests.result("0", "create_pool").unwrap(), Some(json!(0)));
+    }
+}
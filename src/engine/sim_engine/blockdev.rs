@@ -5,15 +5,17 @@
 use std::cell::RefCell;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::time::Duration;
 
 use chrono::{DateTime, TimeZone, Utc};
 use uuid::Uuid;
 
 use devicemapper::{Bytes, Sectors, IEC};
 
-use crate::engine::{BlockDev, BlockDevState, MaybeDbusPath};
+use crate::engine::{BlockDev, BlockDevState, MaybeDbusPath, SimDevProperties};
 
 use crate::engine::sim_engine::randomization::Randomizer;
+use crate::engine::sim_engine::serde_structs::SimDevSave;
 
 #[derive(Debug)]
 /// A simulated device.
@@ -23,6 +25,10 @@ pub struct SimDev {
     user_info: Option<String>,
     hardware_info: Option<String>,
     initialization_time: u64,
+    maintenance: bool,
+    // Whether the pool's data is held on this device
+    in_use: bool,
+    properties: SimDevProperties,
     dbus_path: MaybeDbusPath,
 }
 
@@ -31,6 +37,10 @@ impl BlockDev for SimDev {
         self.devnode.clone()
     }
 
+    fn persistent_devnode(&self) -> PathBuf {
+        self.devnode.clone()
+    }
+
     fn user_info(&self) -> Option<&str> {
         self.user_info.as_ref().map(|x| &**x)
     }
@@ -44,11 +54,25 @@ impl BlockDev for SimDev {
     }
 
     fn size(&self) -> Sectors {
-        Bytes(IEC::Gi).sectors()
+        self.properties
+            .size
+            .unwrap_or_else(|| Bytes(IEC::Gi).sectors())
     }
 
     fn state(&self) -> BlockDevState {
-        BlockDevState::InUse
+        if self.in_use {
+            BlockDevState::InUse
+        } else {
+            BlockDevState::NotInUse
+        }
+    }
+
+    fn in_maintenance(&self) -> bool {
+        self.maintenance
+    }
+
+    fn rotational(&self) -> Option<bool> {
+        Some(self.properties.rotational)
     }
 
     fn set_dbus_path(&mut self, path: MaybeDbusPath) {
@@ -63,7 +87,10 @@ impl BlockDev for SimDev {
 impl SimDev {
     /// Generates a new device from any devnode.
     #[allow(clippy::new_ret_no_self)]
-    pub fn new(rdm: Rc<RefCell<Randomizer>>, devnode: &Path) -> (Uuid, SimDev) {
+    /// Make a new device. It is in use if the pool's data is to be held on
+    /// it, as on the devices from which a pool is created.
+    pub fn new(rdm: Rc<RefCell<Randomizer>>, devnode: &Path, in_use: bool) -> (Uuid, SimDev) {
+        let properties = rdm.borrow().device_properties(devnode);
         (
             Uuid::new_v4(),
             SimDev {
@@ -72,15 +99,82 @@ impl SimDev {
                 user_info: None,
                 hardware_info: None,
                 initialization_time: Utc::now().timestamp() as u64,
+                maintenance: false,
+                in_use,
+                properties,
                 dbus_path: MaybeDbusPath(None),
             },
         )
     }
 
+    /// Restore a device from its saved state.
+    pub fn setup(rdm: Rc<RefCell<Randomizer>>, save: &SimDevSave) -> (Uuid, SimDev) {
+        (
+            save.uuid,
+            SimDev {
+                devnode: save.devnode.clone(),
+                rdm,
+                user_info: save.user_info.clone(),
+                hardware_info: save.hardware_info.clone(),
+                initialization_time: save.initialization_time,
+                maintenance: save.maintenance,
+                in_use: save.in_use,
+                properties: SimDevProperties {
+                    size: save.size,
+                    rotational: save.rotational,
+                    latency: save.latency,
+                },
+                dbus_path: MaybeDbusPath(None),
+            },
+        )
+    }
+
+    /// The state of this device that is saved across restarts.
+    pub fn record(&self, uuid: Uuid) -> SimDevSave {
+        SimDevSave {
+            uuid,
+            devnode: self.devnode.clone(),
+            user_info: self.user_info.clone(),
+            hardware_info: self.hardware_info.clone(),
+            initialization_time: self.initialization_time,
+            maintenance: self.maintenance,
+            in_use: self.in_use,
+            size: self.properties.size,
+            rotational: self.properties.rotational,
+            latency: self.properties.latency,
+        }
+    }
+
+    /// The size of the device, if it was given one, rather than the default.
+    pub fn size_property(&self) -> Option<Sectors> {
+        self.properties.size
+    }
+
+    /// How long each operation that writes to this device takes.
+    pub fn latency(&self) -> Duration {
+        self.properties.latency
+    }
+
     /// Set the user info on this blockdev.
     /// The user_info may be None, which unsets user info.
     /// Returns true if the user info was changed, otherwise false.
     pub fn set_user_info(&mut self, user_info: Option<&str>) -> bool {
         set_blockdev_user_info!(self; user_info)
     }
+
+    /// Take this blockdev offline for maintenance or bring it back online.
+    /// Returns true if the maintenance state was changed, otherwise false.
+    pub fn set_maintenance(&mut self, maintenance: bool) -> bool {
+        if self.maintenance != maintenance {
+            self.maintenance = maintenance;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Record whether the pool's data is held on this device.
+    pub fn set_in_use(&mut self, in_use: bool) {
+        self.in_use = in_use;
+    }
 }
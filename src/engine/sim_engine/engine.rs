@@ -5,28 +5,112 @@
 use std::cell::RefCell;
 use std::collections::hash_map::RandomState;
 use std::collections::HashSet;
+use std::fs::{read_to_string, rename, write};
 use std::iter::FromIterator;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
 
-use devicemapper::Device;
+use serde_json::{self, Value};
 
-use crate::engine::{Engine, Name, Pool, PoolUuid, Redundancy, RenameAction};
+use devicemapper::{Bytes, Device, Sectors, IEC};
+
+use crate::engine::{
+    AllocationStrategy, ChunkSize, DevUuid, Engine, EngineEvent, Filesystem, FilesystemUuid, Name,
+    PlannedBlockDev, Pool, PoolId, PoolPlan, PoolUuid, Redundancy, RenameAction, SimDevProperties,
+    SimFailureModel, SimOperation, ThinRepairSummary,
+};
 use crate::stratis::{ErrorEnum, StratisError, StratisResult};
 
+use crate::engine::check::CheckFinding;
 use crate::engine::engine::Eventable;
+use crate::engine::event::get_engine_listener_list;
+use crate::engine::jobs::{JobKind, JobStatus, JobTable, JobUuid, JOB_STEP_TIME};
+use crate::engine::reconcile::{self, PoolSpec, ReconcileAction};
+use crate::engine::records::{
+    blockdev_records, filesystem_records, pool_records, BlockDevRecord, FilesystemRecord,
+    PoolRecord,
+};
+use crate::engine::requests::CompletedRequests;
 use crate::engine::structures::Table;
 
-use crate::engine::sim_engine::pool::SimPool;
+use crate::engine::sim_engine::pool::{choose_chunk_size, SimPool};
 use crate::engine::sim_engine::randomization::Randomizer;
+use crate::engine::sim_engine::serde_structs::SimEngineSave;
 
 #[derive(Debug, Default)]
 pub struct SimEngine {
     pools: Table<SimPool>,
+    exported_pools: Table<SimPool>,
+    stopped_pools: Table<SimPool>,
     rdm: Rc<RefCell<Randomizer>>,
+    state_file: Option<PathBuf>,
+    saved_state: String,
+    jobs: JobTable,
+    completed_requests: CompletedRequests,
 }
 
-impl SimEngine {}
+impl SimEngine {
+    /// A simulator whose state is saved to state_file by save_state(),
+    /// restored from the file if it already exists.
+    pub fn with_state_file(state_file: &Path) -> StratisResult<SimEngine> {
+        let mut engine = SimEngine::default();
+        if state_file.exists() {
+            let saved_state = read_to_string(state_file)?;
+            engine.setup(&serde_json::from_str::<SimEngineSave>(&saved_state)?);
+            engine.saved_state = saved_state;
+        }
+        engine.state_file = Some(state_file.to_owned());
+        Ok(engine)
+    }
+
+    fn setup(&mut self, save: &SimEngineSave) {
+        let rdm = &self.rdm;
+        let setup_pools = |saves: &[_], pools: &mut Table<SimPool>| {
+            for save in saves {
+                let (name, uuid, pool) = SimPool::setup(rdm, save);
+                pools.insert(name, uuid, pool);
+            }
+        };
+        setup_pools(&save.pools, &mut self.pools);
+        setup_pools(&save.exported_pools, &mut self.exported_pools);
+        setup_pools(&save.stopped_pools, &mut self.stopped_pools);
+    }
+
+    fn record(&self) -> SimEngineSave {
+        let record_pools = |pools: &Table<SimPool>| {
+            let mut saves = pools
+                .iter()
+                .map(|(name, uuid, pool)| pool.record(name, *uuid))
+                .collect::<Vec<_>>();
+            saves.sort_by_key(|save| save.uuid);
+            saves
+        };
+        SimEngineSave {
+            pools: record_pools(&self.pools),
+            exported_pools: record_pools(&self.exported_pools),
+            stopped_pools: record_pools(&self.stopped_pools),
+        }
+    }
+
+    /// Write the state of the simulator to its state file, if it has one
+    /// and the state has changed since it was last written. The state is
+    /// written to a temporary file which is then renamed, so that the
+    /// state file is never left half written.
+    pub fn save_state(&mut self) -> StratisResult<()> {
+        let state_file = match self.state_file {
+            Some(ref state_file) => state_file,
+            None => return Ok(()),
+        };
+        let state = serde_json::to_string(&self.record())?;
+        if state != self.saved_state {
+            let tmp_file = state_file.with_extension("tmp");
+            write(&tmp_file, &state)?;
+            rename(&tmp_file, state_file)?;
+            self.saved_state = state;
+        }
+        Ok(())
+    }
+}
 
 impl Engine for SimEngine {
     fn create_pool(
@@ -34,8 +118,11 @@ impl Engine for SimEngine {
         name: &str,
         blockdev_paths: &[&Path],
         redundancy: Option<u16>,
+        chunk_size: ChunkSize,
+        allocation: AllocationStrategy,
     ) -> StratisResult<PoolUuid> {
         let redundancy = calculate_redundancy!(redundancy);
+        let chunk_size = choose_chunk_size(chunk_size)?;
 
         if self.pools.contains_name(name) {
             return Err(StratisError::Engine(ErrorEnum::AlreadyExists, name.into()));
@@ -44,12 +131,92 @@ impl Engine for SimEngine {
         let device_set: HashSet<_, RandomState> = HashSet::from_iter(blockdev_paths);
         let devices = device_set.into_iter().cloned().collect::<Vec<&Path>>();
 
-        let (pool_uuid, pool) = SimPool::new(&Rc::clone(&self.rdm), &devices, redundancy);
+        let (pool_uuid, pool) = SimPool::new(
+            &Rc::clone(&self.rdm),
+            &devices,
+            redundancy,
+            chunk_size,
+            allocation,
+        );
+        pool.simulate_latency();
+
+        self.rdm
+            .borrow_mut()
+            .check_failure(SimOperation::CreatePool)?;
 
-        if self.rdm.borrow_mut().throw_die() {
-            return Err(StratisError::Engine(ErrorEnum::Error, "X".into()));
+        get_engine_listener_list().notify(&EngineEvent::PoolCreated {
+            name,
+            uuid: pool_uuid,
+        });
+        self.pools
+            .insert(Name::new(name.to_owned()), pool_uuid, pool);
+
+        Ok(pool_uuid)
+    }
+
+    fn plan_pool(
+        &self,
+        name: &str,
+        blockdev_paths: &[&Path],
+        redundancy: Option<u16>,
+        chunk_size: ChunkSize,
+    ) -> StratisResult<PoolPlan> {
+        let redundancy = calculate_redundancy!(redundancy);
+        let chunk_size = choose_chunk_size(chunk_size)?;
+
+        if self.pools.contains_name(name) {
+            return Err(StratisError::Engine(ErrorEnum::AlreadyExists, name.into()));
+        }
+
+        let device_set: HashSet<_, RandomState> = HashSet::from_iter(blockdev_paths);
+        let rdm = self.rdm.borrow();
+        let devices = device_set
+            .into_iter()
+            .map(|path| PlannedBlockDev {
+                devnode: path.to_path_buf(),
+                size: rdm
+                    .device_properties(path)
+                    .size
+                    .unwrap_or_else(|| Bytes(IEC::Gi).sectors()),
+                metadata_size: Sectors(0),
+                optimal_io_size: None,
+            })
+            .collect::<Vec<_>>();
+        let usable_size = devices.iter().map(|dev| dev.size).sum::<Sectors>();
+
+        Ok(PoolPlan {
+            devices,
+            redundancy,
+            usable_size,
+            initial_allocation: Sectors(0),
+            chunk_size,
+        })
+    }
+
+    fn clone_pool(
+        &mut self,
+        uuid: PoolUuid,
+        name: &str,
+        blockdev_paths: &[&Path],
+    ) -> StratisResult<PoolUuid> {
+        if self.pools.contains_name(name) {
+            return Err(StratisError::Engine(ErrorEnum::AlreadyExists, name.into()));
         }
 
+        let (pool_uuid, pool) = match self.pools.get_by_uuid(uuid) {
+            Some((_, pool)) => pool.clone_onto(blockdev_paths),
+            None => {
+                return Err(StratisError::Engine(
+                    ErrorEnum::NotFound,
+                    format!("no pool with UUID {}", uuid),
+                ))
+            }
+        };
+
+        get_engine_listener_list().notify(&EngineEvent::PoolCreated {
+            name,
+            uuid: pool_uuid,
+        });
         self.pools
             .insert(Name::new(name.to_owned()), pool_uuid, pool);
 
@@ -67,7 +234,8 @@ impl Engine for SimEngine {
     }
 
     fn destroy_pool(&mut self, uuid: PoolUuid) -> StratisResult<bool> {
-        if let Some((_, pool)) = self.pools.get_by_uuid(uuid) {
+        if let Some((name, pool)) = self.pools.get_by_uuid(uuid) {
+            refuse_if_quiesced!(pool; name);
             if pool.has_filesystems() {
                 return Err(StratisError::Engine(
                     ErrorEnum::Busy,
@@ -77,16 +245,184 @@ impl Engine for SimEngine {
         } else {
             return Ok(false);
         }
-        self.pools
+        self.rdm
+            .borrow_mut()
+            .check_failure(SimOperation::DestroyPool)?;
+        let (name, mut pool) = self
+            .pools
             .remove_by_uuid(uuid)
-            .expect("Must succeed since self.pool.get_by_uuid() returned a value")
-            .1
-            .destroy()?;
+            .expect("Must succeed since self.pool.get_by_uuid() returned a value");
+        pool.destroy()?;
+        self.jobs.cancel(uuid, "the pool was destroyed");
+        get_engine_listener_list().notify(&EngineEvent::PoolDestroyed {
+            dbus_path: pool.get_dbus_path(),
+            name: &name,
+            uuid,
+        });
         Ok(true)
     }
 
+    fn export_pool(&mut self, uuid: PoolUuid) -> StratisResult<bool> {
+        match self.pools.get_by_uuid(uuid) {
+            Some((name, pool)) => refuse_if_quiesced!(pool; name),
+            None => return Ok(false),
+        }
+
+        match self.pools.remove_by_uuid(uuid) {
+            Some((name, pool)) => {
+                self.jobs.cancel(uuid, "the pool was exported");
+                get_engine_listener_list().notify(&EngineEvent::PoolDestroyed {
+                    dbus_path: pool.get_dbus_path(),
+                    name: &name,
+                    uuid,
+                });
+                self.exported_pools.insert(name, uuid, pool);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn stop_pool(&mut self, uuid: PoolUuid) -> StratisResult<bool> {
+        match self.pools.get_by_uuid(uuid) {
+            Some((name, pool)) => refuse_if_quiesced!(pool; name),
+            None => return Ok(false),
+        }
+
+        match self.pools.remove_by_uuid(uuid) {
+            Some((name, pool)) => {
+                self.jobs.cancel(uuid, "the pool was stopped");
+                get_engine_listener_list().notify(&EngineEvent::PoolDestroyed {
+                    dbus_path: pool.get_dbus_path(),
+                    name: &name,
+                    uuid,
+                });
+                self.stopped_pools.insert(name, uuid, pool);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn start_pool(&mut self, uuid: PoolUuid, _force: bool) -> StratisResult<bool> {
+        if self.pools.contains_uuid(uuid) {
+            return Ok(false);
+        }
+
+        let (name, pool) = self.stopped_pools.remove_by_uuid(uuid).ok_or_else(|| {
+            StratisError::Engine(
+                ErrorEnum::NotFound,
+                format!("no stopped pool with uuid {}", uuid),
+            )
+        })?;
+
+        if self.pools.contains_name(&name) {
+            let err_msg = format!("a pool with name {} is already set up", name);
+            self.stopped_pools.insert(name, uuid, pool);
+            return Err(StratisError::Engine(ErrorEnum::AlreadyExists, err_msg));
+        }
+
+        get_engine_listener_list().notify(&EngineEvent::PoolCreated { name: &name, uuid });
+        self.pools.insert(name, uuid, pool);
+        Ok(true)
+    }
+
+    fn forget_missing_device(
+        &mut self,
+        pool_uuid: PoolUuid,
+        dev_uuid: DevUuid,
+    ) -> StratisResult<bool> {
+        // The simulator never loses devices.
+        match self.pools.get_by_uuid(pool_uuid) {
+            Some((_, pool)) => {
+                if pool.get_blockdev(dev_uuid).is_some() {
+                    let err_msg = format!("device with uuid {} is not missing", dev_uuid);
+                    Err(StratisError::Engine(ErrorEnum::Invalid, err_msg))
+                } else {
+                    Ok(false)
+                }
+            }
+            None => Err(StratisError::Engine(
+                ErrorEnum::NotFound,
+                format!("no pool with uuid {}", pool_uuid),
+            )),
+        }
+    }
+
+    fn import_pool(&mut self, id: &PoolId, _force: bool) -> StratisResult<PoolUuid> {
+        let uuid = match *id {
+            PoolId::Uuid(uuid) => uuid,
+            PoolId::Name(ref name) => self
+                .exported_pools
+                .get_by_name(name)
+                .map(|(uuid, _)| uuid)
+                .ok_or_else(|| {
+                    StratisError::Engine(
+                        ErrorEnum::NotFound,
+                        format!("no exported pool with name {}", name),
+                    )
+                })?,
+        };
+
+        if self.pools.contains_uuid(uuid) {
+            return Err(StratisError::Engine(
+                ErrorEnum::AlreadyExists,
+                format!("pool with uuid {} is already set up", uuid),
+            ));
+        }
+
+        let (name, pool) = self.exported_pools.remove_by_uuid(uuid).ok_or_else(|| {
+            StratisError::Engine(
+                ErrorEnum::NotFound,
+                format!("no exported pool with uuid {}", uuid),
+            )
+        })?;
+
+        if self.pools.contains_name(&name) {
+            let err_msg = format!("a pool with name {} is already set up", name);
+            self.exported_pools.insert(name, uuid, pool);
+            return Err(StratisError::Engine(ErrorEnum::AlreadyExists, err_msg));
+        }
+
+        get_engine_listener_list().notify(&EngineEvent::PoolCreated { name: &name, uuid });
+        self.pools.insert(name, uuid, pool);
+        Ok(uuid)
+    }
+
+    fn restore_pool_metadata(&mut self, backup: &Path, force: bool) -> StratisResult<PoolUuid> {
+        let backup: serde_json::Value = serde_json::from_str(&read_to_string(backup)?)?;
+        let uuid = match backup.get("pool_uuid").and_then(|uuid| uuid.as_str()) {
+            Some(uuid) => PoolUuid::parse_str(uuid)?,
+            None => {
+                return Err(StratisError::Engine(
+                    ErrorEnum::Invalid,
+                    "backup has no pool UUID".into(),
+                ))
+            }
+        };
+        self.import_pool(&PoolId::Uuid(uuid), force)
+    }
+
+    fn repair_thin_metadata(&mut self, uuid: PoolUuid) -> StratisResult<ThinRepairSummary> {
+        // The simulator's thin metadata is never damaged.
+        if self.pools.contains_uuid(uuid)
+            || self.exported_pools.contains_uuid(uuid)
+            || self.stopped_pools.contains_uuid(uuid)
+        {
+            let err_msg = format!("thin metadata of pool with uuid {} needs no repair", uuid);
+            return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+        }
+        Err(StratisError::Engine(
+            ErrorEnum::NotFound,
+            format!("no pool with uuid {}", uuid),
+        ))
+    }
+
     fn rename_pool(&mut self, uuid: PoolUuid, new_name: &str) -> StratisResult<RenameAction> {
         rename_pool_pre!(self; uuid; new_name);
+        self.rdm
+            .borrow_mut()
+            .check_failure(SimOperation::RenamePool)?;
 
         let (_, pool) = self
             .pools
@@ -106,12 +442,49 @@ impl Engine for SimEngine {
         get_mut_pool!(self; uuid)
     }
 
+    fn get_filesystem(&self, uuid: FilesystemUuid) -> Option<(PoolUuid, Name, &dyn Filesystem)> {
+        get_filesystem!(self; uuid)
+    }
+
+    fn get_mut_filesystem(
+        &mut self,
+        uuid: FilesystemUuid,
+    ) -> Option<(PoolUuid, Name, &mut dyn Filesystem)> {
+        get_mut_filesystem!(self; uuid)
+    }
+
     /// Set properties of the simulator
     fn configure_simulator(&mut self, denominator: u32) -> StratisResult<()> {
         self.rdm.borrow_mut().set_probability(denominator);
         Ok(())
     }
 
+    fn configure_simulator_operation(
+        &mut self,
+        operation: SimOperation,
+        model: SimFailureModel,
+    ) -> StratisResult<()> {
+        self.rdm.borrow_mut().set_failure_model(operation, model);
+        Ok(())
+    }
+
+    fn configure_simulator_device(
+        &mut self,
+        devnode: &Path,
+        properties: SimDevProperties,
+    ) -> StratisResult<()> {
+        if properties.size == Some(Sectors(0)) {
+            return Err(StratisError::Engine(
+                ErrorEnum::Invalid,
+                "device size must be greater than 0".into(),
+            ));
+        }
+        self.rdm
+            .borrow_mut()
+            .set_device_properties(devnode, properties);
+        Ok(())
+    }
+
     fn pools(&self) -> Vec<(Name, PoolUuid, &dyn Pool)> {
         self.pools
             .iter()
@@ -126,6 +499,77 @@ impl Engine for SimEngine {
             .collect()
     }
 
+    fn pool_records(&self) -> Vec<PoolRecord> {
+        pool_records(self.pools())
+    }
+
+    fn filesystem_records(&self) -> Vec<FilesystemRecord> {
+        filesystem_records(self.pools())
+    }
+
+    fn blockdev_records(&self) -> Vec<BlockDevRecord> {
+        blockdev_records(self.pools())
+    }
+
+    fn reconcile(
+        &mut self,
+        desired: &[PoolSpec],
+        dry_run: bool,
+    ) -> StratisResult<Vec<ReconcileAction>> {
+        if dry_run {
+            reconcile::plan(self, desired)
+        } else {
+            reconcile::apply(self, desired)
+        }
+    }
+
+    fn start_job(&mut self, pool_uuid: PoolUuid, kind: JobKind) -> StratisResult<JobUuid> {
+        let devices = match self.pools.get_by_uuid(pool_uuid) {
+            Some((_, pool)) => pool
+                .blockdevs()
+                .iter()
+                .map(|(_, bd)| (bd.devnode(), bd.size()))
+                .collect(),
+            None => {
+                return Err(StratisError::Engine(
+                    ErrorEnum::NotFound,
+                    pool_uuid.to_string(),
+                ))
+            }
+        };
+        match kind {
+            JobKind::Scrub => self.jobs.start_scrub(pool_uuid, devices),
+        }
+    }
+
+    fn job_status(&self, uuid: JobUuid) -> Option<JobStatus> {
+        self.jobs.status(uuid)
+    }
+
+    fn jobs(&self) -> Vec<JobStatus> {
+        self.jobs.statuses()
+    }
+
+    fn step_jobs(&mut self) -> bool {
+        // The simulated devices are always readable.
+        self.jobs.step(|_, _, _| Ok(()), JOB_STEP_TIME)
+    }
+
+    fn completed_request(&self, request_id: &str, operation: &str) -> StratisResult<Option<Value>> {
+        self.completed_requests.result(request_id, operation)
+    }
+
+    fn complete_request(&mut self, request_id: &str, operation: &str, result: Value) {
+        self.completed_requests
+            .insert(request_id, operation, result)
+    }
+
+    fn check(&mut self, _repair: bool) -> StratisResult<Vec<CheckFinding>> {
+        // The simulator has no blockdevs, DM devices or links whose state
+        // could differ from its own.
+        Ok(vec![])
+    }
+
     fn get_eventable(&self) -> Option<&'static dyn Eventable> {
         None
     }
@@ -140,11 +584,16 @@ mod tests {
 
     use std;
     use std::path::Path;
+    use std::time::{Duration, Instant};
 
     use proptest::prelude::any;
     use uuid::Uuid;
 
-    use crate::engine::{Engine, RenameAction};
+    use crate::engine::engine::create_pool_with_defaults;
+    use crate::engine::sim_engine::filesystem::DEFAULT_SIZE;
+    use crate::engine::{
+        run_once, Engine, FilesystemSpec, JobKind, JobState, OverprovisionPolicy, RenameAction,
+    };
     use crate::stratis::{ErrorEnum, StratisError};
 
     use super::*;
@@ -176,7 +625,7 @@ mod tests {
     /// Destroying an empty pool should succeed.
     fn destroy_empty_pool() {
         let mut engine = SimEngine::default();
-        let uuid = engine.create_pool("name", &[], None).unwrap();
+        let uuid = create_pool_with_defaults(&mut engine, "name", &[], None).unwrap();
         assert!(engine.destroy_pool(uuid).is_ok());
     }
 
@@ -184,20 +633,43 @@ mod tests {
     /// Destroying a pool with devices should succeed
     fn destroy_pool_w_devices() {
         let mut engine = SimEngine::default();
-        let uuid = engine
-            .create_pool("name", &[Path::new("/s/d")], None)
-            .unwrap();
+        let uuid =
+            create_pool_with_defaults(&mut engine, "name", &[Path::new("/s/d")], None).unwrap();
         assert!(engine.destroy_pool(uuid).is_ok());
     }
 
+    #[test]
+    /// A filesystem can be found by its UUID alone, and is found in its
+    /// pool under its current name.
+    fn get_filesystem_by_uuid() {
+        let mut engine = SimEngine::default();
+        let pool_name = "pool_name";
+        let uuid =
+            create_pool_with_defaults(&mut engine, pool_name, &[Path::new("/s/d")], None).unwrap();
+        let fs_uuid = {
+            let pool = engine.get_mut_pool(uuid).unwrap().1;
+            let fs_uuid = pool
+                .create_filesystems(uuid, pool_name, &[("old_name", None)])
+                .unwrap()[0]
+                .1;
+            pool.rename_filesystem(pool_name, fs_uuid, "new_name")
+                .unwrap();
+            fs_uuid
+        };
+        let (pool_uuid, fs_name, _) = engine.get_filesystem(fs_uuid).unwrap();
+        assert_eq!(pool_uuid, uuid);
+        assert_eq!(&*fs_name, "new_name");
+        assert!(engine.get_mut_filesystem(fs_uuid).is_some());
+        assert!(engine.get_filesystem(Uuid::new_v4()).is_none());
+    }
+
     #[test]
     /// Destroying a pool with filesystems should fail
     fn destroy_pool_w_filesystem() {
         let mut engine = SimEngine::default();
         let pool_name = "pool_name";
-        let uuid = engine
-            .create_pool(pool_name, &[Path::new("/s/d")], None)
-            .unwrap();
+        let uuid =
+            create_pool_with_defaults(&mut engine, pool_name, &[Path::new("/s/d")], None).unwrap();
         {
             let pool = engine.get_mut_pool(uuid).unwrap().1;
             pool.create_filesystems(uuid, pool_name, &[("test", None)])
@@ -206,17 +678,178 @@ mod tests {
         assert!(engine.destroy_pool(uuid).is_err());
     }
 
+    #[test]
+    /// Exporting a pool removes it from the engine, so exporting it again
+    /// does nothing.
+    fn export_pool_twice() {
+        let mut engine = SimEngine::default();
+        let uuid =
+            create_pool_with_defaults(&mut engine, "name", &[Path::new("/s/d")], None).unwrap();
+        assert_matches!(engine.export_pool(uuid), Ok(true));
+        assert!(engine.get_pool(uuid).is_none());
+        assert_matches!(engine.export_pool(uuid), Ok(false));
+    }
+
+    #[test]
+    /// A quiesced pool can not be destroyed, stopped or exported until it
+    /// is resumed.
+    fn quiesced_pool_busy() {
+        let mut engine = SimEngine::default();
+        let uuid =
+            create_pool_with_defaults(&mut engine, "name", &[Path::new("/s/d")], None).unwrap();
+        engine
+            .get_mut_pool(uuid)
+            .unwrap()
+            .1
+            .quiesce(Duration::from_secs(60))
+            .unwrap();
+        assert_matches!(
+            engine.destroy_pool(uuid),
+            Err(StratisError::Engine(ErrorEnum::Busy, _))
+        );
+        assert_matches!(
+            engine.stop_pool(uuid),
+            Err(StratisError::Engine(ErrorEnum::Busy, _))
+        );
+        assert_matches!(
+            engine.export_pool(uuid),
+            Err(StratisError::Engine(ErrorEnum::Busy, _))
+        );
+        assert!(engine.get_pool(uuid).is_some());
+        engine.get_mut_pool(uuid).unwrap().1.unquiesce().unwrap();
+        assert_matches!(engine.destroy_pool(uuid), Ok(true));
+    }
+
+    #[test]
+    /// A device that is present can not be forgotten, and forgetting a
+    /// device of which the pool has no record changes nothing.
+    fn forget_missing_device() {
+        let mut engine = SimEngine::default();
+        let uuid =
+            create_pool_with_defaults(&mut engine, "name", &[Path::new("/s/d")], None).unwrap();
+        let dev_uuid = engine.get_pool(uuid).unwrap().1.blockdevs()[0].0;
+        assert_matches!(
+            engine.forget_missing_device(uuid, dev_uuid),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(
+            engine.forget_missing_device(uuid, Uuid::new_v4()),
+            Ok(false)
+        );
+        assert_matches!(
+            engine.forget_missing_device(Uuid::new_v4(), dev_uuid),
+            Err(StratisError::Engine(ErrorEnum::NotFound, _))
+        );
+    }
+
+    #[test]
+    /// A stopped pool can be started again, after which starting it again
+    /// changes nothing.
+    fn stop_start_pool() {
+        let mut engine = SimEngine::default();
+        let uuid =
+            create_pool_with_defaults(&mut engine, "name", &[Path::new("/s/d")], None).unwrap();
+        assert_matches!(engine.stop_pool(uuid), Ok(true));
+        assert!(engine.get_pool(uuid).is_none());
+        assert_matches!(engine.stop_pool(uuid), Ok(false));
+        assert_matches!(engine.start_pool(uuid, false), Ok(true));
+        assert!(engine.get_pool(uuid).is_some());
+        assert_matches!(engine.start_pool(uuid, false), Ok(false));
+        assert_matches!(
+            engine.start_pool(Uuid::new_v4(), false),
+            Err(StratisError::Engine(ErrorEnum::NotFound, _))
+        );
+    }
+
+    #[test]
+    /// The simulator's thin metadata never needs repair, so repairing it
+    /// fails whether or not the pool exists.
+    fn repair_thin_metadata() {
+        let mut engine = SimEngine::default();
+        let uuid =
+            create_pool_with_defaults(&mut engine, "name", &[Path::new("/s/d")], None).unwrap();
+        assert_matches!(
+            engine.repair_thin_metadata(uuid),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(
+            engine.repair_thin_metadata(Uuid::new_v4()),
+            Err(StratisError::Engine(ErrorEnum::NotFound, _))
+        );
+    }
+
+    #[test]
+    /// An exported pool may be imported by name or by UUID, but only once.
+    fn import_exported_pool() {
+        let mut engine = SimEngine::default();
+        let uuid =
+            create_pool_with_defaults(&mut engine, "name", &[Path::new("/s/d")], None).unwrap();
+        engine.export_pool(uuid).unwrap();
+        assert_matches!(
+            engine.import_pool(&PoolId::Name("name".into()), false),
+            Ok(u) if u == uuid
+        );
+        assert!(engine.get_pool(uuid).is_some());
+        assert_matches!(
+            engine.import_pool(&PoolId::Uuid(uuid), false),
+            Err(StratisError::Engine(ErrorEnum::AlreadyExists, _))
+        );
+
+        engine.export_pool(uuid).unwrap();
+        assert_matches!(engine.import_pool(&PoolId::Uuid(uuid), false), Ok(_));
+    }
+
+    #[test]
+    /// Restoring a pool's metadata from a backup fails while the pool is set
+    /// up, but succeeds once it has been exported.
+    fn restore_pool_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = SimEngine::default();
+        let uuid =
+            create_pool_with_defaults(&mut engine, "name", &[Path::new("/s/d")], None).unwrap();
+        let backup = engine
+            .get_pool(uuid)
+            .unwrap()
+            .1
+            .backup_metadata(uuid, "name", dir.path())
+            .unwrap();
+        assert_matches!(
+            engine.restore_pool_metadata(&backup, false),
+            Err(StratisError::Engine(ErrorEnum::AlreadyExists, _))
+        );
+
+        engine.export_pool(uuid).unwrap();
+        assert_matches!(engine.restore_pool_metadata(&backup, false), Ok(u) if u == uuid);
+        assert!(engine.get_pool(uuid).is_some());
+    }
+
+    #[test]
+    /// Importing a pool that was never exported fails.
+    fn import_unknown_pool() {
+        let mut engine = SimEngine::default();
+        assert_matches!(
+            engine.import_pool(&PoolId::Uuid(Uuid::new_v4()), false),
+            Err(StratisError::Engine(ErrorEnum::NotFound, _))
+        );
+        assert_matches!(
+            engine.import_pool(&PoolId::Name("name".into()), true),
+            Err(StratisError::Engine(ErrorEnum::NotFound, _))
+        );
+    }
+
     #[test]
     #[ignore]
     /// Creating a new pool identical to the previous should succeed
     fn create_new_pool_twice() {
         let name = "name";
         let mut engine = SimEngine::default();
-        engine.create_pool(name, &[], None).unwrap();
-        assert!(match engine.create_pool(name, &[], None) {
-            Ok(uuid) => engine.get_pool(uuid).unwrap().1.blockdevs().is_empty(),
-            Err(_) => false,
-        });
+        create_pool_with_defaults(&mut engine, name, &[], None).unwrap();
+        assert!(
+            match create_pool_with_defaults(&mut engine, name, &[], None) {
+                Ok(uuid) => engine.get_pool(uuid).unwrap().1.blockdevs().is_empty(),
+                Err(_) => false,
+            }
+        );
     }
 
     #[test]
@@ -224,11 +857,9 @@ mod tests {
     fn create_pool_name_collision() {
         let name = "name";
         let mut engine = SimEngine::default();
-        engine
-            .create_pool(name, &[Path::new("/s/d")], None)
-            .unwrap();
+        create_pool_with_defaults(&mut engine, name, &[Path::new("/s/d")], None).unwrap();
         assert_matches!(
-            engine.create_pool(name, &[], None),
+            create_pool_with_defaults(&mut engine, name, &[], None),
             Err(StratisError::Engine(ErrorEnum::AlreadyExists, _))
         );
     }
@@ -240,7 +871,7 @@ mod tests {
         let mut engine = SimEngine::default();
         let devices = vec![Path::new(path), Path::new(path)];
         assert_matches!(
-            engine.create_pool("name", &devices, None).map(|uuid| engine
+            create_pool_with_defaults(&mut engine, "name", &devices, None).map(|uuid| engine
                 .get_pool(uuid)
                 .unwrap()
                 .1
@@ -250,13 +881,122 @@ mod tests {
         );
     }
 
+    #[test]
+    /// Planning a pool lists each device once and creates no pool
+    fn plan_pool() {
+        let path = "/s/d";
+        let mut engine = SimEngine::default();
+        let devices = vec![Path::new(path), Path::new(path)];
+        let plan = engine
+            .plan_pool("name", &devices, None, ChunkSize::Auto)
+            .unwrap();
+        assert_eq!(plan.devices.len(), 1);
+        assert!(engine.pools().is_empty());
+
+        create_pool_with_defaults(&mut engine, "name", &devices, None).unwrap();
+        assert_matches!(
+            engine.plan_pool("name", &devices, None, ChunkSize::Auto),
+            Err(StratisError::Engine(ErrorEnum::AlreadyExists, _))
+        );
+        assert_matches!(
+            engine.plan_pool("other", &devices, Some(1), ChunkSize::Auto),
+            Err(StratisError::Engine(ErrorEnum::Error, _))
+        );
+    }
+
+    #[test]
+    /// A pool is given the chunk size requested, and an invalid chunk size
+    /// is refused.
+    fn create_pool_chunk_size() {
+        let mut engine = SimEngine::default();
+        let devices = [Path::new("/s/d")];
+        assert_matches!(
+            engine.create_pool(
+                "name",
+                &devices,
+                None,
+                ChunkSize::Fixed(Sectors(100)),
+                AllocationStrategy::FillFirst
+            ),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+        assert_eq!(
+            engine
+                .plan_pool("name", &devices, None, ChunkSize::AutoSnapshots)
+                .unwrap()
+                .chunk_size,
+            Sectors(128)
+        );
+        let uuid = engine
+            .create_pool(
+                "name",
+                &devices,
+                None,
+                ChunkSize::Fixed(Sectors(256)),
+                AllocationStrategy::FillFirst,
+            )
+            .unwrap();
+        assert_eq!(engine.get_pool(uuid).unwrap().1.chunk_size(), Sectors(256));
+    }
+
+    #[test]
+    /// Cloning a pool makes a new pool with the same filesystem names,
+    /// chunk size and policies, but new UUIDs.
+    fn clone_pool() {
+        let mut engine = SimEngine::default();
+        let uuid = engine
+            .create_pool(
+                "name",
+                &[Path::new("/s/d")],
+                None,
+                ChunkSize::Fixed(Sectors(256)),
+                AllocationStrategy::RoundRobin,
+            )
+            .unwrap();
+        let fs_uuid = {
+            let (_, pool) = engine.get_mut_pool(uuid).unwrap();
+            pool.set_overprovision_policy("name", OverprovisionPolicy::Limit(200))
+                .unwrap();
+            pool.set_reserved_pct("name", 10).unwrap();
+            pool.set_filesystem_limit("name", Some(5)).unwrap();
+            pool.create_filesystems(uuid, "name", &[("fs", None)])
+                .unwrap()[0]
+                .1
+        };
+
+        assert_matches!(
+            engine.clone_pool(uuid, "name", &[Path::new("/s/e")]),
+            Err(StratisError::Engine(ErrorEnum::AlreadyExists, _))
+        );
+        assert_matches!(
+            engine.clone_pool(Uuid::new_v4(), "clone", &[Path::new("/s/e")]),
+            Err(StratisError::Engine(ErrorEnum::NotFound, _))
+        );
+
+        let clone_uuid = engine
+            .clone_pool(uuid, "clone", &[Path::new("/s/e")])
+            .unwrap();
+        assert_ne!(clone_uuid, uuid);
+        let (_, clone) = engine.get_pool(clone_uuid).unwrap();
+        assert_eq!(clone.chunk_size(), Sectors(256));
+        assert_eq!(clone.allocation_strategy(), AllocationStrategy::RoundRobin);
+        assert_eq!(
+            clone.overprovision_policy(),
+            OverprovisionPolicy::Limit(200)
+        );
+        assert_eq!(clone.reserved_pct(), 10);
+        assert_eq!(clone.filesystem_limit(), 5);
+        let filesystems = clone.filesystems();
+        assert_eq!(filesystems.len(), 1);
+        assert_eq!(&*filesystems[0].0, "fs");
+        assert_ne!(filesystems[0].1, fs_uuid);
+    }
+
     #[test]
     /// Creating a pool with an impossible raid level should fail
     fn create_pool_max_u16_raid() {
         let mut engine = SimEngine::default();
-        assert!(engine
-            .create_pool("name", &[], Some(std::u16::MAX))
-            .is_err());
+        assert!(create_pool_with_defaults(&mut engine, "name", &[], Some(std::u16::MAX)).is_err());
     }
 
     #[test]
@@ -274,7 +1014,7 @@ mod tests {
     fn rename_identity() {
         let name = "name";
         let mut engine = SimEngine::default();
-        let uuid = engine.create_pool(name, &[], None).unwrap();
+        let uuid = create_pool_with_defaults(&mut engine, name, &[], None).unwrap();
         assert_matches!(engine.rename_pool(uuid, name), Ok(RenameAction::Identity));
     }
 
@@ -282,7 +1022,7 @@ mod tests {
     /// Renaming a pool to another pool should work if new name not taken
     fn rename_happens() {
         let mut engine = SimEngine::default();
-        let uuid = engine.create_pool("old_name", &[], None).unwrap();
+        let uuid = create_pool_with_defaults(&mut engine, "old_name", &[], None).unwrap();
         assert_matches!(
             engine.rename_pool(uuid, "new_name"),
             Ok(RenameAction::Renamed)
@@ -294,8 +1034,8 @@ mod tests {
     fn rename_fails() {
         let new_name = "new_name";
         let mut engine = SimEngine::default();
-        let uuid = engine.create_pool("old_name", &[], None).unwrap();
-        engine.create_pool(new_name, &[], None).unwrap();
+        let uuid = create_pool_with_defaults(&mut engine, "old_name", &[], None).unwrap();
+        create_pool_with_defaults(&mut engine, new_name, &[], None).unwrap();
         assert_matches!(
             engine.rename_pool(uuid, new_name),
             Err(StratisError::Engine(ErrorEnum::AlreadyExists, _))
@@ -307,11 +1047,298 @@ mod tests {
     fn rename_no_op() {
         let new_name = "new_name";
         let mut engine = SimEngine::default();
-        engine.create_pool(new_name, &[], None).unwrap();
+        create_pool_with_defaults(&mut engine, new_name, &[], None).unwrap();
         assert_matches!(
             engine.rename_pool(Uuid::new_v4(), new_name),
             Ok(RenameAction::NoSource)
         );
     }
 
+    #[test]
+    /// The state saved by one simulator is restored by the next one to use
+    /// the same state file, and is rewritten only when it changes.
+    fn state_file_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_file = dir.path().join("sim.json");
+
+        let mut engine = SimEngine::with_state_file(&state_file).unwrap();
+        engine.save_state().unwrap();
+        assert!(state_file.exists());
+
+        let pool_uuid = create_pool_with_defaults(
+            &mut engine,
+            "name",
+            &[Path::new("/s/d"), Path::new("/s/e")],
+            None,
+        )
+        .unwrap();
+        let stopped_uuid =
+            create_pool_with_defaults(&mut engine, "stopped", &[Path::new("/s/f")], None).unwrap();
+        assert!(engine.stop_pool(stopped_uuid).unwrap());
+        {
+            let (pool_name, pool) = engine.get_mut_pool(pool_uuid).unwrap();
+            let fs_uuid = pool
+                .create_filesystems(pool_uuid, &pool_name, &[("fs", None)])
+                .unwrap()
+                .pop()
+                .unwrap()
+                .1;
+            pool.snapshot_filesystem(pool_uuid, &pool_name, fs_uuid, "snap", false, false)
+                .unwrap();
+            assert!(pool.set_snapshot_limit(&pool_name, Some(2)).unwrap());
+        }
+        engine.save_state().unwrap();
+        let saved_state = read_to_string(&state_file).unwrap();
+
+        let mut restored = SimEngine::with_state_file(&state_file).unwrap();
+        assert_eq!(restored.record(), engine.record());
+        let (pool_name, pool) = restored.get_pool(pool_uuid).unwrap();
+        assert_eq!(&*pool_name, "name");
+        assert_eq!(pool.snapshot_limit(), Some(2));
+        assert_eq!(pool.filesystems().len(), 2);
+        assert!(restored.get_pool(stopped_uuid).is_none());
+        assert!(restored.start_pool(stopped_uuid, false).unwrap());
+
+        restored.save_state().unwrap();
+        assert_ne!(read_to_string(&state_file).unwrap(), saved_state);
+    }
+
+    #[test]
+    /// A device is created with the properties configured for its devnode,
+    /// and a pool with a sized device is only as big as its devices.
+    fn configured_device_properties() {
+        let mut engine = SimEngine::default();
+        let latency = Duration::from_millis(20);
+        engine
+            .configure_simulator_device(
+                Path::new("/s/slow"),
+                SimDevProperties {
+                    size: Some(Bytes(4 * IEC::Gi).sectors()),
+                    rotational: true,
+                    latency,
+                },
+            )
+            .unwrap();
+        assert_matches!(
+            engine.configure_simulator_device(
+                Path::new("/s/empty"),
+                SimDevProperties {
+                    size: Some(Sectors(0)),
+                    ..SimDevProperties::default()
+                },
+            ),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+
+        let plan = engine
+            .plan_pool(
+                "name",
+                &[Path::new("/s/slow"), Path::new("/s/d")],
+                None,
+                ChunkSize::Auto,
+            )
+            .unwrap();
+        assert_eq!(plan.usable_size, Bytes(5 * IEC::Gi).sectors());
+
+        let start = Instant::now();
+        let uuid = create_pool_with_defaults(
+            &mut engine,
+            "name",
+            &[Path::new("/s/slow"), Path::new("/s/d")],
+            None,
+        )
+        .unwrap();
+        assert!(start.elapsed() >= latency);
+
+        let (_, pool) = engine.get_pool(uuid).unwrap();
+        assert_eq!(pool.total_physical_size(), Bytes(5 * IEC::Gi).sectors());
+        let mut rotational = pool
+            .blockdevs()
+            .iter()
+            .map(|(_, bd)| (bd.devnode(), bd.rotational()))
+            .collect::<Vec<_>>();
+        rotational.sort();
+        assert_eq!(
+            rotational,
+            vec![
+                (PathBuf::from("/s/d"), Some(false)),
+                (PathBuf::from("/s/slow"), Some(true)),
+            ]
+        );
+    }
+
+    #[test]
+    /// Records are made of every pool, filesystem and blockdev, in order.
+    fn records() {
+        let mut engine = SimEngine::default();
+        let uuid_b =
+            create_pool_with_defaults(&mut engine, "b", &[Path::new("/s/b")], None).unwrap();
+        let uuid_a = create_pool_with_defaults(
+            &mut engine,
+            "a",
+            &[Path::new("/s/a2"), Path::new("/s/a1")],
+            None,
+        )
+        .unwrap();
+        {
+            let (_, pool) = engine.get_mut_pool(uuid_a).unwrap();
+            pool.create_filesystems(uuid_a, "a", &[("fs2", None), ("fs1", None)])
+                .unwrap();
+        }
+
+        let pool_records = engine.pool_records();
+        assert_eq!(
+            pool_records
+                .iter()
+                .map(|record| (record.uuid, record.filesystem_count, record.blockdev_count))
+                .collect::<Vec<_>>(),
+            vec![(uuid_a, 2, 2), (uuid_b, 0, 1)]
+        );
+
+        assert_eq!(
+            engine
+                .filesystem_records()
+                .iter()
+                .map(|record| (record.pool_uuid, record.name.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(uuid_a, "fs1"), (uuid_a, "fs2")]
+        );
+
+        assert_eq!(
+            engine
+                .blockdev_records()
+                .iter()
+                .map(|record| record.devnode.clone())
+                .collect::<Vec<_>>(),
+            vec![
+                PathBuf::from("/s/a1"),
+                PathBuf::from("/s/a2"),
+                PathBuf::from("/s/b")
+            ]
+        );
+    }
+
+    #[test]
+    /// Reconciling creates what is missing, a dry run changes nothing, and
+    /// reconciling again with the same description does nothing.
+    fn reconcile() {
+        let mut engine = SimEngine::default();
+        let mut desired = vec![PoolSpec {
+            name: "pool".into(),
+            devices: vec![PathBuf::from("/s/d")],
+            filesystems: vec![FilesystemSpec {
+                name: "fs".into(),
+                size: Some(DEFAULT_SIZE),
+            }],
+        }];
+
+        let planned = engine.reconcile(&desired, true).unwrap();
+        assert_eq!(planned.len(), 2);
+        assert!(engine.pools().is_empty());
+        assert_eq!(engine.reconcile(&desired, false).unwrap(), planned);
+        assert_eq!(engine.reconcile(&desired, false).unwrap(), vec![]);
+
+        desired[0].devices.push(PathBuf::from("/s/e"));
+        desired[0].filesystems[0].size = Some(DEFAULT_SIZE + Sectors(2048));
+        assert_eq!(
+            engine.reconcile(&desired, false).unwrap(),
+            vec![
+                ReconcileAction::AddBlockdevs {
+                    pool_name: "pool".into(),
+                    devices: vec![PathBuf::from("/s/e")],
+                },
+                ReconcileAction::ExtendFilesystem {
+                    pool_name: "pool".into(),
+                    name: "fs".into(),
+                    extend_size: Sectors(2048),
+                },
+            ]
+        );
+        assert_eq!(engine.reconcile(&desired, true).unwrap(), vec![]);
+
+        desired.push(desired[0].clone());
+        assert_matches!(
+            engine.reconcile(&desired, true),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+    }
+
+    #[test]
+    /// A scrub of a pool runs a step at a time until every device has been
+    /// read, and only one job at a time may run on a pool.
+    fn scrub_job() {
+        let mut engine = SimEngine::default();
+        assert_matches!(
+            engine.start_job(Uuid::new_v4(), JobKind::Scrub),
+            Err(StratisError::Engine(ErrorEnum::NotFound, _))
+        );
+
+        let pool_uuid = create_pool_with_defaults(
+            &mut engine,
+            "name",
+            &[Path::new("/s/d"), Path::new("/s/e")],
+            None,
+        )
+        .unwrap();
+        let uuid = engine.start_job(pool_uuid, JobKind::Scrub).unwrap();
+        assert_matches!(
+            engine.start_job(pool_uuid, JobKind::Scrub),
+            Err(StratisError::Engine(ErrorEnum::Busy, _))
+        );
+        assert_eq!(engine.job_status(uuid).unwrap().state, JobState::Running);
+
+        while engine.step_jobs() {}
+
+        let status = engine.job_status(uuid).unwrap();
+        assert_eq!(status.state, JobState::Succeeded);
+        assert_eq!(status.progress_pct(), 100);
+        assert_eq!(engine.jobs(), vec![status]);
+        assert!(engine.start_job(pool_uuid, JobKind::Scrub).is_ok());
+    }
+
+    #[test]
+    /// Destroying a pool while its scrub is running cancels the scrub,
+    /// which then fails, and leaves no job running.
+    fn scrub_job_pool_destroyed() {
+        let mut engine = SimEngine::default();
+        let pool_uuid = create_pool_with_defaults(
+            &mut engine,
+            "name",
+            &[Path::new("/s/d"), Path::new("/s/e")],
+            None,
+        )
+        .unwrap();
+        let uuid = engine.start_job(pool_uuid, JobKind::Scrub).unwrap();
+
+        assert!(engine.destroy_pool(pool_uuid).unwrap());
+        assert_matches!(engine.job_status(uuid).unwrap().state, JobState::Failed(_));
+        assert!(!engine.step_jobs());
+    }
+
+    #[test]
+    /// A request made again with the same request ID is given the result
+    /// of the first, without the operation being done again.
+    fn request_replayed() {
+        let mut engine = SimEngine::default();
+        let create = |engine: &mut dyn Engine| {
+            create_pool_with_defaults(engine, "name", &[Path::new("/s/d")], None)
+        };
+        let uuid = run_once(&mut engine, Some("create"), "create_pool", create).unwrap();
+        assert_eq!(
+            run_once(&mut engine, Some("create"), "create_pool", create).unwrap(),
+            uuid
+        );
+        assert_eq!(engine.pools().len(), 1);
+        // Without a request ID, the operation is done again, and fails.
+        assert!(run_once(&mut engine, None, "create_pool", create).is_err());
+
+        let destroy = |engine: &mut dyn Engine| engine.destroy_pool(uuid);
+        assert_matches!(
+            run_once(&mut engine, Some("create"), "destroy_pool", destroy),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+        assert!(run_once(&mut engine, Some("destroy"), "destroy_pool", destroy).unwrap());
+        assert!(run_once(&mut engine, Some("destroy"), "destroy_pool", destroy).unwrap());
+        assert!(engine.pools().is_empty());
+    }
 }
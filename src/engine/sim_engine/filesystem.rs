@@ -2,37 +2,216 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, TimeZone, Utc};
 
 use rand;
 
 use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
-use devicemapper::Bytes;
+use devicemapper::{Bytes, Sectors, IEC};
 
-use crate::engine::{Filesystem, MaybeDbusPath};
-use crate::stratis::StratisResult;
+use crate::engine::{Filesystem, FilesystemUsage, FilesystemUuid, MaybeDbusPath};
+use crate::stratis::{ErrorEnum, StratisError, StratisResult};
+
+use crate::engine::engine::DEV_PATH;
+
+use crate::engine::sim_engine::serde_structs::SimFilesystemSave;
+
+/// The size of a filesystem whose size was not specified at creation.
+pub const DEFAULT_SIZE: Sectors = Sectors(2 * IEC::Gi); // 1 TiB
 
 #[derive(Debug)]
 pub struct SimFilesystem {
     rand: u32,
     created: DateTime<Utc>,
+    size: Sectors,
+    initial_size: Sectors,
+    origin: Option<FilesystemUuid>,
+    xfs_uuid: Option<FilesystemUuid>,
+    size_limit: Option<Sectors>,
+    snapshot_limit: Option<u64>,
+    user_info: Option<String>,
+    read_only: bool,
+    trim_interval: Option<Duration>,
+    next_trim: Option<Instant>,
     dbus_path: MaybeDbusPath,
 }
 
 impl SimFilesystem {
-    pub fn new() -> SimFilesystem {
+    pub fn new(size: Sectors) -> SimFilesystem {
         SimFilesystem {
             rand: rand::random::<u32>(),
             created: Utc::now(),
+            size,
+            initial_size: size,
+            origin: None,
+            xfs_uuid: None,
+            size_limit: None,
+            snapshot_limit: None,
+            user_info: None,
+            read_only: false,
+            trim_interval: None,
+            next_trim: None,
+            dbus_path: MaybeDbusPath(None),
+        }
+    }
+
+    /// Restore a filesystem from its saved state. The interval to the next
+    /// trim starts again from now.
+    pub fn setup(save: &SimFilesystemSave) -> SimFilesystem {
+        let trim_interval = save.trim_interval_secs.map(Duration::from_secs);
+        SimFilesystem {
+            rand: rand::random::<u32>(),
+            created: Utc.timestamp(save.created as i64, 0),
+            size: save.size,
+            initial_size: save.initial_size,
+            origin: save.origin,
+            xfs_uuid: save.xfs_uuid,
+            size_limit: save.size_limit,
+            snapshot_limit: save.snapshot_limit,
+            user_info: save.user_info.clone(),
+            read_only: save.read_only,
+            trim_interval,
+            next_trim: trim_interval.map(|interval| Instant::now() + interval),
             dbus_path: MaybeDbusPath(None),
         }
     }
+
+    /// The state of this filesystem that is saved across restarts.
+    pub fn record(&self, name: &str, uuid: FilesystemUuid) -> SimFilesystemSave {
+        SimFilesystemSave {
+            name: name.to_owned(),
+            uuid,
+            created: self.created.timestamp() as u64,
+            size: self.size,
+            initial_size: self.initial_size,
+            origin: self.origin,
+            xfs_uuid: self.xfs_uuid,
+            size_limit: self.size_limit,
+            snapshot_limit: self.snapshot_limit,
+            user_info: self.user_info.clone(),
+            read_only: self.read_only,
+            trim_interval_secs: self.trim_interval.map(|interval| interval.as_secs()),
+        }
+    }
+
+    /// A snapshot of this filesystem, whose UUID is origin. If keep_xfs_uuid
+    /// is true, the snapshot has the same XFS UUID as this filesystem.
+    pub fn snapshot(&self, origin: FilesystemUuid, keep_xfs_uuid: bool) -> SimFilesystem {
+        SimFilesystem {
+            origin: Some(origin),
+            xfs_uuid: if keep_xfs_uuid {
+                Some(self.xfs_uuid.unwrap_or(origin))
+            } else {
+                None
+            },
+            ..SimFilesystem::new(self.size)
+        }
+    }
+
+    pub fn size(&self) -> Sectors {
+        self.size
+    }
+
+    pub fn set_origin(&mut self, origin: Option<FilesystemUuid>) {
+        self.origin = origin;
+    }
+
+    pub fn set_user_info(&mut self, user_info: Option<&str>) -> bool {
+        if self.user_info.as_ref().map(|x| &**x) != user_info {
+            self.user_info = user_info.map(|x| x.to_owned());
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Set the size limit. The simulated filesystem has no size, so any
+    /// limit but 0 is accepted.
+    pub fn set_size_limit(&mut self, limit: Option<Sectors>) -> StratisResult<bool> {
+        if limit == Some(Sectors(0)) {
+            return Err(StratisError::Engine(
+                ErrorEnum::Invalid,
+                "size limit must be greater than 0".into(),
+            ));
+        }
+        let changed = self.size_limit != limit;
+        self.size_limit = limit;
+        Ok(changed)
+    }
+
+    pub fn set_snapshot_limit(&mut self, limit: Option<u64>) -> bool {
+        let changed = self.snapshot_limit != limit;
+        self.snapshot_limit = limit;
+        changed
+    }
+
+    pub fn extend(&mut self, extend_size: Sectors) -> StratisResult<()> {
+        self.check_writable()?;
+        if let Some(limit) = self.size_limit {
+            if self.size + extend_size > limit {
+                return Err(StratisError::Engine(
+                    ErrorEnum::Invalid,
+                    format!("size limit {} would be exceeded", limit),
+                ));
+            }
+        }
+        self.size += extend_size;
+        Ok(())
+    }
+
+    /// Shrink the filesystem to new_size, which must be less than its size
+    /// and at least its initial size. The simulated XFS filesystem is never
+    /// grown, so it always has the initial size.
+    pub fn shrink(&mut self, new_size: Sectors) -> StratisResult<()> {
+        self.check_writable()?;
+        if new_size >= self.size || new_size < self.initial_size {
+            return Err(StratisError::Engine(
+                ErrorEnum::Invalid,
+                format!(
+                    "new size {} must be less than {} and at least {}",
+                    new_size, self.size, self.initial_size
+                ),
+            ));
+        }
+        self.size = new_size;
+        Ok(())
+    }
+
+    pub fn set_read_only(&mut self, read_only: bool) -> bool {
+        let changed = self.read_only != read_only;
+        self.read_only = read_only;
+        changed
+    }
+
+    pub fn check_writable(&self) -> StratisResult<()> {
+        if self.read_only {
+            return Err(StratisError::Engine(
+                ErrorEnum::Busy,
+                "filesystem is read-only".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn set_trim_interval(&mut self, interval: Option<Duration>) -> StratisResult<bool> {
+        if interval == Some(Duration::from_secs(0)) {
+            return Err(StratisError::Engine(
+                ErrorEnum::Invalid,
+                "trim interval must be greater than 0".into(),
+            ));
+        }
+        let changed = self.trim_interval != interval;
+        self.trim_interval = interval;
+        self.next_trim = interval.map(|interval| Instant::now() + interval);
+        Ok(changed)
+    }
 }
 
 impl Filesystem for SimFilesystem {
     fn devnode(&self) -> PathBuf {
-        ["/stratis", &format!("random-{}", self.rand)]
+        [DEV_PATH, &format!("random-{}", self.rand)]
             .iter()
             .collect()
     }
@@ -41,6 +220,62 @@ impl Filesystem for SimFilesystem {
         self.created
     }
 
+    fn origin(&self) -> Option<FilesystemUuid> {
+        self.origin
+    }
+
+    fn shared_xfs_uuid(&self) -> Option<FilesystemUuid> {
+        self.xfs_uuid
+    }
+
+    fn initial_size(&self) -> Option<Sectors> {
+        Some(self.initial_size)
+    }
+
+    fn size_limit(&self) -> Option<Sectors> {
+        self.size_limit
+    }
+
+    fn snapshot_limit(&self) -> Option<u64> {
+        self.snapshot_limit
+    }
+
+    fn user_info(&self) -> Option<&str> {
+        self.user_info.as_ref().map(|x| &**x)
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn trim_interval(&self) -> Option<Duration> {
+        self.trim_interval
+    }
+
+    fn trim_deadline(&self) -> Option<Instant> {
+        self.next_trim
+    }
+
+    /// The simulated filesystem is never mounted, so there is nothing to
+    /// trim; only the next trim is scheduled.
+    fn trim(&mut self) -> StratisResult<Option<Bytes>> {
+        self.next_trim = self.trim_interval.map(|interval| Instant::now() + interval);
+        Ok(None)
+    }
+
+    fn min_size(&self) -> StratisResult<Sectors> {
+        Ok(self.initial_size)
+    }
+
+    fn usage(&self) -> StratisResult<FilesystemUsage> {
+        Ok(FilesystemUsage {
+            size: self.size,
+            allocated: self.used()?,
+            fs_size: None,
+            fs_used: None,
+        })
+    }
+
     fn used(&self) -> StratisResult<Bytes> {
         Ok(Bytes(12_345_678))
     }
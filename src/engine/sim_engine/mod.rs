@@ -5,3 +5,4 @@ mod engine;
 mod filesystem;
 mod pool;
 mod randomization;
+mod serde_structs;
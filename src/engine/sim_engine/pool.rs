@@ -5,27 +5,58 @@
 use std::cell::RefCell;
 use std::collections::hash_map::RandomState;
 use std::collections::{HashMap, HashSet};
+use std::fs::write;
 use std::iter::FromIterator;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
 use std::vec::Vec;
 
+use serde_json;
 use uuid::Uuid;
 
-use devicemapper::{Sectors, IEC};
+use devicemapper::{MetaBlocks, Sectors, IEC};
 
 use crate::engine::{
-    BlockDev, BlockDevTier, DevUuid, Filesystem, FilesystemUuid, MaybeDbusPath, Name, Pool,
-    PoolUuid, Redundancy, RenameAction,
+    AllocationStrategy, BlockDev, BlockDevState, BlockDevTier, ChunkSize, DevUuid, EngineEvent,
+    Filesystem, FilesystemTree, FilesystemUuid, MaybeDbusPath, Name, OverprovisionPolicy, Pool,
+    PoolSpaceReport, PoolUuid, Redundancy, RenameAction, SimOperation,
 };
 use crate::stratis::{ErrorEnum, StratisError, StratisResult};
 
+use crate::engine::engine::DEV_PATH;
+use crate::engine::event::get_engine_listener_list;
 use crate::engine::structures::Table;
 use crate::engine::types::{FreeSpaceState, PoolExtendState, PoolState};
 
 use crate::engine::sim_engine::blockdev::SimDev;
-use crate::engine::sim_engine::filesystem::SimFilesystem;
+use crate::engine::sim_engine::filesystem::{SimFilesystem, DEFAULT_SIZE};
 use crate::engine::sim_engine::randomization::Randomizer;
+use crate::engine::sim_engine::serde_structs::{SimOverprovisionSave, SimPoolSave};
+
+// The simulated pool has no thin pool metadata to size its filesystem
+// limit by, so it uses a fixed default.
+const DEFAULT_FILESYSTEM_LIMIT: u64 = 64;
+
+/// The chunk size that a simulated pool would be given. It uses the same
+/// sizes and limits as a real pool, but there are no devices to report an
+/// optimal I/O size.
+pub fn choose_chunk_size(chunk_size: ChunkSize) -> StratisResult<Sectors> {
+    match chunk_size {
+        ChunkSize::Auto => Ok(Sectors(2 * IEC::Ki)),
+        ChunkSize::AutoSnapshots => Ok(Sectors(128)),
+        ChunkSize::Fixed(size) => {
+            if size < Sectors(128) || size > Sectors(2 * IEC::Mi) || *size % 128 != 0 {
+                return Err(StratisError::Engine(
+                    ErrorEnum::Invalid,
+                    "chunk size must be a multiple of 64 KiB between 64 KiB and 1 GiB".into(),
+                ));
+            }
+            Ok(size)
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct SimPool {
@@ -37,6 +68,15 @@ pub struct SimPool {
     pool_state: PoolState,
     pool_extend_state: PoolExtendState,
     free_space_state: FreeSpaceState,
+    overprovision: OverprovisionPolicy,
+    allocation: AllocationStrategy,
+    chunk_size: Sectors,
+    reserved_pct: u8,
+    filesystem_limit: Option<u64>,
+    snapshot_limit: Option<u64>,
+    read_only_reason: Option<String>,
+    quiesce_deadline: Option<Instant>,
+    metadata_snapshot_reserved: bool,
     dbus_path: MaybeDbusPath,
 }
 
@@ -46,9 +86,11 @@ impl SimPool {
         rdm: &Rc<RefCell<Randomizer>>,
         paths: &[&Path],
         redundancy: Redundancy,
+        chunk_size: Sectors,
+        allocation: AllocationStrategy,
     ) -> (PoolUuid, SimPool) {
         let devices: HashSet<_, RandomState> = HashSet::from_iter(paths);
-        let device_pairs = devices.iter().map(|p| SimDev::new(Rc::clone(rdm), p));
+        let device_pairs = devices.iter().map(|p| SimDev::new(Rc::clone(rdm), p, true));
         (
             Uuid::new_v4(),
             SimPool {
@@ -60,11 +102,162 @@ impl SimPool {
                 pool_state: PoolState::Initializing,
                 pool_extend_state: PoolExtendState::Good,
                 free_space_state: FreeSpaceState::Good,
+                overprovision: OverprovisionPolicy::Unlimited,
+                allocation,
+                chunk_size,
+                reserved_pct: 0,
+                filesystem_limit: None,
+                snapshot_limit: None,
+                read_only_reason: None,
+                quiesce_deadline: None,
+                metadata_snapshot_reserved: false,
                 dbus_path: MaybeDbusPath(None),
             },
         )
     }
 
+    /// Create a new pool on paths, with the redundancy, chunk size,
+    /// allocation strategy, overprovisioning policy, reserve and limits of
+    /// this pool and a copy of each of its filesystems.
+    pub fn clone_onto(&self, paths: &[&Path]) -> (PoolUuid, SimPool) {
+        let (pool_uuid, mut pool) = SimPool::new(
+            &self.rdm,
+            paths,
+            self.redundancy,
+            self.chunk_size,
+            self.allocation,
+        );
+        pool.overprovision = self.overprovision;
+        pool.reserved_pct = self.reserved_pct;
+        pool.filesystem_limit = self.filesystem_limit;
+        pool.snapshot_limit = self.snapshot_limit;
+        for (name, _, filesystem) in self.filesystems.iter() {
+            pool.filesystems.insert(
+                name.clone(),
+                Uuid::new_v4(),
+                SimFilesystem::new(filesystem.size()),
+            );
+        }
+        (pool_uuid, pool)
+    }
+
+    /// Restore a pool from its saved state.
+    pub fn setup(rdm: &Rc<RefCell<Randomizer>>, save: &SimPoolSave) -> (Name, PoolUuid, SimPool) {
+        let devs = |saves: &[_]| {
+            saves
+                .iter()
+                .map(|save| SimDev::setup(Rc::clone(rdm), save))
+                .collect::<HashMap<_, _>>()
+        };
+        let mut filesystems = Table::default();
+        for save in &save.filesystems {
+            filesystems.insert(
+                Name::new(save.name.clone()),
+                save.uuid,
+                SimFilesystem::setup(save),
+            );
+        }
+        let overprovision = match save.overprovision {
+            None => OverprovisionPolicy::Unlimited,
+            Some(SimOverprovisionSave {
+                ratio_pct,
+                enforce: false,
+            }) => OverprovisionPolicy::Warn(ratio_pct),
+            Some(SimOverprovisionSave {
+                ratio_pct,
+                enforce: true,
+            }) => OverprovisionPolicy::Limit(ratio_pct),
+        };
+        let allocation = if save.round_robin {
+            AllocationStrategy::RoundRobin
+        } else {
+            AllocationStrategy::FillFirst
+        };
+        (
+            Name::new(save.name.clone()),
+            save.uuid,
+            SimPool {
+                block_devs: devs(&save.block_devs),
+                cache_devs: devs(&save.cache_devs),
+                filesystems,
+                redundancy: Redundancy::NONE,
+                rdm: Rc::clone(rdm),
+                pool_state: PoolState::Initializing,
+                pool_extend_state: PoolExtendState::Good,
+                free_space_state: FreeSpaceState::Good,
+                overprovision,
+                allocation,
+                chunk_size: save.chunk_size,
+                reserved_pct: save.reserved_pct,
+                filesystem_limit: save.filesystem_limit,
+                snapshot_limit: save.snapshot_limit,
+                read_only_reason: save.read_only_reason.clone(),
+                quiesce_deadline: None,
+                metadata_snapshot_reserved: false,
+                dbus_path: MaybeDbusPath(None),
+            },
+        )
+    }
+
+    /// The state of this pool that is saved across restarts. A quiesced
+    /// pool is saved as if it had been resumed.
+    pub fn record(&self, name: &str, uuid: PoolUuid) -> SimPoolSave {
+        let devs = |devs: &HashMap<DevUuid, SimDev>| {
+            let mut saves = devs
+                .iter()
+                .map(|(uuid, dev)| dev.record(*uuid))
+                .collect::<Vec<_>>();
+            saves.sort_by_key(|save| save.uuid);
+            saves
+        };
+        let mut filesystems = self
+            .filesystems
+            .iter()
+            .map(|(name, uuid, filesystem)| filesystem.record(name, *uuid))
+            .collect::<Vec<_>>();
+        filesystems.sort_by_key(|save| save.uuid);
+        SimPoolSave {
+            name: name.to_owned(),
+            uuid,
+            block_devs: devs(&self.block_devs),
+            cache_devs: devs(&self.cache_devs),
+            filesystems,
+            chunk_size: self.chunk_size,
+            overprovision: match self.overprovision {
+                OverprovisionPolicy::Unlimited => None,
+                OverprovisionPolicy::Warn(ratio_pct) => Some(SimOverprovisionSave {
+                    ratio_pct,
+                    enforce: false,
+                }),
+                OverprovisionPolicy::Limit(ratio_pct) => Some(SimOverprovisionSave {
+                    ratio_pct,
+                    enforce: true,
+                }),
+            },
+            round_robin: self.allocation == AllocationStrategy::RoundRobin,
+            reserved_pct: self.reserved_pct,
+            filesystem_limit: self.filesystem_limit,
+            snapshot_limit: self.snapshot_limit,
+            read_only_reason: self.read_only_reason.clone(),
+        }
+    }
+
+    /// Take as long as the slowest device of the pool takes to write, as
+    /// an operation that writes the pool's metadata to all its devices would.
+    pub fn simulate_latency(&self) {
+        let latency = self
+            .block_devs
+            .values()
+            .chain(self.cache_devs.values())
+            .map(|bd| bd.latency())
+            .max();
+        if let Some(latency) = latency {
+            if latency > Duration::from_secs(0) {
+                thread::sleep(latency);
+            }
+        }
+    }
+
     pub fn has_filesystems(&self) -> bool {
         !self.filesystems.is_empty()
     }
@@ -80,29 +273,89 @@ impl SimPool {
                     .and_then(|bd| Some((BlockDevTier::Cache, bd)))
             })
     }
+
+    /// Check that the pool can hold additional filesystems without exceeding
+    /// its filesystem limit.
+    fn check_filesystem_limit(&self, additional: usize) -> StratisResult<()> {
+        if (self.filesystems.len() + additional) as u64 > self.filesystem_limit() {
+            return Err(StratisError::Engine(
+                ErrorEnum::Invalid,
+                "the pool's filesystem limit would be exceeded".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Check that another snapshot may be taken of the filesystem with the
+    /// given UUID without exceeding its snapshot limit, or the pool's.
+    fn check_snapshot_limit(&self, origin_uuid: FilesystemUuid) -> StratisResult<()> {
+        let limit = match self.filesystems.get_by_uuid(origin_uuid) {
+            Some((_, origin)) => origin.snapshot_limit().or(self.snapshot_limit),
+            None => None,
+        };
+        if let Some(limit) = limit {
+            let count = self
+                .filesystems
+                .iter()
+                .filter(|(_, _, filesystem)| filesystem.origin() == Some(origin_uuid))
+                .count() as u64;
+            if count >= limit {
+                return Err(StratisError::Engine(
+                    ErrorEnum::Invalid,
+                    "the filesystem's snapshot limit would be exceeded".into(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Return an error if a filesystem named name with size could not be
+    /// added to the pool because the name is in use or the size is 0.
+    fn check_filesystem_spec(&self, name: &str, size: Option<Sectors>) -> StratisResult<()> {
+        if self.filesystems.contains_name(name) {
+            return Err(StratisError::Engine(
+                ErrorEnum::AlreadyExists,
+                name.to_string(),
+            ));
+        }
+        if size == Some(Sectors(0)) {
+            return Err(StratisError::Engine(
+                ErrorEnum::Invalid,
+                format!("size of filesystem {} must be greater than 0", name),
+            ));
+        }
+        Ok(())
+    }
 }
 
 impl Pool for SimPool {
     fn create_filesystems<'a, 'b>(
         &'a mut self,
         _pool_uuid: PoolUuid,
-        _pool_name: &str,
+        pool_name: &str,
         specs: &[(&'b str, Option<Sectors>)],
     ) -> StratisResult<Vec<(&'b str, FilesystemUuid)>> {
         let names: HashMap<_, _> = HashMap::from_iter(specs.iter().map(|&tup| (tup.0, tup.1)));
-        for name in names.keys() {
-            if self.filesystems.contains_name(name) {
-                return Err(StratisError::Engine(
-                    ErrorEnum::AlreadyExists,
-                    name.to_string(),
-                ));
-            }
+        for (name, size) in names.iter() {
+            self.check_filesystem_spec(name, *size)?;
         }
+        self.check_filesystem_limit(names.len())?;
 
+        // A failure partway through leaves the filesystems already created,
+        // as it does in the real engine.
+        self.simulate_latency();
         let mut result = Vec::new();
-        for name in names.keys() {
+        for (name, size) in names.iter() {
+            self.rdm
+                .borrow_mut()
+                .check_failure(SimOperation::CreateFilesystems)?;
             let uuid = Uuid::new_v4();
-            let new_filesystem = SimFilesystem::new();
+            let new_filesystem = SimFilesystem::new(size.unwrap_or(DEFAULT_SIZE));
+            get_engine_listener_list().notify(&EngineEvent::FilesystemCreated {
+                pool_name,
+                name,
+                uuid,
+            });
             self.filesystems
                 .insert(Name::new((&**name).to_owned()), uuid, new_filesystem);
             result.push((*name, uuid));
@@ -111,6 +364,40 @@ impl Pool for SimPool {
         Ok(result)
     }
 
+    fn create_filesystems_each<'b>(
+        &mut self,
+        _pool_uuid: PoolUuid,
+        pool_name: &str,
+        specs: &[(&'b str, Option<Sectors>)],
+    ) -> StratisResult<Vec<(&'b str, StratisResult<FilesystemUuid>)>> {
+        self.simulate_latency();
+        let mut results = Vec::new();
+        for &(name, size) in specs {
+            let result = self
+                .check_filesystem_spec(name, size)
+                .and_then(|_| self.check_filesystem_limit(1))
+                .and_then(|_| {
+                    self.rdm
+                        .borrow_mut()
+                        .check_failure(SimOperation::CreateFilesystems)
+                })
+                .map(|_| {
+                    let uuid = Uuid::new_v4();
+                    let new_filesystem = SimFilesystem::new(size.unwrap_or(DEFAULT_SIZE));
+                    get_engine_listener_list().notify(&EngineEvent::FilesystemCreated {
+                        pool_name,
+                        name,
+                        uuid,
+                    });
+                    self.filesystems
+                        .insert(Name::new(name.to_owned()), uuid, new_filesystem);
+                    uuid
+                });
+            results.push((name, result));
+        }
+        Ok(results)
+    }
+
     fn add_blockdevs(
         &mut self,
         _pool_uuid: PoolUuid,
@@ -118,10 +405,13 @@ impl Pool for SimPool {
         paths: &[&Path],
         tier: BlockDevTier,
     ) -> StratisResult<Vec<DevUuid>> {
+        self.rdm
+            .borrow_mut()
+            .check_failure(SimOperation::AddBlockdevs)?;
         let devices: HashSet<_, RandomState> = HashSet::from_iter(paths);
         let device_pairs: Vec<_> = devices
             .iter()
-            .map(|p| SimDev::new(Rc::clone(&self.rdm), p))
+            .map(|p| SimDev::new(Rc::clone(&self.rdm), p, tier == BlockDevTier::Cache))
             .collect();
         let ret_uuids = device_pairs.iter().map(|&(uuid, _)| uuid).collect();
 
@@ -131,6 +421,7 @@ impl Pool for SimPool {
         };
 
         the_vec.extend(device_pairs);
+        self.simulate_latency();
         Ok(ret_uuids)
     }
 
@@ -141,15 +432,36 @@ impl Pool for SimPool {
 
     fn destroy_filesystems<'a>(
         &'a mut self,
-        _pool_name: &str,
+        pool_name: &str,
         fs_uuids: &[FilesystemUuid],
     ) -> StratisResult<Vec<FilesystemUuid>> {
+        self.simulate_latency();
+        self.rdm
+            .borrow_mut()
+            .check_failure(SimOperation::DestroyFilesystems)?;
         let mut removed = Vec::new();
+        let mut origins = HashMap::new();
         for &uuid in fs_uuids {
-            if self.filesystems.remove_by_uuid(uuid).is_some() {
+            if let Some((name, filesystem)) = self.filesystems.remove_by_uuid(uuid) {
+                origins.insert(uuid, filesystem.origin());
+                get_engine_listener_list().notify(&EngineEvent::FilesystemDestroyed {
+                    dbus_path: filesystem.get_dbus_path(),
+                    pool_name,
+                    name: &name,
+                    uuid,
+                });
                 removed.push(uuid);
             }
         }
+        // A snapshot of a destroyed filesystem becomes a snapshot of that
+        // filesystem's origin, or of its origin, if it was destroyed too.
+        for (_, _, filesystem) in self.filesystems.iter_mut() {
+            let mut origin = filesystem.origin();
+            while let Some(&next) = origin.as_ref().and_then(|uuid| origins.get(uuid)) {
+                origin = next;
+            }
+            filesystem.set_origin(origin);
+        }
         Ok(removed)
     }
 
@@ -160,6 +472,10 @@ impl Pool for SimPool {
         new_name: &str,
     ) -> StratisResult<RenameAction> {
         rename_filesystem_pre!(self; uuid; new_name);
+        self.simulate_latency();
+        self.rdm
+            .borrow_mut()
+            .check_failure(SimOperation::RenameFilesystem)?;
 
         let (_, filesystem) = self
             .filesystems
@@ -175,9 +491,11 @@ impl Pool for SimPool {
     fn snapshot_filesystem(
         &mut self,
         _pool_uuid: PoolUuid,
-        _pool_name: &str,
+        pool_name: &str,
         origin_uuid: FilesystemUuid,
         snapshot_name: &str,
+        keep_xfs_uuid: bool,
+        _freeze: bool,
     ) -> StratisResult<(FilesystemUuid, &mut dyn Filesystem)> {
         if self.filesystems.contains_name(snapshot_name) {
             return Err(StratisError::Engine(
@@ -186,9 +504,16 @@ impl Pool for SimPool {
             ));
         }
 
+        self.check_filesystem_limit(1)?;
+        self.check_snapshot_limit(origin_uuid)?;
+        self.simulate_latency();
+        self.rdm
+            .borrow_mut()
+            .check_failure(SimOperation::SnapshotFilesystem)?;
+
         let uuid = Uuid::new_v4();
-        let snapshot = match self.get_filesystem(origin_uuid) {
-            Some(_filesystem) => SimFilesystem::new(),
+        let snapshot = match self.filesystems.get_by_uuid(origin_uuid) {
+            Some((_, filesystem)) => filesystem.snapshot(origin_uuid, keep_xfs_uuid),
             None => {
                 return Err(StratisError::Engine(
                     ErrorEnum::NotFound,
@@ -196,6 +521,11 @@ impl Pool for SimPool {
                 ));
             }
         };
+        get_engine_listener_list().notify(&EngineEvent::FilesystemCreated {
+            pool_name,
+            name: snapshot_name,
+            uuid,
+        });
         self.filesystems
             .insert(Name::new(snapshot_name.to_owned()), uuid, snapshot);
         Ok((
@@ -207,16 +537,196 @@ impl Pool for SimPool {
         ))
     }
 
+    fn revert_filesystem(
+        &mut self,
+        _pool_uuid: PoolUuid,
+        _pool_name: &str,
+        uuid: FilesystemUuid,
+        snapshot_uuid: FilesystemUuid,
+    ) -> StratisResult<()> {
+        match self.filesystems.get_by_uuid(uuid) {
+            Some((_, filesystem)) => filesystem.check_writable()?,
+            None => return Err(StratisError::Engine(ErrorEnum::NotFound, uuid.to_string())),
+        }
+        match self.get_filesystem(snapshot_uuid) {
+            Some((_, snapshot)) if snapshot.origin() == Some(uuid) => Ok(()),
+            Some(_) => Err(StratisError::Engine(
+                ErrorEnum::Invalid,
+                format!("{} is not a snapshot of {}", snapshot_uuid, uuid),
+            )),
+            None => Err(StratisError::Engine(
+                ErrorEnum::NotFound,
+                snapshot_uuid.to_string(),
+            )),
+        }
+    }
+
+    fn merge_snapshot(
+        &mut self,
+        _pool_uuid: PoolUuid,
+        _pool_name: &str,
+        snapshot_uuid: FilesystemUuid,
+    ) -> StratisResult<FilesystemUuid> {
+        let uuid = match self.get_filesystem(snapshot_uuid) {
+            Some((_, snapshot)) => match snapshot.origin() {
+                Some(uuid) => uuid,
+                None => {
+                    return Err(StratisError::Engine(
+                        ErrorEnum::Invalid,
+                        format!("{} has no origin", snapshot_uuid),
+                    ));
+                }
+            },
+            None => {
+                return Err(StratisError::Engine(
+                    ErrorEnum::NotFound,
+                    snapshot_uuid.to_string(),
+                ));
+            }
+        };
+        if let Some((_, filesystem)) = self.filesystems.get_by_uuid(uuid) {
+            filesystem.check_writable()?;
+        }
+        self.filesystems.remove_by_uuid(snapshot_uuid);
+        // The snapshots of the merged snapshot become snapshots of its
+        // origin, as they do when a filesystem is destroyed.
+        for (_, _, filesystem) in self.filesystems.iter_mut() {
+            if filesystem.origin() == Some(snapshot_uuid) {
+                filesystem.set_origin(Some(uuid));
+            }
+        }
+        Ok(uuid)
+    }
+
+    fn extend_filesystem(
+        &mut self,
+        _pool_name: &str,
+        uuid: FilesystemUuid,
+        extend_size: Sectors,
+    ) -> StratisResult<()> {
+        match self.filesystems.get_mut_by_uuid(uuid) {
+            Some((_, filesystem)) => filesystem.extend(extend_size),
+            None => Err(StratisError::Engine(ErrorEnum::NotFound, uuid.to_string())),
+        }
+    }
+
+    fn set_filesystem_user_info(
+        &mut self,
+        _pool_name: &str,
+        uuid: FilesystemUuid,
+        user_info: Option<&str>,
+    ) -> StratisResult<bool> {
+        match self.filesystems.get_mut_by_uuid(uuid) {
+            Some((_, filesystem)) => Ok(filesystem.set_user_info(user_info)),
+            None => Err(StratisError::Engine(ErrorEnum::NotFound, uuid.to_string())),
+        }
+    }
+
+    fn set_filesystem_size_limit(
+        &mut self,
+        _pool_name: &str,
+        uuid: FilesystemUuid,
+        limit: Option<Sectors>,
+    ) -> StratisResult<bool> {
+        match self.filesystems.get_mut_by_uuid(uuid) {
+            Some((_, filesystem)) => filesystem.set_size_limit(limit),
+            None => Err(StratisError::Engine(ErrorEnum::NotFound, uuid.to_string())),
+        }
+    }
+
+    fn set_filesystem_snapshot_limit(
+        &mut self,
+        _pool_name: &str,
+        uuid: FilesystemUuid,
+        limit: Option<u64>,
+    ) -> StratisResult<bool> {
+        match self.filesystems.get_mut_by_uuid(uuid) {
+            Some((_, filesystem)) => Ok(filesystem.set_snapshot_limit(limit)),
+            None => Err(StratisError::Engine(ErrorEnum::NotFound, uuid.to_string())),
+        }
+    }
+
+    fn shrink_filesystem(
+        &mut self,
+        _pool_name: &str,
+        uuid: FilesystemUuid,
+        new_size: Sectors,
+    ) -> StratisResult<()> {
+        match self.filesystems.get_mut_by_uuid(uuid) {
+            Some((_, filesystem)) => filesystem.shrink(new_size),
+            None => Err(StratisError::Engine(ErrorEnum::NotFound, uuid.to_string())),
+        }
+    }
+
+    fn set_filesystem_read_only(
+        &mut self,
+        _pool_name: &str,
+        uuid: FilesystemUuid,
+        read_only: bool,
+    ) -> StratisResult<bool> {
+        match self.filesystems.get_mut_by_uuid(uuid) {
+            Some((_, filesystem)) => Ok(filesystem.set_read_only(read_only)),
+            None => Err(StratisError::Engine(ErrorEnum::NotFound, uuid.to_string())),
+        }
+    }
+
+    fn set_filesystem_trim_interval(
+        &mut self,
+        _pool_name: &str,
+        uuid: FilesystemUuid,
+        interval: Option<Duration>,
+    ) -> StratisResult<bool> {
+        match self.filesystems.get_mut_by_uuid(uuid) {
+            Some((_, filesystem)) => filesystem.set_trim_interval(interval),
+            None => Err(StratisError::Engine(ErrorEnum::NotFound, uuid.to_string())),
+        }
+    }
+
     fn total_physical_size(&self) -> Sectors {
         // We choose to make our pools very big, and we can change that
-        // if it is inconvenient.
-        Sectors(IEC::Ei)
+        // if it is inconvenient. A pool with a device that was given a size
+        // is only as big as its devices, so that its space can be used up.
+        if self
+            .block_devs
+            .values()
+            .any(|bd| bd.size_property().is_some())
+        {
+            self.block_devs.values().map(|bd| bd.size()).sum()
+        } else {
+            Sectors(IEC::Ei)
+        }
     }
 
     fn total_physical_used(&self) -> StratisResult<Sectors> {
         Ok(Sectors(0))
     }
 
+    fn space_report(&self) -> StratisResult<PoolSpaceReport> {
+        Ok(PoolSpaceReport {
+            raw_size: self.total_physical_size(),
+            metadata_overhead: Sectors(0),
+            allocated: Sectors(0),
+            free: self.total_physical_size(),
+            reserved: Sectors(*self.total_physical_size() * u64::from(self.reserved_pct) / 100),
+            thin_data_used_pct: 0,
+            thin_meta_used_pct: 0,
+            filesystems: self
+                .filesystems
+                .iter()
+                .map(|(_, uuid, fs)| Ok((*uuid, fs.used()?)))
+                .collect::<StratisResult<Vec<_>>>()?,
+        })
+    }
+
+    fn filesystem_trees(&self) -> Vec<FilesystemTree> {
+        FilesystemTree::build(
+            self.filesystems
+                .iter()
+                .map(|(name, uuid, fs)| (name.clone(), *uuid, fs.origin()))
+                .collect(),
+        )
+    }
+
     fn filesystems(&self) -> Vec<(Name, FilesystemUuid, &dyn Filesystem)> {
         self.filesystems
             .iter()
@@ -292,6 +802,95 @@ impl Pool for SimPool {
         )
     }
 
+    fn set_blockdev_maintenance(
+        &mut self,
+        _pool_name: &str,
+        uuid: DevUuid,
+        maintenance: bool,
+    ) -> StratisResult<bool> {
+        match self.get_mut_blockdev_internal(uuid) {
+            Some((_, bd)) => {
+                if maintenance && bd.state() == BlockDevState::InUse {
+                    return Err(StratisError::Engine(
+                        ErrorEnum::Busy,
+                        format!(
+                            "Blockdev {} holds data that can not be redirected to another device",
+                            uuid
+                        ),
+                    ));
+                }
+                Ok(bd.set_maintenance(maintenance))
+            }
+            None => Err(StratisError::Engine(
+                ErrorEnum::NotFound,
+                format!("No blockdev for uuid {} found", uuid),
+            )),
+        }
+    }
+
+    fn drain_blockdev(
+        &mut self,
+        _pool_uuid: PoolUuid,
+        _pool_name: &str,
+        uuid: DevUuid,
+    ) -> StratisResult<bool> {
+        if let Some(state) = self.block_devs.get(&uuid).map(|bd| bd.state()) {
+            if state == BlockDevState::NotInUse {
+                Ok(false)
+            } else if self.block_devs.len() < 2 {
+                Err(StratisError::Engine(
+                    ErrorEnum::Invalid,
+                    format!(
+                        "No other blockdev has space for the data on blockdev {}",
+                        uuid
+                    ),
+                ))
+            } else {
+                // The data is moved onto the other data blockdevs.
+                for (bd_uuid, bd) in self.block_devs.iter_mut() {
+                    bd.set_in_use(*bd_uuid != uuid);
+                }
+                Ok(true)
+            }
+        } else if self.cache_devs.contains_key(&uuid) {
+            Err(StratisError::Engine(
+                ErrorEnum::Invalid,
+                format!("Blockdev {} is in the cache tier", uuid),
+            ))
+        } else {
+            Err(StratisError::Engine(
+                ErrorEnum::NotFound,
+                format!("No blockdev for uuid {} found", uuid),
+            ))
+        }
+    }
+
+    fn reserve_metadata_snapshot(&mut self) -> StratisResult<(PathBuf, MetaBlocks)> {
+        self.metadata_snapshot_reserved = true;
+        Ok((Path::new(DEV_PATH).join("thinmeta"), MetaBlocks(1)))
+    }
+
+    fn release_metadata_snapshot(&mut self) -> StratisResult<bool> {
+        let reserved = self.metadata_snapshot_reserved;
+        self.metadata_snapshot_reserved = false;
+        Ok(reserved)
+    }
+
+    fn backup_metadata(
+        &self,
+        pool_uuid: PoolUuid,
+        pool_name: &str,
+        dir: &Path,
+    ) -> StratisResult<PathBuf> {
+        let path = dir.join(format!("{}.json", pool_uuid.to_simple_ref()));
+        let backup = serde_json::json!({
+            "pool_uuid": pool_uuid,
+            "name": pool_name,
+        });
+        write(&path, backup.to_string())?;
+        Ok(path)
+    }
+
     fn state(&self) -> PoolState {
         self.pool_state
     }
@@ -304,6 +903,120 @@ impl Pool for SimPool {
         self.free_space_state
     }
 
+    fn overprovision_policy(&self) -> OverprovisionPolicy {
+        self.overprovision
+    }
+
+    fn allocation_strategy(&self) -> AllocationStrategy {
+        self.allocation
+    }
+
+    fn set_allocation_strategy(
+        &mut self,
+        _pool_name: &str,
+        strategy: AllocationStrategy,
+    ) -> StratisResult<bool> {
+        let changed = self.allocation != strategy;
+        self.allocation = strategy;
+        Ok(changed)
+    }
+
+    fn quiesce(&mut self, timeout: Duration) -> StratisResult<bool> {
+        if self.quiesce_deadline.is_some() {
+            return Ok(false);
+        }
+        self.quiesce_deadline = Some(Instant::now() + timeout);
+        Ok(true)
+    }
+
+    fn unquiesce(&mut self) -> StratisResult<bool> {
+        Ok(self.quiesce_deadline.take().is_some())
+    }
+
+    fn quiesce_deadline(&self) -> Option<Instant> {
+        self.quiesce_deadline
+    }
+
+    fn read_only_reason(&self) -> Option<&str> {
+        self.read_only_reason.as_ref().map(|reason| &reason[..])
+    }
+
+    fn clear_read_only(&mut self) -> bool {
+        self.read_only_reason.take().is_some()
+    }
+
+    fn set_overprovision_policy(
+        &mut self,
+        _pool_name: &str,
+        policy: OverprovisionPolicy,
+    ) -> StratisResult<bool> {
+        match policy {
+            OverprovisionPolicy::Warn(0) | OverprovisionPolicy::Limit(0) => {
+                Err(StratisError::Engine(
+                    ErrorEnum::Invalid,
+                    "overprovisioning ratio must be greater than 0".into(),
+                ))
+            }
+            _ => {
+                let changed = self.overprovision != policy;
+                self.overprovision = policy;
+                Ok(changed)
+            }
+        }
+    }
+
+    fn chunk_size(&self) -> Sectors {
+        self.chunk_size
+    }
+
+    fn reserved_pct(&self) -> u8 {
+        self.reserved_pct
+    }
+
+    fn set_reserved_pct(&mut self, _pool_name: &str, pct: u8) -> StratisResult<bool> {
+        if pct > 50 {
+            return Err(StratisError::Engine(
+                ErrorEnum::Invalid,
+                "reserved capacity may not exceed 50%".into(),
+            ));
+        }
+        let changed = self.reserved_pct != pct;
+        self.reserved_pct = pct;
+        Ok(changed)
+    }
+
+    fn filesystem_limit(&self) -> u64 {
+        self.filesystem_limit.unwrap_or(DEFAULT_FILESYSTEM_LIMIT)
+    }
+
+    fn set_filesystem_limit(
+        &mut self,
+        _pool_name: &str,
+        limit: Option<u64>,
+    ) -> StratisResult<bool> {
+        if let Some(limit) = limit {
+            if limit == 0 || limit < self.filesystems.len() as u64 {
+                return Err(StratisError::Engine(
+                    ErrorEnum::Invalid,
+                    "filesystem limit must be at least 1 and no less than the number of filesystems in the pool".into(),
+                ));
+            }
+        }
+        let changed = self.filesystem_limit != limit;
+        self.filesystem_limit = limit;
+        Ok(changed)
+    }
+
+    fn snapshot_limit(&self) -> Option<u64> {
+        self.snapshot_limit
+    }
+
+    fn set_snapshot_limit(&mut self, _pool_name: &str, limit: Option<u64>) -> StratisResult<bool> {
+        let changed = self.snapshot_limit != limit;
+        self.snapshot_limit = limit;
+        Ok(changed)
+    }
+
     fn set_dbus_path(&mut self, path: MaybeDbusPath) {
         self.dbus_path = path
     }
@@ -320,8 +1033,11 @@ mod tests {
 
     use uuid::Uuid;
 
-    use crate::engine::Engine;
+    use devicemapper::Bytes;
+
+    use crate::engine::{Engine, FilesystemUsage, SimFailureModel};
 
+    use crate::engine::engine::create_pool_with_defaults;
     use crate::engine::sim_engine::SimEngine;
 
     use super::*;
@@ -331,7 +1047,7 @@ mod tests {
     fn rename_empty() {
         let mut engine = SimEngine::default();
         let pool_name = "pool_name";
-        let uuid = engine.create_pool(pool_name, &[], None).unwrap();
+        let uuid = create_pool_with_defaults(&mut engine, pool_name, &[], None).unwrap();
         let pool = engine.get_mut_pool(uuid).unwrap().1;
         assert!(
             match pool.rename_filesystem(pool_name, Uuid::new_v4(), "new_name") {
@@ -346,7 +1062,7 @@ mod tests {
     fn rename_happens() {
         let mut engine = SimEngine::default();
         let pool_name = "pool_name";
-        let uuid = engine.create_pool(pool_name, &[], None).unwrap();
+        let uuid = create_pool_with_defaults(&mut engine, pool_name, &[], None).unwrap();
         let pool = engine.get_mut_pool(uuid).unwrap().1;
         let infos = pool
             .create_filesystems(uuid, pool_name, &[("old_name", None)])
@@ -366,7 +1082,7 @@ mod tests {
         let new_name = "new_name";
         let mut engine = SimEngine::default();
         let pool_name = "pool_name";
-        let uuid = engine.create_pool(pool_name, &[], None).unwrap();
+        let uuid = create_pool_with_defaults(&mut engine, pool_name, &[], None).unwrap();
         let pool = engine.get_mut_pool(uuid).unwrap().1;
         let results = pool
             .create_filesystems(uuid, pool_name, &[(old_name, None), (new_name, None)])
@@ -386,7 +1102,7 @@ mod tests {
         let new_name = "new_name";
         let mut engine = SimEngine::default();
         let pool_name = "pool_name";
-        let uuid = engine.create_pool(pool_name, &[], None).unwrap();
+        let uuid = create_pool_with_defaults(&mut engine, pool_name, &[], None).unwrap();
         let pool = engine.get_mut_pool(uuid).unwrap().1;
         assert!(
             match pool.rename_filesystem(pool_name, Uuid::new_v4(), new_name) {
@@ -401,7 +1117,7 @@ mod tests {
     fn destroy_fs_empty() {
         let mut engine = SimEngine::default();
         let pool_name = "pool_name";
-        let uuid = engine.create_pool(pool_name, &[], None).unwrap();
+        let uuid = create_pool_with_defaults(&mut engine, pool_name, &[], None).unwrap();
         let pool = engine.get_mut_pool(uuid).unwrap().1;
         assert!(match pool.destroy_filesystems(pool_name, &[]) {
             Ok(names) => names.is_empty(),
@@ -414,7 +1130,7 @@ mod tests {
     fn destroy_fs_some() {
         let mut engine = SimEngine::default();
         let pool_name = "pool_name";
-        let uuid = engine.create_pool(pool_name, &[], None).unwrap();
+        let uuid = create_pool_with_defaults(&mut engine, pool_name, &[], None).unwrap();
         let pool = engine.get_mut_pool(uuid).unwrap().1;
         assert!(pool
             .destroy_filesystems(pool_name, &[Uuid::new_v4()])
@@ -426,7 +1142,7 @@ mod tests {
     fn destroy_fs_any() {
         let mut engine = SimEngine::default();
         let pool_name = "pool_name";
-        let uuid = engine.create_pool(pool_name, &[], None).unwrap();
+        let uuid = create_pool_with_defaults(&mut engine, pool_name, &[], None).unwrap();
         let pool = engine.get_mut_pool(uuid).unwrap().1;
         let fs_results = pool
             .create_filesystems(uuid, pool_name, &[("fs_name", None)])
@@ -445,7 +1161,7 @@ mod tests {
     fn create_fs_none() {
         let mut engine = SimEngine::default();
         let pool_name = "pool_name";
-        let uuid = engine.create_pool(pool_name, &[], None).unwrap();
+        let uuid = create_pool_with_defaults(&mut engine, pool_name, &[], None).unwrap();
         let pool = engine.get_mut_pool(uuid).unwrap().1;
         assert!(match pool.create_filesystems(uuid, pool_name, &[]) {
             Ok(names) => names.is_empty(),
@@ -458,7 +1174,7 @@ mod tests {
     fn create_fs_some() {
         let mut engine = SimEngine::default();
         let pool_name = "pool_name";
-        let uuid = engine.create_pool(pool_name, &[], None).unwrap();
+        let uuid = create_pool_with_defaults(&mut engine, pool_name, &[], None).unwrap();
         let pool = engine.get_mut_pool(uuid).unwrap().1;
         assert!(
             match pool.create_filesystems(uuid, pool_name, &[("name", None)]) {
@@ -474,7 +1190,7 @@ mod tests {
         let fs_name = "fs_name";
         let mut engine = SimEngine::default();
         let pool_name = "pool_name";
-        let uuid = engine.create_pool(pool_name, &[], None).unwrap();
+        let uuid = create_pool_with_defaults(&mut engine, pool_name, &[], None).unwrap();
         let pool = engine.get_mut_pool(uuid).unwrap().1;
         pool.create_filesystems(uuid, pool_name, &[(fs_name, None)])
             .unwrap();
@@ -492,7 +1208,7 @@ mod tests {
         let fs_name = "fs_name";
         let mut engine = SimEngine::default();
         let pool_name = "pool_name";
-        let uuid = engine.create_pool(pool_name, &[], None).unwrap();
+        let uuid = create_pool_with_defaults(&mut engine, pool_name, &[], None).unwrap();
         let pool = engine.get_mut_pool(uuid).unwrap().1;
         assert!(
             match pool.create_filesystems(uuid, pool_name, &[(fs_name, None), (fs_name, None)]) {
@@ -506,7 +1222,7 @@ mod tests {
     /// Adding a list of devices to an empty pool should yield list.
     fn add_device_empty() {
         let mut engine = SimEngine::default();
-        let uuid = engine.create_pool("pool_name", &[], None).unwrap();
+        let uuid = create_pool_with_defaults(&mut engine, "pool_name", &[], None).unwrap();
         let (pool_name, pool) = engine.get_mut_pool(uuid).unwrap();
         let devices = [Path::new("/s/a"), Path::new("/s/b")];
         assert!(
@@ -516,4 +1232,823 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    /// Reserving a metadata snapshot twice yields the same snapshot, and
+    /// only the first release releases anything.
+    fn metadata_snapshot_reserve_release() {
+        let mut engine = SimEngine::default();
+        let uuid = create_pool_with_defaults(&mut engine, "pool_name", &[Path::new("/s/d")], None)
+            .unwrap();
+        let pool = engine.get_mut_pool(uuid).unwrap().1;
+        let snapshot = pool.reserve_metadata_snapshot().unwrap();
+        assert_eq!(pool.reserve_metadata_snapshot().unwrap(), snapshot);
+        assert!(pool.release_metadata_snapshot().unwrap());
+        assert!(!pool.release_metadata_snapshot().unwrap());
+    }
+
+    #[test]
+    /// The space report of a pool lists each of its filesystems.
+    fn space_report_filesystems() {
+        let mut engine = SimEngine::default();
+        let pool_name = "pool_name";
+        let uuid =
+            create_pool_with_defaults(&mut engine, pool_name, &[Path::new("/s/d")], None).unwrap();
+        let pool = engine.get_mut_pool(uuid).unwrap().1;
+        let fs_uuid = pool
+            .create_filesystems(uuid, pool_name, &[("fs_name", None)])
+            .unwrap()[0]
+            .1;
+        let report = pool.space_report().unwrap();
+        assert_eq!(report.raw_size, pool.total_physical_size());
+        assert_eq!(report.filesystems.len(), 1);
+        assert_eq!(report.filesystems[0].0, fs_uuid);
+
+        let usage = pool.get_filesystem(fs_uuid).unwrap().1.usage().unwrap();
+        assert_eq!(usage.allocated, report.filesystems[0].1);
+        assert_eq!(usage.fs_used, None);
+    }
+
+    #[test]
+    /// Trimming is recommended only for a mounted filesystem whose allocated
+    /// space exceeds what it reports as used by more than the threshold.
+    fn filesystem_usage_trim_recommended() {
+        let usage = |allocated, fs_used: Option<u64>| FilesystemUsage {
+            size: Sectors(2048),
+            allocated: Bytes(allocated),
+            fs_size: fs_used.map(|_| Bytes(1000)),
+            fs_used: fs_used.map(Bytes),
+        };
+        assert_eq!(usage(500, None).unreclaimed(), None);
+        assert!(!usage(500, None).trim_recommended());
+        assert_eq!(usage(400, Some(500)).unreclaimed(), Some(Bytes(0)));
+        assert_eq!(usage(600, Some(500)).unreclaimed(), Some(Bytes(100)));
+        assert!(!usage(600, Some(500)).trim_recommended());
+        assert!(usage(601, Some(500)).trim_recommended());
+    }
+
+    #[test]
+    /// Setting the same overprovisioning policy twice changes nothing the
+    /// second time, and a ratio of 0 is refused.
+    fn overprovision_policy_set() {
+        let mut engine = SimEngine::default();
+        let pool_name = "pool_name";
+        let uuid =
+            create_pool_with_defaults(&mut engine, pool_name, &[Path::new("/s/d")], None).unwrap();
+        let pool = engine.get_mut_pool(uuid).unwrap().1;
+        assert_eq!(pool.overprovision_policy(), OverprovisionPolicy::Unlimited);
+        assert_matches!(
+            pool.set_overprovision_policy(pool_name, OverprovisionPolicy::Warn(150)),
+            Ok(true)
+        );
+        assert_matches!(
+            pool.set_overprovision_policy(pool_name, OverprovisionPolicy::Warn(150)),
+            Ok(false)
+        );
+        assert_matches!(
+            pool.set_overprovision_policy(pool_name, OverprovisionPolicy::Limit(0)),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+        assert_eq!(pool.overprovision_policy(), OverprovisionPolicy::Warn(150));
+    }
+
+    #[test]
+    /// Draining a data tier blockdev that holds no data moves nothing, while
+    /// draining a cache tier blockdev or an unknown blockdev is refused.
+    fn drain_blockdev() {
+        let mut engine = SimEngine::default();
+        let pool_name = "pool_name";
+        let uuid =
+            create_pool_with_defaults(&mut engine, pool_name, &[Path::new("/s/d")], None).unwrap();
+        let pool = engine.get_mut_pool(uuid).unwrap().1;
+        let data_uuid = pool
+            .add_blockdevs(uuid, pool_name, &[Path::new("/s/e")], BlockDevTier::Data)
+            .unwrap()[0];
+        let cache_uuid = pool
+            .add_blockdevs(uuid, pool_name, &[Path::new("/s/f")], BlockDevTier::Cache)
+            .unwrap()[0];
+        assert_matches!(pool.drain_blockdev(uuid, pool_name, data_uuid), Ok(false));
+        assert_matches!(
+            pool.drain_blockdev(uuid, pool_name, cache_uuid),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(
+            pool.drain_blockdev(uuid, pool_name, Uuid::new_v4()),
+            Err(StratisError::Engine(ErrorEnum::NotFound, _))
+        );
+    }
+
+    #[test]
+    /// Setting the same reserved percentage twice changes nothing the second
+    /// time, the reserve is reported, and more than 50% is refused.
+    fn reserved_pct_set() {
+        let mut engine = SimEngine::default();
+        let pool_name = "pool_name";
+        let uuid =
+            create_pool_with_defaults(&mut engine, pool_name, &[Path::new("/s/d")], None).unwrap();
+        let pool = engine.get_mut_pool(uuid).unwrap().1;
+        assert_eq!(pool.reserved_pct(), 0);
+        assert_matches!(pool.set_reserved_pct(pool_name, 10), Ok(true));
+        assert_matches!(pool.set_reserved_pct(pool_name, 10), Ok(false));
+        assert_matches!(
+            pool.set_reserved_pct(pool_name, 51),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+        assert_eq!(pool.reserved_pct(), 10);
+        assert_eq!(
+            pool.space_report().unwrap().reserved,
+            Sectors(*pool.total_physical_size() / 10)
+        );
+    }
+
+    #[test]
+    /// Filesystems and snapshots beyond the pool's filesystem limit are
+    /// refused, and the limit may not be set below the number of
+    /// filesystems already in the pool.
+    fn filesystem_limit_set() {
+        let mut engine = SimEngine::default();
+        let pool_name = "pool_name";
+        let uuid =
+            create_pool_with_defaults(&mut engine, pool_name, &[Path::new("/s/d")], None).unwrap();
+        let pool = engine.get_mut_pool(uuid).unwrap().1;
+        assert_eq!(pool.filesystem_limit(), DEFAULT_FILESYSTEM_LIMIT);
+        let fs_uuid = pool
+            .create_filesystems(uuid, pool_name, &[("fs1", None), ("fs2", None)])
+            .unwrap()[0]
+            .1;
+        assert_matches!(
+            pool.set_filesystem_limit(pool_name, Some(1)),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(pool.set_filesystem_limit(pool_name, Some(2)), Ok(true));
+        assert_matches!(pool.set_filesystem_limit(pool_name, Some(2)), Ok(false));
+        assert_matches!(
+            pool.create_filesystems(uuid, pool_name, &[("fs3", None)]),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(
+            pool.snapshot_filesystem(uuid, pool_name, fs_uuid, "snap", false, false),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(pool.set_filesystem_limit(pool_name, None), Ok(true));
+        assert!(pool
+            .snapshot_filesystem(uuid, pool_name, fs_uuid, "snap", false, false)
+            .is_ok());
+    }
+
+    #[test]
+    /// A snapshot records the filesystem it was taken of as its origin, also
+    /// when that is itself a snapshot, and takes the origin of its origin
+    /// when that is destroyed.
+    fn snapshot_origin() {
+        let pool_name = "pool_name";
+        let mut engine = SimEngine::default();
+        let uuid =
+            create_pool_with_defaults(&mut engine, pool_name, &[Path::new("/s/d")], None).unwrap();
+        let pool = engine.get_mut_pool(uuid).unwrap().1;
+        let fs_uuid = pool
+            .create_filesystems(uuid, pool_name, &[("fs", None)])
+            .unwrap()[0]
+            .1;
+        assert_eq!(pool.get_filesystem(fs_uuid).unwrap().1.origin(), None);
+        let snapshot_uuid = {
+            let (snapshot_uuid, snapshot) = pool
+                .snapshot_filesystem(uuid, pool_name, fs_uuid, "snap", false, false)
+                .unwrap();
+            assert_eq!(snapshot.origin(), Some(fs_uuid));
+            snapshot_uuid
+        };
+
+        let (clone_uuid, clone) = pool
+            .snapshot_filesystem(uuid, pool_name, snapshot_uuid, "clone", false, false)
+            .unwrap();
+        assert_eq!(clone.origin(), Some(snapshot_uuid));
+
+        pool.destroy_filesystems(pool_name, &[snapshot_uuid])
+            .unwrap();
+        assert_eq!(
+            pool.get_filesystem(clone_uuid).unwrap().1.origin(),
+            Some(fs_uuid)
+        );
+
+        pool.destroy_filesystems(pool_name, &[fs_uuid]).unwrap();
+        assert_eq!(pool.get_filesystem(clone_uuid).unwrap().1.origin(), None);
+    }
+
+    #[test]
+    /// The filesystem trees of a pool show each snapshot beneath its origin,
+    /// and a snapshot whose origin is destroyed moves up beneath the origin
+    /// of that.
+    fn filesystem_trees() {
+        let pool_name = "pool_name";
+        let mut engine = SimEngine::default();
+        let uuid =
+            create_pool_with_defaults(&mut engine, pool_name, &[Path::new("/s/d")], None).unwrap();
+        let pool = engine.get_mut_pool(uuid).unwrap().1;
+        let fs_uuid = pool
+            .create_filesystems(uuid, pool_name, &[("fs1", None)])
+            .unwrap()[0]
+            .1;
+        let snap_a_uuid = pool
+            .snapshot_filesystem(uuid, pool_name, fs_uuid, "snap-a", false, false)
+            .unwrap()
+            .0;
+        let snap_b_uuid = pool
+            .snapshot_filesystem(uuid, pool_name, fs_uuid, "snap-b", false, false)
+            .unwrap()
+            .0;
+        let clone_uuid = pool
+            .snapshot_filesystem(uuid, pool_name, snap_a_uuid, "clone-b", false, false)
+            .unwrap()
+            .0;
+        let other_uuid = pool
+            .create_filesystems(uuid, pool_name, &[("fs0", None)])
+            .unwrap()[0]
+            .1;
+
+        let tree = |name: &str, uuid, snapshots| FilesystemTree {
+            name: Name::new(name.to_owned()),
+            uuid,
+            snapshots,
+        };
+        assert_eq!(
+            pool.filesystem_trees(),
+            vec![
+                tree("fs0", other_uuid, vec![]),
+                tree(
+                    "fs1",
+                    fs_uuid,
+                    vec![
+                        tree(
+                            "snap-a",
+                            snap_a_uuid,
+                            vec![tree("clone-b", clone_uuid, vec![])]
+                        ),
+                        tree("snap-b", snap_b_uuid, vec![]),
+                    ]
+                ),
+            ]
+        );
+
+        pool.destroy_filesystems(pool_name, &[snap_a_uuid]).unwrap();
+        assert_eq!(
+            pool.filesystem_trees(),
+            vec![
+                tree("fs0", other_uuid, vec![]),
+                tree(
+                    "fs1",
+                    fs_uuid,
+                    vec![
+                        tree("clone-b", clone_uuid, vec![]),
+                        tree("snap-b", snap_b_uuid, vec![]),
+                    ]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    /// A filesystem is created with its requested size, or the default size
+    /// if none is requested, but not with a size of 0.
+    fn create_filesystem_size() {
+        let pool_name = "pool_name";
+        let mut engine = SimEngine::default();
+        let uuid =
+            create_pool_with_defaults(&mut engine, pool_name, &[Path::new("/s/d")], None).unwrap();
+        let pool = engine.get_mut_pool(uuid).unwrap().1;
+        assert_matches!(
+            pool.create_filesystems(uuid, pool_name, &[("zero", Some(Sectors(0)))]),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+        let infos = pool
+            .create_filesystems(
+                uuid,
+                pool_name,
+                &[("default", None), ("sized", Some(Sectors(1024)))],
+            )
+            .unwrap();
+        for (name, fs_uuid) in infos {
+            let filesystem = pool.get_filesystem(fs_uuid).unwrap().1;
+            let size = if name == "sized" {
+                Sectors(1024)
+            } else {
+                DEFAULT_SIZE
+            };
+            assert_eq!(filesystem.initial_size(), Some(size));
+            assert_eq!(filesystem.usage().unwrap().size, size);
+        }
+    }
+
+    #[test]
+    /// Each filesystem in a batch is created or refused independently of
+    /// the others, including those earlier in the batch.
+    fn create_filesystems_each() {
+        let pool_name = "pool_name";
+        let mut engine = SimEngine::default();
+        let uuid =
+            create_pool_with_defaults(&mut engine, pool_name, &[Path::new("/s/d")], None).unwrap();
+        let pool = engine.get_mut_pool(uuid).unwrap().1;
+        let results = pool
+            .create_filesystems_each(
+                uuid,
+                pool_name,
+                &[
+                    ("root", None),
+                    ("home", Some(Sectors(1024))),
+                    ("root", None),
+                    ("var", Some(Sectors(0))),
+                ],
+            )
+            .unwrap();
+        assert_eq!(
+            results.iter().map(|&(name, _)| name).collect::<Vec<_>>(),
+            vec!["root", "home", "root", "var"]
+        );
+        assert!(results[0].1.is_ok());
+        assert!(results[1].1.is_ok());
+        assert_matches!(
+            results[2].1,
+            Err(StratisError::Engine(ErrorEnum::AlreadyExists, _))
+        );
+        assert_matches!(
+            results[3].1,
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+        assert_eq!(pool.filesystems().len(), 2);
+    }
+
+    #[test]
+    /// A scripted failure partway through creating filesystems leaves those
+    /// already created, and a transient failure clears on retry.
+    fn create_filesystems_injected_failure() {
+        let pool_name = "pool_name";
+        let mut engine = SimEngine::default();
+        let uuid =
+            create_pool_with_defaults(&mut engine, pool_name, &[Path::new("/s/d")], None).unwrap();
+        engine
+            .configure_simulator_operation(
+                SimOperation::CreateFilesystems,
+                SimFailureModel::Script(vec![None, Some(ErrorEnum::Busy)]),
+            )
+            .unwrap();
+        let pool = engine.get_mut_pool(uuid).unwrap().1;
+        assert_matches!(
+            pool.create_filesystems(uuid, pool_name, &[("fs1", None), ("fs2", None)]),
+            Err(StratisError::Engine(ErrorEnum::Busy, _))
+        );
+        assert_eq!(pool.filesystems().len(), 1);
+        assert!(pool
+            .create_filesystems(uuid, pool_name, &[("fs3", None)])
+            .is_ok());
+        assert_eq!(pool.filesystems().len(), 2);
+    }
+
+    #[test]
+    /// A filesystem's size limit can be set, changed, and removed, but not
+    /// set to 0.
+    fn filesystem_size_limit() {
+        let pool_name = "pool_name";
+        let mut engine = SimEngine::default();
+        let uuid =
+            create_pool_with_defaults(&mut engine, pool_name, &[Path::new("/s/d")], None).unwrap();
+        let pool = engine.get_mut_pool(uuid).unwrap().1;
+        let fs_uuid = pool
+            .create_filesystems(uuid, pool_name, &[("fs", None)])
+            .unwrap()[0]
+            .1;
+        assert_matches!(
+            pool.set_filesystem_size_limit(pool_name, fs_uuid, Some(Sectors(0))),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(
+            pool.set_filesystem_size_limit(pool_name, Uuid::new_v4(), None),
+            Err(StratisError::Engine(ErrorEnum::NotFound, _))
+        );
+        assert_matches!(
+            pool.set_filesystem_size_limit(pool_name, fs_uuid, Some(Sectors(1024))),
+            Ok(true)
+        );
+        assert_eq!(
+            pool.get_filesystem(fs_uuid).unwrap().1.size_limit(),
+            Some(Sectors(1024))
+        );
+        assert_matches!(
+            pool.set_filesystem_size_limit(pool_name, fs_uuid, None),
+            Ok(true)
+        );
+        assert_matches!(
+            pool.set_filesystem_size_limit(pool_name, fs_uuid, None),
+            Ok(false)
+        );
+    }
+
+    #[test]
+    /// A filesystem's own snapshot limit takes precedence over the pool's
+    /// default, and both are enforced when a snapshot is taken.
+    fn snapshot_limit() {
+        let pool_name = "pool_name";
+        let mut engine = SimEngine::default();
+        let uuid =
+            create_pool_with_defaults(&mut engine, pool_name, &[Path::new("/s/d")], None).unwrap();
+        let pool = engine.get_mut_pool(uuid).unwrap().1;
+        let fs_uuid = pool
+            .create_filesystems(uuid, pool_name, &[("fs", None)])
+            .unwrap()[0]
+            .1;
+        assert_eq!(pool.snapshot_limit(), None);
+        assert_matches!(pool.set_snapshot_limit(pool_name, Some(1)), Ok(true));
+        assert_matches!(pool.set_snapshot_limit(pool_name, Some(1)), Ok(false));
+        assert!(pool
+            .snapshot_filesystem(uuid, pool_name, fs_uuid, "snap-a", false, false)
+            .is_ok());
+        assert_matches!(
+            pool.snapshot_filesystem(uuid, pool_name, fs_uuid, "snap-b", false, false),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+
+        assert_matches!(
+            pool.set_filesystem_snapshot_limit(pool_name, Uuid::new_v4(), None),
+            Err(StratisError::Engine(ErrorEnum::NotFound, _))
+        );
+        assert_matches!(
+            pool.set_filesystem_snapshot_limit(pool_name, fs_uuid, Some(2)),
+            Ok(true)
+        );
+        assert_eq!(
+            pool.get_filesystem(fs_uuid).unwrap().1.snapshot_limit(),
+            Some(2)
+        );
+        assert!(pool
+            .snapshot_filesystem(uuid, pool_name, fs_uuid, "snap-b", false, false)
+            .is_ok());
+        assert_matches!(
+            pool.snapshot_filesystem(uuid, pool_name, fs_uuid, "snap-c", false, false),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+
+        assert_matches!(
+            pool.set_filesystem_snapshot_limit(pool_name, fs_uuid, None),
+            Ok(true)
+        );
+        assert_matches!(pool.set_snapshot_limit(pool_name, None), Ok(true));
+        assert!(pool
+            .snapshot_filesystem(uuid, pool_name, fs_uuid, "snap-c", false, false)
+            .is_ok());
+    }
+
+    #[test]
+    /// A filesystem's trim interval can be set and removed, but not set to 0,
+    /// and trimming it schedules its next trim.
+    fn filesystem_trim_interval() {
+        let pool_name = "pool_name";
+        let mut engine = SimEngine::default();
+        let uuid =
+            create_pool_with_defaults(&mut engine, pool_name, &[Path::new("/s/d")], None).unwrap();
+        let pool = engine.get_mut_pool(uuid).unwrap().1;
+        let fs_uuid = pool
+            .create_filesystems(uuid, pool_name, &[("fs", None)])
+            .unwrap()[0]
+            .1;
+        assert_eq!(
+            pool.get_filesystem(fs_uuid).unwrap().1.trim_deadline(),
+            None
+        );
+        assert_matches!(
+            pool.set_filesystem_trim_interval(pool_name, fs_uuid, Some(Duration::from_secs(0))),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(
+            pool.set_filesystem_trim_interval(pool_name, Uuid::new_v4(), None),
+            Err(StratisError::Engine(ErrorEnum::NotFound, _))
+        );
+
+        let interval = Duration::from_secs(3600);
+        let before = Instant::now();
+        assert_matches!(
+            pool.set_filesystem_trim_interval(pool_name, fs_uuid, Some(interval)),
+            Ok(true)
+        );
+        assert_matches!(
+            pool.set_filesystem_trim_interval(pool_name, fs_uuid, Some(interval)),
+            Ok(false)
+        );
+        let fs = pool.get_mut_filesystem(fs_uuid).unwrap().1;
+        assert_eq!(fs.trim_interval(), Some(interval));
+        let deadline = fs.trim_deadline().unwrap();
+        assert!(deadline >= before + interval);
+        assert_matches!(fs.trim(), Ok(None));
+        assert!(fs.trim_deadline().unwrap() >= deadline);
+
+        assert_matches!(
+            pool.set_filesystem_trim_interval(pool_name, fs_uuid, None),
+            Ok(true)
+        );
+        assert_eq!(
+            pool.get_filesystem(fs_uuid).unwrap().1.trim_deadline(),
+            None
+        );
+    }
+
+    #[test]
+    /// Setting a filesystem's user info to what it already is changes
+    /// nothing.
+    fn filesystem_user_info() {
+        let pool_name = "pool_name";
+        let mut engine = SimEngine::default();
+        let uuid =
+            create_pool_with_defaults(&mut engine, pool_name, &[Path::new("/s/d")], None).unwrap();
+        let pool = engine.get_mut_pool(uuid).unwrap().1;
+        let fs_uuid = pool
+            .create_filesystems(uuid, pool_name, &[("fs", None)])
+            .unwrap()[0]
+            .1;
+        assert_matches!(
+            pool.set_filesystem_user_info(pool_name, fs_uuid, Some("info")),
+            Ok(true)
+        );
+        assert_matches!(
+            pool.set_filesystem_user_info(pool_name, fs_uuid, Some("info")),
+            Ok(false)
+        );
+        assert_eq!(
+            pool.get_filesystem(fs_uuid).unwrap().1.user_info(),
+            Some("info")
+        );
+        assert_matches!(
+            pool.set_filesystem_user_info(pool_name, Uuid::new_v4(), None),
+            Err(StratisError::Engine(ErrorEnum::NotFound, _))
+        );
+    }
+
+    #[test]
+    /// A filesystem can be reverted only to one of its own snapshots.
+    fn revert_filesystem() {
+        let pool_name = "pool_name";
+        let mut engine = SimEngine::default();
+        let uuid =
+            create_pool_with_defaults(&mut engine, pool_name, &[Path::new("/s/d")], None).unwrap();
+        let pool = engine.get_mut_pool(uuid).unwrap().1;
+        let fs_uuid = pool
+            .create_filesystems(uuid, pool_name, &[("fs", None)])
+            .unwrap()[0]
+            .1;
+        let snapshot_uuid = pool
+            .snapshot_filesystem(uuid, pool_name, fs_uuid, "snap", false, false)
+            .unwrap()
+            .0;
+        assert_matches!(
+            pool.revert_filesystem(uuid, pool_name, snapshot_uuid, fs_uuid),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(
+            pool.revert_filesystem(uuid, pool_name, fs_uuid, Uuid::new_v4()),
+            Err(StratisError::Engine(ErrorEnum::NotFound, _))
+        );
+        assert_matches!(
+            pool.revert_filesystem(uuid, pool_name, fs_uuid, snapshot_uuid),
+            Ok(())
+        );
+    }
+
+    #[test]
+    /// A filesystem can be shrunk after it has been extended, but no smaller
+    /// than its minimum size.
+    fn shrink_filesystem() {
+        let pool_name = "pool_name";
+        let mut engine = SimEngine::default();
+        let uuid =
+            create_pool_with_defaults(&mut engine, pool_name, &[Path::new("/s/d")], None).unwrap();
+        let pool = engine.get_mut_pool(uuid).unwrap().1;
+        let fs_uuid = pool
+            .create_filesystems(uuid, pool_name, &[("fs", Some(Sectors(1024)))])
+            .unwrap()[0]
+            .1;
+        assert_matches!(
+            pool.shrink_filesystem(pool_name, fs_uuid, Sectors(512)),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+        pool.extend_filesystem(pool_name, fs_uuid, Sectors(1024))
+            .unwrap();
+        assert_matches!(
+            pool.shrink_filesystem(pool_name, fs_uuid, Sectors(2048)),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+        assert_eq!(
+            pool.get_filesystem(fs_uuid).unwrap().1.min_size().unwrap(),
+            Sectors(1024)
+        );
+        assert_matches!(
+            pool.shrink_filesystem(pool_name, fs_uuid, Sectors(1536)),
+            Ok(())
+        );
+        assert_eq!(
+            pool.get_filesystem(fs_uuid)
+                .unwrap()
+                .1
+                .usage()
+                .unwrap()
+                .size,
+            Sectors(1536)
+        );
+    }
+
+    #[test]
+    /// A read-only filesystem can be neither extended nor reverted until it
+    /// is made writable again.
+    fn filesystem_read_only() {
+        let pool_name = "pool_name";
+        let mut engine = SimEngine::default();
+        let uuid =
+            create_pool_with_defaults(&mut engine, pool_name, &[Path::new("/s/d")], None).unwrap();
+        let pool = engine.get_mut_pool(uuid).unwrap().1;
+        let fs_uuid = pool
+            .create_filesystems(uuid, pool_name, &[("fs", None)])
+            .unwrap()[0]
+            .1;
+        let snapshot_uuid = pool
+            .snapshot_filesystem(uuid, pool_name, fs_uuid, "snap", false, false)
+            .unwrap()
+            .0;
+        assert_matches!(
+            pool.set_filesystem_read_only(pool_name, fs_uuid, true),
+            Ok(true)
+        );
+        assert_matches!(
+            pool.set_filesystem_read_only(pool_name, fs_uuid, true),
+            Ok(false)
+        );
+        assert!(pool.get_filesystem(fs_uuid).unwrap().1.read_only());
+        assert_matches!(
+            pool.extend_filesystem(pool_name, fs_uuid, Sectors(1024)),
+            Err(StratisError::Engine(ErrorEnum::Busy, _))
+        );
+        assert_matches!(
+            pool.revert_filesystem(uuid, pool_name, fs_uuid, snapshot_uuid),
+            Err(StratisError::Engine(ErrorEnum::Busy, _))
+        );
+        assert_matches!(
+            pool.merge_snapshot(uuid, pool_name, snapshot_uuid),
+            Err(StratisError::Engine(ErrorEnum::Busy, _))
+        );
+
+        assert_matches!(
+            pool.set_filesystem_read_only(pool_name, fs_uuid, false),
+            Ok(true)
+        );
+        assert_matches!(
+            pool.extend_filesystem(pool_name, fs_uuid, Sectors(1024)),
+            Ok(())
+        );
+    }
+
+    #[test]
+    /// Merging a snapshot removes it and makes its snapshots snapshots of its
+    /// origin; a filesystem without an origin can not be merged.
+    fn merge_snapshot() {
+        let pool_name = "pool_name";
+        let mut engine = SimEngine::default();
+        let uuid =
+            create_pool_with_defaults(&mut engine, pool_name, &[Path::new("/s/d")], None).unwrap();
+        let pool = engine.get_mut_pool(uuid).unwrap().1;
+        let fs_uuid = pool
+            .create_filesystems(uuid, pool_name, &[("fs", None)])
+            .unwrap()[0]
+            .1;
+        let snapshot_uuid = pool
+            .snapshot_filesystem(uuid, pool_name, fs_uuid, "snap", false, false)
+            .unwrap()
+            .0;
+        let child_uuid = pool
+            .snapshot_filesystem(uuid, pool_name, snapshot_uuid, "child", false, false)
+            .unwrap()
+            .0;
+        assert_matches!(
+            pool.merge_snapshot(uuid, pool_name, fs_uuid),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(
+            pool.merge_snapshot(uuid, pool_name, Uuid::new_v4()),
+            Err(StratisError::Engine(ErrorEnum::NotFound, _))
+        );
+        assert_eq!(
+            pool.merge_snapshot(uuid, pool_name, snapshot_uuid).unwrap(),
+            fs_uuid
+        );
+        assert!(pool.get_filesystem(snapshot_uuid).is_none());
+        assert_eq!(
+            pool.get_filesystem(child_uuid).unwrap().1.origin(),
+            Some(fs_uuid)
+        );
+    }
+
+    #[test]
+    /// Quiescing or resuming a pool twice changes nothing the second time.
+    fn quiesce_twice() {
+        let mut engine = SimEngine::default();
+        let uuid = create_pool_with_defaults(&mut engine, "pool_name", &[Path::new("/s/d")], None)
+            .unwrap();
+        let pool = engine.get_mut_pool(uuid).unwrap().1;
+        assert_matches!(pool.quiesce(Duration::from_secs(60)), Ok(true));
+        assert_matches!(pool.quiesce(Duration::from_secs(60)), Ok(false));
+        assert!(pool.quiesce_deadline().is_some());
+        assert_matches!(pool.unquiesce(), Ok(true));
+        assert_matches!(pool.unquiesce(), Ok(false));
+        assert_eq!(pool.quiesce_deadline(), None);
+    }
+
+    #[test]
+    /// A new pool allocates fill-first, and setting the same strategy twice
+    /// changes nothing the second time.
+    fn allocation_strategy_set() {
+        let mut engine = SimEngine::default();
+        let pool_name = "pool_name";
+        let uuid =
+            create_pool_with_defaults(&mut engine, pool_name, &[Path::new("/s/d")], None).unwrap();
+        let pool = engine.get_mut_pool(uuid).unwrap().1;
+        assert_eq!(pool.allocation_strategy(), AllocationStrategy::FillFirst);
+        assert_matches!(
+            pool.set_allocation_strategy(pool_name, AllocationStrategy::RoundRobin),
+            Ok(true)
+        );
+        assert_matches!(
+            pool.set_allocation_strategy(pool_name, AllocationStrategy::RoundRobin),
+            Ok(false)
+        );
+        assert_eq!(pool.allocation_strategy(), AllocationStrategy::RoundRobin);
+    }
+
+    #[test]
+    /// A new pool is not in read-only mode, so there is nothing to clear.
+    fn read_only_clear() {
+        let mut engine = SimEngine::default();
+        let uuid = create_pool_with_defaults(&mut engine, "pool_name", &[Path::new("/s/d")], None)
+            .unwrap();
+        let pool = engine.get_mut_pool(uuid).unwrap().1;
+        assert_eq!(pool.read_only_reason(), None);
+        assert!(!pool.clear_read_only());
+    }
+
+    #[test]
+    /// Taking a blockdev that holds no data offline and bringing it back
+    /// online changes its maintenance state, and setting the same state
+    /// twice is a no-op.
+    fn blockdev_maintenance_toggle() {
+        let mut engine = SimEngine::default();
+        let pool_name = "pool_name";
+        let uuid =
+            create_pool_with_defaults(&mut engine, pool_name, &[Path::new("/s/d")], None).unwrap();
+        let pool = engine.get_mut_pool(uuid).unwrap().1;
+        let dev_uuid = pool
+            .add_blockdevs(uuid, pool_name, &[Path::new("/s/e")], BlockDevTier::Data)
+            .unwrap()[0];
+        assert!(pool
+            .set_blockdev_maintenance(pool_name, dev_uuid, true)
+            .unwrap());
+        assert!(pool.get_blockdev(dev_uuid).unwrap().1.in_maintenance());
+        assert!(!pool
+            .set_blockdev_maintenance(pool_name, dev_uuid, true)
+            .unwrap());
+        assert!(pool
+            .set_blockdev_maintenance(pool_name, dev_uuid, false)
+            .unwrap());
+        assert!(!pool.get_blockdev(dev_uuid).unwrap().1.in_maintenance());
+    }
+
+    #[test]
+    /// A blockdev that holds data can not be taken offline, since there is
+    /// no redundancy to allow redirecting I/O. Once its data has been
+    /// drained onto another blockdev it can be.
+    fn blockdev_maintenance_in_use() {
+        let mut engine = SimEngine::default();
+        let pool_name = "pool_name";
+        let uuid =
+            create_pool_with_defaults(&mut engine, pool_name, &[Path::new("/s/d")], None).unwrap();
+        let pool = engine.get_mut_pool(uuid).unwrap().1;
+        let dev_uuid = pool.blockdevs()[0].0;
+        assert_matches!(
+            pool.set_blockdev_maintenance(pool_name, dev_uuid, true),
+            Err(StratisError::Engine(ErrorEnum::Busy, _))
+        );
+        assert!(!pool.get_blockdev(dev_uuid).unwrap().1.in_maintenance());
+
+        pool.add_blockdevs(uuid, pool_name, &[Path::new("/s/e")], BlockDevTier::Data)
+            .unwrap();
+        assert!(pool.drain_blockdev(uuid, pool_name, dev_uuid).unwrap());
+        assert!(pool
+            .set_blockdev_maintenance(pool_name, dev_uuid, true)
+            .unwrap());
+    }
+
+    #[test]
+    /// Setting maintenance on a nonexistent blockdev is an error.
+    fn blockdev_maintenance_not_found() {
+        let mut engine = SimEngine::default();
+        let pool_name = "pool_name";
+        let uuid = create_pool_with_defaults(&mut engine, pool_name, &[], None).unwrap();
+        let pool = engine.get_mut_pool(uuid).unwrap().1;
+        assert_matches!(
+            pool.set_blockdev_maintenance(pool_name, Uuid::new_v4(), true),
+            Err(StratisError::Engine(ErrorEnum::NotFound, _))
+        );
+    }
 }
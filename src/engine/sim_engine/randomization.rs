@@ -2,13 +2,20 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use std::collections::HashMap;
 use std::fmt;
+use std::path::{Path, PathBuf};
 
-use rand::{thread_rng, Rng, ThreadRng};
+use rand::{rngs::ThreadRng, thread_rng, Rng};
+
+use crate::engine::{SimDevProperties, SimFailureModel, SimOperation};
+use crate::stratis::{ErrorEnum, StratisError, StratisResult};
 
 pub struct Randomizer {
     rng: ThreadRng,
     denominator: u32,
+    failures: HashMap<SimOperation, SimFailureModel>,
+    devices: HashMap<PathBuf, SimDevProperties>,
 }
 
 impl Default for Randomizer {
@@ -16,6 +23,8 @@ impl Default for Randomizer {
         Randomizer {
             rng: thread_rng(),
             denominator: 0u32,
+            failures: HashMap::new(),
+            devices: HashMap::new(),
         }
     }
 }
@@ -24,7 +33,11 @@ impl Default for Randomizer {
 /// See: https://github.com/rust-lang-nursery/rand/issues/118
 impl fmt::Debug for Randomizer {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{{Randomizer {:?}", self.denominator)
+        write!(
+            f,
+            "{{Randomizer {:?} {:?} {:?}}}",
+            self.denominator, self.failures, self.devices
+        )
     }
 }
 
@@ -35,7 +48,7 @@ impl Randomizer {
         if self.denominator == 0 {
             false
         } else {
-            self.rng.gen_weighted_bool(self.denominator)
+            self.rng.gen_ratio(1, self.denominator)
         }
     }
 
@@ -44,6 +57,69 @@ impl Randomizer {
         self.denominator = denominator;
         self
     }
+
+    /// Set how attempts at operation are decided, in place of any model
+    /// set for it before.
+    pub fn set_failure_model(
+        &mut self,
+        operation: SimOperation,
+        model: SimFailureModel,
+    ) -> &mut Self {
+        self.failures.insert(operation, model);
+        self
+    }
+
+    /// Set the properties of devices at devnode, in place of any set before.
+    pub fn set_device_properties(
+        &mut self,
+        devnode: &Path,
+        properties: SimDevProperties,
+    ) -> &mut Self {
+        self.devices.insert(devnode.to_owned(), properties);
+        self
+    }
+
+    /// The properties of a device at devnode.
+    pub fn device_properties(&self, devnode: &Path) -> SimDevProperties {
+        self.devices.get(devnode).cloned().unwrap_or_default()
+    }
+
+    /// Decide whether an attempt at operation fails, returning the error it
+    /// fails with if so. An operation without a failure model of its own
+    /// does not fail, except for pool creation, which fails with the
+    /// probability set by set_probability().
+    pub fn check_failure(&mut self, operation: SimOperation) -> StratisResult<()> {
+        let failure = match self.failures.get_mut(&operation) {
+            Some(SimFailureModel::Probability(denominator)) => {
+                if *denominator != 0 && self.rng.gen_ratio(1, *denominator) {
+                    Some(ErrorEnum::Error)
+                } else {
+                    None
+                }
+            }
+            Some(SimFailureModel::Script(script)) => {
+                if script.is_empty() {
+                    None
+                } else {
+                    script.remove(0)
+                }
+            }
+            None => {
+                if operation == SimOperation::CreatePool && self.throw_die() {
+                    Some(ErrorEnum::Error)
+                } else {
+                    None
+                }
+            }
+        };
+        match failure {
+            Some(err) => Err(StratisError::Engine(
+                err,
+                format!("simulated failure of {:?}", operation),
+            )),
+            None => Ok(()),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -65,4 +141,47 @@ mod tests {
                          || (denominator == 0 && result));
         }
     }
+
+    #[test]
+    /// Verify that a scripted operation fails as scripted and then
+    /// succeeds, and that other operations are unaffected.
+    fn scripted_failures() {
+        let mut rdm = Randomizer::default();
+        rdm.set_failure_model(
+            SimOperation::RenamePool,
+            SimFailureModel::Script(vec![Some(ErrorEnum::Busy), None, Some(ErrorEnum::Error)]),
+        );
+        assert_matches!(
+            rdm.check_failure(SimOperation::RenamePool),
+            Err(StratisError::Engine(ErrorEnum::Busy, _))
+        );
+        assert_matches!(rdm.check_failure(SimOperation::RenamePool), Ok(()));
+        assert_matches!(rdm.check_failure(SimOperation::DestroyPool), Ok(()));
+        assert_matches!(
+            rdm.check_failure(SimOperation::RenamePool),
+            Err(StratisError::Engine(ErrorEnum::Error, _))
+        );
+        assert_matches!(rdm.check_failure(SimOperation::RenamePool), Ok(()));
+    }
+
+    #[test]
+    /// Verify that the simulator-wide probability applies only to pool
+    /// creation, and that an operation's own model takes its place.
+    fn probability_failures() {
+        let mut rdm = Randomizer::default();
+        rdm.set_probability(1);
+        assert_matches!(rdm.check_failure(SimOperation::CreatePool), Err(_));
+        assert_matches!(rdm.check_failure(SimOperation::CreateFilesystems), Ok(()));
+
+        rdm.set_failure_model(SimOperation::CreatePool, SimFailureModel::Probability(0))
+            .set_failure_model(
+                SimOperation::CreateFilesystems,
+                SimFailureModel::Probability(1),
+            );
+        assert_matches!(rdm.check_failure(SimOperation::CreatePool), Ok(()));
+        assert_matches!(
+            rdm.check_failure(SimOperation::CreateFilesystems),
+            Err(StratisError::Engine(ErrorEnum::Error, _))
+        );
+    }
 }
@@ -0,0 +1,115 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// The simulator can save its state to a JSON file, so that a client test
+// scenario can span restarts of the daemon. As for the real engine's
+// metadata, the state is described by *Save structs, rather than by the
+// in-memory structs, which hold much that need not, or can not, be saved.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use devicemapper::Sectors;
+
+use crate::engine::{DevUuid, FilesystemUuid, PoolUuid};
+
+#[derive(Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SimEngineSave {
+    pub pools: Vec<SimPoolSave>,
+    #[serde(default)]
+    pub exported_pools: Vec<SimPoolSave>,
+    #[serde(default)]
+    pub stopped_pools: Vec<SimPoolSave>,
+}
+
+#[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SimPoolSave {
+    pub name: String,
+    pub uuid: PoolUuid,
+    pub block_devs: Vec<SimDevSave>,
+    #[serde(default)]
+    pub cache_devs: Vec<SimDevSave>,
+    pub filesystems: Vec<SimFilesystemSave>,
+    pub chunk_size: Sectors,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub overprovision: Option<SimOverprovisionSave>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub round_robin: bool,
+    #[serde(default)]
+    pub reserved_pct: u8,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filesystem_limit: Option<u64>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snapshot_limit: Option<u64>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_only_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SimOverprovisionSave {
+    pub ratio_pct: u32,
+    pub enforce: bool,
+}
+
+#[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SimDevSave {
+    pub uuid: DevUuid,
+    pub devnode: PathBuf,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_info: Option<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hardware_info: Option<String>,
+    pub initialization_time: u64,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub maintenance: bool,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub in_use: bool,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<Sectors>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub rotational: bool,
+    #[serde(default)]
+    pub latency: Duration,
+}
+
+#[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SimFilesystemSave {
+    pub name: String,
+    pub uuid: FilesystemUuid,
+    pub created: u64, // Unix timestamp
+    pub size: Sectors,
+    pub initial_size: Sectors,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub origin: Option<FilesystemUuid>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub xfs_uuid: Option<FilesystemUuid>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size_limit: Option<Sectors>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snapshot_limit: Option<u64>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_info: Option<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub read_only: bool,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trim_interval_secs: Option<u64>,
+}
@@ -8,22 +8,24 @@ use std::cmp;
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 
-use chrono::{DateTime, Utc};
-
 use devicemapper::{CacheDev, Device, DmDevice, LinearDev, Sectors};
 
-use crate::engine::{BlockDevTier, DevUuid, PoolUuid};
+use crate::engine::{
+    AllocationStrategy, BlockDev, BlockDevState, BlockDevTier, DevUuid, PlannedBlockDev, PoolUuid,
+};
 use crate::stratis::{ErrorEnum, StratisError, StratisResult};
 
+use crate::engine::check::Discrepancy;
+
 use crate::engine::strat_engine::backstore::{StratBlockDev, MIN_MDA_SECTORS};
-use crate::engine::strat_engine::device::wipe_sectors;
+use crate::engine::strat_engine::device::{copy_sectors, wipe_sectors};
 use crate::engine::strat_engine::dm::get_dm;
 use crate::engine::strat_engine::names::{format_backstore_ids, CacheRole};
 use crate::engine::strat_engine::serde_structs::{BackstoreSave, CapSave, Recordable};
 
 use crate::engine::strat_engine::backstore::blockdevmgr::{map_to_dm, BlockDevMgr};
 use crate::engine::strat_engine::backstore::cache_tier::CacheTier;
-use crate::engine::strat_engine::backstore::data_tier::DataTier;
+use crate::engine::strat_engine::backstore::data_tier::{DataTier, Drain};
 use crate::engine::strat_engine::backstore::setup::get_blockdevs;
 
 /// Use a cache block size that the kernel docs indicate is the largest
@@ -109,11 +111,10 @@ impl Backstore {
     pub fn setup(
         pool_uuid: PoolUuid,
         backstore_save: &BackstoreSave,
-        devnodes: &HashMap<Device, PathBuf>,
-        last_update_time: Option<DateTime<Utc>>,
+        devnodes: &HashMap<DevUuid, (Device, PathBuf)>,
     ) -> StratisResult<Backstore> {
         let (datadevs, cachedevs) = get_blockdevs(pool_uuid, backstore_save, devnodes)?;
-        let block_mgr = BlockDevMgr::new(datadevs, last_update_time);
+        let block_mgr = BlockDevMgr::new(datadevs);
         let data_tier = DataTier::setup(block_mgr, &backstore_save.data_tier)?;
         let (dm_name, dm_uuid) = format_backstore_ids(pool_uuid, CacheRole::OriginSub);
         let origin = LinearDev::setup(
@@ -124,7 +125,7 @@ impl Backstore {
         )?;
 
         let (cache_tier, cache, origin) = if !cachedevs.is_empty() {
-            let block_mgr = BlockDevMgr::new(cachedevs, last_update_time);
+            let block_mgr = BlockDevMgr::new(cachedevs);
             match backstore_save.cache_tier {
                 Some(ref cache_tier_save) => {
                     let cache_tier = CacheTier::setup(block_mgr, &cache_tier_save)?;
@@ -172,6 +173,12 @@ impl Backstore {
         })
     }
 
+    /// Check the specified devs as initialize() would, without writing to
+    /// any of them, and return how each would be laid out.
+    pub fn plan(paths: &[&Path], mda_size: Sectors) -> StratisResult<Vec<PlannedBlockDev>> {
+        BlockDevMgr::plan(paths, mda_size)
+    }
+
     /// Add cachedevs to the backstore.
     ///
     /// If the cache tier does not already exist, create it.
@@ -255,33 +262,31 @@ impl Backstore {
     /// device if it does not already exist. Return an error if DM
     /// operations fail. Use all segments currently allocated in the data tier.
     fn extend_cap_device(&mut self, pool_uuid: PoolUuid) -> StratisResult<()> {
-        let create = match (self.cache.as_mut(), self.linear.as_mut()) {
-            (None, None) => true,
-            (Some(cache), None) => {
-                let table = map_to_dm(&self.data_tier.segments);
-                cache.set_origin_table(get_dm(), table)?;
-                cache.resume(get_dm())?;
-                false
-            }
-            (None, Some(linear)) => {
-                let table = map_to_dm(&self.data_tier.segments);
-                linear.set_table(get_dm(), table)?;
-                linear.resume(get_dm())?;
-                false
-            }
-            _ => panic!("NOT (self.cache().is_some() AND self.linear.is_some())"),
-        };
-
-        if create {
-            let table = map_to_dm(&self.data_tier.segments);
-            let (dm_name, dm_uuid) = format_backstore_ids(pool_uuid, CacheRole::OriginSub);
-            let origin = LinearDev::setup(get_dm(), &dm_name, Some(&dm_uuid), table)?;
-            self.linear = Some(origin);
+        if self.load_cap_table()? {
+            return self.resume_cap_device();
         }
 
+        let table = map_to_dm(&self.data_tier.segments);
+        let (dm_name, dm_uuid) = format_backstore_ids(pool_uuid, CacheRole::OriginSub);
+        let origin = LinearDev::setup(get_dm(), &dm_name, Some(&dm_uuid), table)?;
+        self.linear = Some(origin);
         Ok(())
     }
 
+    /// Load a table mapping the data tier's segments into the cap device,
+    /// leaving the cap device suspended. Return false if there is no cap
+    /// device, so none was loaded.
+    fn load_cap_table(&mut self) -> StratisResult<bool> {
+        let table = map_to_dm(&self.data_tier.segments);
+        match (self.cache.as_mut(), self.linear.as_mut()) {
+            (None, None) => return Ok(false),
+            (Some(cache), None) => cache.set_origin_table(get_dm(), table)?,
+            (None, Some(linear)) => linear.set_table(get_dm(), table)?,
+            _ => panic!("NOT (self.cache().is_some() AND self.linear.is_some())"),
+        };
+        Ok(true)
+    }
+
     /// Satisfy a request for multiple segments. This request must
     /// always be satisfied exactly, None is returned if this can not
     /// be done.
@@ -300,11 +305,44 @@ impl Backstore {
         &mut self,
         pool_uuid: PoolUuid,
         sizes: &[Sectors],
+    ) -> StratisResult<Option<Vec<(Sectors, Sectors)>>> {
+        self.alloc_placed(pool_uuid, sizes, false)
+    }
+
+    /// As alloc(), but for thin pool metadata. Any space that must be newly
+    /// allocated from the data tier is taken preferentially from
+    /// non-rotational blockdevs.
+    ///
+    /// WARNING: metadata changing event
+    pub fn alloc_metadata(
+        &mut self,
+        pool_uuid: PoolUuid,
+        sizes: &[Sectors],
+    ) -> StratisResult<Option<Vec<(Sectors, Sectors)>>> {
+        self.alloc_placed(pool_uuid, sizes, true)
+    }
+
+    /// Allocate the amount from the data tier, for thin pool metadata if
+    /// metadata is true.
+    fn alloc_from_data_tier(&mut self, amount: Sectors, metadata: bool) -> bool {
+        if metadata {
+            self.data_tier.alloc_metadata(amount)
+        } else {
+            self.data_tier.alloc(amount)
+        }
+    }
+
+    /// Do the real work of alloc() and alloc_metadata().
+    fn alloc_placed(
+        &mut self,
+        pool_uuid: PoolUuid,
+        sizes: &[Sectors],
+        metadata: bool,
     ) -> StratisResult<Option<Vec<(Sectors, Sectors)>>> {
         let total_required = sizes.iter().cloned().sum();
         let available = self.available_in_cap();
         if available < total_required {
-            if self.data_tier.alloc(total_required - available) {
+            if self.alloc_from_data_tier(total_required - available, metadata) {
                 self.extend_cap_device(pool_uuid)?;
             } else {
                 return Ok(None);
@@ -349,6 +387,31 @@ impl Backstore {
         pool_uuid: PoolUuid,
         request: Sectors,
         modulus: Sectors,
+    ) -> StratisResult<Option<(Sectors, Sectors)>> {
+        self.request_placed(pool_uuid, request, modulus, false)
+    }
+
+    /// As request(), but for thin pool metadata. Any space that must be
+    /// newly allocated from the data tier is taken preferentially from
+    /// non-rotational blockdevs.
+    ///
+    /// WARNING: metadata changing event
+    pub fn request_metadata(
+        &mut self,
+        pool_uuid: PoolUuid,
+        request: Sectors,
+        modulus: Sectors,
+    ) -> StratisResult<Option<(Sectors, Sectors)>> {
+        self.request_placed(pool_uuid, request, modulus, true)
+    }
+
+    /// Do the real work of request() and request_metadata().
+    fn request_placed(
+        &mut self,
+        pool_uuid: PoolUuid,
+        request: Sectors,
+        modulus: Sectors,
+        metadata: bool,
     ) -> StratisResult<Option<(Sectors, Sectors)>> {
         assert!(modulus != Sectors(0));
 
@@ -362,7 +425,7 @@ impl Backstore {
         if available < internal_request {
             let mut allocated = false;
             while !allocated && internal_request != Sectors(0) {
-                allocated = self.data_tier.alloc(internal_request - available);
+                allocated = self.alloc_from_data_tier(internal_request - available, metadata);
                 let temp = internal_request / 2usize;
                 internal_request = (temp / modulus) * modulus;
             }
@@ -418,6 +481,12 @@ impl Backstore {
         self.data_tier.usable_size()
     }
 
+    /// The largest optimal I/O size reported by any of the data tier's
+    /// blockdevs.
+    pub fn datatier_optimal_io_size(&self) -> Option<Sectors> {
+        self.data_tier.optimal_io_size()
+    }
+
     /// The size of the cap device.
     ///
     /// The size of the cap device is obtained from the size of the component
@@ -474,8 +543,26 @@ impl Backstore {
         self.data_tier.destroy()
     }
 
+    /// Compare the backstore's upper-most DM device, and the BDAs of its
+    /// blockdevs, with what is on the system, returning the differences.
+    pub fn discrepancies(&self, pool_uuid: PoolUuid) -> StratisResult<Vec<Discrepancy>> {
+        let mut found = Vec::new();
+        if let Some(discrepancy) = match (&self.cache, &self.linear) {
+            (Some(cache), _) => check_dm_device!(pool_uuid, cache, CacheDev)?,
+            (None, Some(linear)) => check_dm_device!(pool_uuid, linear, LinearDev)?,
+            (None, None) => None,
+        } {
+            found.push(discrepancy);
+        }
+        found.extend(
+            self.blockdevs()
+                .into_iter()
+                .filter_map(|(_, bd)| bd.discrepancy()),
+        );
+        Ok(found)
+    }
+
     /// Teardown the DM devices in the backstore.
-    #[cfg(test)]
     pub fn teardown(&mut self) -> StratisResult<()> {
         match self.cache {
             Some(ref mut cache) => cache.teardown(get_dm()),
@@ -531,8 +618,160 @@ impl Backstore {
         self.data_tier.metadata_size()
     }
 
-    /// Write the given data to the data tier's devices.
-    pub fn save_state(&mut self, metadata: &[u8]) -> StratisResult<()> {
+    /// The strategy by which space is allocated from the data tier.
+    pub fn allocation_strategy(&self) -> AllocationStrategy {
+        self.data_tier.allocation_strategy()
+    }
+
+    /// Set the strategy by which space is allocated from the data tier.
+    /// Return true if the strategy was changed, otherwise false.
+    /// WARNING: metadata changing event
+    pub fn set_allocation_strategy(&mut self, strategy: AllocationStrategy) -> bool {
+        if self.data_tier.allocation_strategy() == strategy {
+            false
+        } else {
+            self.data_tier.set_allocation_strategy(strategy);
+            true
+        }
+    }
+
+    /// Suspend the cap device, without flushing.
+    /// Precondition: space has been allocated, so the cap device exists.
+    fn suspend_cap_device(&mut self) -> StratisResult<()> {
+        match (self.cache.as_mut(), self.linear.as_mut()) {
+            (Some(cache), None) => cache.suspend(get_dm(), false)?,
+            (None, Some(linear)) => linear.suspend(get_dm(), false)?,
+            _ => panic!("exactly one of self.cache and self.linear must exist"),
+        };
+        Ok(())
+    }
+
+    /// Resume the cap device.
+    /// Precondition: space has been allocated, so the cap device exists.
+    fn resume_cap_device(&mut self) -> StratisResult<()> {
+        match (self.cache.as_mut(), self.linear.as_mut()) {
+            (Some(cache), None) => cache.resume(get_dm())?,
+            (None, Some(linear)) => linear.resume(get_dm())?,
+            _ => panic!("exactly one of self.cache and self.linear must exist"),
+        };
+        Ok(())
+    }
+
+    /// Copy all the data allocated from the data tier blockdev with the
+    /// given UUID to space on the other blockdevs of the data tier, and map
+    /// the copies in its place. The blockdev remains in the data tier, but
+    /// nothing is allocated from it.
+    /// The cap device is suspended while the data is copied, and its
+    /// offsets do not change, so nothing above it need be changed. The new
+    /// table is loaded into the cap device, but the cap device is left
+    /// suspended, so that no data is written to the copies until the new
+    /// mapping has been recorded in the metadata; the drain returned must
+    /// then be passed to finish_drain(), or, if the metadata could not be
+    /// written, to abandon_drain().
+    /// Return None if there was no data to move.
+    /// Return an error if the blockdev is not in the data tier, or if the
+    /// other blockdevs do not have space for its data. On error, the cap
+    /// device is resumed with its old table.
+    ///
+    /// Precondition: Nothing above the cap device is doing I/O, e.g. the
+    /// thin pool is suspended.
+    ///
+    /// WARNING: metadata changing event
+    pub fn drain_datadev(
+        &mut self,
+        pool_uuid: PoolUuid,
+        uuid: DevUuid,
+    ) -> StratisResult<Option<Drain>> {
+        let devnode = match self.data_tier.block_mgr.get_blockdev_by_uuid(uuid) {
+            Some(bd) => bd.devnode.clone(),
+            None => {
+                let in_cache = self
+                    .cache_tier
+                    .as_ref()
+                    .map(|cache_tier| cache_tier.block_mgr.get_blockdev_by_uuid(uuid).is_some())
+                    .unwrap_or(false);
+                return Err(if in_cache {
+                    StratisError::Engine(
+                        ErrorEnum::Invalid,
+                        format!("blockdev {} is in the cache tier; only data tier blockdevs may be drained", uuid),
+                    )
+                } else {
+                    StratisError::Engine(ErrorEnum::NotFound, format!("no blockdev {}", uuid))
+                });
+            }
+        };
+
+        let drain = match self.data_tier.plan_drain(uuid)? {
+            Some(drain) => drain,
+            None => return Ok(None),
+        };
+
+        let copied = self.suspend_cap_device().and_then(|_| {
+            for (old, new) in &drain.moves {
+                let mut src_offset = old.segment.start;
+                for seg in new {
+                    let dst = self
+                        .data_tier
+                        .block_mgr
+                        .get_blockdev_by_uuid(seg.uuid)
+                        .expect("replacement space was allocated from this blockdev");
+                    copy_sectors(
+                        &devnode,
+                        src_offset,
+                        &dst.devnode,
+                        seg.segment.start,
+                        seg.segment.length,
+                    )?;
+                    src_offset += seg.segment.length;
+                }
+            }
+            Ok(())
+        });
+
+        let loaded = copied.and_then(|_| {
+            self.data_tier.map_drain(&drain);
+            self.load_cap_table()
+        });
+
+        if let Err(err) = loaded {
+            if let Err(e) = self.abandon_drain(drain) {
+                warn!(
+                    "Could not restore the cap device of pool {}: {}",
+                    pool_uuid, e
+                );
+            }
+            return Err(err);
+        }
+
+        Ok(Some(drain))
+    }
+
+    /// Release the space from which drain_datadev() moved the data, and
+    /// resume the cap device with its new table.
+    /// Precondition: the new mapping has been recorded in the metadata.
+    pub fn finish_drain(&mut self, drain: Drain) -> StratisResult<()> {
+        self.data_tier.finish_drain(drain);
+        self.resume_cap_device()
+    }
+
+    /// Undo drain_datadev(): map the data where it was before, release the
+    /// space to which it was copied, and resume the cap device with its old
+    /// table. Nothing was written to the copies, so no data is lost.
+    pub fn abandon_drain(&mut self, drain: Drain) -> StratisResult<()> {
+        self.data_tier.abandon_drain(drain);
+        self.load_cap_table()?;
+        self.resume_cap_device()
+    }
+
+    /// The sequence number of the most recent metadata written to the data
+    /// tier's devices, if any.
+    pub fn last_sequence(&self) -> Option<u64> {
+        self.data_tier.block_mgr.last_sequence()
+    }
+
+    /// Write the given data to the data tier's devices. Returns a warning
+    /// if the data reached too few of them.
+    pub fn save_state(&mut self, metadata: &[u8]) -> StratisResult<Option<String>> {
         self.data_tier.save_state(metadata)
     }
 
@@ -553,6 +792,59 @@ impl Backstore {
             |(_, b)| Ok(b.set_user_info(user_info)),
         )
     }
+
+    /// Take the specified blockdev offline for maintenance or bring it back
+    /// online. A blockdev from which space has been allocated can not be
+    /// taken offline, since there is no redundant copy of its data to which
+    /// I/O could be redirected.
+    /// May return an error if there is no blockdev for the given UUID.
+    pub fn set_blockdev_maintenance(
+        &mut self,
+        uuid: DevUuid,
+        maintenance: bool,
+    ) -> StratisResult<bool> {
+        match self.get_mut_blockdev_by_uuid(uuid) {
+            Some((_, bd)) => {
+                if maintenance && bd.state() == BlockDevState::InUse {
+                    return Err(StratisError::Engine(
+                        ErrorEnum::Busy,
+                        format!(
+                            "Blockdev {} holds data that can not be redirected to another device",
+                            uuid
+                        ),
+                    ));
+                }
+                Ok(bd.set_maintenance(maintenance))
+            }
+            None => Err(StratisError::Engine(
+                ErrorEnum::NotFound,
+                format!("No blockdev for uuid {} found", uuid),
+            )),
+        }
+    }
+
+    /// Bring the metadata on the specified blockdev up to date with the
+    /// metadata most recently written to the data tier. The metadata must
+    /// be the metadata that was most recently written.
+    /// Cache tier blockdevs do not hold pool metadata, so this method
+    /// does nothing for them.
+    pub fn resync_blockdev(&mut self, uuid: DevUuid, metadata: &[u8]) -> StratisResult<()> {
+        match self.get_blockdev_by_uuid(uuid) {
+            Some((BlockDevTier::Data, _)) => self.data_tier.resync_blockdev(uuid, metadata),
+            Some((BlockDevTier::Cache, _)) => Ok(()),
+            None => Err(StratisError::Engine(
+                ErrorEnum::NotFound,
+                format!("No blockdev for uuid {} found", uuid),
+            )),
+        }
+    }
+
+    /// Bring the metadata on all data tier blockdevs that hold older
+    /// metadata up to date. The metadata must be the metadata that was most
+    /// recently written. Return the UUIDs of the blockdevs rewritten.
+    pub fn resync_stale_blockdevs(&mut self, metadata: &[u8]) -> Vec<DevUuid> {
+        self.data_tier.resync_stale_blockdevs(metadata)
+    }
 }
 
 impl Recordable<BackstoreSave> for Backstore {
@@ -569,6 +861,8 @@ impl Recordable<BackstoreSave> for Backstore {
 
 #[cfg(test)]
 mod tests {
+    #![cfg_attr(not(feature = "device_tests"), allow(dead_code, unused_imports))]
+
     use uuid::Uuid;
 
     use devicemapper::{CacheDevStatus, DataBlocks, IEC};
@@ -683,6 +977,7 @@ mod tests {
         backstore.destroy().unwrap();
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn loop_test_add_cache_devs() {
         loopbacked::test_with_spec(
@@ -691,6 +986,7 @@ mod tests {
         );
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn real_test_add_cache_devs() {
         real::test_with_spec(
@@ -749,11 +1045,13 @@ mod tests {
         cmd::udev_settle().unwrap();
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn loop_test_request() {
         loopbacked::test_with_spec(&loopbacked::DeviceLimits::Range(1, 3, None), test_request);
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn real_test_request() {
         real::test_with_spec(&real::DeviceLimits::AtLeast(1, None, None), test_request);
@@ -796,9 +1094,9 @@ mod tests {
         let backstore_save = backstore.record();
 
         cmd::udev_settle().unwrap();
-        let map = find_all().unwrap();
+        let (map, _) = find_all().unwrap();
         let map = &map[&pool_uuid];
-        let mut backstore = Backstore::setup(pool_uuid, &backstore_save, &map, None).unwrap();
+        let mut backstore = Backstore::setup(pool_uuid, &backstore_save, &map).unwrap();
         invariant(&backstore);
 
         let backstore_save2 = backstore.record();
@@ -808,9 +1106,9 @@ mod tests {
         backstore.teardown().unwrap();
 
         cmd::udev_settle().unwrap();
-        let map = find_all().unwrap();
+        let (map, _) = find_all().unwrap();
         let map = &map[&pool_uuid];
-        let mut backstore = Backstore::setup(pool_uuid, &backstore_save, &map, None).unwrap();
+        let mut backstore = Backstore::setup(pool_uuid, &backstore_save, &map).unwrap();
         invariant(&backstore);
 
         let backstore_save2 = backstore.record();
@@ -820,11 +1118,13 @@ mod tests {
         backstore.destroy().unwrap();
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn loop_test_setup() {
         loopbacked::test_with_spec(&loopbacked::DeviceLimits::Range(2, 3, None), test_setup);
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn real_test_setup() {
         real::test_with_spec(&real::DeviceLimits::AtLeast(2, None, None), test_setup);
@@ -12,35 +12,47 @@ use chrono::{DateTime, TimeZone, Utc};
 use devicemapper::{Device, Sectors};
 
 use crate::engine::{BlockDev, BlockDevState, DevUuid, EngineEvent, MaybeDbusPath, PoolUuid};
-use crate::stratis::StratisResult;
+use crate::stratis::{StratisError, StratisResult};
+
+use crate::engine::check::Discrepancy;
 
 use crate::engine::event::get_engine_listener_list;
 
 use crate::engine::strat_engine::serde_structs::{BaseBlockDevSave, Recordable};
 
+use crate::engine::strat_engine::backstore::device::{
+    is_rotational, open_for_metadata, ZonedModel,
+};
 use crate::engine::strat_engine::backstore::metadata::BDA;
 use crate::engine::strat_engine::backstore::range_alloc::RangeAllocator;
+use crate::engine::strat_engine::backstore::util::persistent_devnode_lookup;
 
 #[derive(Debug)]
 pub struct StratBlockDev {
     dev: Device,
     pub(super) devnode: PathBuf,
+    persistent_devnode: Option<PathBuf>,
     bda: BDA,
     used: RangeAllocator,
     user_info: Option<String>,
     hardware_info: Option<String>,
+    zoned: ZonedModel,
+    maintenance: bool,
     dbus_path: MaybeDbusPath,
 }
 
 impl StratBlockDev {
     /// Make a new BlockDev from the parameters.
     /// Allocate space for the Stratis metadata on the device.
+    /// Look up a path to the device that is stable across reboots for
+    /// reporting purposes; failure to find one is not an error.
     /// - dev: the device, identified by number
     /// - devnode: the device node
     /// - bda: the device's BDA
     /// - other_segments: segments claimed for non-Stratis metadata use
     /// - user_info: user settable identifying information
     /// - hardware_info: identifying information in the hardware
+    /// - zoned: the zoned model of the device
     /// Returns an error if it is impossible to allocate all segments on the
     /// device.
     /// NOTE: It is possible that the actual device size is greater than
@@ -56,18 +68,24 @@ impl StratBlockDev {
         upper_segments: &[(Sectors, Sectors)],
         user_info: Option<String>,
         hardware_info: Option<String>,
+        zoned: ZonedModel,
     ) -> StratisResult<StratBlockDev> {
         let mut segments = vec![(Sectors(0), bda.size())];
         segments.extend(upper_segments);
         let allocator = RangeAllocator::new(bda.dev_size(), &segments)?;
 
+        let persistent_devnode = persistent_devnode_lookup(&devnode).unwrap_or(None);
+
         Ok(StratBlockDev {
             dev,
             devnode,
+            persistent_devnode,
             bda,
             used: allocator,
             user_info,
             hardware_info,
+            zoned,
+            maintenance: false,
             dbus_path: MaybeDbusPath(None),
         })
     }
@@ -77,14 +95,68 @@ impl StratBlockDev {
         &self.dev
     }
 
+    /// Record a new devnode for the blockdev, e.g., because the device
+    /// was renamed by udev. The device itself is identified by its UUID,
+    /// so the devnode is only an attribute which may change.
+    pub fn set_devnode(&mut self, devnode: PathBuf) {
+        self.persistent_devnode = persistent_devnode_lookup(&devnode).unwrap_or(None);
+        self.devnode = devnode;
+    }
+
     pub fn wipe_metadata(&self) -> StratisResult<()> {
-        let mut f = OpenOptions::new().write(true).open(&self.devnode)?;
+        let mut f = open_for_metadata(&self.devnode)?;
         BDA::wipe(&mut f)
     }
 
-    pub fn save_state(&mut self, time: &DateTime<Utc>, metadata: &[u8]) -> StratisResult<()> {
-        let mut f = OpenOptions::new().write(true).open(&self.devnode)?;
-        self.bda.save_state(time, metadata, &mut f)
+    /// Compare the BDA on the device with the one in memory, returning the
+    /// difference, if any.
+    pub fn discrepancy(&self) -> Option<Discrepancy> {
+        let pool_uuid = self.pool_uuid();
+        let dev_uuid = self.uuid();
+        let devnode = self.devnode.clone();
+        let reason = match OpenOptions::new()
+            .read(true)
+            .open(&self.devnode)
+            .map_err(StratisError::from)
+            .and_then(|mut f| BDA::load(&mut f))
+        {
+            Ok(Some(ref bda)) if bda.pool_uuid() == pool_uuid && bda.dev_uuid() == dev_uuid => {
+                return if bda.last_sequence() == self.last_sequence() {
+                    None
+                } else {
+                    Some(Discrepancy::MetadataSequenceMismatch {
+                        pool_uuid,
+                        dev_uuid,
+                        devnode,
+                        on_disk: bda.last_sequence(),
+                        in_memory: self.last_sequence(),
+                    })
+                };
+            }
+            Ok(Some(bda)) => format!(
+                "BDA identifies device {} of pool {}",
+                bda.dev_uuid(),
+                bda.pool_uuid()
+            ),
+            Ok(None) => "no BDA found".into(),
+            Err(err) => format!("BDA could not be read: {}", err),
+        };
+        Some(Discrepancy::BdaMismatch {
+            pool_uuid,
+            dev_uuid,
+            devnode,
+            reason,
+        })
+    }
+
+    pub fn save_state(
+        &mut self,
+        sequence: u64,
+        time: &DateTime<Utc>,
+        metadata: &[u8],
+    ) -> StratisResult<()> {
+        let mut f = open_for_metadata(&self.devnode)?;
+        self.bda.save_state(sequence, time, metadata, &mut f)
     }
 
     /// The device's UUID.
@@ -99,11 +171,21 @@ impl StratBlockDev {
     }
 
     /// Last time metadata was written to this device.
-    #[allow(dead_code)]
     pub fn last_update_time(&self) -> Option<&DateTime<Utc>> {
         self.bda.last_update_time()
     }
 
+    /// Sequence number of the last metadata written to this device.
+    pub fn last_sequence(&self) -> Option<u64> {
+        self.bda.last_sequence()
+    }
+
+    /// The zoned model of the device.
+    #[allow(dead_code)]
+    pub fn zoned(&self) -> ZonedModel {
+        self.zoned
+    }
+
     /// Find some sector ranges that could be allocated. If more
     /// sectors are needed than are available, return partial results.
     /// If all sectors are desired, use available() method to get all.
@@ -119,6 +201,20 @@ impl StratBlockDev {
         result
     }
 
+    /// Release space previously obtained by request_space(), so that it may
+    /// be allocated again.
+    pub fn release_space(&mut self, segs: &[(Sectors, Sectors)]) {
+        let prev_state = self.state();
+        self.used.remove_ranges(segs);
+        let state = self.state();
+        if state != prev_state {
+            get_engine_listener_list().notify(&EngineEvent::BlockdevStateChanged {
+                dbus_path: self.get_dbus_path(),
+                state,
+            });
+        }
+    }
+
     // ALL SIZE METHODS (except size(), which is in BlockDev impl.)
     /// The number of Sectors on this device used by Stratis for metadata
     pub fn metadata_size(&self) -> Sectors {
@@ -143,6 +239,17 @@ impl StratBlockDev {
     pub fn set_user_info(&mut self, user_info: Option<&str>) -> bool {
         set_blockdev_user_info!(self; user_info)
     }
+
+    /// Take this blockdev offline for maintenance or bring it back online.
+    /// Returns true if the maintenance state was changed, otherwise false.
+    pub fn set_maintenance(&mut self, maintenance: bool) -> bool {
+        if self.maintenance != maintenance {
+            self.maintenance = maintenance;
+            true
+        } else {
+            false
+        }
+    }
 }
 
 impl BlockDev for StratBlockDev {
@@ -150,6 +257,12 @@ impl BlockDev for StratBlockDev {
         self.devnode.clone()
     }
 
+    fn persistent_devnode(&self) -> PathBuf {
+        self.persistent_devnode
+            .clone()
+            .unwrap_or_else(|| self.devnode.clone())
+    }
+
     fn user_info(&self) -> Option<&str> {
         self.user_info.as_ref().map(|x| &**x)
     }
@@ -179,6 +292,16 @@ impl BlockDev for StratBlockDev {
         }
     }
 
+    fn in_maintenance(&self) -> bool {
+        self.maintenance
+    }
+
+    fn rotational(&self) -> Option<bool> {
+        is_rotational(self.dev)
+            .ok()
+            .and_then(|rotational| rotational)
+    }
+
     fn set_dbus_path(&mut self, path: MaybeDbusPath) {
         self.dbus_path = path
     }
@@ -194,6 +317,11 @@ impl Recordable<BaseBlockDevSave> for StratBlockDev {
             uuid: self.uuid(),
             user_info: self.user_info.clone(),
             hardware_info: self.hardware_info.clone(),
+            zoned: match self.zoned {
+                ZonedModel::None => None,
+                zoned => Some(zoned.as_str().to_owned()),
+            },
+            maintenance: self.maintenance,
         }
     }
 }
@@ -4,13 +4,16 @@
 
 // Code to handle a collection of block devices.
 
+use std::cmp;
 use std::collections::{HashMap, HashSet};
 use std::fmt;
-use std::fs::{File, OpenOptions};
+use std::fs::File;
+use std::hash::Hash;
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
-use chrono::{DateTime, Duration, Utc};
-use rand::seq::IteratorRandom;
+use chrono::{DateTime, Utc};
+use rand::seq::SliceRandom;
 use rand::thread_rng;
 use uuid::Uuid;
 
@@ -18,19 +21,117 @@ use devicemapper::{
     Bytes, Device, LinearDevTargetParams, LinearTargetParams, Sectors, TargetLine, IEC,
 };
 
-use crate::engine::{BlockDev, DevUuid, PoolUuid};
+use crate::engine::{AllocationStrategy, BlockDev, DevUuid, PlannedBlockDev, PoolUuid};
 use crate::stratis::{ErrorEnum, StratisError, StratisResult};
 
 use crate::engine::strat_engine::backstore::{blkdev_size, StratBlockDev, MIN_MDA_SECTORS};
 use crate::engine::strat_engine::serde_structs::{BaseBlockDevSave, BaseDevSave, Recordable};
 
 use crate::engine::strat_engine::backstore::cleanup::wipe_blockdevs;
-use crate::engine::strat_engine::backstore::device::{identify, resolve_devices, DevOwnership};
-use crate::engine::strat_engine::backstore::metadata::{validate_mda_size, BDA};
+use crate::engine::strat_engine::backstore::device::{
+    identify, is_rotational, open_for_metadata, optimal_io_size, resolve_devices, validate_stack,
+    verify_not_in_use, zoned_model, DevOwnership, ZonedModel,
+};
+use crate::engine::strat_engine::backstore::metadata::{validate_mda_size, StaticHeader, BDA};
 use crate::engine::strat_engine::backstore::util::hw_lookup;
 
-const MIN_DEV_SIZE: Bytes = Bytes(IEC::Gi);
-const MAX_NUM_TO_WRITE: usize = 10;
+const DEFAULT_MIN_DEV_SIZE: Bytes = Bytes(IEC::Gi);
+const DEFAULT_METADATA_REDUNDANCY: usize = 10;
+
+/// The largest amount allocated from a single blockdev at a time by the
+/// round-robin allocation strategy, 1 GiB.
+const ROUND_ROBIN_EXTENT_SIZE: Sectors = Sectors(IEC::Mi * 2);
+
+/// The smallest value to which the minimum device size may be lowered.
+/// A device must at least be able to accommodate the Stratis BDA with some
+/// room to spare.
+const MIN_DEV_SIZE_LOWER_BOUND: Bytes = Bytes(16 * IEC::Mi);
+
+/// The minimum size, in bytes, of a device that may be added to a pool.
+static MIN_DEV_SIZE: AtomicU64 = AtomicU64::new(DEFAULT_MIN_DEV_SIZE.0);
+
+/// The minimum size of a device that may be added to a pool.
+fn min_dev_size() -> Bytes {
+    Bytes(MIN_DEV_SIZE.load(Ordering::Relaxed))
+}
+
+/// Set the minimum size of a device that may be added to a pool.
+/// The default is suitable for production use; lowering it is intended
+/// for development and testing on small loopback or sparse file backed
+/// devices. The size actually recorded in each device's metadata is always
+/// the real size of the device.
+/// Returns an error if the size is too small to accommodate Stratis
+/// metadata.
+pub fn set_min_dev_size(size: Bytes) -> StratisResult<()> {
+    if size < MIN_DEV_SIZE_LOWER_BOUND {
+        let err_msg = format!(
+            "minimum device size {} is less than the lower bound {}",
+            size, MIN_DEV_SIZE_LOWER_BOUND
+        );
+        return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+    }
+    MIN_DEV_SIZE.store(*size, Ordering::Relaxed);
+    Ok(())
+}
+
+/// The number of blockdevs in a pool to which pool-level metadata is written.
+static METADATA_REDUNDANCY: AtomicUsize = AtomicUsize::new(DEFAULT_METADATA_REDUNDANCY);
+
+/// The number of blockdevs in a pool to which pool-level metadata is written.
+fn metadata_redundancy() -> usize {
+    METADATA_REDUNDANCY.load(Ordering::Relaxed)
+}
+
+/// Set the number of blockdevs in a pool to which pool-level metadata is
+/// written. If a pool has fewer blockdevs, metadata is written to all of
+/// them. Every blockdev carries its own static header regardless.
+/// Returns an error if the number is 0.
+pub fn set_metadata_redundancy(copies: usize) -> StratisResult<()> {
+    if copies == 0 {
+        let err_msg = "metadata must be written to at least one blockdev";
+        return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg.into()));
+    }
+    METADATA_REDUNDANCY.store(copies, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Choose no more than n of the candidates.
+/// Preferred candidates are chosen before others. Among candidates that are
+/// equally preferred, those in a failure domain, as given by domain, from
+/// which no candidate has yet been chosen are chosen first. Otherwise, the
+/// choice is random.
+fn choose_diverse<T, K, D, P>(mut candidates: Vec<T>, domain: D, preferred: P, n: usize) -> Vec<T>
+where
+    K: Eq + Hash,
+    D: Fn(&T) -> K,
+    P: Fn(&T) -> bool,
+{
+    candidates.shuffle(&mut thread_rng());
+    let (preferred, others): (Vec<T>, Vec<T>) = candidates.into_iter().partition(|c| preferred(c));
+
+    let mut domains = HashSet::new();
+    let (preferred_distinct, preferred_rest): (Vec<T>, Vec<T>) = preferred
+        .into_iter()
+        .partition(|c| domains.insert(domain(c)));
+    let (others_distinct, others_rest): (Vec<T>, Vec<T>) =
+        others.into_iter().partition(|c| domains.insert(domain(c)));
+    preferred_distinct
+        .into_iter()
+        .chain(preferred_rest)
+        .chain(others_distinct)
+        .chain(others_rest)
+        .take(n)
+        .collect()
+}
+
+/// The failure domain of a blockdev. Blockdevs with the same hardware
+/// information, e.g., partitions of one disk, are in the same domain.
+/// A blockdev without hardware information is in a domain of its own.
+fn failure_domain(bd: &StratBlockDev) -> String {
+    bd.hardware_info()
+        .map(|info| info.to_owned())
+        .unwrap_or_else(|| bd.uuid().to_simple_ref().to_string())
+}
 
 /// struct to represent a continuous set of sectors on a disk
 #[derive(Debug, Clone)]
@@ -161,17 +262,24 @@ pub fn map_to_dm(bsegs: &[BlkDevSegment]) -> Vec<TargetLine<LinearDevTargetParam
 #[derive(Debug)]
 pub struct BlockDevMgr {
     block_devs: Vec<StratBlockDev>,
+    last_sequence: Option<u64>,
     last_update_time: Option<DateTime<Utc>>,
 }
 
 impl BlockDevMgr {
     /// Make a struct that represents an existing BlockDevMgr.
-    pub fn new(
-        block_devs: Vec<StratBlockDev>,
-        last_update_time: Option<DateTime<Utc>>,
-    ) -> BlockDevMgr {
+    /// The sequence number and time of the most recent metadata write are
+    /// taken from the blockdev with the highest sequence number.
+    pub fn new(block_devs: Vec<StratBlockDev>) -> BlockDevMgr {
+        let (last_sequence, last_update_time) = block_devs
+            .iter()
+            .filter_map(|bd| bd.last_sequence().map(|seq| (seq, bd.last_update_time())))
+            .max_by_key(|&(seq, time)| (seq, time))
+            .map(|(seq, time)| (Some(seq), time.cloned()))
+            .unwrap_or((None, None));
         BlockDevMgr {
             block_devs,
+            last_sequence,
             last_update_time,
         }
     }
@@ -183,10 +291,31 @@ impl BlockDevMgr {
         mda_size: Sectors,
     ) -> StratisResult<BlockDevMgr> {
         let devices = resolve_devices(paths)?;
-        Ok(BlockDevMgr::new(
-            initialize(pool_uuid, devices, mda_size, &HashSet::new())?,
-            None,
-        ))
+        Ok(BlockDevMgr::new(initialize(
+            pool_uuid,
+            devices,
+            mda_size,
+            &HashSet::new(),
+        )?))
+    }
+
+    /// Check the devices as initialize() would, but without writing to any
+    /// of them. Return how each device would be laid out.
+    pub fn plan(paths: &[&Path], mda_size: Sectors) -> StratisResult<Vec<PlannedBlockDev>> {
+        let devices = resolve_devices(paths)?;
+        let checked = check_devs(Uuid::new_v4(), devices, mda_size, &HashSet::new())?;
+        Ok(checked
+            .into_iter()
+            .map(|(device, (devnode, size, _, _))| PlannedBlockDev {
+                devnode: devnode.to_owned(),
+                size: size.sectors(),
+                metadata_size: BDA::size_for(mda_size),
+                optimal_io_size: optimal_io_size(device)
+                    .ok()
+                    .and_then(|size| size)
+                    .map(|size| size.sectors()),
+            })
+            .collect())
     }
 
     /// Get a function that maps UUIDs to Devices.
@@ -259,14 +388,99 @@ impl BlockDevMgr {
     /// Allocate space according to sizes vector request.
     /// Return the segments allocated for each request, or None if it was
     /// not possible to satisfy the request.
+    /// The strategy determines how the allocation is spread over the
+    /// blockdevs.
+    /// This method is atomic, it either allocates all requested or allocates
+    /// nothing.
+    pub fn alloc_space(
+        &mut self,
+        sizes: &[Sectors],
+        strategy: AllocationStrategy,
+    ) -> Option<Vec<Vec<BlkDevSegment>>> {
+        self.alloc_space_placed(sizes, strategy, false, None)
+    }
+
+    /// Allocate space according to sizes vector request, as alloc_space()
+    /// does, but from any blockdev other than the one with the given UUID.
+    /// This method is atomic, it either allocates all requested or allocates
+    /// nothing.
+    pub fn alloc_space_excluding(
+        &mut self,
+        sizes: &[Sectors],
+        strategy: AllocationStrategy,
+        exclude: DevUuid,
+    ) -> Option<Vec<Vec<BlkDevSegment>>> {
+        self.alloc_space_placed(sizes, strategy, false, Some(exclude))
+    }
+
+    /// Release the space of previously allocated segments, so that it may
+    /// be allocated again.
+    pub fn release_space(&mut self, segs: &[BlkDevSegment]) {
+        for seg in segs {
+            self.get_mut_blockdev_by_uuid(seg.uuid)
+                .expect("segments are allocated from blockdevs belonging to this manager")
+                .release_space(&[(seg.segment.start, seg.segment.length)]);
+        }
+    }
+
+    /// Allocate space for thin pool metadata according to sizes vector
+    /// request. Space is taken first from non-rotational blockdevs, then
+    /// from the rest, so that metadata is placed on the fastest blockdevs
+    /// available.
     /// This method is atomic, it either allocates all requested or allocates
     /// nothing.
-    pub fn alloc_space(&mut self, sizes: &[Sectors]) -> Option<Vec<Vec<BlkDevSegment>>> {
+    pub fn alloc_metadata_space(&mut self, sizes: &[Sectors]) -> Option<Vec<Vec<BlkDevSegment>>> {
+        self.alloc_space_placed(sizes, AllocationStrategy::FillFirst, true, None)
+    }
+
+    /// Do the real work of alloc_space() and alloc_metadata_space(). If
+    /// prefer_fast is true, non-rotational blockdevs are asked for space
+    /// before any others. A blockdev for which the kernel does not report
+    /// whether it is rotational is taken to be rotational. No space is taken
+    /// from the blockdev with the UUID exclude, if any.
+    fn alloc_space_placed(
+        &mut self,
+        sizes: &[Sectors],
+        strategy: AllocationStrategy,
+        prefer_fast: bool,
+        exclude: Option<DevUuid>,
+    ) -> Option<Vec<Vec<BlkDevSegment>>> {
         let total_needed: Sectors = sizes.iter().cloned().sum();
-        if self.avail_space() < total_needed {
+        let avail_space: Sectors = self
+            .block_devs
+            .iter()
+            .filter(|bd| Some(bd.uuid()) != exclude)
+            .map(|bd| bd.available())
+            .sum();
+        if avail_space < total_needed {
             return None;
         }
 
+        // With the fill-first strategy, each blockdev is asked for all that
+        // remains to be allocated, with round-robin for no more than one
+        // extent at a time, so that successive extents come from successive
+        // blockdevs. Round-robin starts from the blockdev with the most
+        // space available, so that a sequence of small allocations is also
+        // spread across the blockdevs.
+        let extent_size = match strategy {
+            AllocationStrategy::FillFirst => None,
+            AllocationStrategy::RoundRobin => Some(ROUND_ROBIN_EXTENT_SIZE),
+        };
+
+        let rotational: Vec<bool> = if prefer_fast {
+            self.block_devs
+                .iter()
+                .map(|bd| {
+                    is_rotational(*bd.device())
+                        .ok()
+                        .and_then(|rotational| rotational)
+                        .unwrap_or(true)
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+
         let mut lists = Vec::new();
         for &needed in sizes {
             let mut alloc = Sectors(0);
@@ -278,65 +492,172 @@ impl BlockDevMgr {
             // In the context of this major inefficiency that ensues over time
             // the obvious but more minor inefficiency of this inner loop is
             // not worth worrying about.
-            for bd in &mut self.block_devs {
-                if alloc == needed {
-                    break;
-                }
+            let mut order = (0..self.block_devs.len())
+                .filter(|&i| Some(self.block_devs[i].uuid()) != exclude)
+                .collect::<Vec<_>>();
+            if extent_size.is_some() {
+                let block_devs = &self.block_devs;
+                order.sort_by_key(|&i| cmp::Reverse(block_devs[i].available()));
+            }
+            if prefer_fast {
+                order.sort_by_key(|&i| rotational[i]);
+            }
+            while alloc != needed {
+                let prev_alloc = alloc;
+                for &i in &order {
+                    if alloc == needed {
+                        break;
+                    }
 
-                let (gotten, r_segs) = bd.request_space(needed - alloc);
-                let blkdev_segs = r_segs.into_iter().map(|(start, length)| {
-                    BlkDevSegment::new(bd.uuid(), Segment::new(*bd.device(), start, length))
-                });
-                segs.extend(blkdev_segs);
-                alloc += gotten;
+                    let bd = &mut self.block_devs[i];
+
+                    let request = match extent_size {
+                        Some(extent_size) => cmp::min(extent_size, needed - alloc),
+                        None => needed - alloc,
+                    };
+                    let (gotten, r_segs) = bd.request_space(request);
+                    let blkdev_segs = r_segs.into_iter().map(|(start, length)| {
+                        BlkDevSegment::new(bd.uuid(), Segment::new(*bd.device(), start, length))
+                    });
+                    segs.extend(blkdev_segs);
+                    alloc += gotten;
+                }
+                // avail_space guarantees that every pass makes progress
+                assert!(alloc > prev_alloc);
             }
-            assert_eq!(alloc, needed);
             lists.push(segs);
         }
 
         Some(lists)
     }
 
-    /// Write the given data to all blockdevs marking with current time.
-    /// Return an error if data was not written to any blockdev.
-    /// Omit blockdevs which do not have sufficient space in BDA to accommodate
-    /// metadata. If current time is not more recent than previously written
-    /// time, use a time that is one nanosecond greater than that previously
-    /// written. Randomly select no more than MAX_NUM_TO_WRITE blockdevs to
-    /// write to.
-    pub fn save_state(&mut self, metadata: &[u8]) -> StratisResult<()> {
-        let current_time = Utc::now();
-        let stamp_time = if Some(current_time) <= self.last_update_time {
-            self.last_update_time
-                .expect("self.last_update_time >= Some(current_time")
-                .checked_add_signed(Duration::nanoseconds(1))
-                .expect("self.last_update_time << maximum representable DateTime")
-        } else {
-            current_time
-        };
-
-        let data_size = Bytes(metadata.len() as u64).sectors();
+    /// The blockdevs, no more than the metadata redundancy level, that
+    /// should hold the pool-level metadata. Blockdevs which already hold the
+    /// most recent metadata are chosen first, so that the same blockdevs
+    /// continue to be written, then blockdevs in failure domains not yet
+    /// chosen. Omit blockdevs which do not have sufficient space in BDA to
+    /// accommodate data_size and blockdevs which are offline for maintenance.
+    fn metadata_blockdevs(&mut self, data_size: Sectors) -> Vec<&mut StratBlockDev> {
+        let last_sequence = self.last_sequence;
         let candidates = self
             .block_devs
             .iter_mut()
-            .filter(|b| b.max_metadata_size() >= data_size);
+            .filter(|b| !b.in_maintenance() && b.max_metadata_size() >= data_size)
+            .collect::<Vec<_>>();
+
+        choose_diverse(
+            candidates,
+            |b| failure_domain(b),
+            |b| last_sequence.is_some() && b.last_sequence() == last_sequence,
+            metadata_redundancy(),
+        )
+    }
 
-        // TODO: consider making selection not entirely random, i.e, ensuring
-        // distribution of metadata over different paths.
-        let saved = candidates
-            .choose_multiple(&mut thread_rng(), MAX_NUM_TO_WRITE)
-            .iter_mut()
-            .fold(false, |acc, b| {
-                acc | b.save_state(&stamp_time, metadata).is_ok()
-            });
+    /// Write the given data to blockdevs marking with the next sequence
+    /// number and the current time.
+    /// Return an error if data was not written to any blockdev. Once it has
+    /// been written to one, it is the most recent metadata, and will be
+    /// found when the pool is next set up, so the write has happened; if
+    /// it was written to no more than half of the blockdevs attempted, a
+    /// warning saying so is returned instead.
+    /// Write to no more than the metadata redundancy level of blockdevs,
+    /// chosen by metadata_blockdevs(). Blockdevs which are offline for
+    /// maintenance are not written to. The sequence number, not the time,
+    /// determines which metadata is most recent, so a clock that has been
+    /// set back does no harm.
+    pub fn save_state(&mut self, metadata: &[u8]) -> StratisResult<Option<String>> {
+        let sequence = self.last_sequence.map_or(1, |seq| seq + 1);
+        let stamp_time = Utc::now();
 
-        if saved {
-            self.last_update_time = Some(stamp_time);
-            Ok(())
-        } else {
+        let data_size = Bytes(metadata.len() as u64).sectors();
+        let blockdevs = self.metadata_blockdevs(data_size);
+        let attempted = blockdevs.len();
+        let saved = blockdevs.into_iter().fold(0, |acc, b| {
+            acc + b.save_state(sequence, &stamp_time, metadata).is_ok() as usize
+        });
+
+        if saved == 0 {
             let err_msg = "Failed to save metadata to even one device in pool";
-            Err(StratisError::Engine(ErrorEnum::Error, err_msg.into()))
+            return Err(StratisError::Engine(ErrorEnum::Error, err_msg.into()));
+        }
+
+        self.last_sequence = Some(sequence);
+        self.last_update_time = Some(stamp_time);
+
+        // Metadata that could be saved to no more than half of the devices
+        // to which it was written is in danger of being lost.
+        if saved * 2 <= attempted && attempted > 1 {
+            let warning = format!(
+                "Saved metadata to only {} of {} devices in pool",
+                saved, attempted
+            );
+            warn!("{}", warning);
+            return Ok(Some(warning));
         }
+        Ok(None)
+    }
+
+    /// The sequence number of the most recent metadata written to the
+    /// blockdevs, if any.
+    pub fn last_sequence(&self) -> Option<u64> {
+        self.last_sequence
+    }
+
+    /// Write the given data, which must be the data most recently written
+    /// by save_state(), to the specified blockdev, marking it with the
+    /// sequence number and time of the most recent write. This brings a
+    /// blockdev that has missed metadata updates, e.g., because it was
+    /// offline for maintenance, up to date with the other blockdevs.
+    /// Does nothing if the blockdev already has the most recent metadata or
+    /// if no metadata has yet been written.
+    pub fn resync_blockdev(&mut self, uuid: DevUuid, metadata: &[u8]) -> StratisResult<()> {
+        let (sequence, stamp_time) = match (self.last_sequence, self.last_update_time) {
+            (Some(sequence), Some(time)) => (sequence, time),
+            _ => return Ok(()),
+        };
+
+        let bd = self.get_mut_blockdev_by_uuid(uuid).ok_or_else(|| {
+            StratisError::Engine(
+                ErrorEnum::NotFound,
+                format!("No blockdev for uuid {} found", uuid),
+            )
+        })?;
+
+        if bd.last_sequence() >= Some(sequence) {
+            return Ok(());
+        }
+
+        bd.save_state(sequence, &stamp_time, metadata)
+    }
+
+    /// Write the given data, which must be the data most recently written
+    /// by save_state(), to those blockdevs chosen by metadata_blockdevs()
+    /// that hold older metadata, so that the most recent generation is held
+    /// by as many blockdevs as the metadata redundancy level requires.
+    /// A failure to write to a blockdev is logged, but is not an error.
+    /// Return the UUIDs of the blockdevs that were brought up to date.
+    pub fn resync_stale_blockdevs(&mut self, metadata: &[u8]) -> Vec<DevUuid> {
+        let (sequence, stamp_time) = match (self.last_sequence, self.last_update_time) {
+            (Some(sequence), Some(time)) => (sequence, time),
+            _ => return Vec::new(),
+        };
+
+        let data_size = Bytes(metadata.len() as u64).sectors();
+        self.metadata_blockdevs(data_size)
+            .into_iter()
+            .filter(|bd| bd.last_sequence() < Some(sequence))
+            .filter_map(|bd| match bd.save_state(sequence, &stamp_time, metadata) {
+                Ok(_) => Some(bd.uuid()),
+                Err(err) => {
+                    warn!(
+                        "failed to bring metadata on blockdev {} up to date: {:?}",
+                        bd.devnode().display(),
+                        err
+                    );
+                    None
+                }
+            })
+            .collect()
     }
 
     /// Get references to managed blockdevs.
@@ -373,6 +694,16 @@ impl BlockDevMgr {
         self.block_devs.iter().map(|b| b.size()).sum()
     }
 
+    /// The largest optimal I/O size reported by any of the blockdevs, if
+    /// any reports one.
+    pub fn optimal_io_size(&self) -> Option<Sectors> {
+        self.block_devs
+            .iter()
+            .filter_map(|bd| optimal_io_size(*bd.device()).ok().and_then(|size| size))
+            .map(|size| size.sectors())
+            .max()
+    }
+
     /// The number of sectors given over to Stratis metadata
     /// self.size() - self.metadata_size() >= self.avail_space()
     pub fn metadata_size(&self) -> Sectors {
@@ -386,25 +717,88 @@ impl Recordable<Vec<BaseBlockDevSave>> for BlockDevMgr {
     }
 }
 
-/// Initialize multiple blockdevs at once. This allows all of them
-/// to be checked for usability before writing to any of them.
-fn initialize(
+/// Write metadata directly to the devices of a pool which is not set up,
+/// marking it with the given sequence number and the current time. This
+/// allows restoring the metadata of a pool whose blockdevs can not be
+/// set up, because the metadata on all of them is damaged. Only the static
+/// header of each device's BDA is read; its MDA regions, whose headers may
+/// be damaged, are initialized afresh before the metadata is written.
+/// Returns an error if any of the devices does not belong to the pool, or
+/// if the metadata could not be written to every device. All of the devices
+/// are checked before writing to any of them.
+pub fn restore_state(
     pool_uuid: PoolUuid,
-    devices: HashMap<Device, &Path>,
+    devnodes: &[&Path],
+    sequence: u64,
+    metadata: &[u8],
+) -> StratisResult<()> {
+    let mut files = Vec::new();
+    for devnode in devnodes {
+        let mut f = open_for_metadata(devnode)?;
+        match StaticHeader::device_identifiers(&mut f)? {
+            Some((uuid, _)) if uuid == pool_uuid => files.push((devnode, f)),
+            _ => {
+                let err_msg = format!(
+                    "device {} does not belong to pool with UUID {}",
+                    devnode.display(),
+                    pool_uuid
+                );
+                return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+            }
+        }
+    }
+
+    let stamp_time = Utc::now();
+    for (devnode, mut f) in files {
+        BDA::reinitialize(&mut f)
+            .and_then(|bda| {
+                bda.expect("device_identifiers() found a BDA").save_state(
+                    sequence,
+                    &stamp_time,
+                    metadata,
+                    &mut f,
+                )
+            })
+            .map_err(|err| {
+                let err_msg = format!(
+                    "failed to restore metadata to device {}: {:?}",
+                    devnode.display(),
+                    err
+                );
+                StratisError::Engine(ErrorEnum::Error, err_msg)
+            })?;
+    }
+    Ok(())
+}
+
+/// Check that each of the devices may be initialized as a blockdev of the
+/// pool, without writing to any of them. Return, for each device, its
+/// device node, its size, its zoned model, and an open File handle.
+#[allow(clippy::type_complexity)]
+fn check_devs<'a>(
+    pool_uuid: PoolUuid,
+    devices: HashMap<Device, &'a Path>,
     mda_size: Sectors,
     owned_devs: &HashSet<DevUuid>,
-) -> StratisResult<Vec<StratBlockDev>> {
+) -> StratisResult<Vec<(Device, (&'a Path, Bytes, ZonedModel, File))>> {
     /// Get device information, returns an error if problem with obtaining
     /// that information.
     /// Returns a tuple with the device's path, its size in bytes,
     /// its signature as determined by calling device::identify(),
-    /// and an open File handle, all of which are needed later.
-    fn dev_info(devnode: &Path) -> StratisResult<(&Path, Bytes, DevOwnership, File)> {
-        let f = OpenOptions::new().read(true).write(true).open(&devnode)?;
+    /// its zoned model, and an open File handle, all of which are needed
+    /// later.
+    #[allow(clippy::type_complexity)]
+    fn dev_info(
+        dev: Device,
+        devnode: &Path,
+    ) -> StratisResult<(&Path, Bytes, DevOwnership, ZonedModel, File)> {
+        let f = open_for_metadata(devnode)?;
         let dev_size = blkdev_size(&f)?;
         let ownership = identify(devnode)?;
+        validate_stack(dev)?;
+        let zoned = zoned_model(dev)?;
 
-        Ok((devnode, dev_size, ownership, f))
+        Ok((devnode, dev_size, ownership, zoned, f))
     }
 
     /// Filter devices for admission to pool based on dev_infos.
@@ -415,20 +809,40 @@ fn initialize(
         dev_infos: I,
         pool_uuid: PoolUuid,
         owned_devs: &HashSet<DevUuid>,
-    ) -> StratisResult<Vec<(Device, (&'a Path, Bytes, File))>>
+    ) -> StratisResult<Vec<(Device, (&'a Path, Bytes, ZonedModel, File))>>
     where
-        I: Iterator<Item = (Device, StratisResult<(&'a Path, Bytes, DevOwnership, File)>)>,
+        I: Iterator<
+            Item = (
+                Device,
+                StratisResult<(&'a Path, Bytes, DevOwnership, ZonedModel, File)>,
+            ),
+        >,
     {
         let mut add_devs = Vec::new();
         for (dev, dev_result) in dev_infos {
-            let (devnode, dev_size, ownership, f) = dev_result?;
-            if dev_size < MIN_DEV_SIZE {
+            let (devnode, dev_size, ownership, zoned, f) = dev_result?;
+            let min_dev_size = min_dev_size();
+            if dev_size < min_dev_size {
                 let error_message =
-                    format!("{} too small, minimum {}", devnode.display(), MIN_DEV_SIZE);
+                    format!("{} too small, minimum {}", devnode.display(), min_dev_size);
                 return Err(StratisError::Engine(ErrorEnum::Invalid, error_message));
             };
+            // The metadata areas are rewritten in place and the thin pool
+            // writes randomly to its data and metadata devices; the
+            // sequential write requirement of a host-managed zoned device
+            // can not be satisfied.
+            if zoned == ZonedModel::HostManaged {
+                let error_message = format!(
+                    "{} is a host-managed zoned device, which Stratis does not support",
+                    devnode.display()
+                );
+                return Err(StratisError::Engine(ErrorEnum::Invalid, error_message));
+            }
             match ownership {
-                DevOwnership::Unowned => add_devs.push((dev, (devnode, dev_size, f))),
+                DevOwnership::Unowned => {
+                    verify_not_in_use(dev, devnode)?;
+                    add_devs.push((dev, (devnode, dev_size, zoned, f)))
+                }
                 DevOwnership::Theirs(signature) => {
                     let err_str = format!(
                         "Device {} has an existing signature {}",
@@ -462,38 +876,61 @@ fn initialize(
 
     validate_mda_size(mda_size)?;
 
-    let dev_infos = devices.into_iter().map(|(d, p)| (d, dev_info(p)));
+    let dev_infos = devices.into_iter().map(|(d, p)| (d, dev_info(d, p)));
 
-    let add_devs = filter_devs(dev_infos, pool_uuid, owned_devs)?;
+    filter_devs(dev_infos, pool_uuid, owned_devs)
+}
+
+/// Initialize multiple blockdevs at once. This allows all of them
+/// to be checked for usability before writing to any of them.
+fn initialize(
+    pool_uuid: PoolUuid,
+    devices: HashMap<Device, &Path>,
+    mda_size: Sectors,
+    owned_devs: &HashSet<DevUuid>,
+) -> StratisResult<Vec<StratBlockDev>> {
+    let add_devs = check_devs(pool_uuid, devices, mda_size, owned_devs)?;
 
     let mut bds: Vec<StratBlockDev> = Vec::new();
-    for (dev, (devnode, dev_size, mut f)) in add_devs {
-        let bda = BDA::initialize(
+    for (dev, (devnode, dev_size, zoned, mut f)) in add_devs {
+        let blockdev = BDA::initialize(
             &mut f,
             pool_uuid,
             Uuid::new_v4(),
             mda_size,
             dev_size.sectors(),
             Utc::now().timestamp() as u64,
-        );
-        if let Ok(bda) = bda {
+        )
+        .and_then(|bda| {
             let hw_id = match hw_lookup(devnode) {
                 Ok(id) => id,
                 Err(_) => None, // TODO: Log this failure so that it can be addressed.
             };
 
-            // FIXME: The expect is only provisionally true.
-            // The dev_size is at least MIN_DEV_SIZE, but the size of the
-            // metadata is not really bounded from above.
-            let blockdev = StratBlockDev::new(dev, devnode.to_owned(), bda, &[], None, hw_id)
-                .expect("bda.size() == dev_size; only allocating space for metadata");
-            bds.push(blockdev);
-        } else {
-            // TODO: check the return values and update state machine on failure
-            let _ = BDA::wipe(&mut f);
-            let _ = wipe_blockdevs(&bds);
-
-            return Err(bda.unwrap_err());
+            StratBlockDev::new(dev, devnode.to_owned(), bda, &[], None, hw_id, zoned)
+        });
+
+        match blockdev {
+            Ok(blockdev) => bds.push(blockdev),
+            Err(err) => {
+                // Wipe the device that failed, which may have been partially
+                // written, and all the devices initialized so far, so that
+                // no device is left claimed by a pool that was never made.
+                let wipe_current = BDA::wipe(&mut f);
+                let wipe_previous = wipe_blockdevs(&bds);
+                return Err(match (wipe_current, wipe_previous) {
+                    (Ok(_), Ok(_)) => err,
+                    (Err(wipe_err), _) | (_, Err(wipe_err)) => {
+                        let err_msg = format!(
+                            "Failed to initialize device {}: {}; failed to roll back initialization: {}",
+                            devnode.display(),
+                            err,
+                            wipe_err
+                        );
+                        StratisError::Engine(ErrorEnum::Error, err_msg)
+                    }
+                });
+            }
         }
     }
     Ok(bds)
@@ -501,20 +938,409 @@ fn initialize(
 
 #[cfg(test)]
 mod tests {
+    #![cfg_attr(not(feature = "device_tests"), allow(dead_code, unused_imports))]
+
     use std::fs::OpenOptions;
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+    use std::os::unix::fs::OpenOptionsExt;
+
+    use libc;
 
     use rand;
     use uuid::Uuid;
 
-    use crate::engine::strat_engine::backstore::{find_all, get_metadata, MIN_MDA_SECTORS};
+    use crate::engine::strat_engine::backstore::{
+        find_all, get_last_sequence, get_metadata, MIN_MDA_SECTORS,
+    };
     use crate::engine::strat_engine::cmd;
     use crate::engine::strat_engine::device::wipe_sectors;
     use crate::engine::strat_engine::tests::{loopbacked, real};
 
-    use crate::engine::strat_engine::backstore::metadata::StaticHeader;
+    use devicemapper::{devnode_to_devno, DmDevice, DmName, DmUuid, LinearDev};
+
+    use crate::engine::strat_engine::dm::get_dm;
 
     use super::*;
 
+    #[test]
+    /// Verify that the minimum device size can not be set so low that Stratis
+    /// metadata would not fit, and that a rejected value leaves the minimum
+    /// unchanged.
+    fn test_set_min_dev_size_lower_bound() {
+        let current = min_dev_size();
+        assert_matches!(
+            set_min_dev_size(MIN_DEV_SIZE_LOWER_BOUND - Bytes(1)),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+        assert_eq!(min_dev_size(), current);
+    }
+
+    #[test]
+    /// Verify that metadata redundancy can not be set to 0.
+    fn test_set_metadata_redundancy_zero() {
+        let current = metadata_redundancy();
+        assert_matches!(
+            set_metadata_redundancy(0),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+        assert_eq!(metadata_redundancy(), current);
+    }
+
+    #[test]
+    /// Verify that preferred candidates are chosen first, that candidates in
+    /// distinct failure domains are chosen before candidates in a domain
+    /// already chosen, and that no more than the number requested are chosen.
+    fn test_choose_diverse() {
+        // (id, failure domain, preferred)
+        let candidates = vec![
+            (0, "a", false),
+            (1, "a", false),
+            (2, "b", false),
+            (3, "b", true),
+            (4, "c", false),
+        ];
+
+        let chosen = choose_diverse(candidates.clone(), |c| c.1, |c| c.2, 3);
+        assert_eq!(chosen.len(), 3);
+        assert_eq!(chosen[0].0, 3);
+        let mut domains = chosen.iter().map(|c| c.1).collect::<Vec<_>>();
+        domains.sort();
+        assert_eq!(domains, vec!["a", "b", "c"]);
+
+        let chosen = choose_diverse(candidates.clone(), |c| c.1, |c| c.2, 10);
+        assert_eq!(chosen.len(), candidates.len());
+
+        assert!(choose_diverse(candidates, |c| c.1, |c| c.2, 0).is_empty());
+    }
+
+    #[test]
+    /// Verify that preferred candidates which share a failure domain are
+    /// still chosen before a candidate in a domain not yet chosen, and that
+    /// the remaining candidates are chosen from domains not yet chosen first.
+    fn test_choose_diverse_preferred_same_domain() {
+        // (id, failure domain, preferred)
+        let candidates = vec![
+            (0, "a", true),
+            (1, "a", true),
+            (2, "a", false),
+            (3, "b", false),
+        ];
+
+        let chosen = choose_diverse(candidates.clone(), |c| c.1, |c| c.2, 2);
+        let mut ids = chosen.iter().map(|c| c.0).collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(ids, vec![0, 1]);
+
+        let chosen = choose_diverse(candidates, |c| c.1, |c| c.2, 3);
+        assert_eq!(chosen[2].0, 3);
+    }
+
+    /// Make a linear DM device mapping the given range of the given device.
+    /// The name begins with "stratis-1" so that the test cleanup code will
+    /// remove the device if the test fails.
+    fn make_linear_dev(
+        devnode: &Path,
+        start: Sectors,
+        length: Sectors,
+        dm_uuid: Option<&DmUuid>,
+    ) -> LinearDev {
+        let params = LinearTargetParams::new(
+            Device::from(devnode_to_devno(devnode).unwrap().unwrap()),
+            start,
+        );
+        let table = vec![TargetLine::new(
+            Sectors(0),
+            length,
+            LinearDevTargetParams::Linear(params),
+        )];
+        LinearDev::setup(
+            get_dm(),
+            DmName::new(&format!(
+                "stratis-1-test-{}",
+                Uuid::new_v4().to_simple_ref()
+            ))
+            .expect("valid format"),
+            dm_uuid,
+            table,
+        )
+        .unwrap()
+    }
+
+    /// Verify that a DM device built on a Stratis pool member, or a DM
+    /// device made by Stratis, can not be used as a pool member, but that
+    /// a DM device built on an ordinary device passes the stacking checks.
+    fn test_stacking(paths: &[&Path]) {
+        let bd_mgr = BlockDevMgr::initialize(Uuid::new_v4(), &paths[..1], MIN_MDA_SECTORS).unwrap();
+        cmd::udev_settle().unwrap();
+
+        let (start, length) = {
+            let bd = bd_mgr.blockdevs()[0].1;
+            (bd.metadata_size(), bd.available())
+        };
+
+        let mut on_member = make_linear_dev(paths[0], start, length, None);
+        cmd::udev_settle().unwrap();
+        assert_matches!(
+            BlockDevMgr::initialize(Uuid::new_v4(), &[&on_member.devnode()], MIN_MDA_SECTORS),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+        on_member.teardown(get_dm()).unwrap();
+
+        let uuid = format!("stratis-1-test-{}", Uuid::new_v4().to_simple_ref());
+        let mut stratis_made = make_linear_dev(
+            paths[1],
+            Sectors(0),
+            Bytes(IEC::Mi).sectors(),
+            Some(DmUuid::new(&uuid).expect("valid format")),
+        );
+        cmd::udev_settle().unwrap();
+        assert_matches!(
+            validate_stack(stratis_made.device()),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+        stratis_made.teardown(get_dm()).unwrap();
+
+        let mut ordinary = make_linear_dev(paths[1], Sectors(0), Bytes(IEC::Mi).sectors(), None);
+        cmd::udev_settle().unwrap();
+        assert!(validate_stack(ordinary.device()).is_ok());
+        ordinary.teardown(get_dm()).unwrap();
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_stacking() {
+        loopbacked::test_with_spec(&loopbacked::DeviceLimits::Exactly(2, None), test_stacking);
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_stacking() {
+        real::test_with_spec(&real::DeviceLimits::Exactly(2, None, None), test_stacking);
+    }
+
+    /// Verify that if initialization fails after some Stratis metadata has
+    /// been written, no device is left with a Stratis signature.
+    /// The MDA size requested is larger than the devices, so writing the
+    /// MDA regions fails after the static header has already been written.
+    fn test_initialize_rollback(paths: &[&Path]) {
+        let mda_size = Bytes(2 * IEC::Gi).sectors();
+        assert!(BlockDevMgr::initialize(Uuid::new_v4(), paths, mda_size).is_err());
+        cmd::udev_settle().unwrap();
+
+        for path in paths {
+            assert_matches!(identify(path).unwrap(), DevOwnership::Unowned);
+        }
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_initialize_rollback() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Range(1, 3, None),
+            test_initialize_rollback,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_initialize_rollback() {
+        real::test_with_spec(
+            &real::DeviceLimits::AtLeast(1, None, Some(Bytes(IEC::Gi).sectors())),
+            test_initialize_rollback,
+        );
+    }
+
+    /// Verify that once initialization has returned, the static headers of
+    /// all the blockdevs can be read from the devices themselves, using
+    /// O_DIRECT so that the page cache is bypassed.
+    /// Verify that once the manager is torn down and its blockdevs found
+    /// again, the saved state is read back from the devices the same way.
+    fn test_durable_sigblock(paths: &[&Path]) {
+        // O_DIRECT requires a buffer aligned to the logical block size.
+        #[repr(align(4096))]
+        struct AlignedBuf([u8; 16 * 512]);
+
+        // Read the first sectors of devnode, bypassing the page cache.
+        fn read_direct(devnode: &Path, sectors: Sectors) -> Vec<u8> {
+            let mut f = OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_DIRECT)
+                .open(devnode)
+                .unwrap();
+            let mut buf = AlignedBuf([0u8; 16 * 512]);
+            let mut data = Vec::new();
+            for _ in 0..*sectors / 16 {
+                f.read_exact(&mut buf.0).unwrap();
+                data.extend_from_slice(&buf.0);
+            }
+            data
+        }
+
+        let pool_uuid = Uuid::new_v4();
+        let mut mgr = BlockDevMgr::initialize(pool_uuid, paths, MIN_MDA_SECTORS).unwrap();
+
+        for (dev_uuid, bd) in mgr.blockdevs() {
+            let buf = read_direct(&bd.devnode, Sectors(16));
+            assert_eq!(
+                StaticHeader::device_identifiers(&mut Cursor::new(buf)).unwrap(),
+                Some((pool_uuid, dev_uuid))
+            );
+        }
+
+        mgr.save_state(b"durable").unwrap();
+        drop(mgr);
+
+        cmd::udev_settle().unwrap();
+        let devices = find_all().unwrap().0.remove(&pool_uuid).unwrap();
+        assert_eq!(devices.len(), paths.len());
+        for (_, devnode) in devices.values() {
+            let mut buf = Cursor::new(read_direct(devnode, Sectors(16) + MIN_MDA_SECTORS));
+            let bda = BDA::load(&mut buf).unwrap().unwrap();
+            assert_eq!(bda.pool_uuid(), pool_uuid);
+            assert_eq!(bda.load_state(&mut buf).unwrap(), Some(b"durable".to_vec()));
+        }
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_durable_sigblock() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Range(1, 3, None),
+            test_durable_sigblock,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_durable_sigblock() {
+        real::test_with_spec(
+            &real::DeviceLimits::AtLeast(1, None, None),
+            test_durable_sigblock,
+        );
+    }
+
+    /// Verify that a blockdev that missed a metadata update while it was
+    /// offline for maintenance is brought up to date by
+    /// resync_stale_blockdevs(), and that the other blockdevs, which are
+    /// already up to date, are not rewritten.
+    /// Verify that after the resync the most recent metadata is found.
+    fn test_resync_stale_blockdevs(paths: &[&Path]) {
+        let pool_uuid = Uuid::new_v4();
+        let mut mgr = BlockDevMgr::initialize(pool_uuid, paths, MIN_MDA_SECTORS).unwrap();
+        mgr.save_state(b"old").unwrap();
+
+        let stale_uuid = mgr.blockdevs()[0].0;
+        mgr.get_mut_blockdev_by_uuid(stale_uuid)
+            .unwrap()
+            .set_maintenance(true);
+        mgr.save_state(b"new").unwrap();
+        mgr.get_mut_blockdev_by_uuid(stale_uuid)
+            .unwrap()
+            .set_maintenance(false);
+
+        let stale_count = mgr
+            .blockdevs()
+            .iter()
+            .filter(|(_, bd)| bd.last_sequence() < mgr.last_sequence)
+            .count();
+        assert!(stale_count >= 1);
+
+        let resynced = mgr.resync_stale_blockdevs(b"new");
+        assert_eq!(resynced.len(), stale_count);
+        assert!(resynced.contains(&stale_uuid));
+        assert!(mgr.resync_stale_blockdevs(b"new").is_empty());
+
+        assert!(mgr
+            .blockdevs()
+            .iter()
+            .all(|(_, bd)| bd.last_sequence() == mgr.last_sequence));
+
+        let mut f = OpenOptions::new()
+            .read(true)
+            .open(&mgr.get_blockdev_by_uuid(stale_uuid).unwrap().devnode)
+            .unwrap();
+        let bda = BDA::load(&mut f).unwrap().unwrap();
+        assert_eq!(bda.load_state(&mut f).unwrap(), Some(b"new".to_vec()));
+
+        mgr.destroy_all().unwrap();
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_resync_stale_blockdevs() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Range(1, 3, None),
+            test_resync_stale_blockdevs,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_resync_stale_blockdevs() {
+        real::test_with_spec(
+            &real::DeviceLimits::AtLeast(1, None, None),
+            test_resync_stale_blockdevs,
+        );
+    }
+
+    /// Verify that metadata can be restored to devices on which the headers
+    /// of all the MDA regions are damaged, so that no BDA can be loaded, and
+    /// that the restored metadata is then found.
+    fn test_restore_state_damaged_mdas(paths: &[&Path]) {
+        let pool_uuid = Uuid::new_v4();
+        let mut mgr = BlockDevMgr::initialize(pool_uuid, paths, MIN_MDA_SECTORS).unwrap();
+        mgr.save_state(b"first").unwrap();
+        mgr.save_state(b"second").unwrap();
+
+        for path in paths {
+            let mut f = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(path)
+                .unwrap();
+            for offset in BDA::mda_header_offsets(MIN_MDA_SECTORS) {
+                // A byte covered by the region header's CRC.
+                f.seek(SeekFrom::Start(offset + 8)).unwrap();
+                f.write_all(&[0xff]).unwrap();
+            }
+            f.sync_all().unwrap();
+            assert!(BDA::load(&mut f).is_err());
+        }
+
+        cmd::udev_settle().unwrap();
+        let devices = find_all().unwrap().0.remove(&pool_uuid).unwrap();
+        assert_eq!(get_last_sequence(pool_uuid, &devices).unwrap(), None);
+
+        restore_state(pool_uuid, paths, 5, b"restored").unwrap();
+        for path in paths {
+            let mut f = OpenOptions::new().read(true).open(path).unwrap();
+            let bda = BDA::load(&mut f).unwrap().unwrap();
+            assert_eq!(bda.last_sequence(), Some(5));
+            assert_eq!(bda.load_state(&mut f).unwrap(), Some(b"restored".to_vec()));
+        }
+        assert_eq!(get_last_sequence(pool_uuid, &devices).unwrap(), Some(5));
+
+        mgr.destroy_all().unwrap();
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_restore_state_damaged_mdas() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Range(1, 3, None),
+            test_restore_state_damaged_mdas,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_restore_state_damaged_mdas() {
+        real::test_with_spec(
+            &real::DeviceLimits::AtLeast(1, None, None),
+            test_restore_state_damaged_mdas,
+        );
+    }
+
     /// Verify that initially,
     /// size() - metadata_size() = avail_space().
     /// After 2 Sectors have been allocated, that amount must also be included
@@ -524,13 +1350,15 @@ mod tests {
         assert_eq!(mgr.avail_space() + mgr.metadata_size(), mgr.size());
 
         let allocated = Sectors(2);
-        mgr.alloc_space(&[allocated]).unwrap();
+        mgr.alloc_space(&[allocated], AllocationStrategy::FillFirst)
+            .unwrap();
         assert_eq!(
             mgr.avail_space() + allocated + mgr.metadata_size(),
             mgr.size()
         );
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn loop_test_blockdevmgr_used() {
         loopbacked::test_with_spec(
@@ -539,6 +1367,7 @@ mod tests {
         );
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn real_test_blockdevmgr_used() {
         real::test_with_spec(
@@ -555,6 +1384,94 @@ mod tests {
         );
     }
 
+    /// Verify that with the fill-first strategy successive small allocations
+    /// come from the same blockdev, while with the round-robin strategy they
+    /// come from different blockdevs.
+    fn test_allocation_strategy(paths: &[&Path]) {
+        assert!(paths.len() > 1);
+        let mut mgr = BlockDevMgr::initialize(Uuid::new_v4(), paths, MIN_MDA_SECTORS).unwrap();
+
+        let parents = |mgr: &mut BlockDevMgr, strategy| {
+            mgr.alloc_space(&[Sectors(2), Sectors(2)], strategy)
+                .unwrap()
+                .iter()
+                .map(|segs| {
+                    assert_eq!(segs.len(), 1);
+                    segs[0].uuid
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let fill_first = parents(&mut mgr, AllocationStrategy::FillFirst);
+        assert_eq!(fill_first[0], fill_first[1]);
+
+        let round_robin = parents(&mut mgr, AllocationStrategy::RoundRobin);
+        assert_ne!(round_robin[0], round_robin[1]);
+
+        mgr.destroy_all().unwrap();
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_allocation_strategy() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Range(2, 3, None),
+            test_allocation_strategy,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_allocation_strategy() {
+        real::test_with_spec(
+            &real::DeviceLimits::AtLeast(2, None, None),
+            test_allocation_strategy,
+        );
+    }
+
+    /// Verify that space for metadata comes from a non-rotational blockdev
+    /// if there is one, and otherwise from the same blockdev as fill-first
+    /// allocation would use.
+    fn test_alloc_metadata_space(paths: &[&Path]) {
+        let mut mgr = BlockDevMgr::initialize(Uuid::new_v4(), paths, MIN_MDA_SECTORS).unwrap();
+
+        let fast_devs = mgr
+            .block_devs
+            .iter()
+            .filter(|bd| is_rotational(*bd.device()).unwrap() == Some(false))
+            .map(|bd| bd.uuid())
+            .collect::<Vec<_>>();
+        let first_dev = mgr.block_devs[0].uuid();
+
+        let segs = mgr.alloc_metadata_space(&[Sectors(2)]).unwrap();
+        assert_eq!(segs[0].len(), 1);
+        if fast_devs.is_empty() {
+            assert_eq!(segs[0][0].uuid, first_dev);
+        } else {
+            assert!(fast_devs.contains(&segs[0][0].uuid));
+        }
+
+        mgr.destroy_all().unwrap();
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_alloc_metadata_space() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Range(1, 3, None),
+            test_alloc_metadata_space,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_alloc_metadata_space() {
+        real::test_with_spec(
+            &real::DeviceLimits::AtLeast(1, None, None),
+            test_alloc_metadata_space,
+        );
+    }
+
     /// Verify that it is impossible to initialize a set of disks of which
     /// even one of them has a signature.  Choose the dirty disk randomly.
     fn test_fail_single_signature(paths: &[&Path]) {
@@ -593,6 +1510,7 @@ mod tests {
         }
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn loop_test_fail_single_signature() {
         loopbacked::test_with_spec(
@@ -601,6 +1519,7 @@ mod tests {
         );
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn real_test_fail_single_signature() {
         real::test_with_spec(
@@ -644,6 +1563,7 @@ mod tests {
         assert!(bd_mgr.add(uuid, paths2).is_err());
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn loop_test_initialization_stratis() {
         loopbacked::test_with_spec(
@@ -652,6 +1572,7 @@ mod tests {
         );
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn real_test_initialization_stratis() {
         real::test_with_spec(
@@ -687,7 +1608,7 @@ mod tests {
         BlockDevMgr::initialize(uuid1, paths1, MIN_MDA_SECTORS).unwrap();
 
         cmd::udev_settle().unwrap();
-        let pools = find_all().unwrap();
+        let (pools, _) = find_all().unwrap();
         assert_eq!(pools.len(), 1);
         assert!(pools.contains_key(&uuid1));
         let devices = pools.get(&uuid1).expect("pools.contains_key() was true");
@@ -697,7 +1618,7 @@ mod tests {
         BlockDevMgr::initialize(uuid2, paths2, MIN_MDA_SECTORS).unwrap();
 
         cmd::udev_settle().unwrap();
-        let pools = find_all().unwrap();
+        let (pools, _) = find_all().unwrap();
         assert_eq!(pools.len(), 2);
 
         assert!(pools.contains_key(&uuid1));
@@ -714,6 +1635,7 @@ mod tests {
             .all(|x| x.unwrap().is_none()));
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn loop_test_initialize() {
         loopbacked::test_with_spec(
@@ -722,6 +1644,7 @@ mod tests {
         );
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn real_test_initialize() {
         real::test_with_spec(&real::DeviceLimits::AtLeast(2, None, None), test_initialize);
@@ -768,11 +1691,13 @@ mod tests {
         }
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn loop_test_ownership() {
         loopbacked::test_with_spec(&loopbacked::DeviceLimits::Range(1, 3, None), test_ownership);
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn real_test_ownership() {
         real::test_with_spec(&real::DeviceLimits::AtLeast(1, None, None), test_ownership);
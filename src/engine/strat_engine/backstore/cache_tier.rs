@@ -8,7 +8,7 @@ use std::path::Path;
 
 use devicemapper::{Sectors, IEC, SECTOR_SIZE};
 
-use crate::engine::{BlockDevTier, DevUuid, PoolUuid};
+use crate::engine::{AllocationStrategy, BlockDevTier, DevUuid, PoolUuid};
 use crate::stratis::{ErrorEnum, StratisError, StratisResult};
 
 use crate::engine::strat_engine::backstore::StratBlockDev;
@@ -133,7 +133,7 @@ impl CacheTier {
 
         let segments = self
             .block_mgr
-            .alloc_space(&[avail_space])
+            .alloc_space(&[avail_space], AllocationStrategy::FillFirst)
             .expect("asked for exactly the space available, must get")
             .iter()
             .flat_map(|s| s.iter())
@@ -176,7 +176,10 @@ impl CacheTier {
         }
 
         let mut segments = block_mgr
-            .alloc_space(&[meta_space, avail_space - meta_space])
+            .alloc_space(
+                &[meta_space, avail_space - meta_space],
+                AllocationStrategy::FillFirst,
+            )
             .expect("asked for exactly the space available, must get");
 
         let cache_segments = segments.pop().expect("segments.len() == 2");
@@ -234,6 +237,7 @@ impl Recordable<CacheTierSave> for CacheTier {
 
 #[cfg(test)]
 mod tests {
+    #![cfg_attr(not(feature = "device_tests"), allow(dead_code, unused_imports))]
 
     use uuid::Uuid;
 
@@ -296,11 +300,13 @@ mod tests {
         cache_tier.destroy().unwrap();
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn loop_cache_test_add() {
         loopbacked::test_with_spec(&loopbacked::DeviceLimits::Range(2, 3, None), cache_test_add);
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn real_cache_test_add() {
         real::test_with_spec(&real::DeviceLimits::AtLeast(2, None, None), cache_test_add);
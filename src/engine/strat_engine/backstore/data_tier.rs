@@ -5,15 +5,16 @@
 // Code to handle the backing store of a pool.
 
 use std::path::Path;
+use std::slice;
 
 use devicemapper::Sectors;
 
-use crate::engine::{BlockDevTier, DevUuid, PoolUuid};
+use crate::engine::{AllocationStrategy, BlockDevTier, DevUuid, PoolUuid};
 use crate::stratis::{ErrorEnum, StratisError, StratisResult};
 
 use crate::engine::strat_engine::backstore::StratBlockDev;
 use crate::engine::strat_engine::serde_structs::{
-    BaseDevSave, BlockDevSave, DataTierSave, Recordable,
+    AllocationSave, BaseDevSave, BlockDevSave, DataTierSave, Recordable,
 };
 
 use crate::engine::strat_engine::backstore::blockdevmgr::{
@@ -27,6 +28,21 @@ pub struct DataTier {
     pub block_mgr: BlockDevMgr,
     /// The list of segments granted by block_mgr and used by dm_device
     pub segments: Vec<BlkDevSegment>,
+    /// How new segments are allocated from block_mgr
+    allocation: AllocationStrategy,
+}
+
+/// The move of the segments mapped from one block device onto space
+/// allocated from the others, as planned by DataTier::plan_drain().
+#[derive(Debug)]
+pub struct Drain {
+    /// The UUID of the block device being drained
+    uuid: DevUuid,
+    /// Each segment mapped from the block device, in the order in which it
+    /// is mapped, with the segments allocated to replace it
+    pub moves: Vec<(BlkDevSegment, Vec<BlkDevSegment>)>,
+    /// The segments mapped when the drain was planned
+    previous: Vec<BlkDevSegment>,
 }
 
 impl DataTier {
@@ -52,9 +68,15 @@ impl DataTier {
             .map(&mapper)
             .collect::<StratisResult<Vec<_>>>()?;
 
+        let allocation = match data_tier_save.allocation {
+            None | Some(AllocationSave::FillFirst) => AllocationStrategy::FillFirst,
+            Some(AllocationSave::RoundRobin) => AllocationStrategy::RoundRobin,
+        };
+
         Ok(DataTier {
             block_mgr,
             segments,
+            allocation,
         })
     }
 
@@ -67,9 +89,22 @@ impl DataTier {
         DataTier {
             block_mgr,
             segments: vec![],
+            allocation: AllocationStrategy::FillFirst,
         }
     }
 
+    /// The strategy by which segments are allocated.
+    pub fn allocation_strategy(&self) -> AllocationStrategy {
+        self.allocation
+    }
+
+    /// Set the strategy by which segments are allocated. Segments that are
+    /// already allocated are not moved.
+    /// WARNING: metadata changing event
+    pub fn set_allocation_strategy(&mut self, strategy: AllocationStrategy) {
+        self.allocation = strategy;
+    }
+
     /// Add the given paths to self. Return UUIDs of the new blockdevs
     /// corresponding to the specified paths.
     /// WARNING: metadata changing event
@@ -81,7 +116,22 @@ impl DataTier {
     /// block devices belonging to the data tier. Return true if requested
     /// amount or more was allocated, otherwise, false.
     pub fn alloc(&mut self, request: Sectors) -> bool {
-        match self.block_mgr.alloc_space(&[request]) {
+        let segments = self.block_mgr.alloc_space(&[request], self.allocation);
+        self.add_segments(segments)
+    }
+
+    /// Allocate at least request sectors for thin pool metadata, preferring
+    /// non-rotational block devices. Return true if requested amount or more
+    /// was allocated, otherwise, false.
+    pub fn alloc_metadata(&mut self, request: Sectors) -> bool {
+        let segments = self.block_mgr.alloc_metadata_space(&[request]);
+        self.add_segments(segments)
+    }
+
+    /// Add newly allocated segments, if any, to the segments mapped to an
+    /// upper device. Return true if there were any.
+    fn add_segments(&mut self, segments: Option<Vec<Vec<BlkDevSegment>>>) -> bool {
+        match segments {
             Some(segments) => {
                 self.segments = coalesce_blkdevsegs(
                     &self.segments,
@@ -97,6 +147,93 @@ impl DataTier {
         }
     }
 
+    /// Allocate space on the other block devices of the tier for every
+    /// segment now mapped from the block device with the given UUID. Return
+    /// None if nothing is mapped from it. Return an error, and allocate
+    /// nothing, if the other block devices do not have sufficient space.
+    /// The drain returned must be passed to either finish_drain() or
+    /// abandon_drain().
+    pub fn plan_drain(&mut self, uuid: DevUuid) -> StratisResult<Option<Drain>> {
+        let draining = self
+            .segments
+            .iter()
+            .filter(|seg| seg.uuid == uuid)
+            .cloned()
+            .collect::<Vec<_>>();
+        if draining.is_empty() {
+            return Ok(None);
+        }
+
+        let sizes = draining
+            .iter()
+            .map(|seg| seg.segment.length)
+            .collect::<Vec<_>>();
+        match self
+            .block_mgr
+            .alloc_space_excluding(&sizes, self.allocation, uuid)
+        {
+            Some(replacements) => Ok(Some(Drain {
+                uuid,
+                moves: draining.into_iter().zip(replacements).collect(),
+                previous: self.segments.clone(),
+            })),
+            None => {
+                let err_msg = format!(
+                    "the other blockdevs in the data tier do not have space for the {} sectors allocated from blockdev {}",
+                    sizes.iter().cloned().sum::<Sectors>(),
+                    uuid
+                );
+                Err(StratisError::Engine(ErrorEnum::Invalid, err_msg))
+            }
+        }
+    }
+
+    /// Map the replacement segments of the drain in place of the segments
+    /// they replace. The replaced segments remain allocated, so that the
+    /// drain may still be abandoned.
+    /// Precondition: self.segments is unchanged since plan_drain().
+    /// WARNING: metadata changing event
+    pub fn map_drain(&mut self, drain: &Drain) {
+        let mut moves_iter = drain.moves.iter();
+        let mut segments = Vec::new();
+        for seg in &self.segments {
+            if seg.uuid == drain.uuid {
+                let (old, new) = moves_iter
+                    .next()
+                    .expect("plan_drain() returned a replacement for every segment");
+                assert_eq!(old.segment.start, seg.segment.start);
+                segments = coalesce_blkdevsegs(&segments, new);
+            } else {
+                segments = coalesce_blkdevsegs(&segments, slice::from_ref(seg));
+            }
+        }
+        self.segments = segments;
+    }
+
+    /// Release the segments replaced by the drain, once the mapping of its
+    /// replacement segments has been recorded in the metadata.
+    /// Precondition: map_drain() has been called with the drain.
+    pub fn finish_drain(&mut self, drain: Drain) {
+        let old = drain
+            .moves
+            .into_iter()
+            .map(|(old, _)| old)
+            .collect::<Vec<_>>();
+        self.block_mgr.release_space(&old);
+    }
+
+    /// Map the segments that the drain would have replaced again, if
+    /// map_drain() was called, and release the replacement segments.
+    pub fn abandon_drain(&mut self, drain: Drain) {
+        self.segments = drain.previous;
+        let new = drain
+            .moves
+            .into_iter()
+            .flat_map(|(_, new)| new)
+            .collect::<Vec<_>>();
+        self.block_mgr.release_space(&new);
+    }
+
     /// The sum of the lengths of all the sectors that have been mapped to an
     /// upper device.
     #[cfg(test)]
@@ -122,17 +259,35 @@ impl DataTier {
         self.size() - self.metadata_size()
     }
 
+    /// The largest optimal I/O size reported by any of the blockdevs.
+    pub fn optimal_io_size(&self) -> Option<Sectors> {
+        self.block_mgr.optimal_io_size()
+    }
+
     /// Destroy the store. Wipe its blockdevs.
     pub fn destroy(&mut self) -> StratisResult<()> {
         self.block_mgr.destroy_all()
     }
 
     /// Save the given state to the devices. This action bypasses the DM
-    /// device entirely.
-    pub fn save_state(&mut self, metadata: &[u8]) -> StratisResult<()> {
+    /// device entirely. Returns a warning if the state reached too few of
+    /// the devices.
+    pub fn save_state(&mut self, metadata: &[u8]) -> StratisResult<Option<String>> {
         self.block_mgr.save_state(metadata)
     }
 
+    /// Write the most recently saved state to the specified device, if it
+    /// does not already have it.
+    pub fn resync_blockdev(&mut self, uuid: DevUuid, metadata: &[u8]) -> StratisResult<()> {
+        self.block_mgr.resync_blockdev(uuid, metadata)
+    }
+
+    /// Write the most recently saved state to every device that does not
+    /// already have it.
+    pub fn resync_stale_blockdevs(&mut self, metadata: &[u8]) -> Vec<DevUuid> {
+        self.block_mgr.resync_stale_blockdevs(metadata)
+    }
+
     /// Lookup an immutable blockdev by its Stratis UUID.
     pub fn get_blockdev_by_uuid(&self, uuid: DevUuid) -> Option<(BlockDevTier, &StratBlockDev)> {
         self.block_mgr
@@ -167,12 +322,17 @@ impl Recordable<DataTierSave> for DataTier {
                 allocs: vec![self.segments.record()],
                 devs: self.block_mgr.record(),
             },
+            allocation: match self.allocation {
+                AllocationStrategy::FillFirst => None,
+                AllocationStrategy::RoundRobin => Some(AllocationSave::RoundRobin),
+            },
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    #![cfg_attr(not(feature = "device_tests"), allow(dead_code, unused_imports))]
 
     use uuid::Uuid;
 
@@ -230,6 +390,7 @@ mod tests {
         data_tier.destroy().unwrap();
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn loop_test_add_and_alloc() {
         loopbacked::test_with_spec(
@@ -238,6 +399,7 @@ mod tests {
         );
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn real_test_add_and_alloc() {
         real::test_with_spec(
@@ -5,9 +5,14 @@
 // Functions for dealing with devices.
 
 use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Read};
+use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::prelude::AsRawFd;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+
+use libc;
+use libmount;
 
 use devicemapper::{devnode_to_devno, Bytes, Device};
 
@@ -16,6 +21,7 @@ use crate::stratis::{ErrorEnum, StratisError, StratisResult};
 
 use crate::engine::strat_engine::backstore::metadata::StaticHeader;
 use crate::engine::strat_engine::backstore::util::get_udev_block_device;
+use crate::engine::strat_engine::names::is_stratis_dm_uuid;
 
 ioctl_read!(blkgetsize64, 0x12, 114, u64);
 
@@ -48,6 +54,281 @@ pub fn resolve_devices<'a>(paths: &'a [&Path]) -> StratisResult<HashMap<Device,
     Ok(map)
 }
 
+/// The zoned model of a block device, as reported by the kernel.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ZonedModel {
+    /// A conventional device, which accepts writes anywhere
+    None,
+    /// A zoned device which still accepts random writes, although they may
+    /// perform poorly
+    HostAware,
+    /// A zoned device which requires sequential writes within each zone
+    HostManaged,
+}
+
+impl ZonedModel {
+    /// Interpret the representation of a zoned model, as found in a device's
+    /// queue/zoned sysfs attribute or in Stratis metadata.
+    pub fn parse(value: &str) -> Option<ZonedModel> {
+        match value.trim() {
+            "none" => Some(ZonedModel::None),
+            "host-aware" => Some(ZonedModel::HostAware),
+            "host-managed" => Some(ZonedModel::HostManaged),
+            _ => None,
+        }
+    }
+
+    /// The representation of the model used by sysfs and in Stratis
+    /// metadata.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ZonedModel::None => "none",
+            ZonedModel::HostAware => "host-aware",
+            ZonedModel::HostManaged => "host-managed",
+        }
+    }
+}
+
+/// Read a sysfs attribute. Returns None if the attribute does not exist.
+fn read_sysfs_attr(path: &Path) -> StratisResult<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(value) => Ok(Some(value.trim().to_owned())),
+        Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Find the zoned model of a block device by consulting sysfs.
+/// A partition does not have its own queue attributes; those of the
+/// containing disk are used instead. If the kernel does not report a zoned
+/// model at all, it predates zoned block device support, and the device is
+/// assumed to be conventional.
+pub fn zoned_model(device: Device) -> StratisResult<ZonedModel> {
+    let sysfs_path: PathBuf = ["/sys/dev/block", &device.to_string()].iter().collect();
+    for attr_path in &[
+        sysfs_path.join("queue/zoned"),
+        sysfs_path.join("../queue/zoned"),
+    ] {
+        if let Some(value) = read_sysfs_attr(attr_path)? {
+            return ZonedModel::parse(&value).ok_or_else(|| {
+                StratisError::Engine(
+                    ErrorEnum::Invalid,
+                    format!(
+                        "Unrecognized zoned model \"{}\" for device {}",
+                        value, device
+                    ),
+                )
+            });
+        }
+    }
+    Ok(ZonedModel::None)
+}
+
+/// Find whether a block device is rotational by consulting sysfs. As with
+/// the zoned model, a partition takes the attribute of its containing disk.
+/// Returns None if the kernel does not report the attribute.
+pub fn is_rotational(device: Device) -> StratisResult<Option<bool>> {
+    let sysfs_path: PathBuf = ["/sys/dev/block", &device.to_string()].iter().collect();
+    for attr_path in &[
+        sysfs_path.join("queue/rotational"),
+        sysfs_path.join("../queue/rotational"),
+    ] {
+        if let Some(value) = read_sysfs_attr(attr_path)? {
+            return Ok(Some(value != "0"));
+        }
+    }
+    Ok(None)
+}
+
+/// Find the optimal I/O size of a block device by consulting sysfs. As with
+/// the zoned model, a partition takes the attribute of its containing disk.
+/// Returns None if the kernel does not report the attribute, or reports it
+/// as 0, which means that the device has no preferred I/O size.
+pub fn optimal_io_size(device: Device) -> StratisResult<Option<Bytes>> {
+    let sysfs_path: PathBuf = ["/sys/dev/block", &device.to_string()].iter().collect();
+    for attr_path in &[
+        sysfs_path.join("queue/optimal_io_size"),
+        sysfs_path.join("../queue/optimal_io_size"),
+    ] {
+        if let Some(value) = read_sysfs_attr(attr_path)? {
+            let size = value.parse::<u64>().map_err(|_| {
+                StratisError::Engine(
+                    ErrorEnum::Invalid,
+                    format!(
+                        "Unrecognized optimal I/O size \"{}\" for device {}",
+                        value, device
+                    ),
+                )
+            })?;
+            return Ok(if size == 0 { None } else { Some(Bytes(size)) });
+        }
+    }
+    Ok(None)
+}
+
+/// Verify that the device is not layered on top of devices in a way that
+/// makes it unsuitable as a pool member. A device is rejected if it, or any
+/// device that it is built on, is a device-mapper device created by Stratis,
+/// or if any device that it is built on is a member of a Stratis pool.
+/// Other layered devices, such as dm-crypt or multipath devices, are
+/// acceptable.
+pub fn validate_stack(device: Device) -> StratisResult<()> {
+    fn validate(device: Device, top: Device) -> StratisResult<()> {
+        let sysfs_path: PathBuf = ["/sys/dev/block", &device.to_string()].iter().collect();
+
+        if let Some(dm_uuid) = read_sysfs_attr(&sysfs_path.join("dm/uuid"))? {
+            if is_stratis_dm_uuid(&dm_uuid) {
+                let err_msg = format!(
+                    "Device {} is built on device-mapper device {}, which was created by Stratis; Stratis devices can not be used as pool members",
+                    top, device
+                );
+                return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+            }
+        }
+
+        let slaves = match fs::read_dir(sysfs_path.join("slaves")) {
+            Ok(slaves) => slaves,
+            Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+
+        for slave in slaves {
+            let slave = slave?;
+            let slave_dev = read_sysfs_attr(&slave.path().join("dev"))?
+                .and_then(|dev| dev.parse::<Device>().ok())
+                .ok_or_else(|| {
+                    StratisError::Engine(
+                        ErrorEnum::Error,
+                        format!(
+                            "Could not determine the device number of {}, on which device {} is built",
+                            slave.path().display(),
+                            top
+                        ),
+                    )
+                })?;
+
+            let slave_devnode = Path::new("/dev").join(slave.file_name());
+            if let Some((pool_uuid, _)) = StaticHeader::device_identifiers(
+                &mut OpenOptions::new().read(true).open(&slave_devnode)?,
+            )? {
+                let err_msg = format!(
+                    "Device {} is built on {}, which belongs to Stratis pool {}",
+                    top,
+                    slave_devnode.display(),
+                    pool_uuid
+                );
+                return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+            }
+
+            validate(slave_dev, top)?;
+        }
+        Ok(())
+    }
+
+    validate(device, device)
+}
+
+/// Get the paths of the swap areas listed in the contents of /proc/swaps.
+fn swap_paths(swaps: &str) -> Vec<PathBuf> {
+    swaps
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_whitespace().next())
+        .map(PathBuf::from)
+        .collect()
+}
+
+/// Verify that the device is not currently in use by the system.
+/// Returns an error if the device is mounted, is in use as swap, or has
+/// holders, i.e., some other device, like a device-mapper device or an md
+/// RAID array, is built on top of it. As a final check, returns an error if
+/// the device can not be opened exclusively, which catches any other
+/// exclusive claims on the device by the kernel.
+pub fn verify_not_in_use(device: Device, devnode: &Path) -> StratisResult<()> {
+    let mut mount_data = String::new();
+    File::open("/proc/self/mountinfo")?.read_to_string(&mut mount_data)?;
+    for mount in libmount::mountinfo::Parser::new(mount_data.as_bytes()) {
+        match mount {
+            Ok(mount) => {
+                if mount.major as u32 == device.major && mount.minor as u32 == device.minor {
+                    let err_msg = format!(
+                        "Device {} is mounted at {}; unmount it before adding it to a pool",
+                        devnode.display(),
+                        Path::new(&mount.mount_point).display()
+                    );
+                    return Err(StratisError::Engine(ErrorEnum::Busy, err_msg));
+                }
+            }
+            Err(err) => {
+                let err_msg = format!("Error while parsing mount information: {:?}", err);
+                return Err(StratisError::Engine(ErrorEnum::Error, err_msg));
+            }
+        }
+    }
+
+    for swap_path in swap_paths(&fs::read_to_string("/proc/swaps")?) {
+        if let Ok(Some(devno)) = devnode_to_devno(&swap_path) {
+            if Device::from(devno) == device {
+                let err_msg = format!(
+                    "Device {} is in use as swap; disable it with swapoff before adding it to a pool",
+                    devnode.display()
+                );
+                return Err(StratisError::Engine(ErrorEnum::Busy, err_msg));
+            }
+        }
+    }
+
+    let holders_path: PathBuf = ["/sys/dev/block", &device.to_string(), "holders"]
+        .iter()
+        .collect();
+    let holders = match fs::read_dir(holders_path) {
+        Ok(entries) => entries
+            .map(|entry| entry.map(|e| e.file_name().to_string_lossy().into_owned()))
+            .collect::<Result<Vec<_>, _>>()?,
+        Err(ref err) if err.kind() == ErrorKind::NotFound => Vec::new(),
+        Err(err) => return Err(err.into()),
+    };
+    if !holders.is_empty() {
+        let err_msg = format!(
+            "Device {} is in use by {}; remove those devices before adding it to a pool",
+            devnode.display(),
+            holders.join(", ")
+        );
+        return Err(StratisError::Engine(ErrorEnum::Busy, err_msg));
+    }
+
+    if let Err(err) = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_EXCL)
+        .open(devnode)
+    {
+        if err.raw_os_error() == Some(libc::EBUSY) {
+            let err_msg = format!(
+                "Device {} is in use by the system and can not be opened exclusively",
+                devnode.display()
+            );
+            return Err(StratisError::Engine(ErrorEnum::Busy, err_msg));
+        }
+        return Err(err.into());
+    }
+
+    Ok(())
+}
+
+/// Open a device for reading and writing Stratis metadata.
+/// The device is opened with O_DSYNC, so that each write reaches stable
+/// storage, bypassing any volatile write cache on the device, before the
+/// write returns. Consequently, metadata written in separate writes, e.g.,
+/// the two copies of the static header, is persisted in the order in which
+/// it was written, even if the system loses power between the writes.
+pub fn open_for_metadata(devnode: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_DSYNC)
+        .open(devnode)
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum DevOwnership {
     Ours(PoolUuid, DevUuid),
@@ -138,8 +419,13 @@ pub fn is_stratis_device(devnode: &Path) -> StratisResult<Option<(PoolUuid, DevU
 
 #[cfg(test)]
 mod test {
+    #![cfg_attr(not(feature = "device_tests"), allow(dead_code, unused_imports))]
+
     use std::path::Path;
 
+    use nix::mount::{mount, umount, MsFlags};
+    use tempfile;
+
     use crate::engine::strat_engine::cmd;
     use crate::engine::strat_engine::tests::{loopbacked, real};
 
@@ -171,6 +457,151 @@ mod test {
         assert_eq!(is_stratis_device(paths[0]).unwrap(), None);
     }
 
+    /// Verify that a device which is not zoned is reported as conventional.
+    fn test_zoned_model(paths: &[&Path]) {
+        let devices = resolve_devices(paths).unwrap();
+        for device in devices.keys() {
+            assert_eq!(zoned_model(*device).unwrap(), ZonedModel::None);
+        }
+    }
+
+    /// Verify that the kernel reports whether each device is rotational.
+    fn test_is_rotational(paths: &[&Path]) {
+        let devices = resolve_devices(paths).unwrap();
+        for device in devices.keys() {
+            assert!(is_rotational(*device).unwrap().is_some());
+        }
+    }
+
+    /// Verify that the optimal I/O size of each device, if it reports one,
+    /// is a whole number of sectors.
+    fn test_optimal_io_size(paths: &[&Path]) {
+        let devices = resolve_devices(paths).unwrap();
+        for device in devices.keys() {
+            if let Some(size) = optimal_io_size(*device).unwrap() {
+                assert_eq!(size.sectors().bytes(), size);
+            }
+        }
+    }
+
+    /// Verify that a device is not in use before it is mounted, but is in use
+    /// once it is mounted.
+    fn test_in_use(paths: &[&Path]) {
+        let devices = resolve_devices(paths).unwrap();
+        let (device, devnode) = devices.iter().next().unwrap();
+        assert!(verify_not_in_use(*device, devnode).is_ok());
+
+        cmd::create_ext3_fs(devnode).unwrap();
+        cmd::udev_settle().unwrap();
+
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("stratis_testing")
+            .tempdir()
+            .unwrap();
+        mount(
+            Some(*devnode),
+            tmp_dir.path(),
+            Some("ext3"),
+            MsFlags::empty(),
+            None as Option<&str>,
+        )
+        .unwrap();
+        assert_matches!(
+            verify_not_in_use(*device, devnode),
+            Err(StratisError::Engine(ErrorEnum::Busy, _))
+        );
+        umount(tmp_dir.path()).unwrap();
+    }
+
+    #[test]
+    /// Verify that the paths of swap areas are found in /proc/swaps format.
+    fn test_swap_paths() {
+        let swaps = "Filename\t\t\t\tType\t\tSize\tUsed\tPriority\n\
+                     /dev/sda2                               partition\t8388604\t0\t-2\n\
+                     /swapfile                               file\t\t1048572\t0\t-3\n";
+        assert_eq!(
+            swap_paths(swaps),
+            vec![PathBuf::from("/dev/sda2"), PathBuf::from("/swapfile")]
+        );
+        assert!(swap_paths("Filename\tType\tSize\tUsed\tPriority\n").is_empty());
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_in_use() {
+        loopbacked::test_with_spec(&loopbacked::DeviceLimits::Exactly(1, None), test_in_use);
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_in_use() {
+        real::test_with_spec(&real::DeviceLimits::Exactly(1, None, None), test_in_use);
+    }
+
+    #[test]
+    /// Verify that every zoned model is parsed from its own representation
+    /// and that unknown values are rejected.
+    fn test_zoned_model_parse() {
+        for zoned in &[
+            ZonedModel::None,
+            ZonedModel::HostAware,
+            ZonedModel::HostManaged,
+        ] {
+            assert_eq!(ZonedModel::parse(zoned.as_str()), Some(*zoned));
+        }
+        assert_eq!(
+            ZonedModel::parse("host-managed\n"),
+            Some(ZonedModel::HostManaged)
+        );
+        assert_eq!(ZonedModel::parse("drive-managed"), None);
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_zoned_model() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Range(1, 3, None),
+            test_zoned_model,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_is_rotational() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Range(1, 3, None),
+            test_is_rotational,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_is_rotational() {
+        real::test_with_spec(
+            &real::DeviceLimits::AtLeast(1, None, None),
+            test_is_rotational,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_optimal_io_size() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Range(1, 3, None),
+            test_optimal_io_size,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_optimal_io_size() {
+        real::test_with_spec(
+            &real::DeviceLimits::AtLeast(1, None, None),
+            test_optimal_io_size,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn loop_test_device_other_ownership() {
         loopbacked::test_with_spec(
@@ -179,6 +610,7 @@ mod test {
         );
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn real_test_device_other_ownership() {
         real::test_with_spec(
@@ -187,11 +619,13 @@ mod test {
         );
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn loop_test_device_empty() {
         loopbacked::test_with_spec(&loopbacked::DeviceLimits::Range(1, 3, None), test_empty);
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn real_test_device_empty() {
         real::test_with_spec(&real::DeviceLimits::AtLeast(1, None, None), test_empty);
@@ -29,6 +29,16 @@ const STRAT_MAGIC: &[u8] = b"!Stra0tis\x86\xff\x02^\x41rh";
 
 const STRAT_SIGBLOCK_VERSION: u8 = 1;
 
+// Feature masks in the sigblock, following ext4 and XFS practice.
+// A compat feature may be ignored by a daemon that does not know of it.
+// An ro-compat feature may be ignored only by a daemon that does not write
+// to the device; since stratisd always writes metadata, it treats these
+// like incompat features. A daemon must not use a device which has an
+// incompat feature it does not know of.
+const SUPPORTED_COMPAT_FEATURES: u64 = 0;
+const SUPPORTED_RO_COMPAT_FEATURES: u64 = 0;
+const SUPPORTED_INCOMPAT_FEATURES: u64 = 0;
+
 #[derive(Debug)]
 pub struct BDA {
     header: StaticHeader,
@@ -150,11 +160,61 @@ impl BDA {
             None => return Ok(None),
         };
 
+        header.check_features()?;
+
         let regions = mda::MDARegions::load(BDA_STATIC_HDR_SIZE, header.mda_size, f)?;
 
         Ok(Some(BDA { header, regions }))
     }
 
+    /// Load the static header of a BDA, and initialize its MDA regions
+    /// afresh, discarding whatever metadata they held. Unlike load(), this
+    /// succeeds even if the MDA region headers are damaged, so that
+    /// metadata can be restored to a device on which they all are.
+    /// Returns None if no BDA appears to exist.
+    pub fn reinitialize<F>(f: &mut F) -> StratisResult<Option<BDA>>
+    where
+        F: Read + Seek + SyncAll,
+    {
+        let header = match StaticHeader::setup(f)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+
+        header.check_features()?;
+
+        let regions = mda::MDARegions::initialize(BDA_STATIC_HDR_SIZE, header.mda_size, f)?;
+
+        Ok(Some(BDA { header, regions }))
+    }
+
+    /// Read the static header of a BDA, and the greatest sequence number
+    /// among those MDA region headers that can be read. Unlike load(),
+    /// this succeeds even if the MDA region headers are damaged.
+    /// Returns the UUID of the device's pool and the sequence number, if
+    /// any, or None if no BDA appears to exist.
+    pub fn readable_last_sequence<F>(f: &mut F) -> StratisResult<Option<(PoolUuid, Option<u64>)>>
+    where
+        F: Read + Seek + SyncAll,
+    {
+        let header = match StaticHeader::setup(f)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+
+        let sequence =
+            mda::MDARegions::readable_last_sequence(BDA_STATIC_HDR_SIZE, header.mda_size, f);
+
+        Ok(Some((header.pool_uuid, sequence)))
+    }
+
+    /// The offsets from the start of the device of the MDA region headers
+    /// of a BDA with the given MDA size.
+    #[cfg(test)]
+    pub fn mda_header_offsets(mda_size: Sectors) -> Vec<u64> {
+        mda::MDARegions::header_offsets(BDA_STATIC_HDR_SIZE, mda_size)
+    }
+
     /// Zero out Static Header on the blockdev. This causes it to no
     /// longer be seen as a Stratis blockdev.
     pub fn wipe<F>(f: &mut F) -> StratisResult<()>
@@ -173,6 +233,7 @@ impl BDA {
     /// Save metadata to the disk
     pub fn save_state<F>(
         &mut self,
+        sequence: u64,
         time: &DateTime<Utc>,
         metadata: &[u8],
         f: &mut F,
@@ -181,7 +242,7 @@ impl BDA {
         F: Seek + SyncAll,
     {
         self.regions
-            .save_state(BDA_STATIC_HDR_SIZE, time, metadata, f)
+            .save_state(BDA_STATIC_HDR_SIZE, sequence, time, metadata, f)
     }
 
     /// Read latest metadata from the disk
@@ -198,6 +259,12 @@ impl BDA {
         self.regions.last_update_time()
     }
 
+    /// The sequence number of the most recent metadata written to the BDA,
+    /// if any.
+    pub fn last_sequence(&self) -> Option<u64> {
+        self.regions.last_sequence()
+    }
+
     /// The UUID of the device.
     pub fn dev_uuid(&self) -> DevUuid {
         self.header.dev_uuid
@@ -213,6 +280,11 @@ impl BDA {
         self.header.blkdev_size
     }
 
+    /// The number of sectors a BDA with the given MDA size would occupy.
+    pub fn size_for(mda_size: Sectors) -> Sectors {
+        BDA_STATIC_HDR_SIZE.sectors() + mda_size + MDA_RESERVED_SECTORS
+    }
+
     /// The number of sectors the BDA itself occupies.
     pub fn size(&self) -> Sectors {
         BDA_STATIC_HDR_SIZE.sectors() + self.header.mda_size + self.header.reserved_size
@@ -239,6 +311,9 @@ pub struct StaticHeader {
     flags: u64,
     /// Seconds portion of DateTime<Utc> value.
     initialization_time: u64,
+    compat_features: u64,
+    ro_compat_features: u64,
+    incompat_features: u64,
 }
 
 impl StaticHeader {
@@ -257,6 +332,9 @@ impl StaticHeader {
             reserved_size: MDA_RESERVED_SECTORS,
             flags: 0,
             initialization_time,
+            compat_features: 0,
+            ro_compat_features: 0,
+            incompat_features: 0,
         }
     }
 
@@ -264,6 +342,9 @@ impl StaticHeader {
     /// Return the latest copy that validates as a Stratis BDA, however verify both
     /// copies and if one validates but one does not, re-write the one that is incorrect.  If both
     /// copies are valid, but one is newer than the other, rewrite the older one to match.
+    /// Since the copies are written one after the other with a sync in
+    /// between, a write torn by a crash damages at most one of them; the
+    /// damaged copy fails its CRC check and is restored from the other.
     /// Return None if it's not a Stratis device.
     /// Return an error if the metadata seems to indicate that the device is
     /// a Stratis device, but no well-formed signature block could be read.
@@ -405,6 +486,57 @@ impl StaticHeader {
         }
     }
 
+    /// Check that this version of stratisd supports all the features that
+    /// the sigblock on a Stratis device requires.
+    /// Returns an error if it does not, or if the sigblock can not be read.
+    /// Returns Ok if the device is not a Stratis device.
+    pub fn check_device_features<F>(f: &mut F) -> StratisResult<()>
+    where
+        F: Read + Seek + SyncAll,
+    {
+        match StaticHeader::setup(f)? {
+            Some(sh) => sh.check_features(),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns an error if the sigblock requires any feature that this
+    /// version of stratisd does not support. Unknown compat features are
+    /// logged, but otherwise ignored.
+    fn check_features(&self) -> StratisResult<()> {
+        let unknown_incompat = self.incompat_features & !SUPPORTED_INCOMPAT_FEATURES;
+        if unknown_incompat != 0 {
+            let err_msg = format!(
+                "device with UUID {} requires unsupported incompatible features {:#x}",
+                self.dev_uuid.to_simple_ref(),
+                unknown_incompat
+            );
+            return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+        }
+
+        let unknown_ro_compat = self.ro_compat_features & !SUPPORTED_RO_COMPAT_FEATURES;
+        if unknown_ro_compat != 0 {
+            let err_msg = format!(
+                "device with UUID {} requires unsupported read-only compatible features {:#x}; \
+                 stratisd can not use it without writing to it",
+                self.dev_uuid.to_simple_ref(),
+                unknown_ro_compat
+            );
+            return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+        }
+
+        let unknown_compat = self.compat_features & !SUPPORTED_COMPAT_FEATURES;
+        if unknown_compat != 0 {
+            info!(
+                "device with UUID {} has unknown compatible features {:#x}; ignoring them",
+                self.dev_uuid.to_simple_ref(),
+                unknown_compat
+            );
+        }
+
+        Ok(())
+    }
+
     /// Generate a buf suitable for writing to blockdev
     fn sigblock_to_buf(&self) -> [u8; SECTOR_SIZE] {
         let mut buf = [0u8; SECTOR_SIZE];
@@ -416,6 +548,9 @@ impl StaticHeader {
         LittleEndian::write_u64(&mut buf[96..104], *self.mda_size);
         LittleEndian::write_u64(&mut buf[104..112], *self.reserved_size);
         LittleEndian::write_u64(&mut buf[120..128], self.initialization_time);
+        LittleEndian::write_u64(&mut buf[136..144], self.compat_features);
+        LittleEndian::write_u64(&mut buf[144..152], self.ro_compat_features);
+        LittleEndian::write_u64(&mut buf[152..160], self.incompat_features);
 
         let hdr_crc = crc32::checksum_castagnoli(&buf[4..SECTOR_SIZE]);
         LittleEndian::write_u32(&mut buf[..4], hdr_crc);
@@ -464,10 +599,31 @@ impl StaticHeader {
             reserved_size: Sectors(LittleEndian::read_u64(&buf[104..112])),
             flags: 0,
             initialization_time: LittleEndian::read_u64(&buf[120..128]),
+            compat_features: LittleEndian::read_u64(&buf[136..144]),
+            ro_compat_features: LittleEndian::read_u64(&buf[144..152]),
+            incompat_features: LittleEndian::read_u64(&buf[152..160]),
         }))
     }
 }
 
+/// Rewrite both sigblocks of the device open in f to require the given
+/// incompat features, as a later version of stratisd might.
+#[cfg(test)]
+pub fn set_incompat_features<F>(f: &mut F, incompat_features: u64) -> StratisResult<()>
+where
+    F: Read + Seek + SyncAll,
+{
+    let header = StaticHeader::setup(f)?.ok_or_else(|| {
+        StratisError::Engine(ErrorEnum::NotFound, "no Stratis sigblock found".into())
+    })?;
+    let header = StaticHeader {
+        incompat_features,
+        ..header
+    };
+    BDA::write(f, &header.sigblock_to_buf(), MetadataLocation::Both)?;
+    Ok(())
+}
+
 impl fmt::Debug for StaticHeader {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("StaticHeader")
@@ -478,6 +634,9 @@ impl fmt::Debug for StaticHeader {
             .field("reserved_size", &self.reserved_size)
             .field("flags", &self.flags)
             .field("initialization_time", &self.initialization_time)
+            .field("compat_features", &self.compat_features)
+            .field("ro_compat_features", &self.ro_compat_features)
+            .field("incompat_features", &self.incompat_features)
             .finish()
     }
 }
@@ -497,15 +656,25 @@ mod mda {
 
     use crate::engine::strat_engine::device::SyncAll;
 
-    const _MDA_REGION_HDR_SIZE: usize = 32;
+    const _MDA_REGION_HDR_SIZE: usize = 40;
     const MDA_REGION_HDR_SIZE: Bytes = Bytes(_MDA_REGION_HDR_SIZE as u64);
 
+    // Size of a version 1 region header, which has no sequence number.
+    const _MDA_REGION_HDR_V1_SIZE: usize = 32;
+
     const NUM_MDA_REGIONS: usize = 4;
     const PER_MDA_REGION_COPIES: usize = 2;
     const NUM_PRIMARY_MDA_REGIONS: usize = NUM_MDA_REGIONS / PER_MDA_REGION_COPIES;
     pub const MIN_MDA_SECTORS: Sectors = Sectors(2032);
 
-    const STRAT_REGION_HDR_VERSION: u8 = 1;
+    // Version 2 of the region header adds the sequence number. Version 1
+    // headers are still read, but every header written is version 2, so
+    // the first metadata update of a pool written by an earlier stratisd
+    // upgrades it in place. The upgrade is one-way: a stratisd that knows
+    // only version 1 refuses the upgraded regions as having an unknown
+    // region header version, and so can not set up the pool again.
+    const STRAT_REGION_HDR_VERSION: u8 = 2;
+    const STRAT_REGION_HDR_VERSION_1: u8 = 1;
     const STRAT_METADATA_VERSION: u8 = 1;
 
     #[derive(Debug)]
@@ -558,6 +727,29 @@ mod mda {
             })
         }
 
+        /// Load the header of the region at the location specified by index.
+        /// If it appears that no metadata has been written at the location
+        /// return None. If it appears that there is metadata, but it has
+        /// been corrupted, return an error.
+        fn load_header<F>(
+            header_size: Bytes,
+            index: usize,
+            per_region_size: Bytes,
+            f: &mut F,
+        ) -> StratisResult<Option<MDAHeader>>
+        where
+            F: Read + Seek,
+        {
+            let mut hdr_buf = [0u8; _MDA_REGION_HDR_SIZE];
+            f.seek(SeekFrom::Start(MDARegions::mda_offset(
+                header_size,
+                index,
+                per_region_size,
+            )))?;
+            f.read_exact(&mut hdr_buf)?;
+            MDAHeader::from_buf(&hdr_buf, per_region_size)
+        }
+
         /// Construct MDARegions from data on the disk.
         /// Note that this method is always called in a context where a
         /// StaticHeader has already been read. Therefore, it
@@ -570,20 +762,8 @@ mod mda {
             let region_size = size / NUM_MDA_REGIONS;
             let per_region_size = region_size.bytes();
 
-            // Load a single region at the location specified by index.
-            // If it appears that no metadata has been written at the location
-            // return None. If it appears that there is metadata, but it has
-            // been corrupted, return an error.
-            let mut load_a_region = |index: usize| -> StratisResult<Option<MDAHeader>> {
-                let mut hdr_buf = [0u8; _MDA_REGION_HDR_SIZE];
-                f.seek(SeekFrom::Start(MDARegions::mda_offset(
-                    header_size,
-                    index,
-                    per_region_size,
-                )))?;
-                f.read_exact(&mut hdr_buf)?;
-                Ok(MDAHeader::from_buf(&hdr_buf, per_region_size)?)
-            };
+            let mut load_a_region =
+                |index: usize| MDARegions::load_header(header_size, index, per_region_size, f);
 
             // Get an MDAHeader for the given index.
             // If there is a failure reading the first, fall back on the
@@ -598,16 +778,50 @@ mod mda {
             })
         }
 
+        /// The greatest sequence number among those region headers, of all
+        /// the regions, including the copies, that can be read. A region
+        /// header that can not be read is ignored, rather than an error.
+        pub fn readable_last_sequence<F>(
+            header_size: Bytes,
+            size: Sectors,
+            f: &mut F,
+        ) -> Option<u64>
+        where
+            F: Read + Seek,
+        {
+            let per_region_size = (size / NUM_MDA_REGIONS).bytes();
+            (0..NUM_MDA_REGIONS)
+                .filter_map(|index| {
+                    MDARegions::load_header(header_size, index, per_region_size, f)
+                        .ok()
+                        .and_then(|header| header)
+                })
+                .map(|header| header.sequence)
+                .max()
+        }
+
+        /// The offsets from the start of the device of the region headers,
+        /// of all the regions, including the copies.
+        #[cfg(test)]
+        pub fn header_offsets(header_size: Bytes, size: Sectors) -> Vec<u64> {
+            let per_region_size = (size / NUM_MDA_REGIONS).bytes();
+            (0..NUM_MDA_REGIONS)
+                .map(|index| MDARegions::mda_offset(header_size, index, per_region_size))
+                .collect()
+        }
+
         /// Write metadata to the older of the metadata regions.
         /// If operation is completed, update the value of the
         /// older MDAHeader with the new values.
-        /// If time specified is earlier than the last update time, return an
-        /// error. If the size of the data is greater than the available space,
-        /// return an error. If there is an error when writing the data, return
-        /// an error.
+        /// If sequence specified is not greater than the last sequence
+        /// number, return an error. The time is recorded, but is not used
+        /// to order the regions. If the size of the data is greater than the
+        /// available space, return an error. If there is an error when
+        /// writing the data, return an error.
         pub fn save_state<F>(
             &mut self,
             header_size: Bytes,
+            sequence: u64,
             time: &DateTime<Utc>,
             data: &[u8],
             f: &mut F,
@@ -615,7 +829,7 @@ mod mda {
         where
             F: Seek + SyncAll,
         {
-            if self.last_update_time() >= Some(time) {
+            if self.last_sequence() >= Some(sequence) {
                 return Err(StratisError::Engine(
                     ErrorEnum::Invalid,
                     "Overwriting newer data".into(),
@@ -627,9 +841,11 @@ mod mda {
             check_mda_region_size(used, region_size)?;
 
             let header = MDAHeader {
+                sequence,
                 last_updated: *time,
                 used,
                 data_crc: crc32::checksum_castagnoli(data),
+                hdr_version: STRAT_REGION_HDR_VERSION,
             };
             let hdr_buf = header.to_buf();
 
@@ -676,8 +892,7 @@ mod mda {
             // Load the metadata region specified by index.
             // It is an error if the metadata can not be found.
             let mut load_region = |index: usize| -> StratisResult<Vec<u8>> {
-                let offset = MDARegions::mda_offset(header_size, index, region_size)
-                    + _MDA_REGION_HDR_SIZE as u64;
+                let offset = MDARegions::mda_offset(header_size, index, region_size) + mda.size();
                 f.seek(SeekFrom::Start(offset))?;
                 mda.load_region(f)
             };
@@ -690,12 +905,17 @@ mod mda {
         }
 
         /// The index of the older region, or 0 if there is a tie.
+        /// Regions are ordered by sequence number; the update time is
+        /// consulted only if the sequence numbers are equal, which is the
+        /// case for regions written with version 1 headers.
         fn older(&self) -> usize {
             match (&self.mdas[0], &self.mdas[1]) {
                 (&None, _) => 0,
                 (_, &None) => 1,
                 (&Some(ref mda0), &Some(ref mda1)) => {
-                    match mda0.last_updated.cmp(&mda1.last_updated) {
+                    match (mda0.sequence, mda0.last_updated)
+                        .cmp(&(mda1.sequence, mda1.last_updated))
+                    {
                         Ordering::Less => 0,
                         Ordering::Equal | Ordering::Greater => 1,
                     }
@@ -716,16 +936,27 @@ mod mda {
         pub fn last_update_time(&self) -> Option<&DateTime<Utc>> {
             self.mdas[self.newer()].as_ref().map(|h| &h.last_updated)
         }
+
+        /// The sequence number of the most recent write to these MDA regions
+        pub fn last_sequence(&self) -> Option<u64> {
+            self.mdas[self.newer()].as_ref().map(|h| h.sequence)
+        }
     }
 
     #[derive(Debug)]
     pub struct MDAHeader {
+        /// Incremented on every write, used to order the regions.
+        sequence: u64,
+
         last_updated: DateTime<Utc>,
 
         /// Size of region used for pool metadata.
         used: Bytes,
 
         data_crc: u32,
+
+        /// Version of the header as read from or written to disk.
+        hdr_version: u8,
     }
 
     // Implementing Default explicitly because DateTime<Utc> does not implement
@@ -733,9 +964,11 @@ mod mda {
     impl Default for MDAHeader {
         fn default() -> MDAHeader {
             MDAHeader {
+                sequence: 0,
                 last_updated: Utc.timestamp(0, 0),
                 used: Bytes(0),
                 data_crc: 0,
+                hdr_version: STRAT_REGION_HDR_VERSION,
             }
         }
     }
@@ -746,24 +979,30 @@ mod mda {
         /// Return an error if the size of the region used is too large for the given region_size.
         /// Return None if there is no MDAHeader to be read. This is detected if the
         /// timestamp region in the buffer is 0.
+        /// A version 1 header occupies only the first 32 bytes of the buffer
+        /// and has no sequence number; its sequence number is taken to be 0.
         fn from_buf(
             buf: &[u8; _MDA_REGION_HDR_SIZE],
             region_size: Bytes,
         ) -> StratisResult<Option<MDAHeader>> {
-            if LittleEndian::read_u32(&buf[..4]) != crc32::checksum_castagnoli(&buf[4..]) {
-                return Err(StratisError::Engine(
-                    ErrorEnum::Invalid,
-                    "MDA region header CRC".into(),
-                ));
-            }
-
-            // Even though hdr_version is positioned later in struct, check it
-            // right after the CRC
+            // The extent covered by the CRC depends on the header version,
+            // so the version must be examined before the CRC is checked.
             let hdr_version = buf[28];
-            if hdr_version != STRAT_REGION_HDR_VERSION {
+            let hdr_size = match hdr_version {
+                STRAT_REGION_HDR_VERSION => _MDA_REGION_HDR_SIZE,
+                STRAT_REGION_HDR_VERSION_1 => _MDA_REGION_HDR_V1_SIZE,
+                _ => {
+                    return Err(StratisError::Engine(
+                        ErrorEnum::Invalid,
+                        format!("Unknown region header version: {}", hdr_version),
+                    ));
+                }
+            };
+
+            if LittleEndian::read_u32(&buf[..4]) != crc32::checksum_castagnoli(&buf[4..hdr_size]) {
                 return Err(StratisError::Engine(
                     ErrorEnum::Invalid,
-                    format!("Unknown region header version: {}", hdr_version),
+                    "MDA region header CRC".into(),
                 ));
             }
 
@@ -786,15 +1025,25 @@ mod mda {
                     assert!(secs <= std::i64::MAX as u64);
 
                     let nsecs = LittleEndian::read_u32(&buf[24..28]);
+                    let sequence = if hdr_version == STRAT_REGION_HDR_VERSION_1 {
+                        0
+                    } else {
+                        LittleEndian::read_u64(&buf[32..40])
+                    };
                     Ok(Some(MDAHeader {
+                        sequence,
                         used,
                         last_updated: Utc.timestamp(secs as i64, nsecs),
                         data_crc: LittleEndian::read_u32(&buf[4..8]),
+                        hdr_version,
                     }))
                 }
             }
         }
 
+        /// Write the header to a buffer. The header is always written in the
+        /// current format, regardless of the version it was read with; see
+        /// STRAT_REGION_HDR_VERSION.
         fn to_buf(&self) -> [u8; _MDA_REGION_HDR_SIZE] {
             // Unsigned casts are always safe, as sec and nsec values are never negative
             assert!(self.last_updated.timestamp() >= 0);
@@ -807,6 +1056,7 @@ mod mda {
             LittleEndian::write_u32(&mut buf[24..28], self.last_updated.timestamp_subsec_nanos());
             buf[28] = STRAT_REGION_HDR_VERSION;
             buf[29] = STRAT_METADATA_VERSION;
+            LittleEndian::write_u64(&mut buf[32..40], self.sequence);
 
             let buf_crc = crc32::checksum_castagnoli(&buf[4.._MDA_REGION_HDR_SIZE]);
             LittleEndian::write_u32(&mut buf[..4], buf_crc);
@@ -814,6 +1064,16 @@ mod mda {
             buf
         }
 
+        /// The size of the header on disk, which is also the offset of the
+        /// metadata from the start of the region.
+        fn size(&self) -> u64 {
+            if self.hdr_version == STRAT_REGION_HDR_VERSION_1 {
+                _MDA_REGION_HDR_V1_SIZE as u64
+            } else {
+                _MDA_REGION_HDR_SIZE as u64
+            }
+        }
+
         /// Given a pre-seek()ed File, load the MDA region and return the contents.
         /// Return an error if the data can not be read, since the existence
         /// of the MDAHeader implies that the data must be available.
@@ -914,6 +1174,7 @@ mod mda {
             assert_eq!(buf[28], STRAT_REGION_HDR_VERSION);
             assert_eq!(buf[29], STRAT_METADATA_VERSION);
             assert!(buf[30..].iter().all(|x| *x == 0u8));
+            assert_eq!(buf.len(), _MDA_REGION_HDR_SIZE);
         }
 
         #[test]
@@ -941,6 +1202,7 @@ mod mda {
                           // sec == 0: value of 0 is interpreted as no timestamp when read
                           sec in 1..UTC_TIMESTAMP_SECS_BOUND,
                           nsec in 0..UTC_TIMESTAMP_NSECS_BOUND,
+                          sequence in any::<u64>(),
                           region_size_ext in any::<u32>()) {
 
                 // 4 is NUM_MDA_REGIONS which is not imported from super.
@@ -948,17 +1210,21 @@ mod mda {
                     (MIN_MDA_SECTORS / 4usize).bytes() + Bytes(u64::from(region_size_ext));
 
                 let header = MDAHeader {
+                    sequence,
                     last_updated: Utc.timestamp(sec, nsec),
                     used: Bytes(data.len() as u64),
                     data_crc: crc32::checksum_castagnoli(&data),
+                    hdr_version: STRAT_REGION_HDR_VERSION,
                 };
                 let buf = header.to_buf();
                 let mda1 = MDAHeader::from_buf(&buf, region_size).unwrap().unwrap();
                 let mda2 = MDAHeader::from_buf(&buf, region_size).unwrap().unwrap();
 
+                prop_assert_eq!(mda1.sequence, mda2.sequence);
                 prop_assert_eq!(mda1.last_updated, mda2.last_updated);
                 prop_assert_eq!(mda1.used, mda2.used);
                 prop_assert_eq!(mda1.data_crc, mda2.data_crc);
+                prop_assert_eq!(header.sequence, mda1.sequence);
                 prop_assert_eq!(header.last_updated, mda1.last_updated);
                 prop_assert_eq!(header.data_crc, mda1.data_crc);
             }
@@ -969,9 +1235,11 @@ mod mda {
         fn test_from_buf_crc_error() {
             let data = [0u8; 3];
             let header = MDAHeader {
+                sequence: 1,
                 last_updated: Utc::now(),
                 used: Bytes(data.len() as u64),
                 data_crc: crc32::checksum_castagnoli(&data),
+                hdr_version: STRAT_REGION_HDR_VERSION,
             };
             let mut buf = header.to_buf();
             LittleEndian::write_u32(&mut buf[..4], 0u32);
@@ -985,19 +1253,90 @@ mod mda {
         fn test_from_buf_size_error() {
             let data = [0u8; 3];
             let header = MDAHeader {
+                sequence: 1,
                 last_updated: Utc::now(),
                 used: Bytes(data.len() as u64),
                 data_crc: crc32::checksum_castagnoli(&data),
+                hdr_version: STRAT_REGION_HDR_VERSION,
             };
             let buf = header.to_buf();
             assert!(MDAHeader::from_buf(&buf, MDA_REGION_HDR_SIZE).is_err());
         }
+
+        /// Verify that a version 1 header, which has no sequence number and
+        /// covers only the first 32 bytes with its CRC, can still be read.
+        /// Verify that the metadata following it is found at the right offset.
+        #[test]
+        fn test_from_buf_v1() {
+            let data = [1u8; 3];
+            let time = Utc::now();
+            let header = MDAHeader {
+                sequence: 0,
+                last_updated: time,
+                used: Bytes(data.len() as u64),
+                data_crc: crc32::checksum_castagnoli(&data),
+                hdr_version: STRAT_REGION_HDR_VERSION,
+            };
+            let mut buf = header.to_buf();
+            buf[28] = STRAT_REGION_HDR_VERSION_1;
+            let buf_crc = crc32::checksum_castagnoli(&buf[4.._MDA_REGION_HDR_V1_SIZE]);
+            LittleEndian::write_u32(&mut buf[..4], buf_crc);
+            // In a version 1 region, the metadata immediately follows the header.
+            buf[_MDA_REGION_HDR_V1_SIZE..]
+                .iter_mut()
+                .for_each(|x| *x = 0xff);
+
+            let mda = MDAHeader::from_buf(&buf, MIN_MDA_SECTORS.bytes())
+                .unwrap()
+                .unwrap();
+            assert_eq!(mda.sequence, 0);
+            assert_eq!(mda.last_updated, time);
+            assert_eq!(mda.size(), _MDA_REGION_HDR_V1_SIZE as u64);
+
+            let mut region = buf[.._MDA_REGION_HDR_V1_SIZE].to_vec();
+            region.extend_from_slice(&data);
+            let mut f = Cursor::new(region);
+            f.seek(SeekFrom::Start(mda.size())).unwrap();
+            assert_eq!(mda.load_region(&mut f).unwrap(), data.to_vec());
+        }
+
+        /// Verify that the newer region is determined by the sequence number,
+        /// not by the update time.
+        #[test]
+        fn test_sequence_ordering() {
+            let buf_length = *(BDA_STATIC_HDR_SIZE + 4usize * MIN_MDA_SECTORS.bytes()) as usize;
+            let mut buf = Cursor::new(vec![0; buf_length]);
+            let mut regions =
+                MDARegions::initialize(BDA_STATIC_HDR_SIZE, MIN_MDA_SECTORS, &mut buf).unwrap();
+
+            let later = Utc::now();
+            let earlier = Utc.timestamp(later.timestamp() - 3600, 0);
+
+            regions
+                .save_state(BDA_STATIC_HDR_SIZE, 1, &later, b"first", &mut buf)
+                .unwrap();
+            regions
+                .save_state(BDA_STATIC_HDR_SIZE, 2, &earlier, b"second", &mut buf)
+                .unwrap();
+
+            let regions = MDARegions::load(BDA_STATIC_HDR_SIZE, MIN_MDA_SECTORS, &mut buf).unwrap();
+            assert_eq!(regions.last_sequence(), Some(2));
+            assert_eq!(regions.last_update_time(), Some(&earlier));
+            assert_eq!(
+                regions.load_state(BDA_STATIC_HDR_SIZE, &mut buf).unwrap(),
+                Some(b"second".to_vec())
+            );
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    #![cfg_attr(not(feature = "device_tests"), allow(dead_code, unused_imports))]
+
+    use std::fs::{File, OpenOptions};
     use std::io::{Cursor, Write};
+    use std::path::Path;
 
     use devicemapper::{Bytes, Sectors, IEC};
     use proptest::{
@@ -1008,6 +1347,10 @@ mod tests {
     };
     use uuid::Uuid;
 
+    use crate::engine::strat_engine::backstore::{blkdev_size, find_all};
+    use crate::engine::strat_engine::cmd;
+    use crate::engine::strat_engine::tests::{loopbacked, real};
+
     use super::*;
 
     /// Corrupt a byte at the specified position.
@@ -1101,9 +1444,12 @@ mod tests {
     }
 
     #[test]
-    /// Construct a BDA and verify that an error is returned if timestamp
-    /// of saved data is older than timestamp of most recently written data.
-    fn test_early_times_err() {
+    /// Construct a BDA and verify that an error is returned if the sequence
+    /// number of saved data is not greater than the sequence number of the
+    /// most recently written data. Verify that a timestamp older than that
+    /// of the most recently written data is not an error, since the clock
+    /// may have been set back.
+    fn test_early_sequence_err() {
         let data = [0u8; 3];
 
         // Construct a BDA.
@@ -1124,19 +1470,16 @@ mod tests {
         assert_ne!(timestamp0, timestamp1);
 
         let mut buf = Cursor::new(vec![0; *sh.blkdev_size.bytes() as usize]);
-        bda.save_state(&timestamp1, &data, &mut buf).unwrap();
-
-        // Error, because current timestamp is older than written to newer.
-        assert!(bda.save_state(&timestamp0, &data, &mut buf).is_err());
+        bda.save_state(1, &timestamp1, &data, &mut buf).unwrap();
 
-        let timestamp2 = Utc::now();
-        let timestamp3 = Utc::now();
-        assert_ne!(timestamp2, timestamp3);
+        // Error, because sequence number is not greater than written to newer.
+        assert!(bda.save_state(1, &timestamp1, &data, &mut buf).is_err());
+        assert!(bda.save_state(0, &timestamp1, &data, &mut buf).is_err());
 
-        bda.save_state(&timestamp3, &data, &mut buf).unwrap();
-
-        // Error, because current timestamp is older than written to newer.
-        assert!(bda.save_state(&timestamp2, &data, &mut buf).is_err());
+        // Not an error, the sequence number alone determines the ordering.
+        bda.save_state(2, &timestamp0, &data, &mut buf).unwrap();
+        assert_eq!(bda.last_sequence(), Some(2));
+        assert_eq!(bda.last_update_time(), Some(&timestamp0));
     }
 
     proptest! {
@@ -1163,7 +1506,7 @@ mod tests {
                 Utc::now().timestamp() as u64,
             ).unwrap();
             let current_time = Utc::now();
-            bda.save_state(&current_time, &state, &mut buf).unwrap();
+            bda.save_state(1, &current_time, &state, &mut buf).unwrap();
             let loaded_state = bda.load_state(&mut buf).unwrap();
             prop_assert!(bda.last_update_time().map(|t| t == &current_time).unwrap_or(false));
             prop_assert!(loaded_state.map(|s| &s == state).unwrap_or(false));
@@ -1174,11 +1517,12 @@ mod tests {
             prop_assert!(bda.last_update_time().map(|t| t == &current_time).unwrap_or(false));
 
             let current_time = Utc::now();
-            bda.save_state(&current_time, &next_state, &mut buf)
+            bda.save_state(2, &current_time, &next_state, &mut buf)
                 .unwrap();
             let loaded_state = bda.load_state(&mut buf).unwrap();
             prop_assert!(loaded_state.map(|s| &s == next_state).unwrap_or(false));
             prop_assert!(bda.last_update_time().map(|t| t == &current_time).unwrap_or(false));
+            prop_assert_eq!(bda.last_sequence(), Some(2));
 
         }
     }
@@ -1197,6 +1541,9 @@ mod tests {
             prop_assert_eq!(sh1.reserved_size, sh2.reserved_size);
             prop_assert_eq!(sh1.flags, sh2.flags);
             prop_assert_eq!(sh1.initialization_time, sh2.initialization_time);
+            prop_assert_eq!(sh1.compat_features, sh2.compat_features);
+            prop_assert_eq!(sh1.ro_compat_features, sh2.ro_compat_features);
+            prop_assert_eq!(sh1.incompat_features, sh2.incompat_features);
         }
     }
 
@@ -1313,4 +1660,242 @@ mod tests {
         }
     }
 
+    #[test]
+    /// Simulate a sigblock write torn by a crash: the first copy has been
+    /// partially overwritten with a newer sigblock, the second not at all.
+    /// Verify that the torn copy is detected and restored from the other.
+    fn bda_test_torn_write() {
+        let sh = random_static_header(10000, 4);
+        let buf_size = *sh.mda_size.bytes() as usize + _BDA_STATIC_HDR_SIZE;
+        let mut buf = Cursor::new(vec![0; buf_size]);
+        BDA::write(&mut buf, &sh.sigblock_to_buf(), MetadataLocation::Both).unwrap();
+        let reference_buf = buf.clone();
+
+        let newer = StaticHeader {
+            initialization_time: sh.initialization_time + 1,
+            ..sh
+        };
+        let newer_buf = newer.sigblock_to_buf();
+        buf.seek(SeekFrom::Start(SECTOR_SIZE as u64)).unwrap();
+        // Only the part of the sector before the initialization time is
+        // written.
+        buf.write_all(&newer_buf[..120]).unwrap();
+        assert_ne!(reference_buf.get_ref(), buf.get_ref());
+
+        assert_eq!(StaticHeader::setup(&mut buf).unwrap(), Some(sh));
+        assert_eq!(reference_buf.get_ref(), buf.get_ref());
+    }
+
+    #[test]
+    /// Verify that a device with unknown compat features may be used, but
+    /// that one with unknown ro-compat or incompat features may not.
+    fn test_unknown_features() {
+        let sh = random_static_header(10000, 4);
+        let buf_size = *sh.mda_size.bytes() as usize + _BDA_STATIC_HDR_SIZE;
+
+        let write_header = |sh: &StaticHeader| {
+            let mut buf = Cursor::new(vec![0; buf_size]);
+            BDA::write(&mut buf, &sh.sigblock_to_buf(), MetadataLocation::Both).unwrap();
+            mda::MDARegions::initialize(BDA_STATIC_HDR_SIZE, sh.mda_size, &mut buf).unwrap();
+            buf
+        };
+
+        let mut buf = write_header(&StaticHeader {
+            compat_features: 1 << 63,
+            ..sh
+        });
+        assert_matches!(StaticHeader::check_device_features(&mut buf), Ok(()));
+        assert_matches!(BDA::load(&mut buf), Ok(Some(_)));
+
+        for header in &[
+            StaticHeader {
+                ro_compat_features: 1 << 63,
+                ..sh
+            },
+            StaticHeader {
+                incompat_features: 1 << 63,
+                ..sh
+            },
+        ] {
+            let mut buf = write_header(header);
+            assert_matches!(StaticHeader::check_device_features(&mut buf), Err(_));
+            assert_matches!(BDA::load(&mut buf), Err(_));
+            assert_matches!(StaticHeader::device_identifiers(&mut buf), Ok(Some(_)));
+        }
+    }
+
+    /// Open a device for reading and writing.
+    fn open_device(path: &Path) -> File {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .unwrap()
+    }
+
+    /// Verify, on each device, that:
+    /// 1. A BDA is initialized, and metadata saved to it, with the device's
+    ///    actual size.
+    /// 2. The BDA and the most recent metadata are loaded again from the
+    ///    device once it has been reopened.
+    /// 3. Saving metadata with a sequence number that is not greater than
+    ///    the last fails, and does not change what was saved.
+    /// 4. A corrupted sigblock is repaired from the other copy when the BDA
+    ///    is loaded.
+    /// 5. A wiped device has no BDA.
+    fn test_bda_on_device(paths: &[&Path]) {
+        for path in paths {
+            let pool_uuid = Uuid::new_v4();
+            let dev_uuid = Uuid::new_v4();
+            let size = device_size(path);
+            let mut f = open_device(path);
+            let ts = Utc::now().timestamp() as u64;
+
+            let mut bda =
+                BDA::initialize(&mut f, pool_uuid, dev_uuid, MIN_MDA_SECTORS, size, ts).unwrap();
+            bda.save_state(1, &Utc::now(), b"first", &mut f).unwrap();
+            bda.save_state(2, &Utc::now(), b"second", &mut f).unwrap();
+            drop(f);
+
+            let mut f = open_device(path);
+            let mut bda = BDA::load(&mut f).unwrap().unwrap();
+            assert_eq!(bda.pool_uuid(), pool_uuid);
+            assert_eq!(bda.dev_uuid(), dev_uuid);
+            assert_eq!(bda.dev_size(), size);
+            assert_eq!(bda.initialization_time(), ts);
+            assert_eq!(bda.last_sequence(), Some(2));
+            assert_eq!(bda.load_state(&mut f).unwrap(), Some(b"second".to_vec()));
+
+            assert!(bda.save_state(2, &Utc::now(), b"third", &mut f).is_err());
+            assert_eq!(bda.load_state(&mut f).unwrap(), Some(b"second".to_vec()));
+
+            corrupt_byte(&mut f, (SECTOR_SIZE + 32) as u64).unwrap();
+            assert_eq!(BDA::load(&mut f).unwrap().unwrap().dev_uuid(), dev_uuid);
+            assert_matches!(BDA::read(&mut f), (Ok(ref first), Ok(ref second)) if first == second);
+
+            BDA::wipe(&mut f).unwrap();
+            assert_matches!(BDA::load(&mut f), Ok(None));
+        }
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_bda_on_device() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Exactly(1, None),
+            test_bda_on_device,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_bda_on_device() {
+        real::test_with_spec(
+            &real::DeviceLimits::AtLeast(1, None, None),
+            test_bda_on_device,
+        );
+    }
+
+    /// Write header to both sigblock locations of the device, closing it
+    /// afterwards, so that udev sees the change.
+    fn write_sigblock(path: &Path, header: &StaticHeader) {
+        BDA::write(
+            &mut open_device(path),
+            &header.sigblock_to_buf(),
+            MetadataLocation::Both,
+        )
+        .unwrap();
+    }
+
+    /// The size of a device.
+    fn device_size(path: &Path) -> Sectors {
+        blkdev_size(&open_device(path)).unwrap().sectors()
+    }
+
+    /// Verify that find_all() omits a pool which has a device with an
+    /// unknown incompat feature, and ignores a device which claims the
+    /// device UUID of another.
+    /// 1. Give each device but the last a BDA for one pool, and the last a
+    ///    BDA with an unknown incompat feature for another pool.
+    /// 2. Verify that only the first pool is found.
+    /// 3. Copy the sigblock of the first device to the last.
+    /// 4. Verify that the first pool is found, with each device once.
+    /// 5. Wipe all the devices and verify that neither pool is found.
+    fn test_find_all_refusals(paths: &[&Path]) {
+        assert!(paths.len() > 1);
+        let (last, paths) = paths.split_last().expect("paths.len() > 1");
+
+        let pool_uuid = Uuid::new_v4();
+        let mut headers = Vec::new();
+        for path in paths {
+            let header = StaticHeader::new(
+                pool_uuid,
+                Uuid::new_v4(),
+                MIN_MDA_SECTORS,
+                device_size(path),
+                Utc::now().timestamp() as u64,
+            );
+            write_sigblock(path, &header);
+            headers.push(header);
+        }
+
+        let unsupported_uuid = Uuid::new_v4();
+        write_sigblock(
+            last,
+            &StaticHeader {
+                incompat_features: 1 << 63,
+                ..StaticHeader::new(
+                    unsupported_uuid,
+                    Uuid::new_v4(),
+                    MIN_MDA_SECTORS,
+                    device_size(last),
+                    Utc::now().timestamp() as u64,
+                )
+            },
+        );
+
+        cmd::udev_settle().unwrap();
+        let (pools, _) = find_all().unwrap();
+        assert_eq!(
+            pools.get(&pool_uuid).map(|devs| devs.len()),
+            Some(paths.len())
+        );
+        assert!(!pools.contains_key(&unsupported_uuid));
+
+        write_sigblock(last, &headers[0]);
+
+        cmd::udev_settle().unwrap();
+        let (pools, _) = find_all().unwrap();
+        assert_eq!(
+            pools.get(&pool_uuid).map(|devs| devs.len()),
+            Some(paths.len())
+        );
+
+        for path in paths.iter().chain(Some(last)) {
+            BDA::wipe(&mut open_device(path)).unwrap();
+        }
+
+        cmd::udev_settle().unwrap();
+        let (pools, _) = find_all().unwrap();
+        assert!(!pools.contains_key(&pool_uuid));
+        assert!(!pools.contains_key(&unsupported_uuid));
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_find_all_refusals() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Range(2, 3, None),
+            test_find_all_refusals,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_find_all_refusals() {
+        real::test_with_spec(
+            &real::DeviceLimits::AtLeast(2, None, None),
+            test_find_all_refusals,
+        );
+    }
 }
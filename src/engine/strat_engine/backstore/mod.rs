@@ -17,7 +17,10 @@ mod util;
 
 pub use self::backstore::Backstore;
 pub use self::blockdev::StratBlockDev;
+pub use self::blockdevmgr::{restore_state, set_metadata_redundancy, set_min_dev_size};
 pub use self::device::blkdev_size;
 pub use self::device::is_stratis_device;
+#[cfg(test)]
+pub use self::metadata::set_incompat_features;
 pub use self::metadata::MIN_MDA_SECTORS;
-pub use self::setup::{find_all, get_metadata};
+pub use self::setup::{find_all, get_last_sequence, get_metadata};
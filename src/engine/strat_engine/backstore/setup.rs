@@ -5,9 +5,13 @@
 // Code to handle initial setup steps for a pool.
 // Initial setup steps are steps that do not alter the environment.
 
+use std::cmp;
 use std::collections::{HashMap, HashSet};
 use std::fs::OpenOptions;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
 
 use serde_json;
 
@@ -19,96 +23,280 @@ use crate::stratis::{ErrorEnum, StratisError, StratisResult};
 use crate::engine::strat_engine::backstore::{blkdev_size, StratBlockDev};
 use crate::engine::strat_engine::serde_structs::{BackstoreSave, BaseBlockDevSave, PoolSave};
 
+use crate::engine::strat_engine::backstore::device::ZonedModel;
 use crate::engine::strat_engine::backstore::metadata::{StaticHeader, BDA};
 use crate::engine::strat_engine::backstore::util::get_stratis_block_devices;
 
 /// Find all Stratis devices.
 ///
-/// Returns a map of pool uuids to a map of devices to devnodes for each pool.
-pub fn find_all() -> StratisResult<HashMap<PoolUuid, HashMap<Device, PathBuf>>> {
-    let mut pool_map = HashMap::new();
+/// Returns a map of pool uuids to a map of device uuids to devices and
+/// devnodes for each pool.
+/// If more than one device claims the same device uuid, only the first
+/// found is used and an error is logged.
+/// Pools which have a device requiring features this version of stratisd
+/// does not support are omitted from the map, and an error is logged. Their
+/// UUIDs are returned alongside the map, since such a pool may be in use by
+/// a later version of stratisd.
+#[allow(clippy::type_complexity)]
+pub fn find_all() -> StratisResult<(
+    HashMap<PoolUuid, HashMap<DevUuid, (Device, PathBuf)>>,
+    HashSet<PoolUuid>,
+)> {
+    let mut pool_map: HashMap<PoolUuid, HashMap<DevUuid, (Device, PathBuf)>> = HashMap::new();
+    let mut refused_pools = HashSet::new();
 
     for devnode in get_stratis_block_devices()? {
         match devnode_to_devno(&devnode)? {
             None => continue,
             Some(devno) => {
-                if let Some((pool_uuid, _)) = StaticHeader::device_identifiers(
-                    &mut OpenOptions::new().read(true).open(&devnode)?,
-                )? {
-                    pool_map
-                        .entry(pool_uuid)
-                        .or_insert_with(HashMap::new)
-                        .insert(Device::from(devno), devnode);
+                let mut f = OpenOptions::new().read(true).open(&devnode)?;
+                if let Some((pool_uuid, dev_uuid)) = StaticHeader::device_identifiers(&mut f)? {
+                    if let Err(err) = StaticHeader::check_device_features(&mut f) {
+                        error!(
+                            "refusing to set up pool with UUID {}: devnode {}: {}",
+                            pool_uuid,
+                            devnode.display(),
+                            err
+                        );
+                        refused_pools.insert(pool_uuid);
+                    }
+                    let devices = pool_map.entry(pool_uuid).or_insert_with(HashMap::new);
+                    if let Some((_, other)) = devices.get(&dev_uuid) {
+                        error!(
+                            "devnodes {} and {} both claim device UUID {} in pool with UUID {}; ignoring {}",
+                            other.display(),
+                            devnode.display(),
+                            dev_uuid,
+                            pool_uuid,
+                            devnode.display()
+                        );
+                        continue;
+                    }
+                    devices.insert(dev_uuid, (Device::from(devno), devnode));
                 }
             }
         }
     }
-    Ok(pool_map)
+    for pool_uuid in &refused_pools {
+        pool_map.remove(pool_uuid);
+    }
+    Ok((pool_map, refused_pools))
+}
+
+/// The maximum number of devices from which metadata is read concurrently.
+const MAX_CONCURRENT_READS: usize = 8;
+
+/// A BDA and the most recent metadata read using it, if any.
+type BDAAndState = (BDA, Option<Vec<u8>>);
+
+/// Read the BDA from the device and, if the device belongs to the pool with
+/// the given UUID, the most recent metadata that the BDA records.
+/// Return None if the device has no BDA or belongs to another pool.
+/// A failure to read the metadata, e.g., because of a CRC error, is not an
+/// error, since the metadata may be found on other devices; in that case
+/// no metadata is returned with the BDA.
+fn read_bda_and_state(pool_uuid: PoolUuid, devnode: &Path) -> StratisResult<Option<BDAAndState>> {
+    let mut f = OpenOptions::new().read(true).open(devnode)?;
+    match BDA::load(&mut f)? {
+        Some(bda) => {
+            if bda.pool_uuid() != pool_uuid {
+                return Ok(None);
+            }
+            let state = bda.load_state(&mut f).unwrap_or_else(|err| {
+                warn!(
+                    "failed to read metadata from {}: {:?}",
+                    devnode.display(),
+                    err
+                );
+                None
+            });
+            Ok(Some((bda, state)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Read the BDAs and the most recent metadata from all the devices,
+/// reading from no more than MAX_CONCURRENT_READS devices at once.
+/// Return the results in the order of the devices given.
+fn read_all_bdas_and_states(
+    pool_uuid: PoolUuid,
+    devnodes: Vec<PathBuf>,
+) -> Vec<(PathBuf, StratisResult<Option<BDAAndState>>)> {
+    let num_devnodes = devnodes.len();
+    let devnodes = Arc::new(devnodes);
+    let next = Arc::new(AtomicUsize::new(0));
+    let (sender, receiver) = mpsc::channel();
+
+    let workers = (0..cmp::min(num_devnodes, MAX_CONCURRENT_READS))
+        .map(|_| {
+            let devnodes = Arc::clone(&devnodes);
+            let next = Arc::clone(&next);
+            let sender = sender.clone();
+            thread::spawn(move || loop {
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                if index >= devnodes.len() {
+                    break;
+                }
+                let result = read_bda_and_state(pool_uuid, &devnodes[index]);
+                if sender.send((index, result)).is_err() {
+                    break;
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    drop(sender);
+
+    let mut results = receiver.iter().collect::<Vec<_>>();
+    for worker in workers {
+        // A worker panics only if a read panics, in which case the
+        // result for that device is missing, and is reported below.
+        let _ = worker.join();
+    }
+    results.sort_by_key(|&(index, _)| index);
+
+    let mut results = results.into_iter().peekable();
+    (0..num_devnodes)
+        .map(|index| {
+            let devnode = devnodes[index].clone();
+            match results.peek() {
+                Some(&(i, _)) if i == index => {
+                    let (_, result) = results.next().expect("peeked");
+                    (devnode, result)
+                }
+                _ => {
+                    let err_msg = format!("failed to read BDA from {}", devnode.display());
+                    (
+                        devnode,
+                        Err(StratisError::Engine(ErrorEnum::Error, err_msg)),
+                    )
+                }
+            }
+        })
+        .collect()
 }
 
 /// Get the most recent metadata from a set of Devices for a given pool UUID.
+/// The BDAs and metadata are read from the devices concurrently.
+/// If no copy of the most recent generation of metadata can be read, use
+/// the most recent generation that can be read. Log the devices that hold
+/// metadata older than the generation selected.
 /// Returns None if no metadata found for this pool.
 #[allow(clippy::implicit_hasher)]
 pub fn get_metadata(
     pool_uuid: PoolUuid,
-    devnodes: &HashMap<Device, PathBuf>,
+    devnodes: &HashMap<DevUuid, (Device, PathBuf)>,
 ) -> StratisResult<Option<PoolSave>> {
-    // Get pairs of device nodes and matching BDAs
+    // Get device nodes and matching BDAs, with the metadata read from each.
     // If no BDA, or BDA UUID does not match pool UUID, skip.
     // If there is an error reading the BDA, error. There could have been
     // vital information on that BDA, for example, it may have contained
     // the newest metadata.
     let mut bdas = Vec::new();
-    for devnode in devnodes.values() {
-        let bda = BDA::load(&mut OpenOptions::new().read(true).open(devnode)?)?;
-        if let Some(bda) = bda {
-            if bda.pool_uuid() == pool_uuid {
-                bdas.push((devnode, bda));
-            }
+    for (devnode, result) in read_all_bdas_and_states(
+        pool_uuid,
+        devnodes
+            .values()
+            .map(|(_, devnode)| devnode.clone())
+            .collect(),
+    ) {
+        if let Some((bda, state)) = result? {
+            bdas.push((devnode, bda, state));
         }
     }
 
-    // Most recent time should never be None if this was a properly
-    // created pool; this allows for the method to be called in other
-    // circumstances.
-    let most_recent_time = {
-        match bdas
-            .iter()
-            .filter_map(|&(_, ref bda)| bda.last_update_time())
-            .max()
-        {
-            Some(time) => time,
-            None => return Ok(None),
-        }
+    // The generation of the metadata on each BDA, if any, ordered by
+    // sequence number. The sequence number, rather than the time, identifies
+    // the most recent metadata, so that a clock that has been set back can
+    // not cause stale metadata to be selected. The time is compared only
+    // to order metadata with equal sequence numbers, which were written
+    // before sequence numbers were recorded.
+    let generation = |bda: &BDA| {
+        bda.last_sequence()
+            .map(|seq| (seq, bda.last_update_time().cloned()))
     };
 
-    // Try to read from all available devnodes that could contain most
-    // recent metadata. In the event of errors, continue to try until all are
-    // exhausted.
-    for &(devnode, ref bda) in bdas
+    let mut generations = bdas
         .iter()
-        .filter(|&&(_, ref bda)| bda.last_update_time() == Some(most_recent_time))
-    {
-        let poolsave = OpenOptions::new()
-            .read(true)
-            .open(devnode)
-            .ok()
-            .and_then(|mut f| bda.load_state(&mut f).ok())
-            .and_then(|opt| opt)
-            .and_then(|data| serde_json::from_slice(&data).ok());
+        .filter_map(|(_, bda, _)| generation(bda))
+        .collect::<Vec<_>>();
+    generations.sort();
+    generations.dedup();
+
+    // Most recent generation should never be None if this was a properly
+    // created pool; this allows for the method to be called in other
+    // circumstances.
+    if generations.is_empty() {
+        return Ok(None);
+    }
+
+    // Use the metadata from any device that contains the most recent
+    // generation of metadata. If none could be read or parsed, fall back
+    // on the next most recent generation.
+    for gen in generations.iter().rev() {
+        let poolsave = bdas
+            .iter()
+            .filter(|(_, bda, _)| generation(bda) == Some(*gen))
+            .filter_map(|(_, _, state)| state.as_ref())
+            .filter_map(|data| serde_json::from_slice::<PoolSave>(data).ok())
+            .next();
 
         if poolsave.is_some() {
+            if Some(gen) != generations.last() {
+                warn!(
+                    "no valid metadata of generation {} found for pool with UUID {}; using generation {}",
+                    generations.last().expect("!generations.is_empty()").0,
+                    pool_uuid,
+                    gen.0
+                );
+            }
+
+            let behind = bdas
+                .iter()
+                .filter(|(_, bda, _)| generation(bda) < Some(*gen))
+                .map(|(devnode, _, _)| devnode.to_str().expect("Unix is utf-8"))
+                .collect::<Vec<&str>>();
+            if !behind.is_empty() {
+                info!(
+                    "members of pool with UUID {} with metadata older than generation {}: {}",
+                    pool_uuid,
+                    gen.0,
+                    behind.join(", ")
+                );
+            }
+
             return Ok(poolsave);
         }
     }
 
     // If no data has yet returned, we have an error. That is, we should have
-    // some metadata, because we have a most recent time, but we failed to
-    // get any.
-    let err_str = "timestamp indicates data was written, but no data successfully read";
+    // some metadata, because we have a most recent generation, but we failed
+    // to get any.
+    let err_str = "sequence number indicates data was written, but no data successfully read";
     Err(StratisError::Engine(ErrorEnum::NotFound, err_str.into()))
 }
 
+/// Get the sequence number of the most recent metadata written to any of the
+/// given devices which belong to the pool, whether or not that metadata can
+/// be read. An MDA region header that is damaged is passed over, so that
+/// this may be used for a pool whose MDA regions are all damaged.
+/// Returns an error if the static header of a BDA can not be read.
+pub fn get_last_sequence(
+    pool_uuid: PoolUuid,
+    devnodes: &HashMap<DevUuid, (Device, PathBuf)>,
+) -> StratisResult<Option<u64>> {
+    let mut last_sequence = None;
+    for (_, devnode) in devnodes.values() {
+        let mut f = OpenOptions::new().read(true).open(devnode)?;
+        if let Some((uuid, sequence)) = BDA::readable_last_sequence(&mut f)? {
+            if uuid == pool_uuid {
+                last_sequence = cmp::max(last_sequence, sequence);
+            }
+        }
+    }
+    Ok(last_sequence)
+}
+
 /// Get all the blockdevs corresponding to this pool that can be obtained from
 /// the given devices. Sort the blockdevs in the order in which they were
 /// recorded in the metadata.
@@ -123,7 +311,7 @@ pub fn get_metadata(
 pub fn get_blockdevs(
     pool_uuid: PoolUuid,
     backstore_save: &BackstoreSave,
-    devnodes: &HashMap<Device, PathBuf>,
+    devnodes: &HashMap<DevUuid, (Device, PathBuf)>,
 ) -> StratisResult<(Vec<StratBlockDev>, Vec<StratBlockDev>)> {
     let recorded_data_map: HashMap<DevUuid, (usize, &BaseBlockDevSave)> = backstore_save
         .data_tier
@@ -226,21 +414,25 @@ pub fn get_blockdevs(
         // available to be allocated. If this fails, the most likely
         // conclusion is metadata corruption.
         let segments = segment_table.get(&dev_uuid);
-        Ok((
-            tier,
-            StratBlockDev::new(
-                device,
-                devnode.to_owned(),
-                bda,
-                segments.unwrap_or(&vec![]),
-                bd_save.user_info.clone(),
-                bd_save.hardware_info.clone(),
-            )?,
-        ))
+        let mut blockdev = StratBlockDev::new(
+            device,
+            devnode.to_owned(),
+            bda,
+            segments.unwrap_or(&vec![]),
+            bd_save.user_info.clone(),
+            bd_save.hardware_info.clone(),
+            bd_save
+                .zoned
+                .as_ref()
+                .and_then(|zoned| ZonedModel::parse(zoned))
+                .unwrap_or(ZonedModel::None),
+        )?;
+        blockdev.set_maintenance(bd_save.maintenance);
+        Ok((tier, blockdev))
     }
 
     let (mut datadevs, mut cachedevs): (Vec<StratBlockDev>, Vec<StratBlockDev>) = (vec![], vec![]);
-    for (device, devnode) in devnodes {
+    for (device, devnode) in devnodes.values() {
         let bda = BDA::load(&mut OpenOptions::new().read(true).open(devnode)?)?.ok_or_else(|| {
             StratisError::Engine(ErrorEnum::NotFound,
                                                  format!("Device {} with devnode {} was previously determined to belong to pool with uuid {} but no BDA was found",
@@ -313,3 +505,78 @@ pub fn get_blockdevs(
 
     Ok((datadevs, cachedevs))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use chrono::Utc;
+    use tempfile;
+    use uuid::Uuid;
+
+    use devicemapper::{Bytes, IEC};
+
+    use crate::engine::strat_engine::backstore::MIN_MDA_SECTORS;
+
+    use super::*;
+
+    #[test]
+    /// Verify that when reading from more devices than may be read
+    /// concurrently, each device's BDA and metadata are returned in the
+    /// order of the devices given, and that devices without a BDA or
+    /// belonging to another pool yield None.
+    fn test_read_all_bdas_and_states() {
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("stratis_testing")
+            .tempdir()
+            .unwrap();
+
+        let pool_uuid = Uuid::new_v4();
+        let other_pool_uuid = Uuid::new_v4();
+        let dev_size = Bytes(IEC::Mi) + MIN_MDA_SECTORS.bytes();
+
+        let num_devnodes = 2 * MAX_CONCURRENT_READS + 1;
+        let mut devnodes = Vec::new();
+        for index in 0..num_devnodes {
+            let devnode = tmp_dir.path().join(format!("dev{}", index));
+            let mut f = File::create(&devnode).unwrap();
+            f.set_len(*dev_size).unwrap();
+
+            let owner = match index % 3 {
+                0 => Some(pool_uuid),
+                1 => Some(other_pool_uuid),
+                _ => None,
+            };
+            if let Some(owner) = owner {
+                let mut bda = BDA::initialize(
+                    &mut f,
+                    owner,
+                    Uuid::new_v4(),
+                    MIN_MDA_SECTORS,
+                    dev_size.sectors(),
+                    Utc::now().timestamp() as u64,
+                )
+                .unwrap();
+                bda.save_state(1, &Utc::now(), format!("{}", index).as_bytes(), &mut f)
+                    .unwrap();
+            }
+            devnodes.push(devnode);
+        }
+
+        let results = read_all_bdas_and_states(pool_uuid, devnodes.clone());
+        assert_eq!(results.len(), num_devnodes);
+        for (index, (devnode, result)) in results.into_iter().enumerate() {
+            assert_eq!(devnode, devnodes[index]);
+            match result.unwrap() {
+                Some((bda, state)) => {
+                    assert_eq!(index % 3, 0);
+                    assert_eq!(bda.pool_uuid(), pool_uuid);
+                    assert_eq!(state, Some(format!("{}", index).into_bytes()));
+                }
+                None => assert_ne!(index % 3, 0),
+            }
+        }
+
+        assert!(read_all_bdas_and_states(pool_uuid, vec![]).is_empty());
+    }
+}
@@ -52,6 +52,42 @@ pub fn hw_lookup(dev_node_search: &Path) -> StratisResult<Option<String>> {
     Ok(dev.and_then(|dev| dev.get("ID_WWN").and_then(|i| Some(i.clone()))))
 }
 
+/// Choose, from the space separated links in the udev DEVLINKS property of a
+/// device, a link to the device that is stable across reboots. A
+/// /dev/disk/by-id link is preferred to a /dev/disk/by-path link. Among
+/// several by-id links, a WWN based link is preferred. Otherwise, the first
+/// link in lexicographic order is chosen, so that the choice is consistent.
+fn choose_persistent_link(devlinks: &str) -> Option<PathBuf> {
+    let mut links = devlinks.split_whitespace().collect::<Vec<&str>>();
+    links.sort();
+
+    let by_id = links
+        .iter()
+        .cloned()
+        .filter(|l| l.starts_with("/dev/disk/by-id/"))
+        .collect::<Vec<&str>>();
+    by_id
+        .iter()
+        .cloned()
+        .find(|l| l.starts_with("/dev/disk/by-id/wwn-"))
+        .or_else(|| by_id.first().cloned())
+        .or_else(|| {
+            links
+                .iter()
+                .cloned()
+                .find(|l| l.starts_with("/dev/disk/by-path/"))
+        })
+        .map(PathBuf::from)
+}
+
+/// Lookup a path to the device which is stable across reboots, e.g.,
+/// /dev/disk/by-id/wwn-0x5000c500a0b1c2d3, from the udev db using the device
+/// node eg. /dev/sda. Return None if udev records no such path.
+pub fn persistent_devnode_lookup(dev_node_search: &Path) -> StratisResult<Option<PathBuf>> {
+    let dev = get_udev_block_device(dev_node_search)?;
+    Ok(dev.and_then(|dev| dev.get("DEVLINKS").and_then(|l| choose_persistent_link(l))))
+}
+
 /// Collect paths for all the block devices which are not individual multipath paths and which
 /// appear to be empty from a udev perspective.
 fn get_all_empty_devices() -> StratisResult<Vec<PathBuf>> {
@@ -108,3 +144,33 @@ pub fn get_stratis_block_devices() -> StratisResult<Vec<PathBuf>> {
         Ok(devices)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Verify that a WWN based by-id link is preferred, then any by-id link,
+    /// then a by-path link, and that other links are never chosen.
+    fn test_choose_persistent_link() {
+        let by_path = "/dev/disk/by-path/pci-0000:00:1f.2-ata-1";
+        let by_id = "/dev/disk/by-id/ata-SAMSUNG_SSD_S2RBNX0H";
+        let wwn = "/dev/disk/by-id/wwn-0x5002538d40000000";
+        let by_uuid = "/dev/disk/by-uuid/0f8ac8a6";
+
+        assert_eq!(
+            choose_persistent_link(&[by_path, by_id, wwn, by_uuid].join(" ")),
+            Some(PathBuf::from(wwn))
+        );
+        assert_eq!(
+            choose_persistent_link(&[by_uuid, by_path, by_id].join(" ")),
+            Some(PathBuf::from(by_id))
+        );
+        assert_eq!(
+            choose_persistent_link(&[by_uuid, by_path].join(" ")),
+            Some(PathBuf::from(by_path))
+        );
+        assert_eq!(choose_persistent_link(by_uuid), None);
+        assert_eq!(choose_persistent_link(""), None);
+    }
+}
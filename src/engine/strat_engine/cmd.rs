@@ -111,16 +111,18 @@ fn get_executable(name: &str) -> &Path {
         .expect("verify_binaries() was previously called and returned no error")
 }
 
-/// Create a filesystem on devnode.
-pub fn create_fs(devnode: &Path, uuid: Uuid) -> StratisResult<()> {
-    execute_cmd(
-        Command::new(get_executable(MKFS_XFS).as_os_str())
-            .arg("-f")
-            .arg("-q")
-            .arg(&devnode)
-            .arg("-m")
-            .arg(format!("uuid={}", uuid)),
-    )
+/// Create a filesystem on devnode, with the given label, if any.
+pub fn create_fs(devnode: &Path, uuid: Uuid, label: Option<&str>) -> StratisResult<()> {
+    let mut cmd = Command::new(get_executable(MKFS_XFS).as_os_str());
+    cmd.arg("-f")
+        .arg("-q")
+        .arg(&devnode)
+        .arg("-m")
+        .arg(format!("uuid={}", uuid));
+    if let Some(label) = label {
+        cmd.arg("-L").arg(label);
+    }
+    execute_cmd(&mut cmd)
 }
 
 /// Use the xfs_growfs command to expand a filesystem mounted at the given
@@ -143,6 +145,25 @@ pub fn set_uuid(devnode: &Path, uuid: Uuid) -> StratisResult<()> {
     )
 }
 
+/// Set a new label for the unmounted filesystem on the devnode.
+/// xfs_db reads the label from a quoted string in its command line, so a
+/// label containing a double quote or a backslash is rejected rather than
+/// passed to it.
+pub fn set_label(devnode: &Path, label: &str) -> StratisResult<()> {
+    if label.contains('"') || label.contains('\\') {
+        return Err(StratisError::Error(format!(
+            "xfs_db can not set the label \"{}\", which contains a double quote or a backslash",
+            label
+        )));
+    }
+    execute_cmd(
+        Command::new(get_executable(XFS_DB).as_os_str())
+            .arg("-x")
+            .arg(format!("-c label \"{}\"", label))
+            .arg(&devnode),
+    )
+}
+
 /// Call thin_check on a thinpool
 pub fn thin_check(devnode: &Path) -> StratisResult<()> {
     execute_cmd(
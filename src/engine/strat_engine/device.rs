@@ -5,12 +5,17 @@
 // Functions for dealing with devices.
 
 use std::fs::{File, OpenOptions};
-use std::io::{self, BufWriter, Cursor, Seek, SeekFrom, Write};
+use std::io::{self, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
 use std::path::Path;
 
+use libc;
+
 use devicemapper::{Sectors, IEC, SECTOR_SIZE};
 
-use crate::stratis::StratisResult;
+use crate::stratis::{StratisError, StratisResult};
+
+ioctl_write_ptr_bad!(blkroset, request_code_none!(0x12, 93), libc::c_int);
 
 /// The SyncAll trait unifies the File type with other types that do
 /// not implement sync_all(). The purpose is to allow testing of methods
@@ -66,6 +71,127 @@ pub fn write_sectors<P: AsRef<Path>>(
     Ok(())
 }
 
+/// Copy length sectors from src at src_offset to dst at dst_offset.
+/// Any pages of src in the range which are in the page cache are dropped
+/// first, so that what is read is what is on the device, even if it was
+/// last written through some other device stacked on it.
+pub fn copy_sectors<P: AsRef<Path>, Q: AsRef<Path>>(
+    src: P,
+    src_offset: Sectors,
+    dst: Q,
+    dst_offset: Sectors,
+    length: Sectors,
+) -> StratisResult<()> {
+    let mut src = OpenOptions::new().read(true).open(src)?;
+    let mut dst = OpenOptions::new().write(true).open(dst)?;
+
+    // The advice is only advice; if it is not taken, the copy may read
+    // stale data, so an error is returned.
+    let advice = unsafe {
+        libc::posix_fadvise(
+            src.as_raw_fd(),
+            *src_offset.bytes() as libc::off_t,
+            *length.bytes() as libc::off_t,
+            libc::POSIX_FADV_DONTNEED,
+        )
+    };
+    if advice != 0 {
+        return Err(io::Error::from_raw_os_error(advice).into());
+    }
+
+    src.seek(SeekFrom::Start(*src_offset.bytes()))?;
+    dst.seek(SeekFrom::Start(*dst_offset.bytes()))?;
+
+    let mut buf = vec![0u8; IEC::Mi as usize];
+    let mut remaining = *length.bytes();
+    while remaining != 0 {
+        let chunk = if remaining < buf.len() as u64 {
+            remaining as usize
+        } else {
+            buf.len()
+        };
+        src.read_exact(&mut buf[..chunk])?;
+        dst.write_all(&buf[..chunk])?;
+        remaining -= chunk as u64;
+    }
+
+    dst.sync_all()?;
+    Ok(())
+}
+
+/// Read length sectors from path at offset, discarding what is read, to
+/// verify that they can be read. As for copy_sectors(), pages in the page
+/// cache are dropped first, so that it is the device that is read.
+pub fn read_sectors<P: AsRef<Path>>(
+    path: P,
+    offset: Sectors,
+    length: Sectors,
+) -> StratisResult<()> {
+    let mut f = OpenOptions::new().read(true).open(path)?;
+
+    let advice = unsafe {
+        libc::posix_fadvise(
+            f.as_raw_fd(),
+            *offset.bytes() as libc::off_t,
+            *length.bytes() as libc::off_t,
+            libc::POSIX_FADV_DONTNEED,
+        )
+    };
+    if advice != 0 {
+        return Err(io::Error::from_raw_os_error(advice).into());
+    }
+
+    f.seek(SeekFrom::Start(*offset.bytes()))?;
+
+    let mut buf = vec![0u8; IEC::Mi as usize];
+    let mut remaining = *length.bytes();
+    while remaining != 0 {
+        let chunk = if remaining < buf.len() as u64 {
+            remaining as usize
+        } else {
+            buf.len()
+        };
+        f.read_exact(&mut buf[..chunk])?;
+        remaining -= chunk as u64;
+    }
+    Ok(())
+}
+
+/// Copy length sectors from the start of src to the start of dst in
+/// blocks of block_size, skipping any block that is entirely zero.
+/// dst must already read as zero wherever it is not written, e.g., a
+/// newly created thin device, so that skipped blocks are not left
+/// unprovisioned with anything but zeros.
+pub fn copy_nonzero_sectors<P: AsRef<Path>, Q: AsRef<Path>>(
+    src: P,
+    dst: Q,
+    length: Sectors,
+    block_size: Sectors,
+) -> StratisResult<()> {
+    let mut src = OpenOptions::new().read(true).open(src)?;
+    let mut dst = OpenOptions::new().write(true).open(dst)?;
+
+    let mut buf = vec![0u8; *block_size.bytes() as usize];
+    let mut offset = 0;
+    while offset != *length.bytes() {
+        let remaining = *length.bytes() - offset;
+        let chunk = if remaining < buf.len() as u64 {
+            remaining as usize
+        } else {
+            buf.len()
+        };
+        src.read_exact(&mut buf[..chunk])?;
+        if buf[..chunk].iter().any(|b| *b != 0) {
+            dst.seek(SeekFrom::Start(offset))?;
+            dst.write_all(&buf[..chunk])?;
+        }
+        offset += chunk as u64;
+    }
+
+    dst.sync_all()?;
+    Ok(())
+}
+
 /// Zero sectors at the given offset for length sectors.
 pub fn wipe_sectors<P: AsRef<Path>>(
     path: P,
@@ -74,3 +200,15 @@ pub fn wipe_sectors<P: AsRef<Path>>(
 ) -> StratisResult<()> {
     write_sectors(path, offset, length, &[0u8; SECTOR_SIZE])
 }
+
+/// Mark the block device at devnode read-only, so that the kernel refuses
+/// to write to it, or writable again.
+pub fn set_blkdev_read_only(devnode: &Path, read_only: bool) -> StratisResult<()> {
+    let file = File::open(devnode)?;
+    let val: libc::c_int = if read_only { 1 } else { 0 };
+
+    match unsafe { blkroset(file.as_raw_fd(), &val) } {
+        Err(x) => Err(StratisError::Nix(x)),
+        Ok(_) => Ok(()),
+    }
+}
@@ -4,15 +4,19 @@
 
 // Get ability to instantiate a devicemapper context.
 
+use std::collections::HashSet;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::sync::{Once, ONCE_INIT};
 
-use devicemapper::{DmResult, DM};
+use devicemapper::{DevId, DmNameBuf, DmOptions, DmResult, DM};
 
+use crate::engine::PoolUuid;
 use crate::stratis::{ErrorEnum, StratisError, StratisResult};
 
 use crate::engine::engine::Eventable;
 
+use crate::engine::strat_engine::names::dm_name_to_pool_uuid;
+
 static INIT: Once = ONCE_INIT;
 static mut DM_CONTEXT: Option<DmResult<DM>> = None;
 
@@ -38,6 +42,37 @@ pub fn get_dm() -> &'static DM {
     )
 }
 
+/// Remove the DM devices that Stratis created for pools other than those in
+/// pools, e.g., devices left behind if stratisd exited while setting up or
+/// tearing down a pool whose devices have since gone. pools must hold every
+/// pool that has Stratis blockdevs, set up or not: a device of a pool that
+/// is set up has been adopted by it, and one of a pool that is not, e.g.,
+/// an exported or incomplete pool, may still be in use; both are left
+/// alone.
+/// Devices are removed in passes, each removing the devices that no longer
+/// have any device stacked on them, until a pass makes no progress.
+/// Returns the names of the orphaned devices that could not be removed,
+/// e.g., because a filesystem on one of them is still mounted.
+pub fn remove_orphaned_devices(pools: &HashSet<PoolUuid>) -> StratisResult<Vec<DmNameBuf>> {
+    let mut orphans = orphaned_devices(pools)?;
+
+    loop {
+        let count = orphans.len();
+        orphans.retain(|name| {
+            match get_dm().device_remove(&DevId::Name(name), &DmOptions::new()) {
+                Ok(_) => {
+                    info!("Removed orphaned DM device {}", name.to_string());
+                    false
+                }
+                Err(_) => true,
+            }
+        });
+        if orphans.is_empty() || orphans.len() == count {
+            return Ok(orphans);
+        }
+    }
+}
+
 impl Eventable for DM {
     /// Get file we'd like to have monitored for activity
     fn get_pollable_fd(&self) -> RawFd {
@@ -49,3 +84,16 @@ impl Eventable for DM {
         Ok(())
     }
 }
+
+/// The DM devices that Stratis created for pools other than those in pools.
+pub fn orphaned_devices(pools: &HashSet<PoolUuid>) -> StratisResult<Vec<DmNameBuf>> {
+    Ok(get_dm()
+        .list_devices()?
+        .into_iter()
+        .map(|(name, _, _)| name)
+        .filter(|name| match dm_name_to_pool_uuid(&name.to_string()) {
+            Some(pool_uuid) => !pools.contains(&pool_uuid),
+            None => false,
+        })
+        .collect())
+}
@@ -3,39 +3,325 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use std::clone::Clone;
-use std::collections::HashMap;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::fs::read_to_string;
 use std::path::{Path, PathBuf};
 
-use devicemapper::{Device, DmNameBuf};
+use serde_json::{self, Value};
+
+use devicemapper::{Bytes, Device, DmNameBuf};
 
 use crate::engine::{
-    devlinks, Engine, EngineEvent, Name, Pool, PoolUuid, Redundancy, RenameAction,
+    devlinks, AllocationStrategy, BlockDev, ChunkSize, DevUuid, Engine, EngineEvent, Filesystem,
+    FilesystemUuid, Name, Pool, PoolId, PoolPlan, PoolUuid, Redundancy, RenameAction,
+    SimDevProperties, SimFailureModel, SimOperation, ThinRepairSummary,
 };
 use crate::stratis::{ErrorEnum, StratisError, StratisResult};
 
+use crate::engine::check::{findings, CheckFinding, Discrepancy};
 use crate::engine::engine::Eventable;
 use crate::engine::event::get_engine_listener_list;
+use crate::engine::jobs::{JobKind, JobStatus, JobTable, JobUuid, JOB_STEP_TIME};
+use crate::engine::reconcile::{self, PoolSpec, ReconcileAction};
+use crate::engine::records::{
+    blockdev_records, filesystem_records, pool_records, BlockDevRecord, FilesystemRecord,
+    PoolRecord,
+};
+use crate::engine::requests::CompletedRequests;
 use crate::engine::structures::Table;
 
-use crate::engine::strat_engine::backstore::{find_all, get_metadata, is_stratis_device};
+use crate::engine::strat_engine::backstore::{
+    find_all, get_last_sequence, get_metadata, is_stratis_device, restore_state,
+    set_metadata_redundancy, set_min_dev_size,
+};
 #[cfg(test)]
 use crate::engine::strat_engine::cleanup::teardown_pools;
 use crate::engine::strat_engine::cmd::verify_binaries;
-use crate::engine::strat_engine::dm::{get_dm, get_dm_init};
+use crate::engine::strat_engine::device::read_sectors;
+use crate::engine::strat_engine::dm::{
+    get_dm, get_dm_init, orphaned_devices, remove_orphaned_devices,
+};
 use crate::engine::strat_engine::names::validate_name;
-use crate::engine::strat_engine::pool::{check_metadata, StratPool};
+use crate::engine::strat_engine::pool::{check_metadata, host_id, StratPool};
+use crate::engine::strat_engine::serde_structs::{MetadataBackupSave, PoolSave};
+use crate::engine::strat_engine::thinpool::set_space_thresholds;
 
 const REQUIRED_DM_MINOR_VERSION: u32 = 37;
 
+/// A description of a pool and its devices, for use in log messages.
+fn info_string(pool_uuid: PoolUuid, devices: &HashMap<DevUuid, (Device, PathBuf)>) -> String {
+    let dev_paths = devices
+        .values()
+        .map(|(_, p)| p.to_str().expect("Unix is utf-8"))
+        .collect::<Vec<&str>>()
+        .join(" ,");
+    format!("(pool UUID: {}, devnodes: {})", pool_uuid, dev_paths)
+}
+
+/// Read the most recent metadata for a pool from its devices.
+/// Return an error if there is none.
+fn read_metadata(
+    pool_uuid: PoolUuid,
+    devices: &HashMap<DevUuid, (Device, PathBuf)>,
+) -> StratisResult<PoolSave> {
+    get_metadata(pool_uuid, devices)?.ok_or_else(|| {
+        let err_msg = format!("no metadata found for {}", info_string(pool_uuid, devices));
+        StratisError::Engine(ErrorEnum::NotFound, err_msg)
+    })
+}
+
+/// The host on which the pool was last set up, if it is known and is not
+/// this host.
+fn foreign_host(metadata: &PoolSave) -> Option<&str> {
+    let this_host = host_id();
+    match metadata.host {
+        Some(ref host) if !this_host.is_empty() && *host != this_host => Some(host),
+        _ => None,
+    }
+}
+
+/// Return an error if any of the devices recorded in the metadata are not
+/// among the devices found for the pool.
+fn check_complete(
+    pool_uuid: PoolUuid,
+    devices: &HashMap<DevUuid, (Device, PathBuf)>,
+    metadata: &PoolSave,
+) -> StratisResult<()> {
+    let backstore = &metadata.backstore;
+    let missing = backstore
+        .data_tier
+        .blockdev
+        .devs
+        .iter()
+        .chain(
+            backstore
+                .cache_tier
+                .iter()
+                .flat_map(|cache_tier| cache_tier.blockdev.devs.iter()),
+        )
+        .map(|bd_save| bd_save.uuid)
+        .filter(|dev_uuid| !devices.contains_key(dev_uuid))
+        .map(|dev_uuid| dev_uuid.to_simple_ref().to_string())
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        let err_msg = format!(
+            "devices with UUIDs {} of pool with name \"{}\" are missing for {}",
+            missing.join(", "),
+            &metadata.name,
+            info_string(pool_uuid, devices)
+        );
+        return Err(StratisError::Engine(ErrorEnum::NotFound, err_msg));
+    }
+    Ok(())
+}
+
 /// Setup a pool from constituent devices in the context of some already
 /// setup pools. Return an error on anything that prevents the pool
 /// being set up.
+/// A pool which has been exported, or which was last set up on another host,
+/// is not set up; it must be imported instead.
 /// Precondition: every device in devices has already been determined to belong
 /// to the pool with pool_uuid.
 pub fn setup_pool(
     pool_uuid: PoolUuid,
-    devices: &HashMap<Device, PathBuf>,
+    devices: &HashMap<DevUuid, (Device, PathBuf)>,
+    pools: &Table<StratPool>,
+) -> StratisResult<(Name, StratPool)> {
+    let metadata = read_metadata(pool_uuid, devices)?;
+
+    if let Some(ref export) = metadata.exported {
+        let err_msg = format!(
+            "pool with name \"{}\" was exported from host \"{}\"; it must be imported to be set up for {}",
+            &metadata.name,
+            export.host,
+            info_string(pool_uuid, devices)
+        );
+        return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+    }
+
+    if let Some(host) = foreign_host(&metadata) {
+        let err_msg = format!(
+            "pool with name \"{}\" was last set up on host \"{}\"; it must be imported to be set up for {}",
+            &metadata.name,
+            host,
+            info_string(pool_uuid, devices)
+        );
+        return Err(StratisError::Engine(ErrorEnum::Busy, err_msg));
+    }
+
+    setup_from_metadata(pool_uuid, devices, pools, &metadata)
+}
+
+/// Import a pool from constituent devices in the context of some already
+/// setup pools, claiming it for this host. The pool may have been exported.
+/// Unless force is true, return an error if the pool was not exported and
+/// was last set up on another host.
+/// Precondition: every device in devices has already been determined to belong
+/// to the pool with pool_uuid.
+pub fn import_pool(
+    pool_uuid: PoolUuid,
+    devices: &HashMap<DevUuid, (Device, PathBuf)>,
+    pools: &Table<StratPool>,
+    force: bool,
+) -> StratisResult<(Name, StratPool)> {
+    let metadata = read_metadata(pool_uuid, devices)?;
+
+    if metadata.exported.is_none() && !force {
+        if let Some(host) = foreign_host(&metadata) {
+            let err_msg = format!(
+                "pool with name \"{}\" may be in use by host \"{}\"; refusing to import it without force for {}",
+                &metadata.name,
+                host,
+                info_string(pool_uuid, devices)
+            );
+            return Err(StratisError::Engine(ErrorEnum::Busy, err_msg));
+        }
+    }
+
+    check_complete(pool_uuid, devices, &metadata)?;
+
+    let (pool_name, mut pool) = setup_from_metadata(pool_uuid, devices, pools, &metadata)?;
+
+    // Record this host, and that the pool is no longer exported.
+    if let Err(err) = pool.write_metadata(&pool_name) {
+        devlinks::pool_removed(&pool_name);
+        if let Err(teardown_err) = pool.teardown() {
+            warn!(
+                "failed to tear down pool {} after failing to import it: {:?}",
+                pool_name, teardown_err
+            );
+        }
+        return Err(err);
+    }
+
+    Ok((pool_name, pool))
+}
+
+/// Restore the metadata of a pool from a backup, writing it to all the
+/// pool's devices, and then import the pool.
+/// Unless force is true, return an error if the metadata on the devices is
+/// more recent than the backup.
+/// Precondition: every device in devices has already been determined to belong
+/// to the pool with the UUID recorded in the backup.
+fn restore_pool(
+    backup: &MetadataBackupSave,
+    devices: &HashMap<DevUuid, (Device, PathBuf)>,
+    pools: &Table<StratPool>,
+    force: bool,
+) -> StratisResult<(Name, StratPool)> {
+    let pool_uuid = backup.pool_uuid;
+    check_complete(pool_uuid, devices, &backup.metadata)?;
+
+    check_metadata(&backup.metadata).map_err(|e| {
+        let err_msg = format!(
+            "inconsistent metadata in backup for {}: reason: {:?}",
+            info_string(pool_uuid, devices),
+            e
+        );
+        StratisError::Engine(ErrorEnum::Invalid, err_msg)
+    })?;
+
+    let last_sequence = get_last_sequence(pool_uuid, devices)?;
+    if let Some(sequence) = last_sequence {
+        if sequence > backup.sequence && !force {
+            let err_msg = format!(
+                "metadata on devices has generation {}, more recent than generation {} of the backup; refusing to restore it without force for {}",
+                sequence,
+                backup.sequence,
+                info_string(pool_uuid, devices)
+            );
+            return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+        }
+    }
+
+    // The restored metadata must supersede whatever is on the devices.
+    let sequence = cmp::max(last_sequence.unwrap_or(0), backup.sequence) + 1;
+    let data = serde_json::to_string(&backup.metadata)?;
+    let devnodes = devices
+        .values()
+        .map(|(_, devnode)| devnode.as_path())
+        .collect::<Vec<_>>();
+    restore_state(pool_uuid, &devnodes, sequence, data.as_bytes())?;
+
+    import_pool(pool_uuid, devices, pools, true)
+}
+
+/// Remove the record of a device which is missing from the metadata of the
+/// pool to which it belonged, and write the changed metadata to the devices
+/// which remain.
+/// Returns an error if any of the pool's data is allocated from the device,
+/// since the pool has no redundancy from which that data could be recovered.
+/// Returns true if the device was forgotten, false if the metadata has no
+/// record of it.
+fn forget_device(
+    pool_uuid: PoolUuid,
+    devices: &HashMap<DevUuid, (Device, PathBuf)>,
+    dev_uuid: DevUuid,
+) -> StratisResult<bool> {
+    let mut metadata = read_metadata(pool_uuid, devices)?;
+
+    {
+        let backstore = &mut metadata.backstore;
+        let blockdev_saves = Some(&mut backstore.data_tier.blockdev).into_iter().chain(
+            backstore
+                .cache_tier
+                .iter_mut()
+                .map(|cache_tier| &mut cache_tier.blockdev),
+        );
+
+        let mut found = false;
+        for blockdev_save in blockdev_saves {
+            if !blockdev_save.devs.iter().any(|dev| dev.uuid == dev_uuid) {
+                continue;
+            }
+            if blockdev_save
+                .allocs
+                .iter()
+                .flat_map(|allocs| allocs.iter())
+                .any(|seg| seg.parent == dev_uuid)
+            {
+                let err_msg = format!(
+                    "data of pool with name \"{}\" is allocated from the device with UUID {}; forgetting it would lose that data",
+                    &metadata.name,
+                    dev_uuid.to_simple_ref()
+                );
+                return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+            }
+            blockdev_save.devs.retain(|dev| dev.uuid != dev_uuid);
+            found = true;
+        }
+        if !found {
+            return Ok(false);
+        }
+
+        // Nothing can be allocated from a cache tier without devices.
+        let cache_tier_empty = match backstore.cache_tier {
+            Some(ref cache_tier) => cache_tier.blockdev.devs.is_empty(),
+            None => false,
+        };
+        if cache_tier_empty {
+            backstore.cache_tier = None;
+        }
+    }
+
+    let sequence = get_last_sequence(pool_uuid, devices)?.unwrap_or(0) + 1;
+    let data = serde_json::to_string(&metadata)?;
+    let devnodes = devices
+        .values()
+        .map(|(_, devnode)| devnode.as_path())
+        .collect::<Vec<_>>();
+    restore_state(pool_uuid, &devnodes, sequence, data.as_bytes())?;
+    Ok(true)
+}
+
+/// Setup a pool from its constituent devices and the metadata read from
+/// them.
+fn setup_from_metadata(
+    pool_uuid: PoolUuid,
+    devices: &HashMap<DevUuid, (Device, PathBuf)>,
     pools: &Table<StratPool>,
+    metadata: &PoolSave,
 ) -> StratisResult<(Name, StratPool)> {
     // FIXME: In this method, various errors are assembled from various
     // sources and combined into strings, so that they
@@ -43,19 +329,7 @@ pub fn setup_pool(
     // error-chaining should be used here and if it is necessary
     // to log the error, the log code should be able to reduce the error
     // chain to something that can be sensibly logged.
-    let info_string = || {
-        let dev_paths = devices
-            .values()
-            .map(|p| p.to_str().expect("Unix is utf-8"))
-            .collect::<Vec<&str>>()
-            .join(" ,");
-        format!("(pool UUID: {}, devnodes: {})", pool_uuid, dev_paths)
-    };
-
-    let metadata = get_metadata(pool_uuid, devices)?.ok_or_else(|| {
-        let err_msg = format!("no metadata found for {}", info_string());
-        StratisError::Engine(ErrorEnum::NotFound, err_msg)
-    })?;
+    let info_string = || info_string(pool_uuid, devices);
 
     if pools.contains_name(&metadata.name) {
         let err_msg = format!(
@@ -66,7 +340,7 @@ pub fn setup_pool(
         return Err(StratisError::Engine(ErrorEnum::AlreadyExists, err_msg));
     }
 
-    check_metadata(&metadata)
+    check_metadata(metadata)
         .or_else(|e| {
             let err_msg = format!(
                 "inconsistent metadata for {}: reason: {:?}",
@@ -76,7 +350,7 @@ pub fn setup_pool(
             Err(StratisError::Engine(ErrorEnum::Error, err_msg))
         })
         .and_then(|_| {
-            StratPool::setup(pool_uuid, devices, &metadata).or_else(|e| {
+            StratPool::setup(pool_uuid, devices, metadata).or_else(|e| {
                 let err_msg = format!(
                     "failed to set up pool for {}: reason: {:?}",
                     info_string(),
@@ -91,17 +365,53 @@ pub fn setup_pool(
         })
 }
 
+/// The devices of a pool that is set up, keyed by device UUID.
+fn pool_devices(pool: &StratPool) -> HashMap<DevUuid, (Device, PathBuf)> {
+    pool.blockdevs()
+        .iter()
+        .filter_map(|&(dev_uuid, _)| pool.get_strat_blockdev(dev_uuid))
+        .map(|(_, bd)| (bd.uuid(), (*bd.device(), bd.devnode())))
+        .collect()
+}
+
 #[derive(Debug)]
 pub struct StratEngine {
     pools: Table<StratPool>,
 
     // Map of stratis devices that have been found but one or more stratis block devices are missing
     // which prevents the associated pools from being setup.
-    incomplete_pools: HashMap<PoolUuid, HashMap<Device, PathBuf>>,
+    // The devices of each pool are keyed by device UUID, so that a device
+    // that reappears with a different device number or devnode replaces
+    // the stale entry rather than being added alongside it.
+    incomplete_pools: HashMap<PoolUuid, HashMap<DevUuid, (Device, PathBuf)>>,
+
+    // Pools that have been stopped. Their devices are kept among the
+    // incomplete pools, but they are not set up when their devices are
+    // found, only when they are started.
+    stopped_pools: HashSet<PoolUuid>,
+
+    // Pools whose thin metadata failed thin_check and could not be
+    // repaired, with the reason. Like stopped pools, their devices are kept
+    // among the incomplete pools and they are not set up when their devices
+    // are found; only maintenance is possible until they are started, once
+    // the metadata has been repaired by other means, or their metadata is
+    // restored.
+    corrupt_pools: HashMap<PoolUuid, String>,
+
+    // Pools found at startup with a device that requires features this
+    // version does not support. They are not set up, but they may be in use
+    // by a later version, so their DM devices are not orphans.
+    refused_pools: HashSet<PoolUuid>,
 
     // Maps name of DM devices we are watching to the most recent event number
     // we've handled for each
     watched_dev_last_event_nrs: HashMap<DmNameBuf, u32>,
+
+    // Jobs started on the pools, stepped from the daemon's loop.
+    jobs: JobTable,
+
+    // The results of recently completed requests, by request ID.
+    completed_requests: CompletedRequests,
 }
 
 impl StratEngine {
@@ -128,10 +438,11 @@ impl StratEngine {
 
         devlinks::setup_dev_path()?;
 
-        let pools = find_all()?;
+        let (pools, refused_pools) = find_all()?;
 
         let mut table = Table::default();
         let mut incomplete_pools = HashMap::new();
+        let mut corrupt_pools = HashMap::new();
         for (pool_uuid, devices) in pools {
             match setup_pool(pool_uuid, &devices, &table) {
                 Ok((pool_name, pool)) => {
@@ -139,15 +450,42 @@ impl StratEngine {
                 }
                 Err(err) => {
                     warn!("no pool set up, reason: {:?}", err);
+                    if let StratisError::Engine(ErrorEnum::Corrupt, ref msg) = err {
+                        corrupt_pools.insert(pool_uuid, msg.to_owned());
+                    }
                     incomplete_pools.insert(pool_uuid, devices);
                 }
             }
         }
 
+        // Devices of the pools that were set up have been adopted by them.
+        // Those of a pool whose blockdevs were found but which was not set
+        // up, e.g., because it is exported, incomplete, or refused, may still
+        // be in use, and are left alone. Any other Stratis devices are left
+        // over from an earlier run that did not exit cleanly.
+        let known_pools = table
+            .iter()
+            .map(|(_, uuid, _)| *uuid)
+            .chain(incomplete_pools.keys().cloned())
+            .chain(refused_pools.iter().cloned())
+            .collect();
+        match remove_orphaned_devices(&known_pools) {
+            Ok(ref remaining) if !remaining.is_empty() => {
+                warn!("Could not remove orphaned DM devices: {:?}", remaining)
+            }
+            Ok(_) => {}
+            Err(err) => warn!("Could not look for orphaned DM devices: {:?}", err),
+        }
+
         let engine = StratEngine {
             pools: table,
             incomplete_pools,
+            stopped_pools: HashSet::new(),
+            corrupt_pools,
+            refused_pools,
             watched_dev_last_event_nrs: HashMap::new(),
+            jobs: JobTable::default(),
+            completed_requests: CompletedRequests::default(),
         };
 
         devlinks::cleanup_devlinks(engine.pools().iter());
@@ -155,6 +493,104 @@ impl StratEngine {
         Ok(engine)
     }
 
+    /// Set the minimum size of a device that may be added to a pool.
+    /// This is intended for development and testing, where the devices are
+    /// often small loopback or sparse file backed devices.
+    /// Returns an error if the size is too small to hold Stratis metadata.
+    pub fn set_min_dev_size(size: Bytes) -> StratisResult<()> {
+        set_min_dev_size(size)
+    }
+
+    /// Set the number of blockdevs in each pool to which pool-level metadata
+    /// is written. Writing every update to every blockdev of a large pool is
+    /// slow, so by default a subset is written.
+    /// Returns an error if the number is 0.
+    pub fn set_metadata_redundancy(copies: usize) -> StratisResult<()> {
+        set_metadata_redundancy(copies)
+    }
+
+    /// Set the percentages of a pool's data space in use at which the pool
+    /// warns that it is running out of space and at which it considers its
+    /// remaining space critical.
+    /// Returns an error unless 0 < warn_pct < crit_pct <= 100.
+    pub fn set_space_thresholds(warn_pct: u8, crit_pct: u8) -> StratisResult<()> {
+        set_space_thresholds(warn_pct, crit_pct)
+    }
+
+    /// The differences between the pools and what is on the system.
+    fn discrepancies(&self) -> StratisResult<Vec<Discrepancy>> {
+        let mut found = devlinks::check_devlinks(&self.pools())?;
+
+        found.extend(
+            orphaned_devices(&self.known_pools())?
+                .into_iter()
+                .map(|name| Discrepancy::OrphanedDmDevice {
+                    name: name.to_string(),
+                }),
+        );
+
+        for (_, pool_uuid, pool) in self.pools.iter() {
+            found.extend(pool.discrepancies(*pool_uuid)?);
+        }
+
+        found.extend(self.corrupt_pools.iter().map(|(pool_uuid, reason)| {
+            Discrepancy::CorruptThinMetadata {
+                pool_uuid: *pool_uuid,
+                reason: reason.to_owned(),
+            }
+        }));
+        Ok(found)
+    }
+
+    /// Set up again a pool that was removed from the table for a teardown
+    /// that failed part way, so that it is not left half torn down. If the
+    /// pool can not be set up, its devices are kept with those of the
+    /// incomplete pools and the pool's removal is announced.
+    fn setup_again(
+        &mut self,
+        pool_name: Name,
+        pool_uuid: PoolUuid,
+        old_pool: &StratPool,
+        devices: HashMap<DevUuid, (Device, PathBuf)>,
+    ) {
+        match setup_pool(pool_uuid, &devices, &self.pools) {
+            Ok((name, mut pool)) => {
+                pool.set_dbus_path(old_pool.get_dbus_path().clone());
+                self.pools.insert(name, pool_uuid, pool);
+            }
+            Err(err) => {
+                warn!("pool {} could not be set up again: {:?}", pool_name, err);
+                self.note_corrupt(pool_uuid, &err);
+                devlinks::pool_removed(&pool_name);
+                get_engine_listener_list().notify(&EngineEvent::PoolDestroyed {
+                    dbus_path: old_pool.get_dbus_path(),
+                    name: &pool_name,
+                    uuid: pool_uuid,
+                });
+                self.incomplete_pools.insert(pool_uuid, devices);
+            }
+        }
+    }
+
+    /// The pools whose blockdevs have been found, whether or not they are
+    /// set up. A DM device named for any other pool is an orphan.
+    fn known_pools(&self) -> HashSet<PoolUuid> {
+        self.pools
+            .iter()
+            .map(|(_, uuid, _)| *uuid)
+            .chain(self.incomplete_pools.keys().cloned())
+            .chain(self.refused_pools.iter().cloned())
+            .collect()
+    }
+
+    /// Record that the pool is corrupt if err says that it is; see
+    /// corrupt_pools.
+    fn note_corrupt(&mut self, pool_uuid: PoolUuid, err: &StratisError) {
+        if let StratisError::Engine(ErrorEnum::Corrupt, ref msg) = *err {
+            self.corrupt_pools.insert(pool_uuid, msg.to_owned());
+        }
+    }
+
     /// Teardown Stratis, preparatory to a shutdown.
     #[cfg(test)]
     pub fn teardown(self) -> StratisResult<()> {
@@ -168,6 +604,8 @@ impl Engine for StratEngine {
         name: &str,
         blockdev_paths: &[&Path],
         redundancy: Option<u16>,
+        chunk_size: ChunkSize,
+        allocation: AllocationStrategy,
     ) -> StratisResult<PoolUuid> {
         let redundancy = calculate_redundancy!(redundancy);
 
@@ -177,14 +615,72 @@ impl Engine for StratEngine {
             return Err(StratisError::Engine(ErrorEnum::AlreadyExists, name.into()));
         }
 
-        let (uuid, pool) = StratPool::initialize(name, blockdev_paths, redundancy)?;
+        let (uuid, pool) =
+            StratPool::initialize(name, blockdev_paths, redundancy, chunk_size, allocation)?;
 
         let name = Name::new(name.to_owned());
         devlinks::pool_added(&name);
+        get_engine_listener_list().notify(&EngineEvent::PoolCreated { name: &name, uuid });
         self.pools.insert(name, uuid, pool);
         Ok(uuid)
     }
 
+    fn plan_pool(
+        &self,
+        name: &str,
+        blockdev_paths: &[&Path],
+        redundancy: Option<u16>,
+        chunk_size: ChunkSize,
+    ) -> StratisResult<PoolPlan> {
+        let redundancy = calculate_redundancy!(redundancy);
+
+        validate_name(name)?;
+
+        if self.pools.contains_name(name) {
+            return Err(StratisError::Engine(ErrorEnum::AlreadyExists, name.into()));
+        }
+
+        StratPool::plan(blockdev_paths, redundancy, chunk_size)
+    }
+
+    fn clone_pool(
+        &mut self,
+        uuid: PoolUuid,
+        name: &str,
+        blockdev_paths: &[&Path],
+    ) -> StratisResult<PoolUuid> {
+        validate_name(name)?;
+
+        if self.pools.contains_name(name) {
+            return Err(StratisError::Engine(ErrorEnum::AlreadyExists, name.into()));
+        }
+
+        let pool_name = Name::new(name.to_owned());
+        devlinks::pool_added(&pool_name);
+        let cloned = match self.pools.get_by_uuid(uuid) {
+            Some((_, pool)) => pool.clone_onto(name, blockdev_paths),
+            None => Err(StratisError::Engine(
+                ErrorEnum::NotFound,
+                format!("no pool with UUID {}", uuid),
+            )),
+        };
+
+        match cloned {
+            Ok((new_uuid, pool)) => {
+                get_engine_listener_list().notify(&EngineEvent::PoolCreated {
+                    name: &pool_name,
+                    uuid: new_uuid,
+                });
+                self.pools.insert(pool_name, new_uuid, pool);
+                Ok(new_uuid)
+            }
+            Err(err) => {
+                devlinks::pool_removed(&pool_name);
+                Err(err)
+            }
+        }
+    }
+
     /// Evaluate a device node & devicemapper::Device to see if it's a valid
     /// stratis device.  If all the devices are present in the pool and the pool isn't already
     /// up and running, it will get setup and the pool uuid will be returned.
@@ -208,10 +704,10 @@ impl Engine for StratEngine {
 
                 let (name, pool) = self
                     .pools
-                    .get_by_uuid(pool_uuid)
+                    .get_mut_by_uuid(pool_uuid)
                     .expect("pools.contains_uuid(pool_uuid)");
 
-                match pool.get_strat_blockdev(device_uuid) {
+                match pool.get_mut_strat_blockdev(device_uuid) {
                     None => {
                         error!(
                             "we have a block device {:?} with pool {}, uuid = {} device uuid = {} \
@@ -231,6 +727,17 @@ impl Engine for StratEngine {
                                 block_dev.device(),
                                 device,
                             );
+                        } else if dev_node != block_dev.devnode() {
+                            // The device is the same, but udev has given
+                            // it a new name; remember the new one.
+                            info!(
+                                "block device with uuid {} in pool {} has moved from {} to {}",
+                                device_uuid,
+                                name,
+                                block_dev.devnode().display(),
+                                dev_node.display()
+                            );
+                            block_dev.set_devnode(dev_node);
                         }
                     }
                 }
@@ -241,7 +748,15 @@ impl Engine for StratEngine {
                     .remove(&pool_uuid)
                     .or_else(|| Some(HashMap::new()))
                     .expect("We just retrieved or created a HashMap");
-                devices.insert(device, dev_node);
+                // A device that has reappeared, possibly with a different
+                // device number or devnode, replaces its previous entry.
+                devices.insert(device_uuid, (device, dev_node));
+                if self.stopped_pools.contains(&pool_uuid)
+                    || self.corrupt_pools.contains_key(&pool_uuid)
+                {
+                    self.incomplete_pools.insert(pool_uuid, devices);
+                    return Ok(None);
+                }
                 match setup_pool(pool_uuid, &devices, &self.pools) {
                     Ok((pool_name, pool)) => {
                         self.pools.insert(pool_name, pool_uuid, pool);
@@ -249,6 +764,7 @@ impl Engine for StratEngine {
                     }
                     Err(err) => {
                         warn!("no pool set up, reason: {:?}", err);
+                        self.note_corrupt(pool_uuid, &err);
                         self.incomplete_pools.insert(pool_uuid, devices);
                         None
                     }
@@ -261,7 +777,8 @@ impl Engine for StratEngine {
     }
 
     fn destroy_pool(&mut self, uuid: PoolUuid) -> StratisResult<bool> {
-        if let Some((_, pool)) = self.pools.get_by_uuid(uuid) {
+        if let Some((name, pool)) = self.pools.get_by_uuid(uuid) {
+            refuse_if_quiesced!(pool; name);
             if pool.has_filesystems() {
                 return Err(StratisError::Engine(
                     ErrorEnum::Busy,
@@ -281,109 +798,607 @@ impl Engine for StratEngine {
             self.pools.insert(pool_name, uuid, pool);
             Err(err)
         } else {
+            self.jobs.cancel(uuid, "the pool was destroyed");
             devlinks::pool_removed(&pool_name);
+            get_engine_listener_list().notify(&EngineEvent::PoolDestroyed {
+                dbus_path: pool.get_dbus_path(),
+                name: &pool_name,
+                uuid,
+            });
             Ok(true)
         }
     }
 
-    fn rename_pool(&mut self, uuid: PoolUuid, new_name: &str) -> StratisResult<RenameAction> {
-        validate_name(new_name)?;
-        let old_name = rename_pool_pre!(self; uuid; new_name);
+    fn export_pool(&mut self, uuid: PoolUuid) -> StratisResult<bool> {
+        match self.pools.get_by_uuid(uuid) {
+            Some((name, pool)) => refuse_if_quiesced!(pool; name),
+            None => return Ok(false),
+        }
 
-        let (_, mut pool) = self
-            .pools
-            .remove_by_uuid(uuid)
-            .expect("Must succeed since self.pools.get_by_uuid() returned a value");
+        let (pool_name, mut pool) = match self.pools.remove_by_uuid(uuid) {
+            Some(entry) => entry,
+            None => return Ok(false),
+        };
 
-        let new_name = Name::new(new_name.to_owned());
-        if let Err(err) = pool.write_metadata(&new_name) {
-            self.pools.insert(old_name, uuid, pool);
-            Err(err)
-        } else {
-            get_engine_listener_list().notify(&EngineEvent::PoolRenamed {
-                dbus_path: pool.get_dbus_path(),
-                from: &*old_name,
-                to: &*new_name,
-            });
+        let devices = pool_devices(&pool);
 
-            self.pools.insert(new_name.clone(), uuid, pool);
-            devlinks::pool_renamed(&old_name, &new_name);
-            Ok(RenameAction::Renamed)
+        if let Err(err) = pool.mark_exported(&pool_name) {
+            self.pools.insert(pool_name, uuid, pool);
+            return Err(err);
         }
-    }
-
-    fn get_pool(&self, uuid: PoolUuid) -> Option<(Name, &dyn Pool)> {
-        get_pool!(self; uuid)
-    }
-
-    fn get_mut_pool(&mut self, uuid: PoolUuid) -> Option<(Name, &mut dyn Pool)> {
-        get_mut_pool!(self; uuid)
-    }
 
-    fn configure_simulator(&mut self, _denominator: u32) -> StratisResult<()> {
-        Ok(()) // we're not the simulator and not configurable, so just say ok
-    }
+        if let Err(err) = pool.teardown() {
+            // Clear the marker, so that the pool can be set up again.
+            if let Err(err) = pool.write_metadata(&pool_name) {
+                warn!(
+                    "exported marker of pool {} could not be cleared: {}",
+                    pool_name, err
+                );
+            }
+            self.setup_again(pool_name, uuid, &pool, devices);
+            return Err(err);
+        }
 
-    fn pools(&self) -> Vec<(Name, PoolUuid, &dyn Pool)> {
-        self.pools
-            .iter()
-            .map(|(name, uuid, pool)| (name.clone(), *uuid, pool as &dyn Pool))
-            .collect()
+        self.jobs.cancel(uuid, "the pool was exported");
+        devlinks::pool_removed(&pool_name);
+        get_engine_listener_list().notify(&EngineEvent::PoolDestroyed {
+            dbus_path: pool.get_dbus_path(),
+            name: &pool_name,
+            uuid,
+        });
+        // Remember the devices, so that the pool can be imported
+        // without waiting for them to be rediscovered.
+        self.incomplete_pools.insert(uuid, devices);
+        Ok(true)
     }
 
-    fn pools_mut(&mut self) -> Vec<(Name, PoolUuid, &mut dyn Pool)> {
-        self.pools
-            .iter_mut()
-            .map(|(name, uuid, pool)| (name.clone(), *uuid, pool as &mut dyn Pool))
-            .collect()
-    }
+    fn stop_pool(&mut self, uuid: PoolUuid) -> StratisResult<bool> {
+        match self.pools.get_by_uuid(uuid) {
+            Some((name, pool)) => refuse_if_quiesced!(pool; name),
+            None => return Ok(false),
+        }
 
-    fn get_eventable(&self) -> Option<&'static dyn Eventable> {
-        Some(get_dm())
-    }
+        let (pool_name, mut pool) = match self.pools.remove_by_uuid(uuid) {
+            Some(entry) => entry,
+            None => return Ok(false),
+        };
 
-    fn evented(&mut self) -> StratisResult<()> {
-        let device_list: HashMap<_, _> = get_dm()
-            .list_devices()?
-            .into_iter()
-            .map(|(dm_name, _, event_nr)| {
-                (
-                    dm_name,
-                    event_nr.expect("Supported DM versions always provide a value"),
-                )
-            })
-            .collect();
+        let devices = pool_devices(&pool);
 
-        for (pool_name, pool_uuid, pool) in &mut self.pools {
-            for dm_name in pool.get_eventing_dev_names(*pool_uuid) {
-                if device_list.get(&dm_name) > self.watched_dev_last_event_nrs.get(&dm_name) {
-                    pool.event_on(*pool_uuid, pool_name, &dm_name)?;
-                }
-            }
+        if let Err(err) = pool.check_unmounted(&pool_name) {
+            self.pools.insert(pool_name, uuid, pool);
+            return Err(err);
         }
 
-        self.watched_dev_last_event_nrs = device_list;
+        if let Err(err) = pool.teardown() {
+            self.setup_again(pool_name, uuid, &pool, devices);
+            return Err(err);
+        }
 
-        Ok(())
+        self.jobs.cancel(uuid, "the pool was stopped");
+        devlinks::pool_removed(&pool_name);
+        get_engine_listener_list().notify(&EngineEvent::PoolDestroyed {
+            dbus_path: pool.get_dbus_path(),
+            name: &pool_name,
+            uuid,
+        });
+        self.incomplete_pools.insert(uuid, devices);
+        self.stopped_pools.insert(uuid);
+        Ok(true)
     }
-}
-
-#[cfg(test)]
-mod test {
-    use std::fs::remove_dir_all;
 
-    use crate::engine::engine::DEV_PATH;
+    fn start_pool(&mut self, uuid: PoolUuid, force: bool) -> StratisResult<bool> {
+        if self.pools.contains_uuid(uuid) {
+            return Ok(false);
+        }
 
-    use crate::engine::strat_engine::tests::{loopbacked, real};
+        let setup = {
+            let devices = self.incomplete_pools.get(&uuid).ok_or_else(|| {
+                StratisError::Engine(
+                    ErrorEnum::NotFound,
+                    format!("no pool with UUID {} found to start", uuid),
+                )
+            })?;
+            if force {
+                import_pool(uuid, devices, &self.pools, true)
+            } else {
+                setup_pool(uuid, devices, &self.pools)
+            }
+        };
+        let (pool_name, pool) = match setup {
+            Ok(setup) => setup,
+            Err(err) => {
+                self.note_corrupt(uuid, &err);
+                return Err(err);
+            }
+        };
 
-    use super::*;
+        self.incomplete_pools.remove(&uuid);
+        self.stopped_pools.remove(&uuid);
+        self.corrupt_pools.remove(&uuid);
+        get_engine_listener_list().notify(&EngineEvent::PoolCreated {
+            name: &pool_name,
+            uuid,
+        });
+        self.pools.insert(pool_name, uuid, pool);
+        Ok(true)
+    }
 
-    /// Verify that a pool rename causes the pool metadata to get the new name.
+    fn forget_missing_device(
+        &mut self,
+        pool_uuid: PoolUuid,
+        dev_uuid: DevUuid,
+    ) -> StratisResult<bool> {
+        if let Some((_, pool)) = self.pools.get_by_uuid(pool_uuid) {
+            if pool.get_blockdev(dev_uuid).is_some() {
+                let err_msg = format!(
+                    "device with UUID {} belongs to a pool that is set up; it is not missing",
+                    dev_uuid.to_simple_ref()
+                );
+                return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+            }
+            return Ok(false);
+        }
+
+        {
+            let devices = self.incomplete_pools.get(&pool_uuid).ok_or_else(|| {
+                StratisError::Engine(
+                    ErrorEnum::NotFound,
+                    format!("no pool with UUID {} found", pool_uuid),
+                )
+            })?;
+
+            if devices.contains_key(&dev_uuid) {
+                let err_msg = format!(
+                    "device with UUID {} of pool with UUID {} has been found; it is not missing",
+                    dev_uuid.to_simple_ref(),
+                    pool_uuid.to_simple_ref()
+                );
+                return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+            }
+
+            if !forget_device(pool_uuid, devices, dev_uuid)? {
+                return Ok(false);
+            }
+        }
+
+        // The pool may now be complete, in which case it can be set up.
+        if !self.stopped_pools.contains(&pool_uuid) && !self.corrupt_pools.contains_key(&pool_uuid)
+        {
+            let devices = self
+                .incomplete_pools
+                .remove(&pool_uuid)
+                .expect("checked above");
+            match setup_pool(pool_uuid, &devices, &self.pools) {
+                Ok((pool_name, pool)) => {
+                    self.pools.insert(pool_name, pool_uuid, pool);
+                }
+                Err(err) => {
+                    info!("pool not set up after forgetting device: {:?}", err);
+                    self.note_corrupt(pool_uuid, &err);
+                    self.incomplete_pools.insert(pool_uuid, devices);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    fn import_pool(&mut self, id: &PoolId, force: bool) -> StratisResult<PoolUuid> {
+        let pool_uuid = match *id {
+            PoolId::Uuid(uuid) => uuid,
+            PoolId::Name(ref name) => {
+                let uuids = self
+                    .incomplete_pools
+                    .iter()
+                    .filter(
+                        |(pool_uuid, devices)| match get_metadata(**pool_uuid, devices) {
+                            Ok(Some(metadata)) => metadata.name == *name,
+                            _ => false,
+                        },
+                    )
+                    .map(|(pool_uuid, _)| *pool_uuid)
+                    .collect::<Vec<_>>();
+                match uuids.len() {
+                    0 => {
+                        return Err(StratisError::Engine(
+                            ErrorEnum::NotFound,
+                            format!("no pool with name {} found to import", name),
+                        ))
+                    }
+                    1 => uuids[0],
+                    _ => {
+                        return Err(StratisError::Engine(
+                            ErrorEnum::Invalid,
+                            format!(
+                                "more than one pool with name {} found; import by UUID instead",
+                                name
+                            ),
+                        ))
+                    }
+                }
+            }
+        };
+
+        if self.pools.contains_uuid(pool_uuid) {
+            return Err(StratisError::Engine(
+                ErrorEnum::AlreadyExists,
+                format!("pool with UUID {} is already set up", pool_uuid),
+            ));
+        }
+
+        let (pool_name, pool) = {
+            let devices = self.incomplete_pools.get(&pool_uuid).ok_or_else(|| {
+                StratisError::Engine(
+                    ErrorEnum::NotFound,
+                    format!("no pool with UUID {} found to import", pool_uuid),
+                )
+            })?;
+            import_pool(pool_uuid, devices, &self.pools, force)?
+        };
+
+        self.incomplete_pools.remove(&pool_uuid);
+        self.stopped_pools.remove(&pool_uuid);
+        self.corrupt_pools.remove(&pool_uuid);
+        get_engine_listener_list().notify(&EngineEvent::PoolCreated {
+            name: &pool_name,
+            uuid: pool_uuid,
+        });
+        self.pools.insert(pool_name, pool_uuid, pool);
+        Ok(pool_uuid)
+    }
+
+    fn restore_pool_metadata(&mut self, backup: &Path, force: bool) -> StratisResult<PoolUuid> {
+        let backup: MetadataBackupSave = serde_json::from_str(&read_to_string(backup)?)?;
+        let pool_uuid = backup.pool_uuid;
+
+        if self.pools.contains_uuid(pool_uuid) {
+            return Err(StratisError::Engine(
+                ErrorEnum::AlreadyExists,
+                format!(
+                    "pool with UUID {} is set up; its metadata can not be restored",
+                    pool_uuid
+                ),
+            ));
+        }
+
+        let (pool_name, pool) = {
+            let devices = self.incomplete_pools.get(&pool_uuid).ok_or_else(|| {
+                StratisError::Engine(
+                    ErrorEnum::NotFound,
+                    format!("no devices found for pool with UUID {}", pool_uuid),
+                )
+            })?;
+            restore_pool(&backup, devices, &self.pools, force)?
+        };
+
+        self.incomplete_pools.remove(&pool_uuid);
+        self.stopped_pools.remove(&pool_uuid);
+        self.corrupt_pools.remove(&pool_uuid);
+        get_engine_listener_list().notify(&EngineEvent::PoolCreated {
+            name: &pool_name,
+            uuid: pool_uuid,
+        });
+        self.pools.insert(pool_name, pool_uuid, pool);
+        Ok(pool_uuid)
+    }
+
+    fn repair_thin_metadata(&mut self, uuid: PoolUuid) -> StratisResult<ThinRepairSummary> {
+        if !self.corrupt_pools.contains_key(&uuid) {
+            if self.pools.contains_uuid(uuid) {
+                let err_msg = format!(
+                    "pool with UUID {} is set up; its thin metadata needs no repair",
+                    uuid
+                );
+                return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+            }
+            return Err(StratisError::Engine(
+                ErrorEnum::NotFound,
+                format!(
+                    "no pool with UUID {} found whose thin metadata needs repair",
+                    uuid
+                ),
+            ));
+        }
+
+        let setup = {
+            let devices = self.incomplete_pools.get(&uuid).ok_or_else(|| {
+                StratisError::Engine(
+                    ErrorEnum::NotFound,
+                    format!("no devices found for pool with UUID {}", uuid),
+                )
+            })?;
+            let metadata = read_metadata(uuid, devices)?;
+            let retained_segments = StratPool::repair_thin_metadata(uuid, devices, metadata)?;
+            setup_pool(uuid, devices, &self.pools).map(|setup| (setup, retained_segments))
+        };
+        let ((pool_name, pool), retained_segments) = match setup {
+            Ok(setup) => setup,
+            Err(err) => {
+                self.note_corrupt(uuid, &err);
+                return Err(err);
+            }
+        };
+
+        let missing_filesystems = pool.missing_filesystems().unwrap_or_else(|err| {
+            warn!(
+                "could not read the filesystems recorded for pool {}: {:?}",
+                pool_name, err
+            );
+            vec![]
+        });
+        let summary = ThinRepairSummary {
+            retained_segments,
+            restored_filesystems: pool
+                .filesystems()
+                .into_iter()
+                .map(|(name, _, _)| name)
+                .collect(),
+            missing_filesystems,
+        };
+
+        self.incomplete_pools.remove(&uuid);
+        self.stopped_pools.remove(&uuid);
+        self.corrupt_pools.remove(&uuid);
+        get_engine_listener_list().notify(&EngineEvent::PoolCreated {
+            name: &pool_name,
+            uuid,
+        });
+        self.pools.insert(pool_name, uuid, pool);
+        Ok(summary)
+    }
+
+    fn rename_pool(&mut self, uuid: PoolUuid, new_name: &str) -> StratisResult<RenameAction> {
+        validate_name(new_name)?;
+        let old_name = rename_pool_pre!(self; uuid; new_name);
+
+        let (_, mut pool) = self
+            .pools
+            .remove_by_uuid(uuid)
+            .expect("Must succeed since self.pools.get_by_uuid() returned a value");
+
+        let new_name = Name::new(new_name.to_owned());
+        if let Err(err) = pool.write_metadata(&new_name) {
+            self.pools.insert(old_name, uuid, pool);
+            Err(err)
+        } else {
+            get_engine_listener_list().notify(&EngineEvent::PoolRenamed {
+                dbus_path: pool.get_dbus_path(),
+                from: &*old_name,
+                to: &*new_name,
+            });
+
+            self.pools.insert(new_name.clone(), uuid, pool);
+            devlinks::pool_renamed(&old_name, &new_name);
+            Ok(RenameAction::Renamed)
+        }
+    }
+
+    fn get_pool(&self, uuid: PoolUuid) -> Option<(Name, &dyn Pool)> {
+        get_pool!(self; uuid)
+    }
+
+    fn get_mut_pool(&mut self, uuid: PoolUuid) -> Option<(Name, &mut dyn Pool)> {
+        get_mut_pool!(self; uuid)
+    }
+
+    fn get_filesystem(&self, uuid: FilesystemUuid) -> Option<(PoolUuid, Name, &dyn Filesystem)> {
+        get_filesystem!(self; uuid)
+    }
+
+    fn get_mut_filesystem(
+        &mut self,
+        uuid: FilesystemUuid,
+    ) -> Option<(PoolUuid, Name, &mut dyn Filesystem)> {
+        get_mut_filesystem!(self; uuid)
+    }
+
+    fn configure_simulator(&mut self, _denominator: u32) -> StratisResult<()> {
+        Ok(()) // we're not the simulator and not configurable, so just say ok
+    }
+
+    fn configure_simulator_operation(
+        &mut self,
+        _operation: SimOperation,
+        _model: SimFailureModel,
+    ) -> StratisResult<()> {
+        Ok(())
+    }
+
+    fn configure_simulator_device(
+        &mut self,
+        _devnode: &Path,
+        _properties: SimDevProperties,
+    ) -> StratisResult<()> {
+        Ok(())
+    }
+
+    fn pools(&self) -> Vec<(Name, PoolUuid, &dyn Pool)> {
+        self.pools
+            .iter()
+            .map(|(name, uuid, pool)| (name.clone(), *uuid, pool as &dyn Pool))
+            .collect()
+    }
+
+    fn pools_mut(&mut self) -> Vec<(Name, PoolUuid, &mut dyn Pool)> {
+        self.pools
+            .iter_mut()
+            .map(|(name, uuid, pool)| (name.clone(), *uuid, pool as &mut dyn Pool))
+            .collect()
+    }
+
+    fn pool_records(&self) -> Vec<PoolRecord> {
+        pool_records(self.pools())
+    }
+
+    fn filesystem_records(&self) -> Vec<FilesystemRecord> {
+        filesystem_records(self.pools())
+    }
+
+    fn blockdev_records(&self) -> Vec<BlockDevRecord> {
+        blockdev_records(self.pools())
+    }
+
+    fn reconcile(
+        &mut self,
+        desired: &[PoolSpec],
+        dry_run: bool,
+    ) -> StratisResult<Vec<ReconcileAction>> {
+        if dry_run {
+            reconcile::plan(self, desired)
+        } else {
+            reconcile::apply(self, desired)
+        }
+    }
+
+    fn start_job(&mut self, pool_uuid: PoolUuid, kind: JobKind) -> StratisResult<JobUuid> {
+        let devices = match self.pools.get_by_uuid(pool_uuid) {
+            Some((_, pool)) => pool
+                .blockdevs()
+                .iter()
+                .map(|(_, bd)| (bd.devnode(), bd.size()))
+                .collect(),
+            None => {
+                return Err(StratisError::Engine(
+                    ErrorEnum::NotFound,
+                    pool_uuid.to_string(),
+                ))
+            }
+        };
+        match kind {
+            JobKind::Scrub => self.jobs.start_scrub(pool_uuid, devices),
+        }
+    }
+
+    fn job_status(&self, uuid: JobUuid) -> Option<JobStatus> {
+        self.jobs.status(uuid)
+    }
+
+    fn jobs(&self) -> Vec<JobStatus> {
+        self.jobs.statuses()
+    }
+
+    fn step_jobs(&mut self) -> bool {
+        self.jobs.step(
+            |devnode, offset, length| read_sectors(devnode, offset, length),
+            JOB_STEP_TIME,
+        )
+    }
+
+    fn completed_request(&self, request_id: &str, operation: &str) -> StratisResult<Option<Value>> {
+        self.completed_requests.result(request_id, operation)
+    }
+
+    fn complete_request(&mut self, request_id: &str, operation: &str, result: Value) {
+        self.completed_requests
+            .insert(request_id, operation, result)
+    }
+
+    fn check(&mut self, repair: bool) -> StratisResult<Vec<CheckFinding>> {
+        let before = self.discrepancies()?;
+        if !repair || !before.iter().any(|d| d.repairable()) {
+            return Ok(findings(before, None));
+        }
+
+        let mut links = false;
+        let mut orphans = false;
+        let mut stale_pools = HashSet::new();
+        for discrepancy in before.iter().filter(|d| d.repairable()) {
+            match discrepancy {
+                Discrepancy::StaleLink { .. } | Discrepancy::MissingLink { .. } => links = true,
+                Discrepancy::OrphanedDmDevice { .. } => orphans = true,
+                Discrepancy::MetadataSequenceMismatch { pool_uuid, .. } => {
+                    stale_pools.insert(*pool_uuid);
+                }
+                _ => {}
+            }
+        }
+
+        if links {
+            devlinks::cleanup_devlinks(self.pools().iter());
+            for (pool_name, _, pool) in self.pools() {
+                devlinks::setup_pool_devlinks(&pool_name, pool);
+            }
+        }
+
+        if orphans {
+            remove_orphaned_devices(&self.known_pools())?;
+        }
+
+        // Writing a pool's metadata again brings blockdevs that hold older
+        // metadata up to date.
+        for pool_uuid in stale_pools {
+            let (pool_name, pool) = self
+                .pools
+                .get_mut_by_uuid(pool_uuid)
+                .expect("discrepancies are found only for known pools");
+            if let Err(err) = pool.write_metadata(&pool_name) {
+                warn!("could not rewrite metadata of pool {}: {}", pool_name, err);
+            }
+        }
+
+        let after = self.discrepancies()?;
+        Ok(findings(before, Some(&after)))
+    }
+
+    fn get_eventable(&self) -> Option<&'static dyn Eventable> {
+        Some(get_dm())
+    }
+
+    fn evented(&mut self) -> StratisResult<()> {
+        let device_list: HashMap<_, _> = get_dm()
+            .list_devices()?
+            .into_iter()
+            .map(|(dm_name, _, event_nr)| {
+                (
+                    dm_name,
+                    event_nr.expect("Supported DM versions always provide a value"),
+                )
+            })
+            .collect();
+
+        for (pool_name, pool_uuid, pool) in &mut self.pools {
+            for dm_name in pool.get_eventing_dev_names(*pool_uuid) {
+                if device_list.get(&dm_name) > self.watched_dev_last_event_nrs.get(&dm_name) {
+                    pool.event_on(*pool_uuid, pool_name, &dm_name)?;
+                }
+            }
+        }
+
+        self.watched_dev_last_event_nrs = device_list;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![cfg_attr(not(feature = "device_tests"), allow(dead_code, unused_imports))]
+
+    use std::fs::{remove_dir_all, remove_file, OpenOptions};
+    use std::os::unix::fs::symlink;
+
+    use devicemapper::{Bytes, DevId, DmOptions, Sectors, IEC};
+    use uuid::Uuid;
+
+    use crate::engine::engine::{create_pool_with_defaults, DEV_PATH};
+    use crate::engine::strat_engine::backstore::set_incompat_features;
+    use crate::engine::strat_engine::cmd;
+    use crate::engine::strat_engine::device::wipe_sectors;
+    use crate::engine::strat_engine::names::{
+        format_flex_ids, format_thinpool_ids, FlexRole, ThinPoolRole,
+    };
+    use crate::engine::{filesystem_mount_path, BlockDevTier, FilesystemSpec, JobState};
+
+    use crate::engine::strat_engine::tests::{loopbacked, real};
+
+    use super::*;
+
+    /// Verify that a pool rename causes the pool metadata to get the new name.
     fn test_pool_rename(paths: &[&Path]) {
         let mut engine = StratEngine::initialize().unwrap();
 
         let name1 = "name1";
-        let uuid1 = engine.create_pool(&name1, paths, None).unwrap();
+        let uuid1 = create_pool_with_defaults(&mut engine, &name1, paths, None).unwrap();
 
         let name2 = "name2";
         let action = engine.rename_pool(uuid1, name2).unwrap();
@@ -396,6 +1411,7 @@ mod test {
         assert_eq!(pool_name, name2);
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn loop_test_pool_rename() {
         loopbacked::test_with_spec(
@@ -404,6 +1420,7 @@ mod test {
         );
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn real_test_pool_rename() {
         real::test_with_spec(
@@ -412,13 +1429,187 @@ mod test {
         );
     }
 
+    /// Verify that planning a pool reports the devices that would be used,
+    /// writes nothing to them, and that the plan agrees with the pool that
+    /// is then created.
+    fn test_plan_pool(paths: &[&Path]) {
+        let mut engine = StratEngine::initialize().unwrap();
+
+        let name = "name";
+        let plan = engine
+            .plan_pool(name, paths, None, ChunkSize::Auto)
+            .unwrap();
+        assert_eq!(plan.devices.len(), paths.len());
+        assert!(plan.usable_size >= plan.initial_allocation);
+        for path in paths {
+            assert_eq!(is_stratis_device(path).unwrap(), None);
+        }
+
+        let uuid = create_pool_with_defaults(&mut engine, name, paths, None).unwrap();
+        let pool = engine.get_pool(uuid).unwrap().1;
+        let report = pool.space_report().unwrap();
+        assert_eq!(
+            report.raw_size,
+            plan.devices.iter().map(|dev| dev.size).sum::<Sectors>()
+        );
+        assert_eq!(report.raw_size - report.metadata_overhead, plan.usable_size);
+        assert_eq!(pool.chunk_size(), plan.chunk_size);
+
+        assert_matches!(
+            engine.plan_pool(name, paths, None, ChunkSize::Auto),
+            Err(StratisError::Engine(ErrorEnum::AlreadyExists, _))
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_plan_pool() {
+        loopbacked::test_with_spec(&loopbacked::DeviceLimits::Range(1, 3, None), test_plan_pool);
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_plan_pool() {
+        real::test_with_spec(&real::DeviceLimits::AtLeast(1, None, None), test_plan_pool);
+    }
+
+    /// Verify that the DM devices of a pool which is refused because its
+    /// devices require an unknown incompat feature are not removed as
+    /// orphans when the engine is initialized.
+    fn test_refused_pool_devices_kept(paths: &[&Path]) {
+        let mut engine = StratEngine::initialize().unwrap();
+        let uuid = create_pool_with_defaults(&mut engine, "name", paths, None).unwrap();
+        // The pool's DM devices stay active, as if the engine had exited
+        // without tearing them down.
+        drop(engine);
+
+        let dm_names = || -> HashSet<DmNameBuf> {
+            get_dm()
+                .list_devices()
+                .unwrap()
+                .into_iter()
+                .map(|(name, _, _)| name)
+                .collect()
+        };
+        let before = dm_names();
+
+        for path in paths {
+            let mut f = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(path)
+                .unwrap();
+            set_incompat_features(&mut f, 1 << 63).unwrap();
+        }
+        cmd::udev_settle().unwrap();
+
+        let engine = StratEngine::initialize().unwrap();
+        assert!(engine.get_pool(uuid).is_none());
+        assert!(engine.refused_pools.contains(&uuid));
+        assert!(engine.known_pools().contains(&uuid));
+        assert_eq!(dm_names(), before);
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_refused_pool_devices_kept() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Range(1, 3, None),
+            test_refused_pool_devices_kept,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_refused_pool_devices_kept() {
+        real::test_with_spec(
+            &real::DeviceLimits::AtLeast(1, None, None),
+            test_refused_pool_devices_kept,
+        );
+    }
+
+    /// Verify that DM devices named for a pool that has no blockdevs are
+    /// removed, and that those of a pool that is set up, or whose blockdevs
+    /// have been found but which is not set up, are left alone.
+    fn test_remove_orphaned_devices(paths: &[&Path]) {
+        let mut engine = StratEngine::initialize().unwrap();
+        let uuid = create_pool_with_defaults(&mut engine, "name", paths, None).unwrap();
+
+        let orphan_uuid = Uuid::new_v4();
+        let orphans = [
+            format_flex_ids(orphan_uuid, FlexRole::ThinMeta),
+            format_thinpool_ids(orphan_uuid, ThinPoolRole::Pool),
+        ];
+        for (name, dm_uuid) in &orphans {
+            get_dm()
+                .device_create(name, Some(dm_uuid), &DmOptions::new())
+                .unwrap();
+        }
+
+        let incomplete_uuid = Uuid::new_v4();
+        let (incomplete_name, incomplete_dm_uuid) =
+            format_flex_ids(incomplete_uuid, FlexRole::ThinMeta);
+        get_dm()
+            .device_create(
+                &incomplete_name,
+                Some(&incomplete_dm_uuid),
+                &DmOptions::new(),
+            )
+            .unwrap();
+        engine
+            .incomplete_pools
+            .insert(incomplete_uuid, HashMap::new());
+        let before = get_dm().list_devices().unwrap().len();
+
+        assert!(engine.known_pools().contains(&uuid));
+        assert_eq!(
+            remove_orphaned_devices(&engine.known_pools()).unwrap(),
+            vec![]
+        );
+
+        let names: Vec<DmNameBuf> = get_dm()
+            .list_devices()
+            .unwrap()
+            .into_iter()
+            .map(|(name, _, _)| name)
+            .collect();
+        assert_eq!(names.len(), before - orphans.len());
+        for (name, _) in &orphans {
+            assert!(!names.contains(name));
+        }
+        assert!(names.contains(&incomplete_name));
+
+        get_dm()
+            .device_remove(&DevId::Name(&incomplete_name), &DmOptions::new())
+            .unwrap();
+        engine.teardown().unwrap();
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_remove_orphaned_devices() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Range(1, 3, None),
+            test_remove_orphaned_devices,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_remove_orphaned_devices() {
+        real::test_with_spec(
+            &real::DeviceLimits::AtLeast(1, None, None),
+            test_remove_orphaned_devices,
+        );
+    }
+
     /// Test engine setup.
     /// 1. Create two pools.
     /// 2. Verify that both exist.
     /// 3. Teardown the engine.
     /// 4. Initialize the engine.
     /// 5. Verify that pools can be found again.
-    /// 6. Teardown the engine and remove "/stratis".
+    /// 6. Teardown the engine and remove DEV_PATH.
     /// 7. Initialize the engine one more time.
     /// 8. Verify that both pools are found and that there are no incomplete pools.
     fn test_setup(paths: &[&Path]) {
@@ -429,10 +1620,10 @@ mod test {
         let mut engine = StratEngine::initialize().unwrap();
 
         let name1 = "name1";
-        let uuid1 = engine.create_pool(&name1, paths1, None).unwrap();
+        let uuid1 = create_pool_with_defaults(&mut engine, &name1, paths1, None).unwrap();
 
         let name2 = "name2";
-        let uuid2 = engine.create_pool(&name2, paths2, None).unwrap();
+        let uuid2 = create_pool_with_defaults(&mut engine, &name2, paths2, None).unwrap();
 
         assert!(engine.get_pool(uuid1).is_some());
         assert!(engine.get_pool(uuid2).is_some());
@@ -456,13 +1647,410 @@ mod test {
         engine.teardown().unwrap();
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn loop_test_setup() {
         loopbacked::test_with_spec(&loopbacked::DeviceLimits::Range(2, 3, None), test_setup);
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn real_test_setup() {
         real::test_with_spec(&real::DeviceLimits::AtLeast(2, None, None), test_setup);
     }
+
+    /// Verify that a device that has been lost, and from which no data was
+    /// allocated, can be forgotten, after which the pool is set up without
+    /// it, but that a device from which data was allocated can not be.
+    fn test_forget_missing_device(paths: &[&Path]) {
+        assert!(paths.len() > 2);
+        let (paths1, paths2) = paths.split_at(1);
+
+        let mut engine = StratEngine::initialize().unwrap();
+        let name = "name";
+        let uuid = create_pool_with_defaults(&mut engine, name, paths1, None).unwrap();
+        let (used_uuid, lost_uuid) = {
+            let pool = engine.get_mut_pool(uuid).unwrap().1;
+            let used_uuid = pool.blockdevs()[0].0;
+            let lost_uuids = pool
+                .add_blockdevs(uuid, name, paths2, BlockDevTier::Data)
+                .unwrap();
+            (used_uuid, lost_uuids[0])
+        };
+        engine.teardown().unwrap();
+
+        wipe_sectors(paths2[0], Sectors(0), Bytes(IEC::Mi).sectors()).unwrap();
+
+        let mut engine = StratEngine::initialize().unwrap();
+        assert!(engine.get_pool(uuid).is_none());
+        assert_matches!(engine.forget_missing_device(uuid, lost_uuid), Ok(true));
+        assert_eq!(
+            engine.get_pool(uuid).unwrap().1.blockdevs().len(),
+            paths.len() - 1
+        );
+        engine.teardown().unwrap();
+
+        wipe_sectors(paths1[0], Sectors(0), Bytes(IEC::Mi).sectors()).unwrap();
+
+        let mut engine = StratEngine::initialize().unwrap();
+        assert!(engine.get_pool(uuid).is_none());
+        assert_matches!(
+            engine.forget_missing_device(uuid, used_uuid),
+            Err(StratisError::Engine(ErrorEnum::Invalid, ref msg)) if msg.contains("allocated")
+        );
+        assert!(engine.get_pool(uuid).is_none());
+        engine.teardown().unwrap();
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_forget_missing_device() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Range(3, 3, None),
+            test_forget_missing_device,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_forget_missing_device() {
+        real::test_with_spec(
+            &real::DeviceLimits::AtLeast(3, None, None),
+            test_forget_missing_device,
+        );
+    }
+
+    /// Verify that a stopped pool is not set up again when one of its
+    /// devices is found, but that it can be started, and that stopping a
+    /// pool leaves it to be set up as usual when the engine is initialized.
+    fn test_stop_start(paths: &[&Path]) {
+        let mut engine = StratEngine::initialize().unwrap();
+
+        let uuid = create_pool_with_defaults(&mut engine, "name", paths, None).unwrap();
+        let (device, devnode) = pool_devices(engine.pools.get_by_uuid(uuid).unwrap().1)
+            .values()
+            .next()
+            .cloned()
+            .unwrap();
+
+        assert_matches!(engine.stop_pool(uuid), Ok(true));
+        assert!(engine.get_pool(uuid).is_none());
+        assert_matches!(engine.stop_pool(uuid), Ok(false));
+
+        assert_matches!(engine.block_evaluate(device, devnode), Ok(None));
+        assert!(engine.get_pool(uuid).is_none());
+
+        assert_matches!(engine.start_pool(uuid, false), Ok(true));
+        assert!(engine.get_pool(uuid).is_some());
+        assert_matches!(engine.start_pool(uuid, false), Ok(false));
+
+        engine.stop_pool(uuid).unwrap();
+        engine.teardown().unwrap();
+
+        let engine = StratEngine::initialize().unwrap();
+        assert!(engine.get_pool(uuid).is_some());
+        engine.teardown().unwrap();
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_stop_start() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Range(1, 3, None),
+            test_stop_start,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_stop_start() {
+        real::test_with_spec(&real::DeviceLimits::AtLeast(1, None, None), test_stop_start);
+    }
+
+    /// Verify that an exported pool is not set up when the engine is
+    /// initialized, but that it can be imported by name, after which it is
+    /// set up as usual.
+    fn test_export_import(paths: &[&Path]) {
+        let mut engine = StratEngine::initialize().unwrap();
+
+        let name = "name";
+        let uuid = create_pool_with_defaults(&mut engine, name, paths, None).unwrap();
+
+        assert_matches!(engine.export_pool(uuid), Ok(true));
+        assert!(engine.get_pool(uuid).is_none());
+        engine.teardown().unwrap();
+
+        let mut engine = StratEngine::initialize().unwrap();
+        assert!(engine.get_pool(uuid).is_none());
+        assert!(engine.incomplete_pools.contains_key(&uuid));
+
+        assert_matches!(
+            engine.import_pool(&PoolId::Name(name.into()), false),
+            Ok(u) if u == uuid
+        );
+        assert!(engine.incomplete_pools.is_empty());
+        engine.teardown().unwrap();
+
+        let engine = StratEngine::initialize().unwrap();
+        assert!(engine.get_pool(uuid).is_some());
+        engine.teardown().unwrap();
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_export_import() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Range(1, 3, None),
+            test_export_import,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_export_import() {
+        real::test_with_spec(
+            &real::DeviceLimits::AtLeast(1, None, None),
+            test_export_import,
+        );
+    }
+
+    /// Verify that a pool's metadata can be restored from a backup once the
+    /// pool is no longer set up, but only if forced when the metadata on the
+    /// pool's devices is more recent than the backup.
+    fn test_restore_metadata(paths: &[&Path]) {
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = StratEngine::initialize().unwrap();
+
+        let name = "name";
+        let uuid = create_pool_with_defaults(&mut engine, name, paths, None).unwrap();
+        let backup = engine
+            .get_pool(uuid)
+            .unwrap()
+            .1
+            .backup_metadata(uuid, name, dir.path())
+            .unwrap();
+
+        // A second backup made at once is written to a file of its own.
+        let second_backup = engine
+            .get_pool(uuid)
+            .unwrap()
+            .1
+            .backup_metadata(uuid, name, dir.path())
+            .unwrap();
+        assert_ne!(backup, second_backup);
+
+        assert_matches!(
+            engine.restore_pool_metadata(&backup, false),
+            Err(StratisError::Engine(ErrorEnum::AlreadyExists, _))
+        );
+
+        // Exporting the pool writes metadata more recent than the backup.
+        engine.export_pool(uuid).unwrap();
+        assert_matches!(
+            engine.restore_pool_metadata(&backup, false),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(engine.restore_pool_metadata(&backup, true), Ok(u) if u == uuid);
+        engine.teardown().unwrap();
+
+        let engine = StratEngine::initialize().unwrap();
+        assert!(engine.get_pool(uuid).is_some());
+        engine.teardown().unwrap();
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_restore_metadata() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Range(1, 3, None),
+            test_restore_metadata,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_restore_metadata() {
+        real::test_with_spec(
+            &real::DeviceLimits::AtLeast(1, None, None),
+            test_restore_metadata,
+        );
+    }
+
+    /// Verify that the records of a pool and its filesystem and blockdevs
+    /// agree with the pool.
+    fn test_records(paths: &[&Path]) {
+        let mut engine = StratEngine::initialize().unwrap();
+
+        let pool_name = "pool";
+        let uuid = create_pool_with_defaults(&mut engine, pool_name, paths, None).unwrap();
+        let fs_uuid = {
+            let (_, pool) = engine.get_mut_pool(uuid).unwrap();
+            pool.create_filesystems(uuid, pool_name, &[("fs", None)])
+                .unwrap()[0]
+                .1
+        };
+
+        let pool_records = engine.pool_records();
+        assert_eq!(pool_records.len(), 1);
+        assert_eq!(pool_records[0].uuid, uuid);
+        assert_eq!(pool_records[0].name, pool_name);
+        assert_eq!(pool_records[0].filesystem_count, 1);
+        assert_eq!(pool_records[0].blockdev_count, paths.len());
+        assert!(pool_records[0].space.is_some());
+
+        let filesystem_records = engine.filesystem_records();
+        assert_eq!(filesystem_records.len(), 1);
+        assert_eq!(filesystem_records[0].uuid, fs_uuid);
+        assert_eq!(filesystem_records[0].pool_uuid, uuid);
+        assert!(filesystem_records[0].size.is_some());
+
+        let blockdev_records = engine.blockdev_records();
+        assert_eq!(blockdev_records.len(), paths.len());
+        assert!(blockdev_records
+            .iter()
+            .all(|record| record.pool_uuid == uuid && record.tier == BlockDevTier::Data));
+
+        engine.teardown().unwrap();
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_records() {
+        loopbacked::test_with_spec(&loopbacked::DeviceLimits::Range(1, 3, None), test_records);
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_records() {
+        real::test_with_spec(&real::DeviceLimits::AtLeast(1, None, None), test_records);
+    }
+
+    /// Verify that reconciling creates the pool and filesystem described,
+    /// adds the devices later described, and then does nothing more.
+    fn test_reconcile(paths: &[&Path]) {
+        let (first, rest) = paths.split_at(1);
+        let mut engine = StratEngine::initialize().unwrap();
+
+        let mut desired = vec![PoolSpec {
+            name: "pool".into(),
+            devices: first.iter().map(|p| p.to_path_buf()).collect(),
+            filesystems: vec![FilesystemSpec {
+                name: "fs".into(),
+                size: None,
+            }],
+        }];
+        assert_eq!(engine.reconcile(&desired, false).unwrap().len(), 2);
+        assert_eq!(engine.reconcile(&desired, false).unwrap(), vec![]);
+
+        desired[0].devices = paths.iter().map(|p| p.to_path_buf()).collect();
+        let actions = engine.reconcile(&desired, false).unwrap();
+        assert_eq!(actions.len(), if rest.is_empty() { 0 } else { 1 });
+        assert_eq!(engine.reconcile(&desired, true).unwrap(), vec![]);
+
+        let (_, _, pool) = engine.pools().pop().unwrap();
+        assert_eq!(pool.blockdevs().len(), paths.len());
+        assert_eq!(pool.filesystems().len(), 1);
+
+        engine.teardown().unwrap();
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_reconcile() {
+        loopbacked::test_with_spec(&loopbacked::DeviceLimits::Range(1, 3, None), test_reconcile);
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_reconcile() {
+        real::test_with_spec(&real::DeviceLimits::AtLeast(1, None, None), test_reconcile);
+    }
+
+    /// Verify that a scrub of a pool reads all its devices and succeeds.
+    fn test_scrub(paths: &[&Path]) {
+        let mut engine = StratEngine::initialize().unwrap();
+        let pool_uuid = create_pool_with_defaults(&mut engine, "pool", paths, None).unwrap();
+
+        let uuid = engine.start_job(pool_uuid, JobKind::Scrub).unwrap();
+        while engine.step_jobs() {}
+
+        let status = engine.job_status(uuid).unwrap();
+        assert_eq!(status.state, JobState::Succeeded);
+        assert_eq!(status.progress_pct(), 100);
+
+        engine.teardown().unwrap();
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_scrub() {
+        loopbacked::test_with_spec(&loopbacked::DeviceLimits::Range(1, 2, None), test_scrub);
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_scrub() {
+        real::test_with_spec(&real::DeviceLimits::AtLeast(1, None, None), test_scrub);
+    }
+
+    /// Verify that a check finds nothing amiss with a newly created pool,
+    /// that it finds a missing filesystem link, a stale link, and an
+    /// orphaned DM device, and that a check with repair repairs them.
+    fn test_check(paths: &[&Path]) {
+        let mut engine = StratEngine::initialize().unwrap();
+        let pool_name = "pool";
+        let uuid = create_pool_with_defaults(&mut engine, pool_name, paths, None).unwrap();
+        let fs_name = "fs";
+        {
+            let (_, pool) = engine.get_mut_pool(uuid).unwrap();
+            pool.create_filesystems(uuid, pool_name, &[(fs_name, None)])
+                .unwrap();
+        }
+        assert_eq!(engine.check(false).unwrap(), vec![]);
+
+        let fs_link = filesystem_mount_path(pool_name, fs_name);
+        remove_file(&fs_link).unwrap();
+        let stale_link = filesystem_mount_path(pool_name, "gone");
+        symlink("/dev/null", &stale_link).unwrap();
+        let (orphan_name, orphan_uuid) = format_flex_ids(Uuid::new_v4(), FlexRole::ThinMeta);
+        get_dm()
+            .device_create(&orphan_name, Some(&orphan_uuid), &DmOptions::new())
+            .unwrap();
+
+        let findings = engine.check(false).unwrap();
+        assert_eq!(findings.len(), 3);
+        assert!(findings.iter().all(|finding| !finding.repaired));
+        assert!(findings.iter().any(|finding| match finding.discrepancy {
+            Discrepancy::MissingLink { ref path, .. } => *path == fs_link,
+            _ => false,
+        }));
+        assert!(findings.iter().any(|finding| match finding.discrepancy {
+            Discrepancy::StaleLink { ref path } => *path == stale_link,
+            _ => false,
+        }));
+        assert!(findings.iter().any(|finding| match finding.discrepancy {
+            Discrepancy::OrphanedDmDevice { ref name } => *name == orphan_name.to_string(),
+            _ => false,
+        }));
+
+        let findings = engine.check(true).unwrap();
+        assert_eq!(findings.len(), 3);
+        assert!(findings.iter().all(|finding| finding.repaired));
+        assert_eq!(engine.check(false).unwrap(), vec![]);
+        assert!(fs_link.exists());
+
+        engine.teardown().unwrap();
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_check() {
+        loopbacked::test_with_spec(&loopbacked::DeviceLimits::Range(1, 2, None), test_check);
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_check() {
+        real::test_with_spec(&real::DeviceLimits::AtLeast(1, None, None), test_check);
+    }
 }
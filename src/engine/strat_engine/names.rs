@@ -9,6 +9,7 @@ use std::fmt::Display;
 use std::path::Path;
 
 use devicemapper::{DmNameBuf, DmUuidBuf};
+use uuid::Uuid;
 
 use crate::engine::{FilesystemUuid, PoolUuid};
 use crate::stratis::{ErrorEnum, StratisError, StratisResult};
@@ -183,6 +184,32 @@ pub fn format_backstore_ids(pool_uuid: PoolUuid, role: CacheRole) -> (DmNameBuf,
     )
 }
 
+/// Returns true if the DM uuid is one that Stratis would assign to a device
+/// that it created, regardless of format version.
+pub fn is_stratis_dm_uuid(uuid: &str) -> bool {
+    uuid.starts_with("stratis-")
+}
+
+/// Returns the UUID of the pool to which a DM device with this name belongs,
+/// if the name is one that Stratis would assign with the current format
+/// version.
+pub fn dm_name_to_pool_uuid(name: &str) -> Option<PoolUuid> {
+    let prefix = format!("stratis-{}-", FORMAT_VERSION);
+    if !name.starts_with(&prefix) {
+        return None;
+    }
+    let rest = &name[prefix.len()..];
+    let rest = match rest.find("private-") {
+        Some(0) => &rest[8..],
+        _ => rest,
+    };
+    // The pool UUID is formatted as 32 hex digits, followed by a dash.
+    if rest.len() <= 32 || !rest.is_char_boundary(32) || &rest[32..33] != "-" {
+        return None;
+    }
+    Uuid::parse_str(&rest[..32]).ok()
+}
+
 /// Validate a path for use as a Pool or Filesystem name.
 pub fn validate_name(name: &str) -> StratisResult<()> {
     let name_path = Path::new(name);
@@ -271,4 +298,32 @@ mod tests {
         assert!(validate_name("☺").is_ok());
         assert!(validate_name("ok_name").is_ok());
     }
+
+    #[test]
+    /// Verify that the pool UUID is recovered from the name of every kind of
+    /// DM device, and that other names, or names with another format
+    /// version, are not mistaken for those of a pool's devices.
+    pub fn test_dm_name_to_pool_uuid() {
+        let pool_uuid = Uuid::new_v4();
+        for name in &[
+            format_flex_ids(pool_uuid, FlexRole::ThinMeta).0,
+            format_thin_ids(pool_uuid, ThinRole::Filesystem(Uuid::new_v4())).0,
+            format_thinpool_ids(pool_uuid, ThinPoolRole::Pool).0,
+            format_backstore_ids(pool_uuid, CacheRole::OriginSub).0,
+        ] {
+            assert_eq!(dm_name_to_pool_uuid(&name.to_string()), Some(pool_uuid));
+        }
+
+        let simple = pool_uuid.to_simple_ref().to_string();
+        assert_eq!(dm_name_to_pool_uuid("luks-abcd"), None);
+        assert_eq!(dm_name_to_pool_uuid(&format!("stratis-1-{}", simple)), None);
+        assert_eq!(
+            dm_name_to_pool_uuid(&format!("stratis-2-{}-thin-fs-x", simple)),
+            None
+        );
+        assert_eq!(
+            dm_name_to_pool_uuid(&format!("stratis-1-{}-thin", &simple[1..])),
+            None
+        );
+    }
 }
@@ -2,35 +2,59 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use std::cmp;
 use std::collections::HashMap;
+use std::fs::{read_to_string, OpenOptions};
+use std::io::Write;
 use std::iter::FromIterator;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use std::vec::Vec;
 
+use chrono::Utc;
 use serde_json;
 use uuid::Uuid;
 
-use devicemapper::{Device, DmName, DmNameBuf, Sectors};
+use devicemapper::{Device, DmName, DmNameBuf, MetaBlocks, Sectors};
 
 use crate::engine::{
-    BlockDev, BlockDevTier, DevUuid, Filesystem, FilesystemUuid, MaybeDbusPath, Name, Pool,
+    AllocationStrategy, BlockDev, BlockDevTier, ChunkSize, DevUuid, Filesystem, FilesystemTree,
+    FilesystemUuid, MaybeDbusPath, Name, OverprovisionPolicy, Pool, PoolPlan, PoolSpaceReport,
     PoolUuid, Redundancy, RenameAction,
 };
 use crate::stratis::{ErrorEnum, StratisError, StratisResult};
 
+use crate::engine::check::Discrepancy;
 use crate::engine::types::{FreeSpaceState, PoolExtendState, PoolState};
 
 use crate::engine::strat_engine::backstore::{Backstore, StratBlockDev, MIN_MDA_SECTORS};
 use crate::engine::strat_engine::names::validate_name;
-use crate::engine::strat_engine::serde_structs::{FlexDevsSave, PoolSave, Recordable};
-use crate::engine::strat_engine::thinpool::{ThinPool, ThinPoolSizeParams, DATA_BLOCK_SIZE};
+use crate::engine::strat_engine::serde_structs::{
+    ExportSave, FlexDevsSave, MetadataBackupSave, PoolSave, Recordable,
+};
+use crate::engine::strat_engine::thinpool::{
+    choose_data_block_size, repair_thin_metadata, validate_data_block_size, ThinPool,
+    ThinPoolSizeParams, DEFAULT_THIN_DEV_SIZE,
+};
+
+const MACHINE_ID_PATH: &str = "/etc/machine-id";
+
+/// An identifier for this host, used to record where a pool was last set up
+/// and where it was exported from. This is the systemd machine ID if there
+/// is one, otherwise the empty string.
+pub fn host_id() -> String {
+    read_to_string(MACHINE_ID_PATH)
+        .map(|id| id.trim().to_owned())
+        .unwrap_or_default()
+}
 
 /// Get the index which indicates the start of unallocated space in the cap
 /// device.
 /// NOTE: Since segments are always allocated to each flex dev in order, the
 /// last segment for each is the highest. This allows avoiding sorting all the
 /// segments and just sorting the set consisting of the last segment from
-/// each list of segments.
+/// each list of segments. The retained thin metadata segments, which may be
+/// none, are not allocated in the same way, so all of them are included.
 /// Precondition: This method is called only when setting up a pool, which
 /// ensures that the flex devs metadata lists are all non-empty.
 fn next_index(flex_devs: &FlexDevsSave) -> Sectors {
@@ -54,8 +78,10 @@ fn next_index(flex_devs: &FlexDevsSave) -> Sectors {
             .unwrap_or_else(|| panic!(expect_msg)),
     ]
     .iter()
+    .cloned()
+    .chain(flex_devs.thin_meta_dev_retained.iter())
     .max_by_key(|x| x.0)
-    .map(|&&(start, length)| start + length)
+    .map(|&(start, length)| start + length)
     .expect("iterator is non-empty")
 }
 
@@ -84,6 +110,7 @@ pub fn check_metadata(metadata: &PoolSave) -> StratisResult<()> {
             .chain(flex_devs.thin_meta_dev.iter())
             .chain(flex_devs.thin_data_dev.iter())
             .chain(flex_devs.thin_meta_dev_spare.iter())
+            .chain(flex_devs.thin_meta_dev_retained.iter())
             .map(|x| x.1)
             .sum::<Sectors>();
         if total_allocated != next {
@@ -130,28 +157,44 @@ pub struct StratPool {
     backstore: Backstore,
     redundancy: Redundancy,
     thin_pool: ThinPool,
+    // If the pool is in read-only mode, the reason it entered it
+    read_only_reason: Option<String>,
+    // If the pool is quiesced, the time at which it must be resumed
+    quiesce_deadline: Option<Instant>,
     dbus_path: MaybeDbusPath,
 }
 
 impl StratPool {
     /// Initialize a Stratis Pool.
     /// 1. Initialize the block devices specified by paths.
-    /// 2. Set up thinpool device to back filesystems.
+    /// 2. Set up thinpool device to back filesystems, with a chunk size
+    ///    chosen according to chunk_size, allocating its space from the
+    ///    block devices according to allocation.
     pub fn initialize(
         name: &str,
         paths: &[&Path],
         redundancy: Redundancy,
+        chunk_size: ChunkSize,
+        allocation: AllocationStrategy,
     ) -> StratisResult<(PoolUuid, StratPool)> {
+        if let ChunkSize::Fixed(size) = chunk_size {
+            validate_data_block_size(size)?;
+        }
+
         let pool_uuid = Uuid::new_v4();
 
         let mut backstore = Backstore::initialize(pool_uuid, paths, MIN_MDA_SECTORS)?;
-
-        let thinpool = ThinPool::new(
-            pool_uuid,
-            &ThinPoolSizeParams::default(),
-            DATA_BLOCK_SIZE,
-            &mut backstore,
-        );
+        backstore.set_allocation_strategy(allocation);
+
+        let thinpool = choose_data_block_size(chunk_size, backstore.datatier_optimal_io_size())
+            .and_then(|data_block_size| {
+                ThinPool::new(
+                    pool_uuid,
+                    &ThinPoolSizeParams::default(),
+                    data_block_size,
+                    &mut backstore,
+                )
+            });
 
         let mut thinpool = match thinpool {
             Ok(thinpool) => thinpool,
@@ -161,16 +204,118 @@ impl StratPool {
             }
         };
 
-        thinpool.check(pool_uuid, &mut backstore)?;
+        if let Err(err) = thinpool.check(pool_uuid, &mut backstore) {
+            let _ = thinpool.teardown();
+            let _ = backstore.destroy();
+            return Err(err);
+        }
 
         let mut pool = StratPool {
             backstore,
             redundancy,
             thin_pool: thinpool,
+            read_only_reason: None,
+            quiesce_deadline: None,
             dbus_path: MaybeDbusPath(None),
         };
 
-        pool.write_metadata(&Name::new(name.to_owned()))?;
+        if let Err(err) = pool.write_metadata(&Name::new(name.to_owned())) {
+            let _ = pool.destroy();
+            return Err(err);
+        }
+
+        Ok((pool_uuid, pool))
+    }
+
+    /// Check the devices as initialize() would, but without writing to any
+    /// of them, and return the layout the pool would have.
+    /// Returns an error if the pool could not be created from the devices.
+    pub fn plan(
+        paths: &[&Path],
+        redundancy: Redundancy,
+        chunk_size: ChunkSize,
+    ) -> StratisResult<PoolPlan> {
+        let devices = Backstore::plan(paths, MIN_MDA_SECTORS)?;
+        let usable_size = devices
+            .iter()
+            .map(|dev| dev.size - dev.metadata_size)
+            .sum::<Sectors>();
+
+        let chunk_size = choose_data_block_size(
+            chunk_size,
+            devices.iter().filter_map(|dev| dev.optimal_io_size).max(),
+        )?;
+
+        let sizes = ThinPoolSizeParams::default();
+        let initial_allocation =
+            sizes.meta_size() * 2u64 + sizes.data_size_for(chunk_size) + sizes.mdv_size();
+        if usable_size < initial_allocation {
+            let err_msg = format!(
+                "the devices provide {} usable sectors, but {} are required to create a pool",
+                usable_size, initial_allocation
+            );
+            return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+        }
+
+        Ok(PoolPlan {
+            devices,
+            redundancy,
+            usable_size,
+            initial_allocation,
+            chunk_size,
+        })
+    }
+
+    /// Create a new pool on paths, with the same redundancy, chunk size,
+    /// allocation strategy, overprovisioning policy, reserve and limits as
+    /// this pool, and copy each of this pool's filesystems into it.
+    /// Returns an error if the devices do not provide enough space for the
+    /// data in use in each of this pool's filesystems, or if any of them is
+    /// mounted.
+    pub fn clone_onto(&self, name: &str, paths: &[&Path]) -> StratisResult<(PoolUuid, StratPool)> {
+        let chunk_size = ChunkSize::Fixed(self.thin_pool.data_block_size());
+        let plan = StratPool::plan(paths, self.redundancy, chunk_size)?;
+
+        // Each filesystem is copied separately, so data that snapshots
+        // share in this pool takes up space once for each of them.
+        let data_used = self
+            .thin_pool
+            .filesystems()
+            .iter()
+            .map(|(_, _, fs)| fs.used().map(|used| used.sectors()))
+            .collect::<StratisResult<Vec<_>>>()?
+            .into_iter()
+            .sum::<Sectors>();
+        let initial_data_size = ThinPoolSizeParams::default().data_size_for(plan.chunk_size);
+        let required =
+            plan.initial_allocation - initial_data_size + cmp::max(initial_data_size, data_used);
+        if plan.usable_size < required {
+            let err_msg = format!(
+                "the devices provide {} usable sectors, but the filesystems to be copied require {}",
+                plan.usable_size, required
+            );
+            return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+        }
+
+        let (pool_uuid, mut pool) = StratPool::initialize(
+            name,
+            paths,
+            self.redundancy,
+            chunk_size,
+            self.allocation_strategy(),
+        )?;
+
+        let copied = pool
+            .thin_pool
+            .copy_filesystems(pool_uuid, name, &self.thin_pool, &mut pool.backstore)
+            .and_then(|_| {
+                pool.thin_pool.copy_policies(&self.thin_pool);
+                pool.write_metadata(&Name::new(name.to_owned()))
+            });
+        if let Err(err) = copied {
+            let _ = pool.destroy();
+            return Err(err);
+        }
 
         Ok((pool_uuid, pool))
     }
@@ -181,10 +326,10 @@ impl StratPool {
     /// Precondition: A metadata verification step has already been run.
     pub fn setup(
         uuid: PoolUuid,
-        devnodes: &HashMap<Device, PathBuf>,
+        devnodes: &HashMap<DevUuid, (Device, PathBuf)>,
         metadata: &PoolSave,
     ) -> StratisResult<(Name, StratPool)> {
-        let mut backstore = Backstore::setup(uuid, &metadata.backstore, devnodes, None)?;
+        let mut backstore = Backstore::setup(uuid, &metadata.backstore, devnodes)?;
         let mut thinpool = ThinPool::setup(
             uuid,
             &metadata.thinpool_dev,
@@ -198,6 +343,8 @@ impl StratPool {
             backstore,
             redundancy: Redundancy::NONE,
             thin_pool: thinpool,
+            read_only_reason: None,
+            quiesce_deadline: None,
             dbus_path: MaybeDbusPath(None),
         };
 
@@ -207,32 +354,197 @@ impl StratPool {
             pool.write_metadata(pool_name)?;
         }
 
+        // Some members may have missed the most recent metadata updates,
+        // e.g., because they were detached; bring them up to date.
+        let data = serde_json::to_string(&pool.record(pool_name))?;
+        let resynced = pool.backstore.resync_stale_blockdevs(data.as_bytes());
+        if !resynced.is_empty() {
+            info!(
+                "brought metadata up to date on {} blockdevs of pool {}",
+                resynced.len(),
+                pool_name
+            );
+        }
+
         Ok((Name::new(pool_name.to_owned()), pool))
     }
 
+    /// Repair the thin metadata of a pool which can not be set up because
+    /// its thin metadata is damaged, and write the changed metadata to the
+    /// pool's members. The pool is not set up; the caller sets it up next.
+    /// Returns the segments which held the damaged thin metadata, which are
+    /// retained, or an error if the metadata could not be repaired, in
+    /// which case the metadata on the pool's members is unchanged.
+    pub fn repair_thin_metadata(
+        uuid: PoolUuid,
+        devnodes: &HashMap<DevUuid, (Device, PathBuf)>,
+        mut metadata: PoolSave,
+    ) -> StratisResult<Vec<(Sectors, Sectors)>> {
+        let mut backstore = Backstore::setup(uuid, &metadata.backstore, devnodes)?;
+        let result =
+            repair_thin_metadata(uuid, &metadata.flex_devs, &mut backstore).and_then(|flex_devs| {
+                let retained = metadata.flex_devs.thin_meta_dev.to_vec();
+                metadata.flex_devs = flex_devs;
+                metadata.backstore = backstore.record();
+                let data = serde_json::to_string(&metadata)?;
+                backstore.save_state(data.as_bytes())?;
+                Ok(retained)
+            });
+        if let Err(err) = backstore.teardown() {
+            warn!(
+                "failed to tear down backstore of pool with UUID {} after repairing its thin metadata: {:?}",
+                uuid, err
+            );
+        }
+        result
+    }
+
     /// Write current metadata to pool members.
+    /// If the metadata can not be written, the pool enters read-only mode
+    /// and an error is returned. If it is written to too few members, the
+    /// pool also enters read-only mode, but as the metadata was written, no
+    /// error is returned.
     pub fn write_metadata(&mut self, name: &str) -> StratisResult<()> {
+        self.check_writable()?;
         let data = serde_json::to_string(&self.record(name))?;
-        self.backstore.save_state(data.as_bytes())
+        match self.backstore.save_state(data.as_bytes()) {
+            Ok(None) => Ok(()),
+            // The metadata was written, but too few copies of it exist for
+            // further writes to be safe.
+            Ok(Some(warning)) => {
+                self.enter_read_only(name, warning);
+                Ok(())
+            }
+            Err(err) => {
+                self.enter_read_only(name, format!("metadata could not be written: {}", err));
+                Err(err)
+            }
+        }
+    }
+
+    /// Compare the pool's DM devices and the BDAs of its blockdevs with
+    /// what is on the system, returning the differences.
+    pub fn discrepancies(&self, pool_uuid: PoolUuid) -> StratisResult<Vec<Discrepancy>> {
+        let mut found = self.backstore.discrepancies(pool_uuid)?;
+        found.extend(self.thin_pool.discrepancies(pool_uuid)?);
+        Ok(found)
+    }
+
+    /// Put the pool into read-only mode, in which operations that would
+    /// change the pool are refused, so that damage to the pool's metadata
+    /// is not compounded. The pool's data remains accessible.
+    fn enter_read_only(&mut self, name: &str, reason: String) {
+        if self.read_only_reason.is_none() {
+            error!("pool {} entering read-only mode: {}", name, reason);
+            self.read_only_reason = Some(reason);
+        }
+    }
+
+    /// Return an error if the pool is in read-only mode or is quiesced.
+    fn check_writable(&self) -> StratisResult<()> {
+        if let Some(ref reason) = self.read_only_reason {
+            return Err(StratisError::Engine(
+                ErrorEnum::Busy,
+                format!("pool is in read-only mode: {}", reason),
+            ));
+        }
+        if self.quiesce_deadline.is_some() {
+            return Err(StratisError::Engine(
+                ErrorEnum::Busy,
+                "pool is quiesced".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Return an error if a filesystem named name with size could not be
+    /// added to the pool because the name is invalid or in use, or the size
+    /// is 0. Space and filesystem limits are checked separately.
+    fn check_filesystem_spec(&self, name: &str, size: Option<Sectors>) -> StratisResult<()> {
+        validate_name(name)?;
+        if self.thin_pool.get_filesystem_by_name(name).is_some() {
+            return Err(StratisError::Engine(
+                ErrorEnum::AlreadyExists,
+                name.to_string(),
+            ));
+        }
+        if size == Some(Sectors(0)) {
+            return Err(StratisError::Engine(
+                ErrorEnum::Invalid,
+                format!("size of filesystem {} must be greater than 0", name),
+            ));
+        }
+        Ok(())
     }
 
     /// Teardown a pool.
-    #[cfg(test)]
     pub fn teardown(&mut self) -> StratisResult<()> {
         self.thin_pool.teardown()?;
         self.backstore.teardown()
     }
 
+    /// Mark the pool as exported from this host in the metadata on its
+    /// members. An exported pool is not set up again until it is imported.
+    /// The caller tears the pool down next; the marker is written first so
+    /// that a pool whose DM devices are gone is never recorded as one that
+    /// may be set up automatically. If the teardown fails, write_metadata()
+    /// clears the marker.
+    /// Returns an error, leaving the pool unchanged, if any of the pool's
+    /// filesystems is mounted.
+    pub fn mark_exported(&mut self, name: &str) -> StratisResult<()> {
+        self.check_writable()?;
+        self.check_unmounted(name)?;
+
+        let mut record = self.record(name);
+        record.exported = Some(ExportSave {
+            host: host_id(),
+            time: Utc::now().timestamp() as u64,
+        });
+        let data = serde_json::to_string(&record)?;
+        self.backstore.save_state(data.as_bytes()).map(|_| ())
+    }
+
+    /// Return an error if any of the pool's filesystems is mounted. A pool
+    /// is stopped by tearing down its DM devices once this has succeeded;
+    /// unlike export, stopping leaves the pool's metadata unchanged.
+    pub fn check_unmounted(&self, name: &str) -> StratisResult<()> {
+        let mounted = self.thin_pool.mounted_filesystems()?;
+        if !mounted.is_empty() {
+            let err_msg = format!(
+                "filesystems {} of pool {} are mounted",
+                mounted
+                    .iter()
+                    .map(|name| name.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                name
+            );
+            return Err(StratisError::Engine(ErrorEnum::Busy, err_msg));
+        }
+        Ok(())
+    }
+
     pub fn has_filesystems(&self) -> bool {
         self.thin_pool.has_filesystems()
     }
 
+    /// The names of the pool's filesystems which could not be set up.
+    pub fn missing_filesystems(&self) -> StratisResult<Vec<Name>> {
+        self.thin_pool.missing_filesystems()
+    }
+
     /// The names of DM devices belonging to this pool that may generate events
     pub fn get_eventing_dev_names(&self, pool_uuid: PoolUuid) -> Vec<DmNameBuf> {
         self.thin_pool.get_eventing_dev_names(pool_uuid)
     }
 
     /// Called when a DM device in this pool has generated an event.
+    /// Nothing is done while the pool is quiesced, as its devices are
+    /// suspended, or while it is in read-only mode. In read-only mode the
+    /// thin pool's data and metadata devices are therefore not extended as
+    /// they fill, since the extension could not be recorded in the pool's
+    /// metadata, and would be lost when the pool was next set up; a pool
+    /// that fills while read-only runs out of space instead.
     // TODO: Just check the device that evented. Currently checks
     // everything.
     pub fn event_on(
@@ -246,7 +558,22 @@ impl StratPool {
             .get_eventing_dev_names(pool_uuid)
             .iter()
             .any(|x| dm_name == &**x));
-        if self.thin_pool.check(pool_uuid, &mut self.backstore)? {
+        if self.read_only_reason.is_some() || self.quiesce_deadline.is_some() {
+            return Ok(());
+        }
+        let changed = self.thin_pool.check(pool_uuid, &mut self.backstore)?;
+        match self.thin_pool.state() {
+            PoolState::ReadOnly | PoolState::Failed => {
+                let reason = format!(
+                    "thin pool reports errors, its state is {:?}",
+                    self.thin_pool.state()
+                );
+                self.enter_read_only(pool_name, reason);
+                return Ok(());
+            }
+            _ => (),
+        }
+        if changed {
             self.write_metadata(pool_name)?;
         }
         Ok(())
@@ -258,12 +585,22 @@ impl StratPool {
             backstore: self.backstore.record(),
             flex_devs: self.thin_pool.record(),
             thinpool_dev: self.thin_pool.record(),
+            host: Some(host_id()).filter(|host| !host.is_empty()),
+            exported: None,
         }
     }
 
     pub fn get_strat_blockdev(&self, uuid: DevUuid) -> Option<(BlockDevTier, &StratBlockDev)> {
         self.backstore.get_blockdev_by_uuid(uuid)
     }
+
+    /// Look up a mutable blockdev by its UUID.
+    pub fn get_mut_strat_blockdev(
+        &mut self,
+        uuid: DevUuid,
+    ) -> Option<(BlockDevTier, &mut StratBlockDev)> {
+        self.backstore.get_mut_blockdev_by_uuid(uuid)
+    }
 }
 
 impl Pool for StratPool {
@@ -273,17 +610,20 @@ impl Pool for StratPool {
         pool_name: &str,
         specs: &[(&'b str, Option<Sectors>)],
     ) -> StratisResult<Vec<(&'b str, FilesystemUuid)>> {
+        self.check_writable()?;
         let names: HashMap<_, _> = HashMap::from_iter(specs.iter().map(|&tup| (tup.0, tup.1)));
-        for name in names.keys() {
-            validate_name(name)?;
-            if self.thin_pool.get_mut_filesystem_by_name(*name).is_some() {
-                return Err(StratisError::Engine(
-                    ErrorEnum::AlreadyExists,
-                    name.to_string(),
-                ));
-            }
+        for (name, size) in names.iter() {
+            self.check_filesystem_spec(name, *size)?;
         }
 
+        let requested = names
+            .values()
+            .map(|size| size.unwrap_or(DEFAULT_THIN_DEV_SIZE))
+            .sum();
+        self.thin_pool
+            .check_overprovision(self.backstore.datatier_usable_size(), requested)?;
+        self.thin_pool.check_filesystem_limit(names.len())?;
+
         // TODO: Roll back on filesystem initialization failure.
         let mut result = Vec::new();
         for (name, size) in names {
@@ -296,6 +636,33 @@ impl Pool for StratPool {
         Ok(result)
     }
 
+    fn create_filesystems_each<'b>(
+        &mut self,
+        pool_uuid: PoolUuid,
+        pool_name: &str,
+        specs: &[(&'b str, Option<Sectors>)],
+    ) -> StratisResult<Vec<(&'b str, StratisResult<FilesystemUuid>)>> {
+        self.check_writable()?;
+        let mut results = Vec::new();
+        for &(name, size) in specs {
+            let result = self
+                .check_filesystem_spec(name, size)
+                .and_then(|_| {
+                    self.thin_pool.check_overprovision(
+                        self.backstore.datatier_usable_size(),
+                        size.unwrap_or(DEFAULT_THIN_DEV_SIZE),
+                    )
+                })
+                .and_then(|_| self.thin_pool.check_filesystem_limit(1))
+                .and_then(|_| {
+                    self.thin_pool
+                        .create_filesystem(pool_uuid, pool_name, name, size)
+                });
+            results.push((name, result));
+        }
+        Ok(results)
+    }
+
     fn add_blockdevs(
         &mut self,
         pool_uuid: PoolUuid,
@@ -303,6 +670,7 @@ impl Pool for StratPool {
         paths: &[&Path],
         tier: BlockDevTier,
     ) -> StratisResult<Vec<DevUuid>> {
+        self.check_writable()?;
         let bdev_info = if tier == BlockDevTier::Cache {
             // If adding cache devices, must suspend the pool, since the cache
             // must be augmeneted with the new devices.
@@ -340,6 +708,7 @@ impl Pool for StratPool {
         pool_name: &str,
         fs_uuids: &[FilesystemUuid],
     ) -> StratisResult<Vec<FilesystemUuid>> {
+        self.check_writable()?;
         let mut removed = Vec::new();
         for &uuid in fs_uuids {
             self.thin_pool.destroy_filesystem(pool_name, uuid)?;
@@ -355,6 +724,7 @@ impl Pool for StratPool {
         uuid: FilesystemUuid,
         new_name: &str,
     ) -> StratisResult<RenameAction> {
+        self.check_writable()?;
         validate_name(new_name)?;
         self.thin_pool.rename_filesystem(pool_name, uuid, new_name)
     }
@@ -365,7 +735,10 @@ impl Pool for StratPool {
         pool_name: &str,
         origin_uuid: FilesystemUuid,
         snapshot_name: &str,
+        keep_xfs_uuid: bool,
+        freeze: bool,
     ) -> StratisResult<(FilesystemUuid, &mut dyn Filesystem)> {
+        self.check_writable()?;
         validate_name(snapshot_name)?;
 
         if self
@@ -379,8 +752,118 @@ impl Pool for StratPool {
             ));
         }
 
+        if let Some((_, origin)) = self.thin_pool.get_filesystem_by_uuid(origin_uuid) {
+            self.thin_pool.check_overprovision(
+                self.backstore.datatier_usable_size(),
+                origin.thindev_size(),
+            )?;
+        }
+        self.thin_pool.check_filesystem_limit(1)?;
+        self.thin_pool.check_snapshot_limit(origin_uuid)?;
+
+        self.thin_pool.snapshot_filesystem(
+            pool_uuid,
+            pool_name,
+            origin_uuid,
+            snapshot_name,
+            keep_xfs_uuid,
+            freeze,
+        )
+    }
+
+    fn revert_filesystem(
+        &mut self,
+        pool_uuid: PoolUuid,
+        pool_name: &str,
+        uuid: FilesystemUuid,
+        snapshot_uuid: FilesystemUuid,
+    ) -> StratisResult<()> {
+        self.check_writable()?;
+        self.thin_pool
+            .revert_filesystem(pool_uuid, pool_name, uuid, snapshot_uuid)
+    }
+
+    fn merge_snapshot(
+        &mut self,
+        pool_uuid: PoolUuid,
+        pool_name: &str,
+        snapshot_uuid: FilesystemUuid,
+    ) -> StratisResult<FilesystemUuid> {
+        self.check_writable()?;
+        self.thin_pool
+            .merge_snapshot(pool_uuid, pool_name, snapshot_uuid)
+    }
+
+    fn extend_filesystem(
+        &mut self,
+        _pool_name: &str,
+        uuid: FilesystemUuid,
+        extend_size: Sectors,
+    ) -> StratisResult<()> {
+        self.check_writable()?;
         self.thin_pool
-            .snapshot_filesystem(pool_uuid, pool_name, origin_uuid, snapshot_name)
+            .check_overprovision(self.backstore.datatier_usable_size(), extend_size)?;
+        self.thin_pool.extend_filesystem(uuid, extend_size)
+    }
+
+    fn set_filesystem_user_info(
+        &mut self,
+        _pool_name: &str,
+        uuid: FilesystemUuid,
+        user_info: Option<&str>,
+    ) -> StratisResult<bool> {
+        self.check_writable()?;
+        self.thin_pool.set_filesystem_user_info(uuid, user_info)
+    }
+
+    fn set_filesystem_size_limit(
+        &mut self,
+        _pool_name: &str,
+        uuid: FilesystemUuid,
+        limit: Option<Sectors>,
+    ) -> StratisResult<bool> {
+        self.check_writable()?;
+        self.thin_pool.set_filesystem_size_limit(uuid, limit)
+    }
+
+    fn set_filesystem_snapshot_limit(
+        &mut self,
+        _pool_name: &str,
+        uuid: FilesystemUuid,
+        limit: Option<u64>,
+    ) -> StratisResult<bool> {
+        self.check_writable()?;
+        self.thin_pool.set_filesystem_snapshot_limit(uuid, limit)
+    }
+
+    fn shrink_filesystem(
+        &mut self,
+        _pool_name: &str,
+        uuid: FilesystemUuid,
+        new_size: Sectors,
+    ) -> StratisResult<()> {
+        self.check_writable()?;
+        self.thin_pool.shrink_filesystem(uuid, new_size)
+    }
+
+    fn set_filesystem_read_only(
+        &mut self,
+        _pool_name: &str,
+        uuid: FilesystemUuid,
+        read_only: bool,
+    ) -> StratisResult<bool> {
+        self.check_writable()?;
+        self.thin_pool.set_filesystem_read_only(uuid, read_only)
+    }
+
+    fn set_filesystem_trim_interval(
+        &mut self,
+        _pool_name: &str,
+        uuid: FilesystemUuid,
+        interval: Option<Duration>,
+    ) -> StratisResult<bool> {
+        self.check_writable()?;
+        self.thin_pool.set_filesystem_trim_interval(uuid, interval)
     }
 
     fn total_physical_size(&self) -> Sectors {
@@ -393,6 +876,36 @@ impl Pool for StratPool {
             .and_then(|v| Ok(v + self.backstore.datatier_metadata_size()))
     }
 
+    fn space_report(&self) -> StratisResult<PoolSpaceReport> {
+        let (thin_data_used_pct, thin_meta_used_pct) = self.thin_pool.usage_pcts()?;
+        let free = self.backstore.available_in_backstore();
+        Ok(PoolSpaceReport {
+            raw_size: self.backstore.datatier_size(),
+            metadata_overhead: self.backstore.datatier_metadata_size(),
+            allocated: self.backstore.datatier_usable_size() - free,
+            free,
+            reserved: cmp::min(free, self.thin_pool.reserved_size(&self.backstore)),
+            thin_data_used_pct,
+            thin_meta_used_pct,
+            filesystems: self
+                .thin_pool
+                .filesystems()
+                .iter()
+                .map(|(_, uuid, fs)| Ok((*uuid, fs.used()?)))
+                .collect::<StratisResult<Vec<_>>>()?,
+        })
+    }
+
+    fn filesystem_trees(&self) -> Vec<FilesystemTree> {
+        FilesystemTree::build(
+            self.thin_pool
+                .filesystems()
+                .into_iter()
+                .map(|(name, uuid, fs)| (name, uuid, fs.origin()))
+                .collect(),
+        )
+    }
+
     fn filesystems(&self) -> Vec<(Name, FilesystemUuid, &dyn Filesystem)> {
         self.thin_pool.filesystems()
     }
@@ -446,6 +959,7 @@ impl Pool for StratPool {
         uuid: DevUuid,
         user_info: Option<&str>,
     ) -> StratisResult<bool> {
+        self.check_writable()?;
         if self.backstore.set_blockdev_user_info(uuid, user_info)? {
             self.write_metadata(pool_name)?;
             Ok(true)
@@ -454,6 +968,94 @@ impl Pool for StratPool {
         }
     }
 
+    fn set_blockdev_maintenance(
+        &mut self,
+        pool_name: &str,
+        uuid: DevUuid,
+        maintenance: bool,
+    ) -> StratisResult<bool> {
+        self.check_writable()?;
+        if !self.backstore.set_blockdev_maintenance(uuid, maintenance)? {
+            return Ok(false);
+        }
+
+        // The maintenance state is recorded so that it survives a restart.
+        self.write_metadata(pool_name)?;
+        if !maintenance {
+            let data = serde_json::to_string(&self.record(pool_name))?;
+            self.backstore.resync_blockdev(uuid, data.as_bytes())?;
+        }
+        Ok(true)
+    }
+
+    fn drain_blockdev(
+        &mut self,
+        pool_uuid: PoolUuid,
+        pool_name: &str,
+        uuid: DevUuid,
+    ) -> StratisResult<bool> {
+        self.check_writable()?;
+        self.thin_pool.suspend()?;
+        // The new mapping is recorded in the metadata while the cap device,
+        // and the thin pool above it, are still suspended, so that nothing
+        // is written to the new location of the data unless the metadata
+        // says it is there.
+        let drained = match self.backstore.drain_datadev(pool_uuid, uuid) {
+            Ok(Some(drain)) => match self.write_metadata(pool_name) {
+                Ok(()) => self.backstore.finish_drain(drain).map(|_| true),
+                Err(err) => {
+                    if let Err(e) = self.backstore.abandon_drain(drain) {
+                        warn!(
+                            "Could not restore the cap device of pool {}: {}",
+                            pool_name, e
+                        );
+                    }
+                    Err(err)
+                }
+            },
+            Ok(None) => Ok(false),
+            Err(err) => Err(err),
+        };
+        self.thin_pool.resume()?;
+        drained
+    }
+
+    fn reserve_metadata_snapshot(&mut self) -> StratisResult<(PathBuf, MetaBlocks)> {
+        self.thin_pool.reserve_metadata_snapshot()
+    }
+
+    fn release_metadata_snapshot(&mut self) -> StratisResult<bool> {
+        self.thin_pool.release_metadata_snapshot()
+    }
+
+    fn backup_metadata(
+        &self,
+        pool_uuid: PoolUuid,
+        pool_name: &str,
+        dir: &Path,
+    ) -> StratisResult<PathBuf> {
+        let now = Utc::now();
+        let backup = MetadataBackupSave {
+            pool_uuid,
+            sequence: self.backstore.last_sequence().unwrap_or(0),
+            time: now.timestamp() as u64,
+            metadata: self.record(pool_name),
+        };
+
+        let path = dir.join(format!(
+            "{}-{}.json",
+            pool_uuid.to_simple_ref(),
+            now.format("%Y%m%dT%H%M%S%.9fZ")
+        ));
+        let mut f = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        f.write_all(serde_json::to_string(&backup)?.as_bytes())?;
+        f.sync_all()?;
+        Ok(path)
+    }
+
     fn state(&self) -> PoolState {
         self.thin_pool.state()
     }
@@ -466,65 +1068,206 @@ impl Pool for StratPool {
         self.thin_pool.free_space_state()
     }
 
-    fn set_dbus_path(&mut self, path: MaybeDbusPath) {
-        self.thin_pool.set_dbus_path(path.clone());
-        self.dbus_path = path
+    fn overprovision_policy(&self) -> OverprovisionPolicy {
+        self.thin_pool.overprovision_policy()
     }
 
-    fn get_dbus_path(&self) -> &MaybeDbusPath {
-        &self.dbus_path
+    fn allocation_strategy(&self) -> AllocationStrategy {
+        self.backstore.allocation_strategy()
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::fs::OpenOptions;
-    use std::io::{BufWriter, Read, Write};
+    fn set_allocation_strategy(
+        &mut self,
+        pool_name: &str,
+        strategy: AllocationStrategy,
+    ) -> StratisResult<bool> {
+        self.check_writable()?;
+        if self.backstore.set_allocation_strategy(strategy) {
+            self.write_metadata(pool_name)?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
 
-    use nix::mount::{mount, umount, MsFlags};
-    use tempfile;
+    fn quiesce(&mut self, timeout: Duration) -> StratisResult<bool> {
+        if self.quiesce_deadline.is_some() {
+            return Ok(false);
+        }
+        if let Err(err) = self.thin_pool.quiesce() {
+            if let Err(resume_err) = self.thin_pool.unquiesce() {
+                warn!(
+                    "failed to resume pool after failing to quiesce it: {:?}",
+                    resume_err
+                );
+            }
+            return Err(err);
+        }
+        self.quiesce_deadline = Some(Instant::now() + timeout);
+        Ok(true)
+    }
 
-    use devicemapper::{Bytes, IEC, SECTOR_SIZE};
+    fn unquiesce(&mut self) -> StratisResult<bool> {
+        if self.quiesce_deadline.is_none() {
+            return Ok(false);
+        }
+        self.thin_pool.unquiesce()?;
+        self.quiesce_deadline = None;
+        Ok(true)
+    }
 
-    use crate::engine::devlinks;
-    use crate::engine::types::Redundancy;
+    fn quiesce_deadline(&self) -> Option<Instant> {
+        self.quiesce_deadline
+    }
 
-    use crate::engine::strat_engine::backstore::{find_all, get_metadata};
-    use crate::engine::strat_engine::cmd;
-    use crate::engine::strat_engine::tests::{loopbacked, real};
+    fn read_only_reason(&self) -> Option<&str> {
+        self.read_only_reason.as_ref().map(|reason| &reason[..])
+    }
 
-    use super::*;
+    fn clear_read_only(&mut self) -> bool {
+        self.read_only_reason.take().is_some()
+    }
 
-    fn invariant(pool: &StratPool, pool_name: &str) {
-        check_metadata(&pool.record(&Name::new(pool_name.into()))).unwrap();
+    fn set_overprovision_policy(
+        &mut self,
+        pool_name: &str,
+        policy: OverprovisionPolicy,
+    ) -> StratisResult<bool> {
+        self.check_writable()?;
+        if self.thin_pool.set_overprovision_policy(policy)? {
+            self.write_metadata(pool_name)?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
     }
 
-    /// Verify that metadata can be read from pools.
-    /// 1. Split paths into two separate sets.
-    /// 2. Create pools from the two sets.
-    /// 3. Use find_all() to get the devices in the pool.
-    /// 4. Use get_metadata to find metadata for each pool and verify
-    /// correctness.
-    /// 5. Teardown the engine and repeat.
-    fn test_basic_metadata(paths: &[&Path]) {
-        assert!(paths.len() > 1);
+    fn chunk_size(&self) -> Sectors {
+        self.thin_pool.data_block_size()
+    }
 
-        let (paths1, paths2) = paths.split_at(paths.len() / 2);
+    fn reserved_pct(&self) -> u8 {
+        self.thin_pool.reserved_pct()
+    }
 
-        let name1 = "name1";
-        let (uuid1, mut pool1) = StratPool::initialize(&name1, paths1, Redundancy::NONE).unwrap();
+    fn set_reserved_pct(&mut self, pool_name: &str, pct: u8) -> StratisResult<bool> {
+        self.check_writable()?;
+        if self.thin_pool.set_reserved_pct(pct)? {
+            self.write_metadata(pool_name)?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn filesystem_limit(&self) -> u64 {
+        self.thin_pool.filesystem_limit()
+    }
+
+    fn set_filesystem_limit(&mut self, pool_name: &str, limit: Option<u64>) -> StratisResult<bool> {
+        self.check_writable()?;
+        if self.thin_pool.set_filesystem_limit(limit)? {
+            self.write_metadata(pool_name)?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn snapshot_limit(&self) -> Option<u64> {
+        self.thin_pool.snapshot_limit()
+    }
+
+    fn set_snapshot_limit(&mut self, pool_name: &str, limit: Option<u64>) -> StratisResult<bool> {
+        self.check_writable()?;
+        if self.thin_pool.set_snapshot_limit(limit) {
+            self.write_metadata(pool_name)?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn set_dbus_path(&mut self, path: MaybeDbusPath) {
+        self.thin_pool.set_dbus_path(path.clone());
+        self.dbus_path = path
+    }
+
+    fn get_dbus_path(&self) -> &MaybeDbusPath {
+        &self.dbus_path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![cfg_attr(not(feature = "device_tests"), allow(dead_code, unused_imports))]
+
+    use std::fs::OpenOptions;
+    use std::io::{BufWriter, Read, Write};
+
+    use nix::mount::{mount, umount, MsFlags};
+    use tempfile;
+
+    use devicemapper::{Bytes, IEC, SECTOR_SIZE};
+
+    use crate::engine::devlinks;
+    use crate::engine::types::{BlockDevState, Redundancy};
+
+    use crate::engine::strat_engine::backstore::{find_all, get_metadata, is_stratis_device};
+    use crate::engine::strat_engine::cmd;
+    use crate::engine::strat_engine::serde_structs::{AllocationSave, OverprovisionSave};
+    use crate::engine::strat_engine::tests::{loopbacked, real};
+
+    use super::*;
+
+    /// Initialize a pool with the default chunk size and allocation
+    /// strategy, for tests that do not depend on either.
+    fn initialize_with_defaults(
+        name: &str,
+        paths: &[&Path],
+        redundancy: Redundancy,
+    ) -> StratisResult<(PoolUuid, StratPool)> {
+        StratPool::initialize(
+            name,
+            paths,
+            redundancy,
+            ChunkSize::Auto,
+            AllocationStrategy::FillFirst,
+        )
+    }
+
+    fn invariant(pool: &StratPool, pool_name: &str) {
+        check_metadata(&pool.record(&Name::new(pool_name.into()))).unwrap();
+    }
+
+    /// Verify that metadata can be read from pools.
+    /// 1. Split paths into two separate sets.
+    /// 2. Create pools from the two sets.
+    /// 3. Use find_all() to get the devices in the pool.
+    /// 4. Use get_metadata to find metadata for each pool and verify
+    /// correctness.
+    /// 5. Teardown the engine and repeat.
+    fn test_basic_metadata(paths: &[&Path]) {
+        assert!(paths.len() > 1);
+
+        let (paths1, paths2) = paths.split_at(paths.len() / 2);
+
+        let name1 = "name1";
+        let (uuid1, mut pool1) =
+            initialize_with_defaults(&name1, paths1, Redundancy::NONE).unwrap();
         invariant(&pool1, &name1);
 
         let metadata1 = pool1.record(name1);
 
         let name2 = "name2";
-        let (uuid2, mut pool2) = StratPool::initialize(&name2, paths2, Redundancy::NONE).unwrap();
+        let (uuid2, mut pool2) =
+            initialize_with_defaults(&name2, paths2, Redundancy::NONE).unwrap();
         invariant(&pool2, &name2);
 
         let metadata2 = pool2.record(name2);
 
         cmd::udev_settle().unwrap();
-        let pools = find_all().unwrap();
+        let (pools, _) = find_all().unwrap();
         assert_eq!(pools.len(), 2);
         let devnodes1 = &pools[&uuid1];
         let devnodes2 = &pools[&uuid2];
@@ -537,7 +1280,7 @@ mod tests {
         pool2.teardown().unwrap();
 
         cmd::udev_settle().unwrap();
-        let pools = find_all().unwrap();
+        let (pools, _) = find_all().unwrap();
         assert_eq!(pools.len(), 2);
         let devnodes1 = &pools[&uuid1];
         let devnodes2 = &pools[&uuid2];
@@ -547,6 +1290,7 @@ mod tests {
         assert_eq!(pool_save2, metadata2);
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn loop_test_basic_metadata() {
         loopbacked::test_with_spec(
@@ -555,6 +1299,7 @@ mod tests {
         );
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn real_test_basic_metadata() {
         real::test_with_spec(
@@ -567,14 +1312,16 @@ mod tests {
     /// space required.
     fn test_empty_pool(paths: &[&Path]) {
         assert_eq!(paths.len(), 0);
-        assert!(StratPool::initialize("stratis_test_pool", paths, Redundancy::NONE).is_err());
+        assert!(initialize_with_defaults("stratis_test_pool", paths, Redundancy::NONE).is_err());
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn loop_test_empty_pool() {
         loopbacked::test_with_spec(&loopbacked::DeviceLimits::Exactly(0, None), test_empty_pool);
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn real_test_empty_pool() {
         real::test_with_spec(&real::DeviceLimits::Exactly(0, None, None), test_empty_pool);
@@ -591,7 +1338,7 @@ mod tests {
 
         let name = "stratis-test-pool";
         devlinks::cleanup_devlinks(Vec::new().into_iter());
-        let (uuid, mut pool) = StratPool::initialize(&name, paths2, Redundancy::NONE).unwrap();
+        let (uuid, mut pool) = initialize_with_defaults(&name, paths2, Redundancy::NONE).unwrap();
         devlinks::pool_added(&name);
         invariant(&pool, &name);
 
@@ -653,7 +1400,7 @@ mod tests {
         pool.teardown().unwrap();
 
         cmd::udev_settle().unwrap();
-        let pools = find_all().unwrap();
+        let (pools, _) = find_all().unwrap();
         assert_eq!(pools.len(), 1);
         let devices = &pools[&uuid];
         let (name, pool) = StratPool::setup(
@@ -686,6 +1433,7 @@ mod tests {
         umount(tmp_dir.path()).unwrap();
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn loop_test_add_cachedevs() {
         loopbacked::test_with_spec(
@@ -694,6 +1442,7 @@ mod tests {
         );
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn real_test_add_cachedevs() {
         real::test_with_spec(
@@ -702,6 +1451,101 @@ mod tests {
         );
     }
 
+    /// Verify that draining a data tier blockdev moves all its data to the
+    /// other blockdevs, and that the data in the pool's filesystems is the
+    /// same afterwards.
+    fn test_drain_blockdev(paths: &[&Path]) {
+        assert!(paths.len() > 2);
+
+        let (paths1, paths2) = paths.split_at(1);
+
+        let name = "stratis-test-pool";
+        devlinks::cleanup_devlinks(Vec::new().into_iter());
+        let (uuid, mut pool) = initialize_with_defaults(name, paths1, Redundancy::NONE).unwrap();
+        devlinks::pool_added(name);
+        let drained_uuid = pool.blockdevs()[0].0;
+
+        // Hold half the capacity in reserve, so that the space on the
+        // blockdevs added is not all taken by the thin pool.
+        pool.set_reserved_pct(name, 50).unwrap();
+        pool.add_blockdevs(uuid, name, paths2, BlockDevTier::Data)
+            .unwrap();
+        invariant(&pool, &name);
+
+        let (_, fs_uuid) = pool
+            .create_filesystems(uuid, &name, &[("stratis-filesystem", None)])
+            .unwrap()
+            .pop()
+            .unwrap();
+
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("stratis_testing")
+            .tempdir()
+            .unwrap();
+        let new_file = tmp_dir.path().join("stratis_test.txt");
+        let bytestring = b"some bytes";
+        let fs_devnode = pool.get_filesystem(fs_uuid).unwrap().1.devnode();
+        let mount_fs = || {
+            mount(
+                Some(&fs_devnode),
+                tmp_dir.path(),
+                Some("xfs"),
+                MsFlags::empty(),
+                None as Option<&str>,
+            )
+            .unwrap();
+        };
+
+        mount_fs();
+        OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(&new_file)
+            .unwrap()
+            .write_all(bytestring)
+            .unwrap();
+        umount(tmp_dir.path()).unwrap();
+
+        assert_matches!(pool.drain_blockdev(uuid, name, drained_uuid), Ok(true));
+        invariant(&pool, &name);
+        assert_eq!(
+            pool.get_blockdev(drained_uuid).unwrap().1.state(),
+            BlockDevState::NotInUse
+        );
+        assert_matches!(pool.drain_blockdev(uuid, name, drained_uuid), Ok(false));
+
+        mount_fs();
+        let mut buf = [0u8; 10];
+        OpenOptions::new()
+            .read(true)
+            .open(&new_file)
+            .unwrap()
+            .read_exact(&mut buf)
+            .unwrap();
+        assert_eq!(&buf, bytestring);
+        umount(tmp_dir.path()).unwrap();
+
+        pool.teardown().unwrap();
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_drain_blockdev() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Range(3, 4, None),
+            test_drain_blockdev,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_drain_blockdev() {
+        real::test_with_spec(
+            &real::DeviceLimits::AtLeast(3, None, None),
+            test_drain_blockdev,
+        );
+    }
+
     /// Verify that adding additional blockdevs will cause a pool that is
     /// out of space to be extended.
     fn test_add_datadevs(paths: &[&Path]) {
@@ -711,7 +1555,8 @@ mod tests {
 
         let name = "stratis-test-pool";
         devlinks::cleanup_devlinks(Vec::new().into_iter());
-        let (pool_uuid, mut pool) = StratPool::initialize(&name, paths1, Redundancy::NONE).unwrap();
+        let (pool_uuid, mut pool) =
+            initialize_with_defaults(&name, paths1, Redundancy::NONE).unwrap();
         devlinks::pool_added(&name);
         invariant(&pool, &name);
 
@@ -757,6 +1602,7 @@ mod tests {
         }
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn loop_test_add_datadevs() {
         loopbacked::test_with_spec(
@@ -765,6 +1611,7 @@ mod tests {
         );
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn real_test_add_datadevs() {
         real::test_with_spec(
@@ -776,4 +1623,685 @@ mod tests {
             test_add_datadevs,
         );
     }
+
+    /// Verify that an overprovisioning limit is recorded in the pool's
+    /// metadata and prevents creating filesystems beyond it, while an
+    /// overprovisioning warning does not.
+    fn test_overprovision_policy(paths: &[&Path]) {
+        let name = "stratis-test-pool";
+        let (uuid, mut pool) = initialize_with_defaults(name, paths, Redundancy::NONE).unwrap();
+
+        assert_matches!(
+            pool.set_overprovision_policy(name, OverprovisionPolicy::Limit(1)),
+            Ok(true)
+        );
+        assert_eq!(
+            pool.record(name).thinpool_dev.overprovision,
+            Some(OverprovisionSave {
+                ratio_pct: 1,
+                enforce: true,
+            })
+        );
+        assert_matches!(
+            pool.create_filesystems(uuid, name, &[("stratis_test_filesystem", None)]),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+
+        assert_matches!(
+            pool.set_overprovision_policy(name, OverprovisionPolicy::Warn(1)),
+            Ok(true)
+        );
+        pool.create_filesystems(uuid, name, &[("stratis_test_filesystem", None)])
+            .unwrap();
+
+        assert_matches!(
+            pool.create_filesystems(uuid, name, &[("zero_size_filesystem", Some(Sectors(0)))]),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+        let fs_size = Bytes(512 * IEC::Mi).sectors();
+        let fs_uuid = pool
+            .create_filesystems(uuid, name, &[("sized_filesystem", Some(fs_size))])
+            .unwrap()[0]
+            .1;
+        assert_eq!(
+            pool.get_filesystem(fs_uuid).unwrap().1.initial_size(),
+            Some(fs_size)
+        );
+
+        pool.teardown().unwrap();
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_overprovision_policy() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Range(1, 3, None),
+            test_overprovision_policy,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_overprovision_policy() {
+        real::test_with_spec(
+            &real::DeviceLimits::AtLeast(1, None, None),
+            test_overprovision_policy,
+        );
+    }
+
+    /// Verify that filesystems created in a batch are created or refused
+    /// one by one, and that one refused for exceeding the overprovisioning
+    /// limit does not prevent the creation of a smaller one after it.
+    fn test_create_filesystems_each(paths: &[&Path]) {
+        let name = "stratis-test-pool";
+        let (uuid, mut pool) = initialize_with_defaults(name, paths, Redundancy::NONE).unwrap();
+        pool.set_overprovision_policy(name, OverprovisionPolicy::Limit(100))
+            .unwrap();
+
+        let too_big = pool.total_physical_size() * 2u64;
+        let results = pool
+            .create_filesystems_each(
+                uuid,
+                name,
+                &[
+                    ("root", Some(Bytes(512 * IEC::Mi).sectors())),
+                    ("root", None),
+                    ("home", Some(too_big)),
+                    ("var", Some(Bytes(512 * IEC::Mi).sectors())),
+                ],
+            )
+            .unwrap();
+        assert!(results[0].1.is_ok());
+        assert_matches!(
+            results[1].1,
+            Err(StratisError::Engine(ErrorEnum::AlreadyExists, _))
+        );
+        assert_matches!(
+            results[2].1,
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+        assert!(results[3].1.is_ok());
+        assert_eq!(pool.filesystems().len(), 2);
+
+        pool.teardown().unwrap();
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_create_filesystems_each() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Range(1, 3, None),
+            test_create_filesystems_each,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_create_filesystems_each() {
+        real::test_with_spec(
+            &real::DeviceLimits::AtLeast(1, None, None),
+            test_create_filesystems_each,
+        );
+    }
+
+    /// Verify that a reserve set on a pool is recorded in its metadata, and
+    /// that space added to the pool afterwards goes to the reserve before
+    /// the thin pool's data device may grow into it.
+    fn test_reserved_pct(paths: &[&Path]) {
+        assert!(paths.len() > 1);
+
+        let (paths1, paths2) = paths.split_at(1);
+
+        let name = "stratis-test-pool";
+        let (uuid, mut pool) = initialize_with_defaults(name, paths1, Redundancy::NONE).unwrap();
+        invariant(&pool, name);
+
+        assert_matches!(
+            pool.set_reserved_pct(name, 51),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(pool.set_reserved_pct(name, 10), Ok(true));
+        assert_eq!(pool.record(name).thinpool_dev.reserved_pct, Some(10));
+
+        pool.add_blockdevs(uuid, name, paths2, BlockDevTier::Data)
+            .unwrap();
+        invariant(&pool, name);
+
+        let report = pool.space_report().unwrap();
+        assert_eq!(
+            report.reserved,
+            Sectors(*pool.backstore.datatier_usable_size() / 10)
+        );
+        assert!(report.free >= report.reserved);
+
+        assert_matches!(pool.set_reserved_pct(name, 0), Ok(true));
+        assert_eq!(pool.record(name).thinpool_dev.reserved_pct, None);
+
+        pool.teardown().unwrap();
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_reserved_pct() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Range(2, 3, None),
+            test_reserved_pct,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_reserved_pct() {
+        real::test_with_spec(
+            &real::DeviceLimits::AtLeast(2, None, None),
+            test_reserved_pct,
+        );
+    }
+
+    /// Verify that the default filesystem limit follows the size of the thin
+    /// pool meta device, that an explicit limit is recorded in the pool's
+    /// metadata, and that filesystems and snapshots beyond it are refused.
+    fn test_filesystem_limit(paths: &[&Path]) {
+        let name = "stratis-test-pool";
+        let (uuid, mut pool) = initialize_with_defaults(name, paths, Redundancy::NONE).unwrap();
+        invariant(&pool, name);
+
+        assert!(pool.filesystem_limit() > 1);
+        assert_eq!(pool.record(name).thinpool_dev.filesystem_limit, None);
+
+        let fs_uuid = pool
+            .create_filesystems(uuid, name, &[("stratis-filesystem", None)])
+            .unwrap()
+            .pop()
+            .unwrap()
+            .1;
+
+        assert_matches!(
+            pool.set_filesystem_limit(name, Some(0)),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(pool.set_filesystem_limit(name, Some(1)), Ok(true));
+        assert_eq!(pool.record(name).thinpool_dev.filesystem_limit, Some(1));
+
+        assert_matches!(
+            pool.create_filesystems(uuid, name, &[("other-filesystem", None)]),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(
+            pool.snapshot_filesystem(uuid, name, fs_uuid, "stratis-snapshot", false, false),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+
+        assert_matches!(pool.set_filesystem_limit(name, Some(2)), Ok(true));
+        assert!(pool
+            .snapshot_filesystem(uuid, name, fs_uuid, "stratis-snapshot", false, false)
+            .is_ok());
+        invariant(&pool, name);
+
+        pool.teardown().unwrap();
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_filesystem_limit() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Range(1, 3, None),
+            test_filesystem_limit,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_filesystem_limit() {
+        real::test_with_spec(
+            &real::DeviceLimits::AtLeast(1, None, None),
+            test_filesystem_limit,
+        );
+    }
+
+    /// Verify that a filesystem's snapshot limit, or failing that the
+    /// pool's, is enforced when a snapshot is taken, and that the pool's
+    /// limit is recorded in its metadata.
+    fn test_snapshot_limit(paths: &[&Path]) {
+        let name = "stratis-test-pool";
+        let (uuid, mut pool) = initialize_with_defaults(name, paths, Redundancy::NONE).unwrap();
+        invariant(&pool, name);
+
+        let fs_uuid = pool
+            .create_filesystems(uuid, name, &[("stratis-filesystem", None)])
+            .unwrap()
+            .pop()
+            .unwrap()
+            .1;
+
+        assert_matches!(pool.set_snapshot_limit(name, Some(0)), Ok(true));
+        assert_eq!(pool.record(name).thinpool_dev.snapshot_limit, Some(0));
+        assert_matches!(
+            pool.snapshot_filesystem(uuid, name, fs_uuid, "stratis-snapshot", false, false),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+
+        assert_matches!(
+            pool.set_filesystem_snapshot_limit(name, fs_uuid, Some(1)),
+            Ok(true)
+        );
+        assert!(pool
+            .snapshot_filesystem(uuid, name, fs_uuid, "stratis-snapshot", false, false)
+            .is_ok());
+        assert_matches!(
+            pool.snapshot_filesystem(uuid, name, fs_uuid, "other-snapshot", false, false),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+        assert_eq!(
+            pool.get_filesystem(fs_uuid).unwrap().1.snapshot_limit(),
+            Some(1)
+        );
+        invariant(&pool, name);
+
+        pool.teardown().unwrap();
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_snapshot_limit() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Range(1, 3, None),
+            test_snapshot_limit,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_snapshot_limit() {
+        real::test_with_spec(
+            &real::DeviceLimits::AtLeast(1, None, None),
+            test_snapshot_limit,
+        );
+    }
+
+    /// Verify that an invalid chunk size is refused before any device is
+    /// written to, and that a valid one is given to the thin pool and
+    /// recorded in the pool's metadata.
+    fn test_fixed_chunk_size(paths: &[&Path]) {
+        let name = "stratis-test-pool";
+        assert_matches!(
+            StratPool::initialize(
+                name,
+                paths,
+                Redundancy::NONE,
+                ChunkSize::Fixed(Sectors(100)),
+                AllocationStrategy::FillFirst
+            ),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+        for path in paths {
+            assert_eq!(is_stratis_device(path).unwrap(), None);
+        }
+
+        let (uuid, mut pool) = StratPool::initialize(
+            name,
+            paths,
+            Redundancy::NONE,
+            ChunkSize::Fixed(Sectors(256)),
+            AllocationStrategy::FillFirst,
+        )
+        .unwrap();
+        invariant(&pool, name);
+        assert_eq!(pool.chunk_size(), Sectors(256));
+        assert_eq!(pool.record(name).thinpool_dev.data_block_size, Sectors(256));
+
+        pool.create_filesystems(uuid, name, &[("stratis-filesystem", None)])
+            .unwrap();
+        invariant(&pool, name);
+
+        pool.teardown().unwrap();
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_fixed_chunk_size() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Range(1, 3, None),
+            test_fixed_chunk_size,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_fixed_chunk_size() {
+        real::test_with_spec(
+            &real::DeviceLimits::AtLeast(1, None, None),
+            test_fixed_chunk_size,
+        );
+    }
+
+    /// Verify that a pool can not be cloned while one of its filesystems is
+    /// mounted, and that once it is unmounted the clone has its chunk size
+    /// and a copy of its filesystem's contents under a new UUID.
+    fn test_clone_pool(paths: &[&Path]) {
+        assert!(paths.len() > 1);
+
+        let (paths1, paths2) = paths.split_at(paths.len() / 2);
+
+        let name = "stratis-test-pool";
+        let (uuid, mut pool) = StratPool::initialize(
+            name,
+            paths1,
+            Redundancy::NONE,
+            ChunkSize::Fixed(Sectors(256)),
+            AllocationStrategy::RoundRobin,
+        )
+        .unwrap();
+        pool.set_reserved_pct(name, 10).unwrap();
+        let (_, fs_uuid) = pool
+            .create_filesystems(uuid, name, &[("stratis-filesystem", None)])
+            .unwrap()
+            .pop()
+            .unwrap();
+
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("stratis_testing")
+            .tempdir()
+            .unwrap();
+        let new_file = tmp_dir.path().join("stratis_test.txt");
+        let bytestring = b"some bytes";
+        mount(
+            Some(&pool.get_filesystem(fs_uuid).unwrap().1.devnode()),
+            tmp_dir.path(),
+            Some("xfs"),
+            MsFlags::empty(),
+            None as Option<&str>,
+        )
+        .unwrap();
+        OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(&new_file)
+            .unwrap()
+            .write_all(bytestring)
+            .unwrap();
+
+        let clone_name = "stratis-test-clone";
+        assert_matches!(
+            pool.clone_onto(clone_name, paths2),
+            Err(StratisError::Engine(ErrorEnum::Busy, _))
+        );
+        for path in paths2 {
+            assert_eq!(is_stratis_device(path).unwrap(), None);
+        }
+        umount(tmp_dir.path()).unwrap();
+
+        let (clone_uuid, mut clone) = pool.clone_onto(clone_name, paths2).unwrap();
+        invariant(&clone, clone_name);
+        assert_ne!(clone_uuid, uuid);
+        assert_eq!(clone.chunk_size(), Sectors(256));
+        assert_eq!(clone.allocation_strategy(), AllocationStrategy::RoundRobin);
+        assert_eq!(clone.reserved_pct(), 10);
+
+        let filesystems = clone.filesystems();
+        assert_eq!(filesystems.len(), 1);
+        assert_eq!(&*filesystems[0].0, "stratis-filesystem");
+        assert_ne!(filesystems[0].1, fs_uuid);
+
+        mount(
+            Some(&filesystems[0].2.devnode()),
+            tmp_dir.path(),
+            Some("xfs"),
+            MsFlags::empty(),
+            None as Option<&str>,
+        )
+        .unwrap();
+        let mut buf = [0u8; 10];
+        OpenOptions::new()
+            .read(true)
+            .open(&new_file)
+            .unwrap()
+            .read_exact(&mut buf)
+            .unwrap();
+        assert_eq!(&buf, bytestring);
+        umount(tmp_dir.path()).unwrap();
+
+        clone.teardown().unwrap();
+        pool.teardown().unwrap();
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_clone_pool() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Range(2, 3, None),
+            test_clone_pool,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_clone_pool() {
+        real::test_with_spec(&real::DeviceLimits::AtLeast(2, None, None), test_clone_pool);
+    }
+
+    /// Verify that a quiesced pool refuses changes until it is resumed.
+    fn test_quiesce(paths: &[&Path]) {
+        let name = "stratis-test-pool";
+        let (uuid, mut pool) = initialize_with_defaults(name, paths, Redundancy::NONE).unwrap();
+        pool.create_filesystems(uuid, name, &[("stratis_test_filesystem", None)])
+            .unwrap();
+
+        assert_matches!(pool.quiesce(Duration::from_secs(60)), Ok(true));
+        assert_matches!(pool.quiesce(Duration::from_secs(60)), Ok(false));
+        assert!(pool.quiesce_deadline().is_some());
+        assert_matches!(
+            pool.create_filesystems(uuid, name, &[("stratis_test_filesystem_2", None)]),
+            Err(StratisError::Engine(ErrorEnum::Busy, _))
+        );
+
+        assert_matches!(pool.unquiesce(), Ok(true));
+        assert_matches!(pool.unquiesce(), Ok(false));
+        assert_eq!(pool.quiesce_deadline(), None);
+        pool.create_filesystems(uuid, name, &[("stratis_test_filesystem_2", None)])
+            .unwrap();
+
+        pool.teardown().unwrap();
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_quiesce() {
+        loopbacked::test_with_spec(&loopbacked::DeviceLimits::Range(1, 3, None), test_quiesce);
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_quiesce() {
+        real::test_with_spec(&real::DeviceLimits::AtLeast(1, None, None), test_quiesce);
+    }
+
+    /// Verify that the allocation strategy the pool is created with, and a
+    /// later change to it, is recorded in the metadata.
+    fn test_allocation_strategy(paths: &[&Path]) {
+        let name = "stratis-test-pool";
+        let (_, mut pool) = StratPool::initialize(
+            name,
+            paths,
+            Redundancy::NONE,
+            ChunkSize::Auto,
+            AllocationStrategy::RoundRobin,
+        )
+        .unwrap();
+        assert_eq!(pool.allocation_strategy(), AllocationStrategy::RoundRobin);
+        assert_eq!(
+            pool.record(name).backstore.data_tier.allocation,
+            Some(AllocationSave::RoundRobin)
+        );
+        assert!(serde_json::to_string(&pool.record(name))
+            .unwrap()
+            .contains("\"allocation\":\"round_robin\""));
+
+        assert_matches!(
+            pool.set_allocation_strategy(name, AllocationStrategy::FillFirst),
+            Ok(true)
+        );
+        assert_matches!(
+            pool.set_allocation_strategy(name, AllocationStrategy::FillFirst),
+            Ok(false)
+        );
+        assert_eq!(pool.record(name).backstore.data_tier.allocation, None);
+
+        pool.teardown().unwrap();
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_allocation_strategy() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Range(1, 3, None),
+            test_allocation_strategy,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_allocation_strategy() {
+        real::test_with_spec(
+            &real::DeviceLimits::AtLeast(1, None, None),
+            test_allocation_strategy,
+        );
+    }
+
+    /// Verify that a blockdev that holds data can not be taken offline, that
+    /// one that holds none can be, and that it is still offline after the
+    /// pool is torn down and set up again.
+    fn test_blockdev_maintenance(paths: &[&Path]) {
+        assert!(paths.len() > 1);
+
+        let (paths1, paths2) = paths.split_at(1);
+
+        let name = "stratis-test-pool";
+        let (uuid, mut pool) = initialize_with_defaults(name, paths1, Redundancy::NONE).unwrap();
+        let in_use = pool.blockdevs()[0].0;
+        let offline = pool
+            .add_blockdevs(uuid, name, paths2, BlockDevTier::Data)
+            .unwrap()[0];
+
+        assert_matches!(
+            pool.set_blockdev_maintenance(name, in_use, true),
+            Err(StratisError::Engine(ErrorEnum::Busy, _))
+        );
+        assert_matches!(pool.set_blockdev_maintenance(name, offline, true), Ok(true));
+
+        pool.teardown().unwrap();
+
+        cmd::udev_settle().unwrap();
+        let (pools, _) = find_all().unwrap();
+        let devices = &pools[&uuid];
+        let (name, mut pool) = StratPool::setup(
+            uuid,
+            &devices,
+            &get_metadata(uuid, &devices).unwrap().unwrap(),
+        )
+        .unwrap();
+        assert!(pool.get_blockdev(offline).unwrap().1.in_maintenance());
+        assert!(!pool.get_blockdev(in_use).unwrap().1.in_maintenance());
+
+        assert_matches!(
+            pool.set_blockdev_maintenance(&name, offline, false),
+            Ok(true)
+        );
+        pool.teardown().unwrap();
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_blockdev_maintenance() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Range(2, 3, None),
+            test_blockdev_maintenance,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_blockdev_maintenance() {
+        real::test_with_spec(
+            &real::DeviceLimits::AtLeast(2, None, None),
+            test_blockdev_maintenance,
+        );
+    }
+
+    /// Verify that a pool in read-only mode refuses changes until read-only
+    /// mode is cleared.
+    fn test_read_only(paths: &[&Path]) {
+        let name = "stratis-test-pool";
+        let (uuid, mut pool) = initialize_with_defaults(name, paths, Redundancy::NONE).unwrap();
+        assert_eq!(pool.read_only_reason(), None);
+
+        pool.enter_read_only(name, "test".into());
+        assert_eq!(pool.read_only_reason(), Some("test"));
+        assert_matches!(
+            pool.create_filesystems(uuid, name, &[("stratis_test_filesystem", None)]),
+            Err(StratisError::Engine(ErrorEnum::Busy, _))
+        );
+        assert_matches!(
+            pool.write_metadata(name),
+            Err(StratisError::Engine(ErrorEnum::Busy, _))
+        );
+
+        assert!(pool.clear_read_only());
+        assert!(!pool.clear_read_only());
+        pool.create_filesystems(uuid, name, &[("stratis_test_filesystem", None)])
+            .unwrap();
+
+        pool.teardown().unwrap();
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_read_only() {
+        loopbacked::test_with_spec(&loopbacked::DeviceLimits::Range(1, 3, None), test_read_only);
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_read_only() {
+        real::test_with_spec(&real::DeviceLimits::AtLeast(1, None, None), test_read_only);
+    }
+
+    /// Verify that the space report of a pool accounts for all the space in
+    /// the pool's data tier and for every filesystem.
+    fn test_space_report(paths: &[&Path]) {
+        let name = "stratis-test-pool";
+        let (uuid, mut pool) = initialize_with_defaults(name, paths, Redundancy::NONE).unwrap();
+        pool.create_filesystems(uuid, name, &[("stratis_test_filesystem", None)])
+            .unwrap();
+
+        let report = pool.space_report().unwrap();
+        assert_eq!(report.raw_size, pool.total_physical_size());
+        assert_eq!(
+            report.raw_size,
+            report.metadata_overhead + report.allocated + report.free
+        );
+        assert!(report.thin_data_used_pct <= 100);
+        assert!(report.thin_meta_used_pct <= 100);
+        assert_eq!(report.filesystems.len(), 1);
+
+        pool.teardown().unwrap();
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_space_report() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Range(1, 3, None),
+            test_space_report,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_space_report() {
+        real::test_with_spec(
+            &real::DeviceLimits::AtLeast(1, None, None),
+            test_space_report,
+        );
+    }
 }
@@ -16,7 +16,7 @@ use serde::Serialize;
 
 use devicemapper::{Sectors, ThinDevId};
 
-use crate::engine::{DevUuid, FilesystemUuid};
+use crate::engine::{DevUuid, FilesystemUuid, PoolUuid};
 
 /// Implements saving struct data to a serializable form. The form should be
 /// sufficient, in conjunction with the environment, to reconstruct the
@@ -35,6 +35,30 @@ pub struct PoolSave {
     pub backstore: BackstoreSave,
     pub flex_devs: FlexDevsSave,
     pub thinpool_dev: ThinPoolDevSave,
+    // The host on which the pool was most recently set up
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exported: Option<ExportSave>,
+}
+
+#[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ExportSave {
+    pub host: String,
+    pub time: u64, // Unix timestamp
+}
+
+// Struct representing a backup of a pool's metadata, which is written to a
+// file outside the pool, and from which the metadata can be restored if the
+// metadata on all of the pool's members is damaged.
+#[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct MetadataBackupSave {
+    pub pool_uuid: PoolUuid,
+    pub sequence: u64,
+    pub time: u64, // Unix timestamp
+    pub metadata: PoolSave,
 }
 
 #[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
@@ -48,6 +72,17 @@ pub struct BackstoreSave {
 #[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct DataTierSave {
     pub blockdev: BlockDevSave,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allocation: Option<AllocationSave>,
+}
+
+// The absence of an allocation strategy means fill-first.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AllocationSave {
+    FillFirst,
+    RoundRobin,
 }
 
 #[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
@@ -70,6 +105,13 @@ pub struct BaseBlockDevSave {
     pub user_info: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub hardware_info: Option<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub zoned: Option<String>,
+    // The absence of a maintenance flag means that the blockdev is online.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub maintenance: bool,
 }
 
 #[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
@@ -88,21 +130,83 @@ pub struct FlexDevsSave {
     pub thin_meta_dev: Vec<(Sectors, Sectors)>,
     pub thin_data_dev: Vec<(Sectors, Sectors)>,
     pub thin_meta_dev_spare: Vec<(Sectors, Sectors)>,
+    // Segments which held thin metadata that was replaced by a repair,
+    // kept so that the damaged metadata can be examined.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub thin_meta_dev_retained: Vec<(Sectors, Sectors)>,
 }
 
 #[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct ThinPoolDevSave {
     pub data_block_size: Sectors,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub overprovision: Option<OverprovisionSave>,
+    // The absence of a reserved percentage means that no capacity is
+    // held in reserve.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reserved_pct: Option<u8>,
+    // The absence of a filesystem limit means that the limit is computed
+    // from the size of the thin pool meta device.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filesystem_limit: Option<u64>,
+    // The absence of a snapshot limit means that filesystems without a
+    // limit of their own may be snapshotted without limit.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snapshot_limit: Option<u64>,
 }
 
+// The absence of an overprovisioning policy means that the total size of
+// filesystems is unlimited.
+#[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct OverprovisionSave {
+    pub ratio_pct: u32,
+    pub enforce: bool,
+}
+
+/// The version of the layout of FilesystemSave. A record without a version
+/// was written before versions were recorded, and is version 0.
+pub const FILESYSTEM_SAVE_VERSION: u64 = 1;
+
 // Struct representing filesystem metadata. This metadata is not held in the
 // variable length metadata but on a separate filesystem that is maintained
 // by stratisd.
 #[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct FilesystemSave {
+    #[serde(default)]
+    pub version: u64,
     pub name: String,
     pub uuid: FilesystemUuid,
     pub thin_id: ThinDevId,
     pub size: Sectors,
     pub created: u64, // Unix timestamp
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub origin: Option<FilesystemUuid>,
+    // The UUID of the XFS filesystem, if it is not the filesystem's UUID
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub xfs_uuid: Option<FilesystemUuid>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size_limit: Option<Sectors>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snapshot_limit: Option<u64>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_info: Option<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trim_interval_secs: Option<u64>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub initial_size: Option<Sectors>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub read_only: bool,
 }
@@ -6,6 +6,7 @@ use std::fs::OpenOptions;
 use std::os::unix::io::AsRawFd;
 use std::panic;
 use std::path::{Path, PathBuf};
+use std::thread;
 
 use nix;
 use tempfile;
@@ -60,7 +61,15 @@ impl LoopTestDev {
 
 impl Drop for LoopTestDev {
     fn drop(&mut self) {
-        self.ld.detach().unwrap()
+        // If the test has failed, a second panic would abort the process
+        // without detaching the remaining devices, so only log the error.
+        if let Err(err) = self.ld.detach() {
+            if thread::panicking() {
+                error!("failed to detach loop device: {}", err);
+            } else {
+                panic!("failed to detach loop device: {}", err);
+            }
+        }
     }
 }
 
@@ -2,6 +2,9 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+// The harness is only used by tests built with the device_tests feature.
+#![cfg_attr(not(feature = "device_tests"), allow(dead_code))]
+
 mod logger;
 pub mod loopbacked;
 pub mod real;
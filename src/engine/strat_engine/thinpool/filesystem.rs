@@ -2,14 +2,20 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use byteorder::{BigEndian, ByteOrder};
 use chrono::{DateTime, TimeZone, Utc};
 use uuid::Uuid;
 
-use std::fs::File;
+use std::cmp::min;
+use std::fs::{File, OpenOptions};
 use std::io::Read;
+use std::mem;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::prelude::AsRawFd;
 use std::path::{Path, PathBuf};
-use std::thread::sleep;
-use std::time::Duration;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
 
 use devicemapper::{
     Bytes, DmDevice, DmName, DmUuid, Sectors, ThinDev, ThinDevId, ThinPoolDev, ThinStatus, IEC,
@@ -20,18 +26,20 @@ use nix::mount::{mount, umount, MsFlags};
 use nix::sys::statvfs::statvfs;
 use tempfile;
 
-use crate::engine::{Filesystem, FilesystemUuid, MaybeDbusPath, Name, PoolUuid};
+use crate::engine::check::Discrepancy;
+use crate::engine::{Filesystem, FilesystemUsage, FilesystemUuid, MaybeDbusPath, Name, PoolUuid};
 use crate::stratis::{ErrorEnum, StratisError, StratisResult};
 
-use crate::engine::strat_engine::cmd::{create_fs, set_uuid, udev_settle, xfs_growfs};
+use crate::engine::strat_engine::cmd::{create_fs, set_label, set_uuid, udev_settle, xfs_growfs};
+use crate::engine::strat_engine::device::{copy_nonzero_sectors, set_blkdev_read_only};
 use crate::engine::strat_engine::dm::get_dm;
 use crate::engine::strat_engine::names::{format_thin_ids, ThinRole};
-use crate::engine::strat_engine::serde_structs::FilesystemSave;
+use crate::engine::strat_engine::serde_structs::{FilesystemSave, FILESYSTEM_SAVE_VERSION};
 use crate::engine::strat_engine::thinpool::DATA_BLOCK_SIZE;
 
 use crate::engine::strat_engine::thinpool::thinpool::DATA_LOWATER;
 
-const DEFAULT_THIN_DEV_SIZE: Sectors = Sectors(2 * IEC::Gi); // 1 TiB
+pub const DEFAULT_THIN_DEV_SIZE: Sectors = Sectors(2 * IEC::Gi); // 1 TiB
 
 const TEMP_MNT_POINT_PREFIX: &str = "stratis_mp_";
 
@@ -43,6 +51,26 @@ pub const FILESYSTEM_LOWATER: Sectors = Sectors(4 * (DATA_LOWATER.0 * DATA_BLOCK
 pub struct StratFilesystem {
     thin_dev: ThinDev,
     created: DateTime<Utc>,
+    initial_size: Option<Sectors>,
+    origin: Option<FilesystemUuid>,
+    // The UUID of the XFS filesystem, if it is not the filesystem's own
+    // UUID, because this is a snapshot that kept its origin's.
+    xfs_uuid: Option<FilesystemUuid>,
+    size_limit: Option<Sectors>,
+    // The most snapshots that may be taken of the filesystem; if None, the
+    // pool's default applies.
+    snapshot_limit: Option<u64>,
+    user_info: Option<String>,
+    read_only: bool,
+    // The ThinDev has been extended, but growing the XFS filesystem
+    // to fill it failed.
+    grow_pending: bool,
+    trim_interval: Option<Duration>,
+    // When the filesystem is next due to be trimmed; not persisted, so the
+    // schedule restarts whenever the filesystem is set up.
+    next_trim: Option<Instant>,
+    // Where the next pass of the trim in progress starts.
+    trim_offset: Bytes,
     dbus_path: MaybeDbusPath,
 }
 
@@ -50,6 +78,8 @@ pub enum FilesystemStatus {
     Good,
     XfsGrowFailed,
     ThinDevExtendFailed,
+    ExtendRefused,
+    SizeLimitReached,
     Failed,
 }
 
@@ -65,12 +95,67 @@ pub fn fs_settle() {
 }
 
 impl StratFilesystem {
-    /// Create a StratFilesystem on top of the given ThinDev.
+    /// Create a StratFilesystem on top of the given ThinDev, labeled for
+    /// name.
     pub fn initialize(
         pool_uuid: PoolUuid,
         thinpool_dev: &ThinPoolDev,
+        name: &str,
         size: Option<Sectors>,
         id: ThinDevId,
+    ) -> StratisResult<(FilesystemUuid, StratFilesystem)> {
+        let fs_uuid = Uuid::new_v4();
+        let size = size.unwrap_or(DEFAULT_THIN_DEV_SIZE);
+        let (dm_name, dm_uuid) = format_thin_ids(pool_uuid, ThinRole::Filesystem(fs_uuid));
+        let mut thin_dev =
+            ThinDev::new(get_dm(), &dm_name, Some(&dm_uuid), size, thinpool_dev, id)?;
+
+        if let Err(err) = create_fs(&thin_dev.devnode(), fs_uuid, Some(xfs_label_for(name))) {
+            fs_settle();
+            if let Err(err2) = thin_dev.destroy(get_dm(), thinpool_dev) {
+                error!(
+                    "While handling create_fs error, thin_dev.destroy() failed: {}",
+                    err2
+                );
+                // This will result in a dangling DM device that will prevent
+                // the thinpool from being destroyed, and wasted space in the
+                // thinpool.
+                // TODO: Recover. But how?
+            }
+            return Err(err);
+        }
+
+        Ok((
+            fs_uuid,
+            StratFilesystem {
+                thin_dev,
+                created: Utc::now(),
+                initial_size: Some(size),
+                origin: None,
+                xfs_uuid: None,
+                size_limit: None,
+                snapshot_limit: None,
+                user_info: None,
+                read_only: false,
+                grow_pending: false,
+                trim_interval: None,
+                next_trim: None,
+                trim_offset: Bytes(0),
+                dbus_path: MaybeDbusPath(None),
+            },
+        ))
+    }
+
+    /// Create a StratFilesystem on a new ThinDev in thinpool_dev which is a
+    /// block copy of source, with a new filesystem UUID. The source must
+    /// not be mounted, so that its contents are consistent and its XFS log
+    /// is clean. Blocks of the source which are all zeros are not copied,
+    /// so that they remain unprovisioned in the new ThinDev.
+    pub fn copy(
+        pool_uuid: PoolUuid,
+        thinpool_dev: &ThinPoolDev,
+        source: &StratFilesystem,
+        id: ThinDevId,
     ) -> StratisResult<(FilesystemUuid, StratFilesystem)> {
         let fs_uuid = Uuid::new_v4();
         let (dm_name, dm_uuid) = format_thin_ids(pool_uuid, ThinRole::Filesystem(fs_uuid));
@@ -78,22 +163,25 @@ impl StratFilesystem {
             get_dm(),
             &dm_name,
             Some(&dm_uuid),
-            size.unwrap_or(DEFAULT_THIN_DEV_SIZE),
+            source.thindev_size(),
             thinpool_dev,
             id,
         )?;
 
-        if let Err(err) = create_fs(&thin_dev.devnode(), fs_uuid) {
+        if let Err(err) = copy_nonzero_sectors(
+            source.devnode(),
+            thin_dev.devnode(),
+            source.thindev_size(),
+            thinpool_dev.data_block_size(),
+        )
+        .and_then(|_| set_uuid(&thin_dev.devnode(), fs_uuid))
+        {
             fs_settle();
             if let Err(err2) = thin_dev.destroy(get_dm(), thinpool_dev) {
                 error!(
-                    "While handling create_fs error, thin_dev.destroy() failed: {}",
+                    "While handling copy error, thin_dev.destroy() failed: {}",
                     err2
                 );
-                // This will result in a dangling DM device that will prevent
-                // the thinpool from being destroyed, and wasted space in the
-                // thinpool.
-                // TODO: Recover. But how?
             }
             return Err(err);
         }
@@ -103,6 +191,17 @@ impl StratFilesystem {
             StratFilesystem {
                 thin_dev,
                 created: Utc::now(),
+                initial_size: Some(source.thindev_size()),
+                origin: None,
+                xfs_uuid: None,
+                size_limit: None,
+                snapshot_limit: None,
+                user_info: None,
+                read_only: false,
+                grow_pending: false,
+                trim_interval: None,
+                next_trim: None,
+                trim_offset: Bytes(0),
                 dbus_path: MaybeDbusPath(None),
             },
         ))
@@ -123,11 +222,46 @@ impl StratFilesystem {
             &thinpool_dev,
             fssave.thin_id,
         )?;
-        Ok(StratFilesystem {
+        if fssave.read_only {
+            set_blkdev_read_only(&thin_dev.devnode(), true)?;
+        }
+        let trim_interval = fssave.trim_interval_secs.map(Duration::from_secs);
+        let filesystem = StratFilesystem {
             thin_dev,
             created: Utc.timestamp(fssave.created as i64, 0),
+            initial_size: fssave.initial_size,
+            origin: fssave.origin,
+            xfs_uuid: fssave.xfs_uuid,
+            size_limit: fssave.size_limit,
+            snapshot_limit: fssave.snapshot_limit,
+            user_info: fssave.user_info.clone(),
+            read_only: fssave.read_only,
+            grow_pending: false,
+            trim_interval,
+            next_trim: trim_interval.map(|interval| Instant::now() + interval),
+            trim_offset: Bytes(0),
             dbus_path: MaybeDbusPath(None),
-        })
+        };
+        // The XFS label may have been changed outside of Stratis, or may
+        // predate it being kept in step with the name. A label that can not
+        // be reconciled does not prevent the filesystem from being set up.
+        // The superblock is read first, as that is a single block read and
+        // the label in it is almost always already the right one; it may
+        // be stale only if the filesystem is mounted, and is then checked
+        // again by sync_xfs_label().
+        let label_matches = fssave.read_only
+            || xfs_label(&filesystem.devnode())
+                .map(|label| label == xfs_label_for(&fssave.name))
+                .unwrap_or(false);
+        if !label_matches {
+            if let Err(err) = filesystem.sync_xfs_label(&fssave.name) {
+                warn!(
+                    "Could not set the XFS label of filesystem {} to match its name: {}",
+                    fssave.name, err
+                );
+            }
+        }
+        Ok(filesystem)
     }
 
     /// Create a snapshot of the filesystem. Return the resulting filesystem/ThinDev
@@ -135,7 +269,8 @@ impl StratFilesystem {
     /// snapshot_dmname for the new name of the ThinDev allocated for the snapshot.
     /// Mounting a filesystem with a duplicate UUID would require special handling,
     /// so snapshot_fs_uuid is used to update the new snapshot filesystem so it has
-    /// a unique UUID.
+    /// a unique UUID, unless keep_xfs_uuid is true. origin_uuid, the UUID of
+    /// this filesystem, is recorded as the snapshot's origin.
     #[allow(clippy::too_many_arguments)]
     pub fn snapshot(
         &self,
@@ -146,44 +281,78 @@ impl StratFilesystem {
         snapshot_fs_name: &Name,
         snapshot_fs_uuid: FilesystemUuid,
         snapshot_thin_id: ThinDevId,
+        origin_uuid: FilesystemUuid,
+        keep_xfs_uuid: bool,
+        freeze: bool,
     ) -> StratisResult<StratFilesystem> {
-        match self.thin_dev.snapshot(
-            get_dm(),
-            snapshot_dm_name,
-            snapshot_dm_uuid,
-            thin_pool,
-            snapshot_thin_id,
-        ) {
+        let snapshot = {
+            // Suspending the thin device freezes a mounted filesystem in
+            // any case; freezing it first only bounds how long that may
+            // take. It is thawed at the end of this block.
+            let _frozen = match self.mount_points()?.first() {
+                Some(mount_point) if freeze => Some(freeze_filesystem(
+                    mount_point,
+                    Duration::from_secs(FREEZE_TIMEOUT_SECS),
+                )?),
+                _ => None,
+            };
+            self.thin_dev.snapshot(
+                get_dm(),
+                snapshot_dm_name,
+                snapshot_dm_uuid,
+                thin_pool,
+                snapshot_thin_id,
+            )
+        };
+        match snapshot {
             Ok(thin_dev) => {
-                // If the source is mounted, XFS puts a dummy record in the
-                // log to enforce replay of the snapshot to deal with any
-                // orphaned inodes. The dummy record put the log in a dirty
-                // state. xfs_admin won't allow a filesystem UUID
-                // to be updated when the log is dirty.  To clear the log
-                // we mount/unmount the filesystem before updating the UUID.
-                //
-                // If the source is unmounted the XFS log will be clean so
-                // we can skip the mount/unmount.
-                if !self.mount_points()?.is_empty() {
-                    let tmp_dir = tempfile::Builder::new()
-                        .prefix(TEMP_MNT_POINT_PREFIX)
-                        .tempdir()?;
-                    // Mount the snapshot with the "nouuid" option. mount
-                    // will fail due to duplicate UUID otherwise.
-                    mount(
-                        Some(&thin_dev.devnode()),
-                        tmp_dir.path(),
-                        Some("xfs"),
-                        MsFlags::empty(),
-                        Some("nouuid"),
-                    )?;
-                    umount(tmp_dir.path())?;
-                }
+                if !keep_xfs_uuid {
+                    // If the source is mounted, XFS puts a dummy record in the
+                    // log to enforce replay of the snapshot to deal with any
+                    // orphaned inodes. The dummy record put the log in a dirty
+                    // state. xfs_admin won't allow a filesystem UUID
+                    // to be updated when the log is dirty.  To clear the log
+                    // we mount/unmount the filesystem before updating the UUID.
+                    //
+                    // If the source is unmounted the XFS log will be clean so
+                    // we can skip the mount/unmount.
+                    if !self.mount_points()?.is_empty() {
+                        let tmp_dir = tempfile::Builder::new()
+                            .prefix(TEMP_MNT_POINT_PREFIX)
+                            .tempdir()?;
+                        // Mount the snapshot with the "nouuid" option. mount
+                        // will fail due to duplicate UUID otherwise.
+                        mount(
+                            Some(&thin_dev.devnode()),
+                            tmp_dir.path(),
+                            Some("xfs"),
+                            MsFlags::empty(),
+                            Some("nouuid"),
+                        )?;
+                        umount(tmp_dir.path())?;
+                    }
 
-                set_uuid(&thin_dev.devnode(), snapshot_fs_uuid)?;
+                    set_uuid(&thin_dev.devnode(), snapshot_fs_uuid)?;
+                }
+                let initial_size = Some(thin_dev.size());
                 Ok(StratFilesystem {
                     thin_dev,
                     created: Utc::now(),
+                    initial_size,
+                    origin: Some(origin_uuid),
+                    xfs_uuid: if keep_xfs_uuid {
+                        Some(self.xfs_uuid.unwrap_or(origin_uuid))
+                    } else {
+                        None
+                    },
+                    size_limit: None,
+                    snapshot_limit: None,
+                    user_info: None,
+                    read_only: false,
+                    grow_pending: false,
+                    trim_interval: None,
+                    next_trim: None,
+                    trim_offset: Bytes(0),
                     dbus_path: MaybeDbusPath(None),
                 })
             }
@@ -198,28 +367,50 @@ impl StratFilesystem {
     }
 
     /// check if filesystem is getting full and needs to be extended
+    /// If extend_limit is specified, the filesystem is not extended by more
+    /// than that amount.
     /// TODO: deal with the thindev in a Fail state.
-    pub fn check(&mut self) -> StratisResult<(FilesystemStatus, bool)> {
+    pub fn check(
+        &mut self,
+        extend_limit: Option<Sectors>,
+    ) -> StratisResult<(FilesystemStatus, bool)> {
         match self.thin_dev.status(get_dm())? {
             ThinStatus::Working(_) => {
+                // A read-only filesystem can not become full.
+                if self.read_only {
+                    return Ok((FilesystemStatus::Good, false));
+                }
+                if self.grow_pending {
+                    if self.grow_xfs().is_err() {
+                        return Ok((FilesystemStatus::XfsGrowFailed, false));
+                    }
+                    self.grow_pending = false;
+                }
                 if let Some(mount_point) = self.mount_points()?.first() {
                     let (fs_total_bytes, fs_total_used_bytes) = fs_usage(&mount_point)?;
                     let free_bytes = fs_total_bytes - fs_total_used_bytes;
                     if free_bytes.sectors() < FILESYSTEM_LOWATER {
-                        let mut table = self.thin_dev.table().table.clone();
-                        table.length =
-                            self.thin_dev.size() + self.extend_size(self.thin_dev.size());
-                        if self.thin_dev.set_table(get_dm(), table).is_err() {
+                        let extend_size = self.extend_size(self.thin_dev.size());
+                        if extend_size == Sectors(0) {
+                            return Ok((FilesystemStatus::SizeLimitReached, false));
+                        }
+                        match extend_limit {
+                            Some(limit) if extend_size > limit => {
+                                return Ok((FilesystemStatus::ExtendRefused, false));
+                            }
+                            _ => (),
+                        }
+                        if self.extend_thin_dev(extend_size).is_err() {
                             return Ok((FilesystemStatus::ThinDevExtendFailed, false));
                         }
-                        if xfs_growfs(&mount_point).is_err() {
+                        if self.grow_xfs().is_err() {
+                            self.grow_pending = true;
                             return Ok((FilesystemStatus::XfsGrowFailed, true));
                         }
                         return Ok((FilesystemStatus::Good, true));
                     }
                 }
                 // TODO: do anything when filesystem is not mounted?
-                // TODO: periodically kick off fstrim?
                 Ok((FilesystemStatus::Good, false))
             }
             ThinStatus::Error => {
@@ -237,7 +428,233 @@ impl StratFilesystem {
     /// TODO: returning the current size will double the space provisioned to
     /// the thin device.  We should determine if this is a reasonable value.
     fn extend_size(&self, current_size: Sectors) -> Sectors {
-        current_size
+        match self.size_limit {
+            Some(limit) if current_size + current_size > limit => {
+                if limit > current_size {
+                    limit - current_size
+                } else {
+                    Sectors(0)
+                }
+            }
+            _ => current_size,
+        }
+    }
+
+    /// Extend the ThinDev by extend_size and grow the XFS filesystem to fill
+    /// it. Returns an error if the size limit would be exceeded. If the
+    /// ThinDev is extended but the filesystem can not be grown, growing it
+    /// is retried by each subsequent check().
+    pub fn extend(&mut self, extend_size: Sectors) -> StratisResult<()> {
+        self.check_writable()?;
+        if let Some(limit) = self.size_limit {
+            if self.thindev_size() + extend_size > limit {
+                let err_msg = format!(
+                    "extending the filesystem by {} would exceed its size limit {}",
+                    extend_size, limit
+                );
+                return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+            }
+        }
+        self.extend_thin_dev(extend_size)?;
+        if let Err(err) = self.grow_xfs() {
+            self.grow_pending = true;
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Reduce the ThinDev to new_size, so that the space beyond it no longer
+    /// counts against the pool's overprovisioning limit. Since XFS can not be
+    /// shrunk, new_size must still hold the XFS filesystem, which is
+    /// smaller than the ThinDev only if growing it failed. The filesystem
+    /// must not be mounted.
+    pub fn shrink(&mut self, new_size: Sectors) -> StratisResult<()> {
+        self.check_writable()?;
+        if !self.mount_points()?.is_empty() {
+            return Err(StratisError::Engine(
+                ErrorEnum::Busy,
+                "filesystem must be unmounted to be shrunk".into(),
+            ));
+        }
+        if new_size >= self.thindev_size() {
+            let err_msg = format!(
+                "new size {} is not less than the filesystem's size {}",
+                new_size,
+                self.thindev_size()
+            );
+            return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+        }
+        let fs_size = xfs_size(&self.devnode())?;
+        if new_size < fs_size {
+            let err_msg = format!(
+                "new size {} can not hold the XFS filesystem of size {}",
+                new_size, fs_size
+            );
+            return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+        }
+
+        let mut table = self.thin_dev.table().table.clone();
+        table.length = new_size;
+        self.thin_dev.set_table(get_dm(), table)?;
+        self.grow_pending = false;
+        Ok(())
+    }
+
+    fn extend_thin_dev(&mut self, extend_size: Sectors) -> StratisResult<()> {
+        let mut table = self.thin_dev.table().table.clone();
+        table.length = self.thin_dev.size() + extend_size;
+        self.thin_dev.set_table(get_dm(), table)?;
+        Ok(())
+    }
+
+    /// Grow the XFS filesystem to fill the ThinDev. If the filesystem is not
+    /// mounted, it is mounted on a temporary mount point to be grown.
+    fn grow_xfs(&self) -> StratisResult<()> {
+        if let Some(mount_point) = self.mount_points()?.first() {
+            return xfs_growfs(mount_point);
+        }
+
+        let tmp_dir = tempfile::Builder::new()
+            .prefix(TEMP_MNT_POINT_PREFIX)
+            .tempdir()?;
+        mount(
+            Some(&self.thin_dev.devnode()),
+            tmp_dir.path(),
+            Some("xfs"),
+            MsFlags::empty(),
+            None as Option<&str>,
+        )?;
+        let grown = xfs_growfs(tmp_dir.path());
+        umount(tmp_dir.path())?;
+        grown
+    }
+
+    /// Mark the filesystem read-only, or writable again. The thin device of
+    /// a read-only filesystem is read-only, and the filesystem is neither
+    /// extended nor reverted. A mounted filesystem can not be made read-only.
+    /// Returns true if the flag changed.
+    pub fn set_read_only(&mut self, read_only: bool) -> StratisResult<bool> {
+        if self.read_only == read_only {
+            return Ok(false);
+        }
+        if read_only && !self.mount_points()?.is_empty() {
+            return Err(StratisError::Engine(
+                ErrorEnum::Busy,
+                "a mounted filesystem can not be made read-only".into(),
+            ));
+        }
+        set_blkdev_read_only(&self.devnode(), read_only)?;
+        self.read_only = read_only;
+        Ok(true)
+    }
+
+    /// Return an error if the filesystem is read-only.
+    pub fn check_writable(&self) -> StratisResult<()> {
+        if self.read_only {
+            return Err(StratisError::Engine(
+                ErrorEnum::Busy,
+                "filesystem is read-only".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Set the user-settable string associated with the filesystem.
+    /// Returns true if it changed.
+    pub fn set_user_info(&mut self, user_info: Option<&str>) -> bool {
+        if self.user_info.as_ref().map(|x| &**x) != user_info {
+            self.user_info = user_info.map(|x| x.to_owned());
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Set the size beyond which the filesystem is not extended, or remove
+    /// the limit if limit is None. Returns true if the limit changed.
+    /// Returns an error if the limit is less than the filesystem's size,
+    /// since the filesystem can not be shrunk.
+    pub fn set_size_limit(&mut self, limit: Option<Sectors>) -> StratisResult<bool> {
+        if let Some(limit) = limit {
+            if limit < self.thindev_size() {
+                let err_msg = format!(
+                    "size limit {} is less than the filesystem's size {}",
+                    limit,
+                    self.thindev_size()
+                );
+                return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+            }
+        }
+        let changed = self.size_limit != limit;
+        self.size_limit = limit;
+        Ok(changed)
+    }
+
+    /// Set the most snapshots that may be taken of the filesystem, or
+    /// defer to the pool's default if limit is None.
+    /// Returns true if the limit changed.
+    pub fn set_snapshot_limit(&mut self, limit: Option<u64>) -> bool {
+        let changed = self.snapshot_limit != limit;
+        self.snapshot_limit = limit;
+        changed
+    }
+
+    /// Make the filesystem due to be trimmed now, unless it already is.
+    /// The trim is done by trim(), a pass at a time. Returns true if the
+    /// filesystem was not already due.
+    pub fn request_trim(&mut self) -> bool {
+        let now = Instant::now();
+        if let Some(next) = self.next_trim {
+            if next <= now {
+                return false;
+            }
+        }
+        self.next_trim = Some(now);
+        true
+    }
+
+    /// End the trim in progress, if any, and schedule the next periodic
+    /// trim.
+    fn finish_trim(&mut self) {
+        self.trim_offset = Bytes(0);
+        self.next_trim = self.trim_interval.map(|interval| Instant::now() + interval);
+    }
+
+    /// Do the next pass of the trim in progress. Returns the number of
+    /// bytes discarded and whether any of the filesystem remains to be
+    /// trimmed, or None if the filesystem is not mounted.
+    fn trim_pass(&self) -> StratisResult<Option<(Bytes, bool)>> {
+        let mount_points = self.mount_points()?;
+        let mount_point = match mount_points.first() {
+            Some(mount_point) => mount_point,
+            None => return Ok(None),
+        };
+        let (fs_size, _) = fs_usage(mount_point)?;
+        let len = trim_pass_len(self.trim_offset, fs_size);
+        let trimmed = fstrim(mount_point, self.trim_offset, len)?;
+        Ok(Some((trimmed, len.is_some())))
+    }
+
+    /// Set the interval at which the filesystem is trimmed while it is
+    /// mounted, or stop trimming it periodically if interval is None.
+    /// Returns true if the interval changed. A trim in progress is ended,
+    /// and the next trim is scheduled one interval from now.
+    pub fn set_trim_interval(&mut self, interval: Option<Duration>) -> StratisResult<bool> {
+        if interval == Some(Duration::from_secs(0)) {
+            return Err(StratisError::Engine(
+                ErrorEnum::Invalid,
+                "trim interval must be greater than 0".into(),
+            ));
+        }
+        let changed = self.trim_interval != interval;
+        self.trim_interval = interval;
+        self.finish_trim();
+        Ok(changed)
+    }
+
+    /// Compare the filesystem's thin device with the active device.
+    pub fn discrepancy(&self, pool_uuid: PoolUuid) -> StratisResult<Option<Discrepancy>> {
+        check_dm_device!(pool_uuid, &self.thin_dev, ThinDev)
     }
 
     /// Tear down the filesystem.
@@ -252,13 +669,219 @@ impl StratFilesystem {
         Ok(())
     }
 
+    /// Replace the contents of this filesystem, whose UUID is fs_uuid, with
+    /// those of snapshot, keeping this filesystem's UUIDs and DM name. A new
+    /// snapshot of snapshot, with thin id thin_id, takes the place of this
+    /// filesystem's ThinDev. save is called to record the filesystem with
+    /// its new thin id; only once it succeeds is the old ThinDev destroyed,
+    /// so that the record never refers to a thin id that does not exist. If
+    /// save fails, the filesystem is left as it was. Neither filesystem may
+    /// be mounted.
+    pub fn revert<F>(
+        &mut self,
+        pool_uuid: PoolUuid,
+        thin_pool: &ThinPoolDev,
+        fs_uuid: FilesystemUuid,
+        snapshot: &StratFilesystem,
+        thin_id: ThinDevId,
+        save: F,
+    ) -> StratisResult<()>
+    where
+        F: FnOnce(&StratFilesystem) -> StratisResult<()>,
+    {
+        self.check_writable()?;
+        // The new ThinDev is made under a temporary name, since the
+        // filesystem's own name remains in use until its ThinDev is
+        // destroyed.
+        let (tmp_dm_name, tmp_dm_uuid) =
+            format_thin_ids(pool_uuid, ThinRole::Filesystem(Uuid::new_v4()));
+        let mut thin_dev = snapshot.thin_dev.snapshot(
+            get_dm(),
+            &tmp_dm_name,
+            Some(&tmp_dm_uuid),
+            thin_pool,
+            thin_id,
+        )?;
+
+        if let Err(err) = set_uuid(&thin_dev.devnode(), fs_uuid) {
+            fs_settle();
+            if let Err(err2) = thin_dev.destroy(get_dm(), thin_pool) {
+                error!(
+                    "While handling revert error, thin_dev.destroy() failed: {}",
+                    err2
+                );
+            }
+            return Err(err);
+        }
+
+        let mut old_thin_dev = mem::replace(&mut self.thin_dev, thin_dev);
+        let old_xfs_uuid = self.xfs_uuid.take();
+        if let Err(err) = save(self) {
+            self.xfs_uuid = old_xfs_uuid;
+            let mut thin_dev = mem::replace(&mut self.thin_dev, old_thin_dev);
+            fs_settle();
+            if let Err(err2) = thin_dev.destroy(get_dm(), thin_pool) {
+                error!(
+                    "While handling revert error, thin_dev.destroy() failed: {}",
+                    err2
+                );
+            }
+            return Err(err);
+        }
+
+        fs_settle();
+        if let Err(err) = old_thin_dev.destroy(get_dm(), thin_pool) {
+            // The filesystem is already recorded with its new thin id, so
+            // the revert has happened; only the space of the old ThinDev is
+            // not reclaimed.
+            error!(
+                "Could not destroy thin device {} replaced by revert: {}",
+                old_thin_dev.id(),
+                err
+            );
+        }
+
+        self.thin_dev.teardown(get_dm())?;
+        let (dm_name, dm_uuid) = format_thin_ids(pool_uuid, ThinRole::Filesystem(fs_uuid));
+        self.thin_dev = ThinDev::setup(
+            get_dm(),
+            &dm_name,
+            Some(&dm_uuid),
+            self.thin_dev.size(),
+            thin_pool,
+            thin_id,
+        )?;
+        Ok(())
+    }
+
+    /// Give this filesystem, whose UUID is fs_uuid, the contents of
+    /// snapshot, whose UUID is snapshot_uuid, by taking over snapshot's
+    /// ThinDev in place of its own. This filesystem keeps its UUIDs and DM
+    /// name. save is called to record this filesystem with the snapshot's
+    /// thin id; only once it succeeds is this filesystem's old ThinDev
+    /// destroyed. On success, snapshot is left without a ThinDev and must be
+    /// discarded. If save fails, both filesystems are left as they were.
+    /// Neither filesystem may be mounted.
+    pub fn merge<F>(
+        &mut self,
+        pool_uuid: PoolUuid,
+        thin_pool: &ThinPoolDev,
+        fs_uuid: FilesystemUuid,
+        snapshot: &mut StratFilesystem,
+        snapshot_uuid: FilesystemUuid,
+        save: F,
+    ) -> StratisResult<()>
+    where
+        F: FnOnce(&StratFilesystem) -> StratisResult<()>,
+    {
+        self.check_writable()?;
+        set_uuid(&snapshot.devnode(), fs_uuid)?;
+
+        mem::swap(&mut self.thin_dev, &mut snapshot.thin_dev);
+        let old_xfs_uuid = self.xfs_uuid.take();
+        if let Err(err) = save(self) {
+            self.xfs_uuid = old_xfs_uuid;
+            mem::swap(&mut self.thin_dev, &mut snapshot.thin_dev);
+            fs_settle();
+            if let Err(err2) = set_uuid(&snapshot.devnode(), snapshot_uuid) {
+                error!("While handling merge error, set_uuid() failed: {}", err2);
+            }
+            return Err(err);
+        }
+
+        // snapshot now holds this filesystem's old ThinDev.
+        fs_settle();
+        if let Err(err) = snapshot.thin_dev.destroy(get_dm(), thin_pool) {
+            // The filesystem is already recorded with the snapshot's thin
+            // id, so the merge has happened; only the space of the old
+            // ThinDev is not reclaimed.
+            error!(
+                "Could not destroy thin device {} replaced by merge: {}",
+                snapshot.thin_dev.id(),
+                err
+            );
+        }
+
+        self.thin_dev.teardown(get_dm())?;
+        let (dm_name, dm_uuid) = format_thin_ids(pool_uuid, ThinRole::Filesystem(fs_uuid));
+        self.thin_dev = ThinDev::setup(
+            get_dm(),
+            &dm_name,
+            Some(&dm_uuid),
+            self.thin_dev.size(),
+            thin_pool,
+            self.thin_dev.id(),
+        )?;
+        Ok(())
+    }
+
+    /// The label of the XFS filesystem on this filesystem's device.
+    #[cfg(test)]
+    pub fn xfs_label(&self) -> StratisResult<String> {
+        self.xfs_label_at(self.mount_points()?.first())
+    }
+
+    /// The label of the XFS filesystem, read through mount_point if it is
+    /// mounted there, otherwise from the superblock on the device.
+    fn xfs_label_at(&self, mount_point: Option<&PathBuf>) -> StratisResult<String> {
+        match mount_point {
+            Some(mount_point) => {
+                let dir = File::open(mount_point)?;
+                let mut buf = [0u8; FSLABEL_MAX];
+                unsafe { fs_ioc_getfslabel(dir.as_raw_fd(), &mut buf) }?;
+                Ok(label_from_bytes(&buf))
+            }
+            None => xfs_label(&self.devnode()),
+        }
+    }
+
+    /// Set the label of the XFS filesystem to that for name, if it is not
+    /// already. The label of a read-only filesystem is left as it is.
+    /// Returns true if the label changed.
+    pub fn sync_xfs_label(&self, name: &str) -> StratisResult<bool> {
+        let label = xfs_label_for(name);
+        if self.read_only {
+            return Ok(false);
+        }
+        let mount_points = self.mount_points()?;
+        let mount_point = mount_points.first();
+        if self.xfs_label_at(mount_point)? == label {
+            return Ok(false);
+        }
+        match mount_point {
+            Some(mount_point) => {
+                let dir = File::open(mount_point)?;
+                let mut buf = [0u8; FSLABEL_MAX];
+                buf[..label.len()].copy_from_slice(label.as_bytes());
+                unsafe { fs_ioc_setfslabel(dir.as_raw_fd(), &buf) }?;
+            }
+            None => set_label(&self.devnode(), label)?,
+        }
+        Ok(true)
+    }
+
+    /// Set this filesystem's origin, e.g., to the origin's own origin
+    /// because the origin was removed.
+    pub fn set_origin(&mut self, origin: Option<FilesystemUuid>) {
+        self.origin = origin;
+    }
+
     pub fn record(&self, name: &Name, uuid: FilesystemUuid) -> FilesystemSave {
         FilesystemSave {
+            version: FILESYSTEM_SAVE_VERSION,
             name: name.to_owned(),
             uuid,
             thin_id: self.thin_dev.id(),
             size: self.thin_dev.size(),
             created: self.created.timestamp() as u64,
+            origin: self.origin,
+            xfs_uuid: self.xfs_uuid,
+            size_limit: self.size_limit,
+            snapshot_limit: self.snapshot_limit,
+            user_info: self.user_info.clone(),
+            trim_interval_secs: self.trim_interval.map(|interval| interval.as_secs()),
+            initial_size: self.initial_size,
+            read_only: self.read_only,
         }
     }
 
@@ -273,7 +896,7 @@ impl StratFilesystem {
     }
 
     /// Find places where this filesystem is mounted.
-    fn mount_points(&self) -> StratisResult<Vec<PathBuf>> {
+    pub fn mount_points(&self) -> StratisResult<Vec<PathBuf>> {
         // Use major:minor values to find mounts for this filesystem
         let major = u64::from(self.thin_dev.device().major);
         let minor = u64::from(self.thin_dev.device().minor);
@@ -299,7 +922,8 @@ impl StratFilesystem {
 
         Ok(ret_vec)
     }
-    #[cfg(test)]
+
+    /// The size of the thin device underlying the filesystem.
     pub fn thindev_size(&self) -> Sectors {
         self.thin_dev.size()
     }
@@ -314,6 +938,89 @@ impl Filesystem for StratFilesystem {
         self.created
     }
 
+    fn origin(&self) -> Option<FilesystemUuid> {
+        self.origin
+    }
+
+    fn shared_xfs_uuid(&self) -> Option<FilesystemUuid> {
+        self.xfs_uuid
+    }
+
+    fn initial_size(&self) -> Option<Sectors> {
+        self.initial_size
+    }
+
+    fn size_limit(&self) -> Option<Sectors> {
+        self.size_limit
+    }
+
+    fn snapshot_limit(&self) -> Option<u64> {
+        self.snapshot_limit
+    }
+
+    fn user_info(&self) -> Option<&str> {
+        self.user_info.as_ref().map(|x| &**x)
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn trim_interval(&self) -> Option<Duration> {
+        self.trim_interval
+    }
+
+    fn trim_deadline(&self) -> Option<Instant> {
+        self.next_trim
+    }
+
+    fn trim(&mut self) -> StratisResult<Option<Bytes>> {
+        let pass = if self.read_only {
+            Ok(None)
+        } else {
+            self.trim_pass()
+        };
+        match pass {
+            Ok(Some((trimmed, true))) => {
+                self.trim_offset += TRIM_PASS_SIZE;
+                Ok(Some(trimmed))
+            }
+            Ok(Some((trimmed, false))) => {
+                self.finish_trim();
+                Ok(Some(trimmed))
+            }
+            Ok(None) => {
+                self.finish_trim();
+                Ok(None)
+            }
+            // A failed trim is not resumed.
+            Err(err) => {
+                self.finish_trim();
+                Err(err)
+            }
+        }
+    }
+
+    fn min_size(&self) -> StratisResult<Sectors> {
+        xfs_size(&self.devnode())
+    }
+
+    fn usage(&self) -> StratisResult<FilesystemUsage> {
+        let (fs_size, fs_used) = match self.mount_points()?.first() {
+            Some(mount_point) => {
+                let (total, used) = fs_usage(mount_point)?;
+                (Some(total), Some(used))
+            }
+            None => (None, None),
+        };
+        Ok(FilesystemUsage {
+            size: self.thindev_size(),
+            allocated: self.used()?,
+            fs_size,
+            fs_used,
+        })
+    }
+
     fn used(&self) -> StratisResult<Bytes> {
         match self.thin_dev.status(get_dm())? {
             ThinStatus::Working(wk_status) => Ok(wk_status.nr_mapped_sectors.bytes()),
@@ -355,3 +1062,196 @@ pub fn fs_usage(mount_point: &Path) -> StratisResult<(Bytes, Bytes)> {
         Bytes(block_size * (blocks - blocks_free)),
     ))
 }
+
+/// The length of the leading part of the XFS superblock that is read,
+/// which ends with the filesystem label.
+const XFS_SB_READ_LEN: usize = 120;
+
+/// The offset of the label in the XFS superblock.
+const XFS_SB_LABEL_OFFSET: usize = 108;
+
+/// The longest label XFS can hold, in bytes.
+const XFS_LABEL_MAX: usize = XFS_SB_READ_LEN - XFS_SB_LABEL_OFFSET;
+
+/// The size of the buffer used by the FS_IOC_GETFSLABEL and
+/// FS_IOC_SETFSLABEL ioctls.
+const FSLABEL_MAX: usize = 256;
+
+ioctl_read!(fs_ioc_getfslabel, 0x94, 49, [u8; FSLABEL_MAX]);
+ioctl_write_ptr!(fs_ioc_setfslabel, 0x94, 50, [u8; FSLABEL_MAX]);
+
+/// The length of the block read to get the XFS superblock. O_DIRECT
+/// requires the length and the buffer to be aligned to the logical block
+/// size of the device, which is at most this.
+const XFS_SB_BLOCK_LEN: usize = 4096;
+
+#[repr(align(4096))]
+struct XfsSbBlock([u8; XFS_SB_BLOCK_LEN]);
+
+/// Read the leading part of the XFS superblock on devnode. The superblock
+/// is up to date only if the filesystem is not mounted.
+/// The superblock is read with O_DIRECT, as the page cache of the device
+/// may hold an older copy than the one the filesystem has written.
+fn read_xfs_superblock(devnode: &Path) -> StratisResult<[u8; XFS_SB_READ_LEN]> {
+    let mut block = XfsSbBlock([0u8; XFS_SB_BLOCK_LEN]);
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(devnode)?
+        .read_exact(&mut block.0)?;
+    if &block.0[0..4] != b"XFSB" {
+        let err_msg = format!("no XFS superblock found on {}", devnode.display());
+        return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+    }
+    let mut buf = [0u8; XFS_SB_READ_LEN];
+    buf.copy_from_slice(&block.0[..XFS_SB_READ_LEN]);
+    Ok(buf)
+}
+
+/// Return the size of the XFS filesystem on devnode, as recorded in its
+/// superblock. The superblock is up to date only if the filesystem is not
+/// mounted.
+pub fn xfs_size(devnode: &Path) -> StratisResult<Sectors> {
+    let buf = read_xfs_superblock(devnode)?;
+    let block_size = u64::from(BigEndian::read_u32(&buf[4..8]));
+    let data_blocks = BigEndian::read_u64(&buf[8..16]);
+    Ok(Bytes(block_size * data_blocks).sectors())
+}
+
+/// Return the label of the XFS filesystem on devnode, as recorded in its
+/// superblock. The superblock is up to date only if the filesystem is not
+/// mounted.
+pub fn xfs_label(devnode: &Path) -> StratisResult<String> {
+    let buf = read_xfs_superblock(devnode)?;
+    Ok(label_from_bytes(&buf[XFS_SB_LABEL_OFFSET..]))
+}
+
+/// The label, which is NUL-padded, held in buf.
+fn label_from_bytes(buf: &[u8]) -> String {
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+/// The XFS label for a filesystem named name: the longest prefix of name
+/// that fits in an XFS label.
+pub fn xfs_label_for(name: &str) -> &str {
+    let mut len = min(name.len(), XFS_LABEL_MAX);
+    while !name.is_char_boundary(len) {
+        len -= 1;
+    }
+    &name[..len]
+}
+
+#[repr(C)]
+pub struct FstrimRange {
+    start: u64,
+    len: u64,
+    minlen: u64,
+}
+
+ioctl_readwrite!(fitrim, b'X', 121, FstrimRange);
+
+/// The most of a filesystem that one pass of a trim covers, so that a pass
+/// does not hold up the daemon for long. A trim of a larger filesystem is
+/// done in several passes.
+const TRIM_PASS_SIZE: Bytes = Bytes(16 * IEC::Gi);
+
+/// The length of the pass of a trim which starts at start, of a filesystem
+/// of fs_size; None if the pass is the last, and covers the rest of the
+/// filesystem.
+pub fn trim_pass_len(start: Bytes, fs_size: Bytes) -> Option<Bytes> {
+    if start + TRIM_PASS_SIZE < fs_size {
+        Some(TRIM_PASS_SIZE)
+    } else {
+        None
+    }
+}
+
+/// Discard the blocks of the filesystem mounted at mount_point which it is
+/// not using, so that the space is returned to the thin pool. Only the
+/// range of len bytes at start is trimmed, or, if len is None, all of the
+/// filesystem from start on.
+/// Return the number of bytes discarded.
+pub fn fstrim(mount_point: &Path, start: Bytes, len: Option<Bytes>) -> StratisResult<Bytes> {
+    let dir = File::open(mount_point)?;
+    let mut range = FstrimRange {
+        start: *start,
+        len: len.map_or(u64::max_value(), |len| *len),
+        minlen: 0,
+    };
+
+    match unsafe { fitrim(dir.as_raw_fd(), &mut range) } {
+        Err(x) => Err(StratisError::Nix(x)),
+        Ok(_) => Ok(Bytes(range.len)),
+    }
+}
+
+/// The longest time to wait for a filesystem to freeze before giving up
+/// on a snapshot taken with a bounded freeze.
+const FREEZE_TIMEOUT_SECS: u64 = 30;
+
+ioctl_readwrite!(fifreeze, b'X', 119, libc::c_int);
+ioctl_readwrite!(fithaw, b'X', 120, libc::c_int);
+
+/// A filesystem which has been frozen; it is thawed when this is dropped.
+pub struct FrozenFilesystem {
+    dir: File,
+}
+
+impl Drop for FrozenFilesystem {
+    fn drop(&mut self) {
+        if let Err(err) = unsafe { fithaw(self.dir.as_raw_fd(), &mut 0) } {
+            error!("failed to thaw frozen filesystem: {}", err);
+        }
+    }
+}
+
+/// Freeze the filesystem mounted at mount_point, flushing it to its device
+/// and blocking writes to it until the returned value is dropped. A
+/// filesystem that is already frozen is not frozen again when its device
+/// is suspended.
+/// Freezing waits for writes already in progress, so give up if the
+/// filesystem is not frozen within timeout. The filesystem is then thawed
+/// as soon as the freeze completes.
+pub fn freeze_filesystem(mount_point: &Path, timeout: Duration) -> StratisResult<FrozenFilesystem> {
+    let dir = File::open(mount_point)?;
+    let abandoned = Arc::new(Mutex::new(false));
+    let (sender, receiver) = mpsc::channel();
+
+    {
+        let abandoned = Arc::clone(&abandoned);
+        thread::spawn(move || {
+            let result = unsafe { fifreeze(dir.as_raw_fd(), &mut 0) }
+                .map(|_| FrozenFilesystem { dir })
+                .map_err(StratisError::Nix);
+            // If the caller has given up, dropping the result thaws the
+            // filesystem.
+            let abandoned = abandoned.lock().expect("not poisoned");
+            if !*abandoned {
+                let _ = sender.send(result);
+            }
+        });
+    }
+
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(_) => {
+            // Check once more with the lock held, so that a filesystem
+            // frozen just now is either received here or thawed by the
+            // freezing thread.
+            let mut abandoned = abandoned.lock().expect("not poisoned");
+            match receiver.try_recv() {
+                Ok(result) => result,
+                Err(_) => {
+                    *abandoned = true;
+                    let err_msg = format!(
+                        "timed out after {} seconds freezing filesystem mounted at {}",
+                        timeout.as_secs(),
+                        mount_point.display()
+                    );
+                    Err(StratisError::Engine(ErrorEnum::Busy, err_msg))
+                }
+            }
+        }
+    }
+}
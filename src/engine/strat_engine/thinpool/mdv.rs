@@ -5,7 +5,7 @@
 // Manage the linear volume that stores metadata on pool levels 5-7.
 
 use std::convert::From;
-use std::fs::{create_dir, read_dir, remove_dir, remove_file, rename, OpenOptions};
+use std::fs::{create_dir, create_dir_all, read_dir, remove_dir, remove_file, rename, OpenOptions};
 use std::io::prelude::*;
 use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
@@ -16,13 +16,13 @@ use serde_json;
 
 use devicemapper::{DmDevice, LinearDev, LinearDevTargetParams, TargetLine};
 
+use crate::engine::check::Discrepancy;
 use crate::engine::{FilesystemUuid, Name, PoolUuid};
-use crate::stratis::StratisResult;
+use crate::stratis::{ErrorEnum, StratisError, StratisResult};
 
-use crate::engine::engine::DEV_PATH;
 use crate::engine::strat_engine::cmd::create_fs;
 use crate::engine::strat_engine::dm::get_dm;
-use crate::engine::strat_engine::serde_structs::FilesystemSave;
+use crate::engine::strat_engine::serde_structs::{FilesystemSave, FILESYSTEM_SAVE_VERSION};
 
 use crate::engine::strat_engine::thinpool::filesystem::StratFilesystem;
 
@@ -31,6 +31,10 @@ use crate::engine::strat_engine::thinpool::filesystem::StratFilesystem;
 
 const FILESYSTEM_DIR: &str = "filesystems";
 
+/// The directory in which the MDVs are temporarily mounted. It is outside
+/// of /dev, which is a devtmpfs and should hold only devices and links.
+const MDV_MOUNT_PATH: &str = "/run/stratisd";
+
 #[derive(Debug)]
 pub struct MetadataVol {
     dev: LinearDev,
@@ -89,14 +93,15 @@ impl<'a> Drop for MountedMDV<'a> {
 impl MetadataVol {
     /// Initialize a new Metadata Volume.
     pub fn initialize(pool_uuid: PoolUuid, dev: LinearDev) -> StratisResult<MetadataVol> {
-        create_fs(&dev.devnode(), pool_uuid)?;
+        create_fs(&dev.devnode(), pool_uuid, None)?;
         MetadataVol::setup(pool_uuid, dev)
     }
 
     /// Set up an existing Metadata Volume.
     pub fn setup(pool_uuid: PoolUuid, dev: LinearDev) -> StratisResult<MetadataVol> {
+        create_dir_all(MDV_MOUNT_PATH)?;
         let filename = format!(".mdv-{}", pool_uuid.to_simple_ref());
-        let mount_pt: PathBuf = vec![DEV_PATH, &filename].iter().collect();
+        let mount_pt = Path::new(MDV_MOUNT_PATH).join(filename);
 
         let mdv = MetadataVol { dev, mount_pt };
 
@@ -191,12 +196,17 @@ impl MetadataVol {
             let mut data = Vec::new();
             f.read_to_end(&mut data)?;
 
-            filesystems.push(serde_json::from_slice(&data)?);
+            filesystems.push(migrate_fs_record(serde_json::from_slice(&data)?)?);
         }
 
         Ok(filesystems)
     }
 
+    /// Compare the Metadata Volume's DM device with the active device.
+    pub fn discrepancy(&self, pool_uuid: PoolUuid) -> StratisResult<Option<Discrepancy>> {
+        check_dm_device!(pool_uuid, &self.dev, LinearDev)
+    }
+
     /// Tear down a Metadata Volume.
     pub fn teardown(&mut self) -> StratisResult<()> {
         self.dev.teardown(get_dm())?;
@@ -231,6 +241,24 @@ impl MetadataVol {
     }
 }
 
+/// Bring a filesystem record read from the MDV up to the current version.
+/// Returns an error if the record was written by a later version of
+/// stratisd, since it may hold what this one does not understand.
+fn migrate_fs_record(mut record: FilesystemSave) -> StratisResult<FilesystemSave> {
+    if record.version > FILESYSTEM_SAVE_VERSION {
+        let err_msg = format!(
+            "record of filesystem {} has version {}, but only versions up to {} are supported",
+            record.uuid, record.version, FILESYSTEM_SAVE_VERSION
+        );
+        return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+    }
+
+    // Version 1 only added fields that may be absent, so a version 0 record
+    // is also a version 1 record.
+    record.version = FILESYSTEM_SAVE_VERSION;
+    Ok(record)
+}
+
 /// Remove temp files from the designated directory.
 /// Returns an error if the directory can not be read.
 /// Persists if an individual directory entry can not be read due to an
@@ -251,3 +279,33 @@ fn remove_temp_files(dir: &Path) -> StratisResult<(u64, Vec<PathBuf>)> {
     }
     Ok((found, failed))
 }
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    /// A record without a version is brought up to the current version,
+    /// while a record with a later version is refused.
+    fn migrate_versions() {
+        let record = format!(
+            "{{\"name\":\"fs\",\"uuid\":\"{}\",\"thin_id\":1,\"size\":8192,\"created\":0}}",
+            Uuid::new_v4().to_simple_ref()
+        );
+        let old: FilesystemSave = serde_json::from_str(&record).unwrap();
+        assert_eq!(old.version, 0);
+        assert_eq!(
+            migrate_fs_record(old).unwrap().version,
+            FILESYSTEM_SAVE_VERSION
+        );
+
+        let mut new: FilesystemSave = serde_json::from_str(&record).unwrap();
+        new.version = FILESYSTEM_SAVE_VERSION + 1;
+        assert_matches!(
+            migrate_fs_record(new),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+    }
+}
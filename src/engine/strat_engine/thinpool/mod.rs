@@ -8,4 +8,8 @@ mod thinids;
 #[allow(clippy::module_inception)]
 mod thinpool;
 
-pub use self::thinpool::{ThinPool, ThinPoolSizeParams, DATA_BLOCK_SIZE};
+pub use self::filesystem::DEFAULT_THIN_DEV_SIZE;
+pub use self::thinpool::{
+    choose_data_block_size, repair_thin_metadata, set_space_thresholds, validate_data_block_size,
+    ThinPool, ThinPoolSizeParams, DATA_BLOCK_SIZE,
+};
@@ -6,19 +6,24 @@
 
 use std;
 use std::cmp::{max, min};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::Duration;
 use uuid::Uuid;
 
 use devicemapper::{
-    device_exists, DataBlocks, Device, DmDevice, DmName, DmNameBuf, FlakeyTargetParams, LinearDev,
-    LinearDevTargetParams, LinearTargetParams, MetaBlocks, Sectors, TargetLine, ThinDevId,
-    ThinPoolDev, ThinPoolStatus, ThinPoolStatusSummary, IEC,
+    device_exists, DataBlocks, DevId, Device, DmDevice, DmName, DmNameBuf, FlakeyTargetParams,
+    LinearDev, LinearDevTargetParams, LinearTargetParams, MetaBlocks, Sectors, TargetLine,
+    ThinDevId, ThinPoolDev, ThinPoolStatus, ThinPoolStatusSummary, IEC,
 };
 
 use crate::engine::{
-    devlinks, EngineEvent, Filesystem, FilesystemUuid, MaybeDbusPath, Name, PoolUuid, RenameAction,
+    devlinks, ChunkSize, EngineEvent, Filesystem, FilesystemUuid, MaybeDbusPath, Name,
+    OverprovisionPolicy, PoolUuid, RenameAction,
 };
 use crate::stratis::{ErrorEnum, StratisError, StratisResult};
 
+use crate::engine::check::Discrepancy;
 use crate::engine::event::get_engine_listener_list;
 use crate::engine::structures::Table;
 use crate::engine::types::{FreeSpaceState, PoolExtendState, PoolState};
@@ -30,7 +35,9 @@ use crate::engine::strat_engine::dm::get_dm;
 use crate::engine::strat_engine::names::{
     format_flex_ids, format_thin_ids, format_thinpool_ids, FlexRole, ThinPoolRole, ThinRole,
 };
-use crate::engine::strat_engine::serde_structs::{FlexDevsSave, Recordable, ThinPoolDevSave};
+use crate::engine::strat_engine::serde_structs::{
+    FlexDevsSave, OverprovisionSave, Recordable, ThinPoolDevSave,
+};
 
 use crate::engine::strat_engine::thinpool::filesystem::{
     fs_settle, FilesystemStatus, StratFilesystem,
@@ -39,6 +46,10 @@ use crate::engine::strat_engine::thinpool::mdv::MetadataVol;
 use crate::engine::strat_engine::thinpool::thinids::ThinDevIdPool;
 
 pub const DATA_BLOCK_SIZE: Sectors = Sectors(2 * IEC::Ki);
+// The bounds that dm-thin places on the data block size. It must also be a
+// multiple of the smallest size.
+const MIN_DATA_BLOCK_SIZE: Sectors = Sectors(128); // 64 KiB
+const MAX_DATA_BLOCK_SIZE: Sectors = Sectors(2 * IEC::Mi); // 1 GiB
 pub const DATA_LOWATER: DataBlocks = DataBlocks(2048); // 2 GiB
 
 const INITIAL_META_SIZE: MetaBlocks = MetaBlocks(4 * IEC::Ki);
@@ -47,15 +58,125 @@ const MIN_META_SEGMENT_SIZE: MetaBlocks = MetaBlocks(4 * IEC::Ki);
 const INITIAL_DATA_SIZE: DataBlocks = DataBlocks(768);
 const INITIAL_MDV_SIZE: Sectors = Sectors(32 * IEC::Ki); // 16 MiB
 
-const SPACE_WARN_PCT: u8 = 90;
-const SPACE_CRIT_PCT: u8 = 95;
+/// The largest percentage of a pool's usable capacity that may be held in
+/// reserve.
+pub const MAX_RESERVED_PCT: u8 = 50;
+
+// The amount of thin pool metadata space allotted to each filesystem when
+// computing the default limit on the number of filesystems in a pool.
+const META_BLOCKS_PER_FILESYSTEM: MetaBlocks = MetaBlocks(64); // 256 KiB
+
+const DEFAULT_SPACE_WARN_PCT: u8 = 90;
+const DEFAULT_SPACE_CRIT_PCT: u8 = 95;
+
+/// The percentage of a pool's data space in use at which the pool's
+/// free space state becomes Warn.
+static SPACE_WARN_PCT: AtomicU8 = AtomicU8::new(DEFAULT_SPACE_WARN_PCT);
+/// The percentage of a pool's data space in use at which the pool's
+/// free space state becomes Crit.
+static SPACE_CRIT_PCT: AtomicU8 = AtomicU8::new(DEFAULT_SPACE_CRIT_PCT);
+
+fn space_warn_pct() -> u8 {
+    SPACE_WARN_PCT.load(Ordering::Relaxed)
+}
+
+fn space_crit_pct() -> u8 {
+    SPACE_CRIT_PCT.load(Ordering::Relaxed)
+}
+
+/// Set the percentages of a pool's data space in use at which the pool's
+/// free space state becomes Warn and Crit. The new thresholds take effect
+/// for each pool the next time its usage is checked.
+/// Returns an error unless 0 < warn_pct < crit_pct <= 100.
+pub fn set_space_thresholds(warn_pct: u8, crit_pct: u8) -> StratisResult<()> {
+    if warn_pct == 0 || warn_pct >= crit_pct || crit_pct > 100 {
+        let err_msg = format!(
+            "invalid space thresholds: warn {}%, critical {}%; \
+             must satisfy 0 < warn < critical <= 100",
+            warn_pct, crit_pct
+        );
+        return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+    }
+    SPACE_WARN_PCT.store(warn_pct, Ordering::Relaxed);
+    SPACE_CRIT_PCT.store(crit_pct, Ordering::Relaxed);
+    Ok(())
+}
+
+fn sectors_to_datablocks(sectors: Sectors, data_block_size: Sectors) -> DataBlocks {
+    DataBlocks(sectors / data_block_size)
+}
+
+fn datablocks_to_sectors(data_blocks: DataBlocks, data_block_size: Sectors) -> Sectors {
+    *data_blocks * data_block_size
+}
+
+/// Round size up to the nearest multiple of modulus.
+fn round_up(size: Sectors, modulus: Sectors) -> Sectors {
+    match *size % *modulus {
+        0 => size,
+        rem => size + modulus - Sectors(rem),
+    }
+}
+
+/// Return an error unless dm-thin accepts size as a data block size.
+pub fn validate_data_block_size(size: Sectors) -> StratisResult<()> {
+    if size < MIN_DATA_BLOCK_SIZE
+        || size > MAX_DATA_BLOCK_SIZE
+        || round_up(size, MIN_DATA_BLOCK_SIZE) != size
+    {
+        let err_msg = format!(
+            "chunk size {} must be a multiple of {} between {} and {}",
+            size, MIN_DATA_BLOCK_SIZE, MIN_DATA_BLOCK_SIZE, MAX_DATA_BLOCK_SIZE
+        );
+        return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+    }
+    Ok(())
+}
+
+/// Choose the data block size for a new thin pool. Unless the size is given
+/// explicitly, start from the default, or from the smallest size if the
+/// pool is expected to hold many snapshots, and raise it to the optimal I/O
+/// size of the pool's devices, so that each block is written in one
+/// properly aligned I/O.
+/// Returns an error if an explicitly given size is invalid.
+pub fn choose_data_block_size(
+    chunk_size: ChunkSize,
+    optimal_io_size: Option<Sectors>,
+) -> StratisResult<Sectors> {
+    let base = match chunk_size {
+        ChunkSize::Fixed(size) => {
+            validate_data_block_size(size)?;
+            return Ok(size);
+        }
+        ChunkSize::Auto => DATA_BLOCK_SIZE,
+        ChunkSize::AutoSnapshots => MIN_DATA_BLOCK_SIZE,
+    };
+    // Round up to a size that dm-thin accepts.
+    let io_size = optimal_io_size.map_or(Sectors(0), |size| round_up(size, MIN_DATA_BLOCK_SIZE));
+    Ok(min(max(base, io_size), MAX_DATA_BLOCK_SIZE))
+}
 
-fn sectors_to_datablocks(sectors: Sectors) -> DataBlocks {
-    DataBlocks(sectors / DATA_BLOCK_SIZE)
+/// Return a value from 0 to 100 that is the percentage that "used"
+/// makes up in "total".
+fn used_pct(used: u64, total: u64) -> u8 {
+    assert!(total >= used);
+    let mut val = (used * 100) / total;
+    if (used * 100) % total != 0 {
+        val += 1; // round up
+    }
+    assert!(val <= 100);
+    val as u8
 }
 
-fn datablocks_to_sectors(data_blocks: DataBlocks) -> Sectors {
-    *data_blocks * DATA_BLOCK_SIZE
+/// Bring the XFS label of filesystem in step with its name. Failing to do
+/// so does not undo the rename or other change that made them differ.
+fn sync_xfs_label(name: &Name, filesystem: &StratFilesystem) {
+    if let Err(err) = filesystem.sync_xfs_label(name) {
+        warn!(
+            "Could not set the XFS label of filesystem {} to match its name: {}",
+            name, err
+        );
+    }
 }
 
 /// Transform a list of segments belonging to a single device into a
@@ -129,24 +250,36 @@ fn coalesce_segs(
 /// cap device.)
 /// Lowater needed for three things:
 /// 1. Extend data device (currently not applicable due to greedy allocation)
-/// 2. Get an event when pool exceeds SPACE_CRIT_PCT
+/// 2. Get an event when pool exceeds the critical space threshold
 /// 3. Get an event when usage has increased enough that we might need to
 ///    extend a filesystem
-fn calc_lowater(used: DataBlocks, data_dev_size: DataBlocks, available: DataBlocks) -> DataBlocks {
+fn calc_lowater(
+    used: DataBlocks,
+    data_dev_size: DataBlocks,
+    available: DataBlocks,
+    data_block_size: Sectors,
+) -> DataBlocks {
     let total = data_dev_size + available;
 
+    // DATA_LOWATER is expressed in blocks of the default size.
+    let data_lowater = sectors_to_datablocks(
+        datablocks_to_sectors(DATA_LOWATER, DATA_BLOCK_SIZE),
+        data_block_size,
+    );
+
     // Calculate #2. Calculated against total size.
-    assert!(SPACE_CRIT_PCT <= 100);
-    let low_water_for_crit = total - ((total * SPACE_CRIT_PCT) / 100u8);
-    assert!(DataBlocks(std::u64::MAX) - available >= DATA_LOWATER);
+    let crit_pct = space_crit_pct();
+    assert!(crit_pct <= 100);
+    let low_water_for_crit = total - ((total * crit_pct) / 100u8);
+    assert!(DataBlocks(std::u64::MAX) - available >= data_lowater);
 
     // Compare values of #1 and #2 above to get which one is higher
     // WARNING: Do not alter this if-expression to a max-expression.
     // Doing so would invalidate the assertion below.
     // Need to add available to LOWATER to make it apples-to-apples with
     // low_water_for_crit.
-    let prelim_max = if DATA_LOWATER + available > low_water_for_crit {
-        DATA_LOWATER
+    let prelim_max = if data_lowater + available > low_water_for_crit {
+        data_lowater
     } else {
         assert!(low_water_for_crit >= available);
         // Adjust for against end of data dev instead of total
@@ -155,7 +288,7 @@ fn calc_lowater(used: DataBlocks, data_dev_size: DataBlocks, available: DataBloc
 
     // Calculate #3. This is not the same as #1 because pool might be fully
     // extended, but we still need events to extend filesystems
-    let fs_event_lowater = DataBlocks((*(data_dev_size - used)).saturating_sub(*DATA_LOWATER));
+    let fs_event_lowater = DataBlocks((*(data_dev_size - used)).saturating_sub(*data_lowater));
 
     // Get the highest of the three values
     max(prelim_max, fs_event_lowater)
@@ -166,6 +299,7 @@ fn calc_lowater(used: DataBlocks, data_dev_size: DataBlocks, available: DataBloc
 struct Segments {
     meta_segments: Vec<(Sectors, Sectors)>,
     meta_spare_segments: Vec<(Sectors, Sectors)>,
+    meta_retained_segments: Vec<(Sectors, Sectors)>,
     data_segments: Vec<(Sectors, Sectors)>,
     mdv_segments: Vec<(Sectors, Sectors)>,
 }
@@ -183,7 +317,12 @@ impl ThinPoolSizeParams {
     }
     /// The number of Sectors in the DataBlocks.
     pub fn data_size(&self) -> Sectors {
-        datablocks_to_sectors(self.data_size)
+        datablocks_to_sectors(self.data_size, DATA_BLOCK_SIZE)
+    }
+    /// The number of Sectors in the DataBlocks, rounded up to a whole number
+    /// of blocks of the given size.
+    pub fn data_size_for(&self, data_block_size: Sectors) -> Sectors {
+        round_up(self.data_size(), data_block_size)
     }
     /// MDV size
     pub fn mdv_size(&self) -> Sectors {
@@ -218,6 +357,18 @@ pub struct ThinPool {
     pool_state: PoolState,
     pool_extend_state: PoolExtendState,
     free_space_state: FreeSpaceState,
+    overprovision: OverprovisionPolicy,
+    /// The percentage of the pool's usable capacity that the thin pool data
+    /// device may not grow into. It is kept free for growth of the thin
+    /// pool meta device and for recovery.
+    reserved_pct: u8,
+    /// The largest number of filesystems, snapshots included, that the pool
+    /// may hold. If None, the limit is computed from the size of the thin
+    /// pool meta device.
+    filesystem_limit: Option<u64>,
+    /// The most snapshots that may be taken of each filesystem that has no
+    /// limit of its own. If None, such filesystems are not limited.
+    snapshot_limit: Option<u64>,
     dbus_path: MaybeDbusPath,
 }
 
@@ -230,26 +381,36 @@ impl ThinPool {
         data_block_size: Sectors,
         backstore: &mut Backstore,
     ) -> StratisResult<ThinPool> {
+        let err_msg = "Could not allocate sufficient space for thinpool devices.";
+
+        // The meta device and its spare are allocated separately, so that
+        // they may be placed on the fastest blockdevs.
+        let mut meta_segments_list = match backstore.alloc_metadata(
+            pool_uuid,
+            &[thin_pool_size.meta_size(), thin_pool_size.meta_size()],
+        )? {
+            Some(sl) => sl,
+            None => return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg.into())),
+        };
         let mut segments_list = match backstore.alloc(
             pool_uuid,
             &[
-                thin_pool_size.meta_size(),
-                thin_pool_size.meta_size(),
-                thin_pool_size.data_size(),
+                thin_pool_size.data_size_for(data_block_size),
                 thin_pool_size.mdv_size(),
             ],
         )? {
             Some(sl) => sl,
-            None => {
-                let err_msg = "Could not allocate sufficient space for thinpool devices.";
-                return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg.into()));
-            }
+            None => return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg.into())),
         };
 
-        let mdv_segments = segments_list.pop().expect("len(segments_list) == 4");
-        let data_segments = segments_list.pop().expect("len(segments_list) == 3");
-        let spare_segments = segments_list.pop().expect("len(segments_list) == 2");
-        let meta_segments = segments_list.pop().expect("len(segments_list) == 1");
+        let mdv_segments = segments_list.pop().expect("len(segments_list) == 2");
+        let data_segments = segments_list.pop().expect("len(segments_list) == 1");
+        let spare_segments = meta_segments_list
+            .pop()
+            .expect("len(meta_segments_list) == 2");
+        let meta_segments = meta_segments_list
+            .pop()
+            .expect("len(meta_segments_list) == 1");
 
         let backstore_device = backstore.device().expect(
             "Space has just been allocated from the backstore, so it must have a cap device",
@@ -309,8 +470,9 @@ impl ThinPool {
             data_block_size,
             calc_lowater(
                 DataBlocks(0),
-                sectors_to_datablocks(data_dev_size),
-                sectors_to_datablocks(backstore.available_in_backstore()),
+                sectors_to_datablocks(data_dev_size, data_block_size),
+                sectors_to_datablocks(backstore.available_in_backstore(), data_block_size),
+                data_block_size,
             ),
         )?;
 
@@ -319,6 +481,7 @@ impl ThinPool {
             segments: Segments {
                 meta_segments: vec![meta_segments],
                 meta_spare_segments: vec![spare_segments],
+                meta_retained_segments: vec![],
                 data_segments: vec![data_segments],
                 mdv_segments: vec![mdv_segments],
             },
@@ -329,6 +492,10 @@ impl ThinPool {
             pool_state: PoolState::Initializing,
             pool_extend_state: PoolExtendState::Initializing,
             free_space_state,
+            overprovision: OverprovisionPolicy::Unlimited,
+            reserved_pct: 0,
+            filesystem_limit: None,
+            snapshot_limit: None,
             dbus_path: MaybeDbusPath(None),
         })
     }
@@ -382,8 +549,12 @@ impl ThinPool {
                 // This is smaller than the actual amount used. This value
                 // is updated when the thinpool's check method is invoked.
                 DataBlocks(0),
-                sectors_to_datablocks(data_dev_size),
-                sectors_to_datablocks(backstore.available_in_backstore()),
+                sectors_to_datablocks(data_dev_size, thin_pool_save.data_block_size),
+                sectors_to_datablocks(
+                    backstore.available_in_backstore(),
+                    thin_pool_save.data_block_size,
+                ),
+                thin_pool_save.data_block_size,
             ),
         )?;
 
@@ -431,6 +602,7 @@ impl ThinPool {
             segments: Segments {
                 meta_segments,
                 meta_spare_segments: spare_segments,
+                meta_retained_segments: flex_devs.thin_meta_dev_retained.to_vec(),
                 data_segments,
                 mdv_segments,
             },
@@ -441,6 +613,19 @@ impl ThinPool {
             pool_state: PoolState::Initializing,
             pool_extend_state: PoolExtendState::Initializing,
             free_space_state,
+            overprovision: thin_pool_save.overprovision.as_ref().map_or(
+                OverprovisionPolicy::Unlimited,
+                |save| {
+                    if save.enforce {
+                        OverprovisionPolicy::Limit(save.ratio_pct)
+                    } else {
+                        OverprovisionPolicy::Warn(save.ratio_pct)
+                    }
+                },
+            ),
+            reserved_pct: thin_pool_save.reserved_pct.unwrap_or(0),
+            filesystem_limit: thin_pool_save.filesystem_limit,
+            snapshot_limit: thin_pool_save.snapshot_limit,
             dbus_path: MaybeDbusPath(None),
         })
     }
@@ -510,9 +695,21 @@ impl ThinPool {
                     }
                 }
 
+                // Keep the spare at least as large as the meta device, so
+                // that thin_repair always has somewhere to write to.
+                match self.extend_thin_meta_spare(pool_uuid, backstore) {
+                    Ok(extend_size) => should_save |= extend_size != Sectors(0),
+                    Err(err) => warn!(
+                        "Could not extend thinpool metadata spare belonging to pool with UUID {}: {:?}",
+                        pool_uuid, err
+                    ),
+                }
+
                 // Expand data blocks to fill all available remaining space
-                let free_space = backstore.available_in_backstore();
-                let total_extended = if free_space < DATA_BLOCK_SIZE {
+                // outside the reserve
+                let data_block_size = self.thin_pool.data_block_size();
+                let free_space = self.available_for_data(backstore);
+                let total_extended = if free_space < data_block_size {
                     DataBlocks(0)
                 } else {
                     let amount_allocated =
@@ -522,7 +719,7 @@ impl ThinPool {
                         };
                     data_extend_failed = amount_allocated == Sectors(0);
                     should_save |= !data_extend_failed;
-                    sectors_to_datablocks(amount_allocated)
+                    sectors_to_datablocks(amount_allocated, data_block_size)
                 };
 
                 let current_total = usage.total_data + total_extended;
@@ -530,14 +727,19 @@ impl ThinPool {
                 // Update pool space state
                 self.free_space_check(
                     usage.used_data,
-                    current_total + sectors_to_datablocks(backstore.available_in_backstore())
+                    current_total
+                        + sectors_to_datablocks(
+                            self.available_for_data(backstore),
+                            data_block_size,
+                        )
                         - usage.used_data,
                 )?;
 
                 let lowater = calc_lowater(
                     usage.used_data,
                     current_total,
-                    sectors_to_datablocks(backstore.available_in_backstore()),
+                    sectors_to_datablocks(self.available_for_data(backstore), data_block_size),
+                    data_block_size,
                 );
 
                 self.thin_pool.set_low_water_mark(get_dm(), lowater)?;
@@ -560,8 +762,26 @@ impl ThinPool {
             }
         }
 
+        // Under the Limit policy, filesystems are extended only as far as
+        // the policy allows.
+        let capacity = backstore.datatier_usable_size();
+        let enforced_limit = match self.overprovision {
+            OverprovisionPolicy::Limit(_) => self.overprovision_limit(capacity),
+            _ => None,
+        };
+        let initial_total = self.filesystems_size();
+        let mut fs_total = initial_total;
         for (name, uuid, fs) in self.filesystems.iter_mut() {
-            let (fs_status, save_mdv) = fs.check()?;
+            let old_size = fs.thindev_size();
+            let extend_limit = enforced_limit.map(|limit| {
+                if limit > fs_total {
+                    limit - fs_total
+                } else {
+                    Sectors(0)
+                }
+            });
+            let (fs_status, save_mdv) = fs.check(extend_limit)?;
+            fs_total = fs_total + fs.thindev_size() - old_size;
             if save_mdv {
                 if let Err(e) = self.mdv.save_fs(name, *uuid, &fs) {
                     error!("Could not save MDV for fs with UUID {} and name {} belonging to pool with UUID {}, reason: {:?}", 
@@ -571,6 +791,40 @@ impl ThinPool {
             if let FilesystemStatus::Failed = fs_status {
                 // TODO: filesystem failed, how to recover?
             }
+            if let FilesystemStatus::ExtendRefused = fs_status {
+                warn!(
+                    "Filesystem with UUID {} and name {} belonging to pool with UUID {} is nearly full, but extending it would exceed the pool's overprovisioning limit",
+                    uuid, name, pool_uuid
+                );
+            }
+            if let FilesystemStatus::SizeLimitReached = fs_status {
+                warn!(
+                    "Filesystem with UUID {} and name {} belonging to pool with UUID {} is nearly full, but has reached its size limit",
+                    uuid, name, pool_uuid
+                );
+            }
+            // The pool is filling, so return to it any large amount of
+            // space that a filesystem holds but no longer uses. The trim is
+            // done by the daemon's loop, a pass at a time, not here.
+            match fs.usage() {
+                Ok(ref usage) if usage.trim_recommended() => {
+                    if fs.request_trim() {
+                        info!(
+                            "Trimming the unreclaimed space of filesystem with UUID {} and name {} belonging to pool with UUID {}",
+                            uuid, name, pool_uuid
+                        );
+                    }
+                }
+                Ok(_) => (),
+                Err(err) => warn!(
+                    "Could not get usage of filesystem with UUID {} and name {} belonging to pool with UUID {}: {:?}",
+                    uuid, name, pool_uuid, err
+                ),
+            }
+        }
+        if fs_total > initial_total && enforced_limit.is_none() {
+            // Only warns, since the policy is not enforced.
+            self.check_overprovision(capacity, Sectors(0))?;
         }
         Ok(should_save)
     }
@@ -603,13 +857,24 @@ impl ThinPool {
         }
     }
 
-    fn set_free_space_state(&mut self, new_state: FreeSpaceState) {
+    fn set_free_space_state(&mut self, new_state: FreeSpaceState, used_pct: u8) {
         if self.free_space_state() != new_state {
             self.free_space_state = new_state;
             get_engine_listener_list().notify(&EngineEvent::PoolSpaceStateChanged {
                 dbus_path: self.get_dbus_path(),
                 state: new_state,
             });
+            if new_state != FreeSpaceState::Good {
+                warn!(
+                    "Pool data space usage has reached the {:?} threshold; add blockdevs to the pool to make more space available",
+                    new_state
+                );
+                get_engine_listener_list().notify(&EngineEvent::SpaceLow {
+                    dbus_path: self.get_dbus_path(),
+                    state: new_state,
+                    used_pct,
+                });
+            }
         }
     }
 
@@ -620,30 +885,18 @@ impl ThinPool {
         used: DataBlocks,
         available: DataBlocks,
     ) -> StratisResult<FreeSpaceState> {
-        // Return a value from 0 to 100 that is the percentage that "used"
-        // makes up in "total".
-        fn used_pct(used: u64, total: u64) -> u8 {
-            assert!(total >= used);
-            let mut val = (used * 100) / total;
-            if (used * 100) % total != 0 {
-                val += 1; // round up
-            }
-            assert!(val <= 100);
-            val as u8
-        }
-
         let overall_used_pct = used_pct(*used, *used + *available);
         info!("Data tier percent used: {}", overall_used_pct);
 
-        let new_state = if overall_used_pct < SPACE_WARN_PCT {
+        let new_state = if overall_used_pct < space_warn_pct() {
             FreeSpaceState::Good
-        } else if overall_used_pct < SPACE_CRIT_PCT {
+        } else if overall_used_pct < space_crit_pct() {
             FreeSpaceState::Warn
         } else {
             FreeSpaceState::Crit
         };
 
-        self.set_free_space_state(new_state);
+        self.set_free_space_state(new_state, overall_used_pct);
 
         match (self.free_space_state, new_state) {
             (FreeSpaceState::Good, FreeSpaceState::Crit)
@@ -668,6 +921,21 @@ impl ThinPool {
         Ok(new_state)
     }
 
+    /// Compare the thin pool's DM devices, those of its filesystems, and
+    /// that of its MDV with the active devices, returning the differences.
+    pub fn discrepancies(&self, pool_uuid: PoolUuid) -> StratisResult<Vec<Discrepancy>> {
+        let mut found = vec![
+            check_dm_device!(pool_uuid, &self.thin_pool, ThinPoolDev)?,
+            check_dm_device!(pool_uuid, self.thin_pool.meta_dev(), LinearDev)?,
+            check_dm_device!(pool_uuid, self.thin_pool.data_dev(), LinearDev)?,
+            self.mdv.discrepancy(pool_uuid)?,
+        ];
+        for (_, _, fs) in &self.filesystems {
+            found.push(fs.discrepancy(pool_uuid)?);
+        }
+        Ok(found.into_iter().flatten().collect())
+    }
+
     /// Tear down the components managed here: filesystems, the MDV,
     /// and the actual thinpool device itself.
     pub fn teardown(&mut self) -> StratisResult<()> {
@@ -692,12 +960,13 @@ impl ThinPool {
         backstore: &mut Backstore,
         extend_size: Sectors,
     ) -> StratisResult<Sectors> {
+        let data_block_size = self.thin_pool.data_block_size();
         ThinPool::extend_thin_sub_device(
             pool_uuid,
             &mut self.thin_pool,
             backstore,
             extend_size,
-            DATA_BLOCK_SIZE,
+            data_block_size,
             &mut self.segments.data_segments,
             true,
         )
@@ -721,6 +990,57 @@ impl ThinPool {
         )
     }
 
+    /// Extend the spare for the thinpool's meta dev to the size of the meta
+    /// dev. The spare is not set up as a device unless a repair requires it,
+    /// so only its segments change. After a repair, the old meta dev's
+    /// segments become the spare, so the spare is then already as large as
+    /// the meta dev. The result is the value by which the spare is extended,
+    /// which may be 0.
+    fn extend_thin_meta_spare(
+        &mut self,
+        pool_uuid: PoolUuid,
+        backstore: &mut Backstore,
+    ) -> StratisResult<Sectors> {
+        let meta_size = self
+            .segments
+            .meta_segments
+            .iter()
+            .map(|&(_, length)| length)
+            .sum::<Sectors>();
+        let spare_size = self
+            .segments
+            .meta_spare_segments
+            .iter()
+            .map(|&(_, length)| length)
+            .sum::<Sectors>();
+        if spare_size >= meta_size {
+            return Ok(Sectors(0));
+        }
+
+        let extend_size = meta_size - spare_size;
+        match backstore.request_metadata(pool_uuid, extend_size, MIN_META_SEGMENT_SIZE.sectors())? {
+            Some(region) => {
+                let mut segments = coalesce_segs(&self.segments.meta_spare_segments, &[region]);
+                self.segments.meta_spare_segments.clear();
+                self.segments.meta_spare_segments.append(&mut segments);
+                info!(
+                    "Extended thinpool metadata spare belonging to pool with uuid {} by {}",
+                    pool_uuid.to_simple_ref(),
+                    region.1
+                );
+                Ok(region.1)
+            }
+            None => {
+                warn!(
+                    "Insufficient free space available in backstore; thinpool metadata spare belonging to pool with uuid {} is {} smaller than the metadata device",
+                    pool_uuid.to_simple_ref(),
+                    extend_size
+                );
+                Ok(Sectors(0))
+            }
+        }
+    }
+
     /// Extend the thinpool's data or meta devices. The result is the value
     /// by which the device is extended which may be less than the requested
     /// amount. It is guaranteed that the returned amount is a multiple of the
@@ -746,7 +1066,12 @@ impl ThinPool {
             sub_device_str, pool_uuid_str, extend_size,
         );
 
-        let result = if let Some(region) = backstore.request(pool_uuid, extend_size, modulus)? {
+        let request = if data {
+            backstore.request(pool_uuid, extend_size, modulus)?
+        } else {
+            backstore.request_metadata(pool_uuid, extend_size, modulus)?
+        };
+        let result = if let Some(region) = request {
             let device = backstore
                 .device()
                 .expect("If request succeeded, backstore must have cap device.");
@@ -800,7 +1125,9 @@ impl ThinPool {
     // in use on the data device.
     pub fn total_physical_used(&self) -> StratisResult<Sectors> {
         let data_dev_used = match self.thin_pool.status(get_dm())? {
-            ThinPoolStatus::Working(ref status) => datablocks_to_sectors(status.usage.used_data),
+            ThinPoolStatus::Working(ref status) => {
+                datablocks_to_sectors(status.usage.used_data, self.thin_pool.data_block_size())
+            }
             ThinPoolStatus::Error => {
                 let err_msg = format!(
                     "Devicemapper could not obtain status for devicemapper thin pool device {}",
@@ -823,6 +1150,109 @@ impl ThinPool {
         Ok(data_dev_used + spare_total + meta_dev_total + mdv_total)
     }
 
+    /// The percentages of the thin pool's data and metadata devices in use.
+    pub fn usage_pcts(&self) -> StratisResult<(u8, u8)> {
+        match self.thin_pool.status(get_dm())? {
+            ThinPoolStatus::Working(ref status) => {
+                let usage = &status.usage;
+                Ok((
+                    used_pct(*usage.used_data, *usage.total_data),
+                    used_pct(*usage.used_meta, *usage.total_meta),
+                ))
+            }
+            ThinPoolStatus::Error => {
+                let err_msg = format!(
+                    "Devicemapper could not obtain status for devicemapper thin pool device {}",
+                    self.thin_pool.device(),
+                );
+                Err(StratisError::Engine(ErrorEnum::Invalid, err_msg))
+            }
+            ThinPoolStatus::Fail => {
+                let err_msg = "thin pool failed, could not obtain usage";
+                Err(StratisError::Engine(ErrorEnum::Invalid, err_msg.into()))
+            }
+        }
+    }
+
+    /// The block at which the root of the reserved metadata snapshot is
+    /// located, if there is one.
+    fn held_metadata_root(&self) -> StratisResult<Option<MetaBlocks>> {
+        match self.thin_pool.status(get_dm())? {
+            ThinPoolStatus::Working(ref status) => Ok(status.held_metadata_root),
+            ThinPoolStatus::Error => {
+                let err_msg = format!(
+                    "Devicemapper could not obtain status for devicemapper thin pool device {}",
+                    self.thin_pool.device(),
+                );
+                Err(StratisError::Engine(ErrorEnum::Invalid, err_msg))
+            }
+            ThinPoolStatus::Fail => {
+                let err_msg = "thin pool failed, could not obtain metadata snapshot status";
+                Err(StratisError::Engine(ErrorEnum::Invalid, err_msg.into()))
+            }
+        }
+    }
+
+    /// Reserve a snapshot of the thin pool's metadata, unless one is already
+    /// reserved. Return the devnode of the thin pool's meta device and the
+    /// block at which the root of the snapshot is located.
+    pub fn reserve_metadata_snapshot(&mut self) -> StratisResult<(PathBuf, MetaBlocks)> {
+        if self.held_metadata_root()?.is_none() {
+            get_dm().target_msg(
+                &DevId::Name(self.thin_pool.name()),
+                None,
+                "reserve_metadata_snap",
+            )?;
+        }
+
+        match self.held_metadata_root()? {
+            Some(root) => Ok((self.thin_pool.meta_dev().devnode(), root)),
+            None => {
+                let err_msg = "metadata snapshot was reserved, but thin pool reports none held";
+                Err(StratisError::Engine(ErrorEnum::Error, err_msg.into()))
+            }
+        }
+    }
+
+    /// Release the thin pool's metadata snapshot, if one is reserved.
+    /// Return true if a snapshot was released.
+    pub fn release_metadata_snapshot(&mut self) -> StratisResult<bool> {
+        if self.held_metadata_root()?.is_none() {
+            return Ok(false);
+        }
+
+        get_dm().target_msg(
+            &DevId::Name(self.thin_pool.name()),
+            None,
+            "release_metadata_snap",
+        )?;
+        Ok(true)
+    }
+
+    /// The names of this pool's filesystems which are currently mounted.
+    pub fn mounted_filesystems(&self) -> StratisResult<Vec<Name>> {
+        let mut mounted = Vec::new();
+        for (name, _, fs) in self.filesystems.iter() {
+            if !fs.mount_points()?.is_empty() {
+                mounted.push(name.clone());
+            }
+        }
+        Ok(mounted)
+    }
+
+    /// The names of the filesystems recorded in the metadata volume which
+    /// could not be set up, e.g., because their thin devices are absent
+    /// from repaired thin metadata.
+    pub fn missing_filesystems(&self) -> StratisResult<Vec<Name>> {
+        Ok(self
+            .mdv
+            .filesystems()?
+            .into_iter()
+            .filter(|fssave| self.filesystems.get_by_uuid(fssave.uuid).is_none())
+            .map(|fssave| Name::new(fssave.name))
+            .collect())
+    }
+
     pub fn get_filesystem_by_uuid(&self, uuid: FilesystemUuid) -> Option<(Name, &StratFilesystem)> {
         self.filesystems.get_by_uuid(uuid)
     }
@@ -838,13 +1268,6 @@ impl ThinPool {
         self.filesystems.get_by_name(name)
     }
 
-    pub fn get_mut_filesystem_by_name(
-        &mut self,
-        name: &str,
-    ) -> Option<(FilesystemUuid, &mut StratFilesystem)> {
-        self.filesystems.get_mut_by_name(name)
-    }
-
     pub fn has_filesystems(&self) -> bool {
         !self.filesystems.is_empty()
     }
@@ -872,8 +1295,13 @@ impl ThinPool {
         name: &str,
         size: Option<Sectors>,
     ) -> StratisResult<FilesystemUuid> {
-        let (fs_uuid, mut new_filesystem) =
-            StratFilesystem::initialize(pool_uuid, &self.thin_pool, size, self.id_gen.new_id()?)?;
+        let (fs_uuid, mut new_filesystem) = StratFilesystem::initialize(
+            pool_uuid,
+            &self.thin_pool,
+            name,
+            size,
+            self.id_gen.new_id()?,
+        )?;
         let name = Name::new(name.to_owned());
         if let Err(err) = self.mdv.save_fs(&name, fs_uuid, &new_filesystem) {
             fs_settle();
@@ -886,19 +1314,89 @@ impl ThinPool {
             return Err(err);
         }
         devlinks::filesystem_added(pool_name, &name, &new_filesystem.devnode());
+        get_engine_listener_list().notify(&EngineEvent::FilesystemCreated {
+            pool_name,
+            name: &name,
+            uuid: fs_uuid,
+        });
         self.filesystems.insert(name, fs_uuid, new_filesystem);
 
         Ok(fs_uuid)
     }
 
+    /// Copy every filesystem in source into this thin pool under the same
+    /// name but with a new UUID. None of the source filesystems may be
+    /// mounted. Snapshot relationships are not preserved; each copy is
+    /// independent of the others.
+    pub fn copy_filesystems(
+        &mut self,
+        pool_uuid: PoolUuid,
+        pool_name: &str,
+        source: &ThinPool,
+        backstore: &mut Backstore,
+    ) -> StratisResult<Vec<FilesystemUuid>> {
+        let mounted = source.mounted_filesystems()?;
+        if !mounted.is_empty() {
+            let err_msg = format!(
+                "filesystems {} must be unmounted to be copied",
+                mounted
+                    .iter()
+                    .map(|name| name.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            return Err(StratisError::Engine(ErrorEnum::Busy, err_msg));
+        }
+        self.check_filesystem_limit(source.filesystems.len())?;
+
+        let mut uuids = Vec::new();
+        for (name, _, filesystem) in source.filesystems.iter() {
+            let (fs_uuid, mut new_filesystem) = StratFilesystem::copy(
+                pool_uuid,
+                &self.thin_pool,
+                filesystem,
+                self.id_gen.new_id()?,
+            )?;
+            if let Err(err) = self.mdv.save_fs(name, fs_uuid, &new_filesystem) {
+                fs_settle();
+                if let Err(err2) = new_filesystem.destroy(&self.thin_pool) {
+                    error!(
+                        "When handling failed save_fs(), fs.destroy() failed: {}",
+                        err2
+                    )
+                }
+                return Err(err);
+            }
+            devlinks::filesystem_added(pool_name, name, &new_filesystem.devnode());
+            get_engine_listener_list().notify(&EngineEvent::FilesystemCreated {
+                pool_name,
+                name,
+                uuid: fs_uuid,
+            });
+            self.filesystems
+                .insert(name.clone(), fs_uuid, new_filesystem);
+            uuids.push(fs_uuid);
+
+            // Extend the metadata device, if the copy has used up enough
+            // of it, before making the next copy.
+            self.check(pool_uuid, backstore)?;
+        }
+
+        Ok(uuids)
+    }
+
     /// Create a filesystem snapshot of the origin.  Given origin_uuid
     /// must exist.  Returns the Uuid of the new filesystem.
+    /// If keep_xfs_uuid is true, the snapshot's XFS UUID is not changed
+    /// from the origin's.
     pub fn snapshot_filesystem(
         &mut self,
         pool_uuid: PoolUuid,
         pool_name: &str,
         origin_uuid: FilesystemUuid,
         snapshot_name: &str,
+        keep_xfs_uuid: bool,
+        freeze: bool,
     ) -> StratisResult<(FilesystemUuid, &mut dyn Filesystem)> {
         let snapshot_fs_uuid = Uuid::new_v4();
         let (snapshot_dm_name, snapshot_dm_uuid) =
@@ -913,6 +1411,9 @@ impl ThinPool {
                 &fs_name,
                 snapshot_fs_uuid,
                 snapshot_id,
+                origin_uuid,
+                keep_xfs_uuid,
+                freeze,
             )?,
             None => {
                 return Err(StratisError::Engine(
@@ -922,9 +1423,15 @@ impl ThinPool {
             }
         };
         let new_fs_name = Name::new(snapshot_name.to_owned());
+        sync_xfs_label(&new_fs_name, &new_filesystem);
         self.mdv
             .save_fs(&new_fs_name, snapshot_fs_uuid, &new_filesystem)?;
         devlinks::filesystem_added(pool_name, &new_fs_name, &new_filesystem.devnode());
+        get_engine_listener_list().notify(&EngineEvent::FilesystemCreated {
+            pool_name,
+            name: &new_fs_name,
+            uuid: snapshot_fs_uuid,
+        });
         self.filesystems
             .insert(new_fs_name, snapshot_fs_uuid, new_filesystem);
         Ok((
@@ -944,6 +1451,22 @@ impl ThinPool {
         pool_name: &str,
         uuid: FilesystemUuid,
     ) -> StratisResult<()> {
+        if let Some((fs_name, fs)) = self.filesystems.get_by_uuid(uuid) {
+            let mount_points = fs.mount_points()?;
+            if !mount_points.is_empty() {
+                let err_msg = format!(
+                    "filesystem {} is mounted at {}",
+                    fs_name,
+                    mount_points
+                        .iter()
+                        .map(|p| p.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+                return Err(StratisError::Engine(ErrorEnum::Busy, err_msg));
+            }
+        }
+
         match self.filesystems.remove_by_uuid(uuid) {
             Some((fs_name, mut fs)) => match fs.destroy(&self.thin_pool) {
                 Ok(_) => {
@@ -955,6 +1478,13 @@ impl ThinPool {
                                err);
                     }
                     devlinks::filesystem_removed(pool_name, &fs_name);
+                    get_engine_listener_list().notify(&EngineEvent::FilesystemDestroyed {
+                        dbus_path: fs.get_dbus_path(),
+                        pool_name,
+                        name: &fs_name,
+                        uuid,
+                    });
+                    self.reparent_snapshots(pool_name, uuid, fs.origin());
                     Ok(())
                 }
                 Err(err) => {
@@ -966,51 +1496,629 @@ impl ThinPool {
         }
     }
 
-    pub fn state(&self) -> PoolState {
-        self.pool_state
+    /// Revert the filesystem with the given UUID to the snapshot with
+    /// snapshot_uuid, which must be a snapshot of it. The filesystem keeps
+    /// its name and UUIDs, so that it can be mounted where it was before.
+    /// The snapshot itself is unchanged. Neither may be mounted.
+    pub fn revert_filesystem(
+        &mut self,
+        pool_uuid: PoolUuid,
+        pool_name: &str,
+        uuid: FilesystemUuid,
+        snapshot_uuid: FilesystemUuid,
+    ) -> StratisResult<()> {
+        let (fs_name, mut filesystem) = match self.filesystems.remove_by_uuid(uuid) {
+            Some(entry) => entry,
+            None => {
+                return Err(StratisError::Engine(
+                    ErrorEnum::NotFound,
+                    format!("no filesystem with UUID {}", uuid),
+                ))
+            }
+        };
+
+        let result = self.revert_removed_filesystem(
+            pool_uuid,
+            pool_name,
+            &fs_name,
+            uuid,
+            &mut filesystem,
+            snapshot_uuid,
+        );
+        self.filesystems.insert(fs_name, uuid, filesystem);
+        result
     }
 
-    pub fn extend_state(&self) -> PoolExtendState {
-        self.pool_extend_state
+    /// Do the work of revert_filesystem() for filesystem, which has been
+    /// removed from self.filesystems to be changed.
+    fn revert_removed_filesystem(
+        &mut self,
+        pool_uuid: PoolUuid,
+        pool_name: &str,
+        fs_name: &Name,
+        uuid: FilesystemUuid,
+        filesystem: &mut StratFilesystem,
+        snapshot_uuid: FilesystemUuid,
+    ) -> StratisResult<()> {
+        let snapshot = match self.filesystems.get_by_uuid(snapshot_uuid) {
+            Some((_, snapshot)) if snapshot.origin() == Some(uuid) => snapshot,
+            Some((snapshot_name, _)) => {
+                let err_msg = format!(
+                    "filesystem {} is not a snapshot of filesystem {}",
+                    snapshot_name, fs_name
+                );
+                return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+            }
+            None => {
+                return Err(StratisError::Engine(
+                    ErrorEnum::NotFound,
+                    format!("no filesystem with UUID {}", snapshot_uuid),
+                ))
+            }
+        };
+
+        if !filesystem.mount_points()?.is_empty() || !snapshot.mount_points()?.is_empty() {
+            let err_msg = format!(
+                "filesystem {} and its snapshot must be unmounted to be reverted",
+                fs_name
+            );
+            return Err(StratisError::Engine(ErrorEnum::Busy, err_msg));
+        }
+
+        let mdv = &self.mdv;
+        filesystem.revert(
+            pool_uuid,
+            &self.thin_pool,
+            uuid,
+            snapshot,
+            self.id_gen.new_id()?,
+            |filesystem| mdv.save_fs(fs_name, uuid, filesystem),
+        )?;
+        sync_xfs_label(fs_name, filesystem);
+        devlinks::filesystem_added(pool_name, fs_name, &filesystem.devnode());
+        Ok(())
     }
 
-    pub fn free_space_state(&self) -> FreeSpaceState {
-        self.free_space_state
+    /// Merge the snapshot with snapshot_uuid back into its origin, discarding
+    /// the changes made to the origin since the snapshot was taken. The
+    /// origin keeps its name and UUIDs; the snapshot is removed, and its own
+    /// snapshots become snapshots of the origin. Neither may be mounted.
+    /// Returns the UUID of the origin.
+    pub fn merge_snapshot(
+        &mut self,
+        pool_uuid: PoolUuid,
+        pool_name: &str,
+        snapshot_uuid: FilesystemUuid,
+    ) -> StratisResult<FilesystemUuid> {
+        let uuid = match self.filesystems.get_by_uuid(snapshot_uuid) {
+            Some((snapshot_name, snapshot)) => match snapshot.origin() {
+                Some(uuid) => uuid,
+                None => {
+                    let err_msg = format!("filesystem {} has no origin", snapshot_name);
+                    return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+                }
+            },
+            None => {
+                return Err(StratisError::Engine(
+                    ErrorEnum::NotFound,
+                    format!("no filesystem with UUID {}", snapshot_uuid),
+                ))
+            }
+        };
+
+        let (fs_name, mut filesystem) = match self.filesystems.remove_by_uuid(uuid) {
+            Some(entry) => entry,
+            None => {
+                return Err(StratisError::Engine(
+                    ErrorEnum::NotFound,
+                    format!("no filesystem with UUID {}", uuid),
+                ))
+            }
+        };
+
+        let result = self.merge_into_removed_filesystem(
+            pool_uuid,
+            pool_name,
+            &fs_name,
+            uuid,
+            &mut filesystem,
+            snapshot_uuid,
+        );
+        self.filesystems.insert(fs_name, uuid, filesystem);
+        result.map(|_| uuid)
     }
 
-    /// Rename a filesystem within the thin pool.
-    pub fn rename_filesystem(
+    /// Do the work of merge_snapshot() for filesystem, the origin, which has
+    /// been removed from self.filesystems to be changed.
+    fn merge_into_removed_filesystem(
         &mut self,
+        pool_uuid: PoolUuid,
         pool_name: &str,
+        fs_name: &Name,
         uuid: FilesystemUuid,
-        new_name: &str,
-    ) -> StratisResult<RenameAction> {
-        let old_name = rename_filesystem_pre!(self; uuid; new_name);
-        let new_name = Name::new(new_name.to_owned());
-
-        let filesystem = self
+        filesystem: &mut StratFilesystem,
+        snapshot_uuid: FilesystemUuid,
+    ) -> StratisResult<()> {
+        let (snapshot_name, snapshot) = self
             .filesystems
-            .remove_by_uuid(uuid)
-            .expect("Must succeed since self.filesystems.get_by_uuid() returned a value")
-            .1;
+            .get_mut_by_uuid(snapshot_uuid)
+            .expect("merge_snapshot() found the snapshot");
 
-        if let Err(err) = self.mdv.save_fs(&new_name, uuid, &filesystem) {
-            self.filesystems.insert(old_name, uuid, filesystem);
-            Err(err)
-        } else {
-            get_engine_listener_list().notify(&EngineEvent::FilesystemRenamed {
-                dbus_path: filesystem.get_dbus_path(),
-                from: &*old_name,
-                to: &*new_name,
-            });
-            self.filesystems.insert(new_name.clone(), uuid, filesystem);
-            devlinks::filesystem_renamed(pool_name, &old_name, &new_name);
-            Ok(RenameAction::Renamed)
+        if !filesystem.mount_points()?.is_empty() || !snapshot.mount_points()?.is_empty() {
+            let err_msg = format!(
+                "filesystem {} and its snapshot {} must be unmounted to be merged",
+                fs_name, snapshot_name
+            );
+            return Err(StratisError::Engine(ErrorEnum::Busy, err_msg));
         }
-    }
 
-    /// The names of DM devices belonging to this pool that may generate events
-    pub fn get_eventing_dev_names(&self, pool_uuid: PoolUuid) -> Vec<DmNameBuf> {
+        // The origin, which now refers to the snapshot's thin id, is saved
+        // before the snapshot's record is removed. If it can not be saved,
+        // the snapshot is left as it was, in self.filesystems.
+        let mdv = &self.mdv;
+        filesystem.merge(
+            pool_uuid,
+            &self.thin_pool,
+            uuid,
+            snapshot,
+            snapshot_uuid,
+            |filesystem| mdv.save_fs(fs_name, uuid, filesystem),
+        )?;
+        sync_xfs_label(fs_name, filesystem);
+        let snapshot_origin = self
+            .filesystems
+            .remove_by_uuid(snapshot_uuid)
+            .and_then(|(_, snapshot)| snapshot.origin());
+        if let Err(err) = self.mdv.rm_fs(snapshot_uuid) {
+            error!("Could not remove metadata for fs with UUID {} and name {} belonging to pool {}, reason: {:?}",
+                   snapshot_uuid,
+                   snapshot_name,
+                   pool_name,
+                   err);
+        }
+        devlinks::filesystem_removed(pool_name, &snapshot_name);
+        devlinks::filesystem_added(pool_name, fs_name, &filesystem.devnode());
+        self.reparent_snapshots(pool_name, snapshot_uuid, snapshot_origin);
+        Ok(())
+    }
+
+    /// Make the snapshots of the filesystem with the given UUID, which has
+    /// been removed, snapshots of origin, the removed filesystem's own
+    /// origin, so that the lineage of each is kept. A snapshot whose new
+    /// origin can not be saved is logged.
+    fn reparent_snapshots(
+        &mut self,
+        pool_name: &str,
+        uuid: FilesystemUuid,
+        origin: Option<FilesystemUuid>,
+    ) {
+        for (name, snapshot_uuid, snapshot) in self.filesystems.iter_mut() {
+            if snapshot.origin() == Some(uuid) {
+                snapshot.set_origin(origin);
+                if let Err(err) = self.mdv.save_fs(name, *snapshot_uuid, snapshot) {
+                    error!("Could not change origin for fs with UUID {} and name {} belonging to pool {}, reason: {:?}",
+                           snapshot_uuid,
+                           name,
+                           pool_name,
+                           err);
+                }
+            }
+        }
+    }
+
+    /// Extend the filesystem with the given UUID by extend_size, growing the
+    /// XFS filesystem on it as well.
+    pub fn extend_filesystem(
+        &mut self,
+        uuid: FilesystemUuid,
+        extend_size: Sectors,
+    ) -> StratisResult<()> {
+        match self.filesystems.get_mut_by_uuid(uuid) {
+            Some((name, filesystem)) => {
+                let old_size = filesystem.thindev_size();
+                let result = filesystem.extend(extend_size);
+                if filesystem.thindev_size() != old_size {
+                    self.mdv.save_fs(&name, uuid, filesystem)?;
+                }
+                result
+            }
+            None => Err(StratisError::Engine(
+                ErrorEnum::NotFound,
+                format!("no filesystem with UUID {}", uuid),
+            )),
+        }
+    }
+
+    /// Shrink the ThinDev of the filesystem with the given UUID to new_size.
+    pub fn shrink_filesystem(
+        &mut self,
+        uuid: FilesystemUuid,
+        new_size: Sectors,
+    ) -> StratisResult<()> {
+        match self.filesystems.get_mut_by_uuid(uuid) {
+            Some((name, filesystem)) => {
+                filesystem.shrink(new_size)?;
+                self.mdv.save_fs(&name, uuid, filesystem)
+            }
+            None => Err(StratisError::Engine(
+                ErrorEnum::NotFound,
+                format!("no filesystem with UUID {}", uuid),
+            )),
+        }
+    }
+
+    /// Set the user-settable string of the filesystem with the given UUID.
+    /// Returns true if it changed.
+    pub fn set_filesystem_user_info(
+        &mut self,
+        uuid: FilesystemUuid,
+        user_info: Option<&str>,
+    ) -> StratisResult<bool> {
+        match self.filesystems.get_mut_by_uuid(uuid) {
+            Some((name, filesystem)) => {
+                if filesystem.set_user_info(user_info) {
+                    self.mdv.save_fs(&name, uuid, filesystem)?;
+                    Ok(true)
+                } else {
+                    Ok(false)
+                }
+            }
+            None => Err(StratisError::Engine(
+                ErrorEnum::NotFound,
+                format!("no filesystem with UUID {}", uuid),
+            )),
+        }
+    }
+
+    /// Set the size limit of the filesystem with the given UUID.
+    /// Returns true if the limit changed.
+    pub fn set_filesystem_size_limit(
+        &mut self,
+        uuid: FilesystemUuid,
+        limit: Option<Sectors>,
+    ) -> StratisResult<bool> {
+        match self.filesystems.get_mut_by_uuid(uuid) {
+            Some((name, filesystem)) => {
+                if filesystem.set_size_limit(limit)? {
+                    self.mdv.save_fs(&name, uuid, filesystem)?;
+                    Ok(true)
+                } else {
+                    Ok(false)
+                }
+            }
+            None => Err(StratisError::Engine(
+                ErrorEnum::NotFound,
+                format!("no filesystem with UUID {}", uuid),
+            )),
+        }
+    }
+
+    /// Set the snapshot limit of the filesystem with the given UUID.
+    /// Returns true if the limit changed.
+    pub fn set_filesystem_snapshot_limit(
+        &mut self,
+        uuid: FilesystemUuid,
+        limit: Option<u64>,
+    ) -> StratisResult<bool> {
+        match self.filesystems.get_mut_by_uuid(uuid) {
+            Some((name, filesystem)) => {
+                if filesystem.set_snapshot_limit(limit) {
+                    self.mdv.save_fs(&name, uuid, filesystem)?;
+                    Ok(true)
+                } else {
+                    Ok(false)
+                }
+            }
+            None => Err(StratisError::Engine(
+                ErrorEnum::NotFound,
+                format!("no filesystem with UUID {}", uuid),
+            )),
+        }
+    }
+
+    /// Mark the filesystem with the given UUID read-only, or writable.
+    /// Returns true if the flag changed.
+    pub fn set_filesystem_read_only(
+        &mut self,
+        uuid: FilesystemUuid,
+        read_only: bool,
+    ) -> StratisResult<bool> {
+        match self.filesystems.get_mut_by_uuid(uuid) {
+            Some((name, filesystem)) => {
+                if filesystem.set_read_only(read_only)? {
+                    self.mdv.save_fs(&name, uuid, filesystem)?;
+                    Ok(true)
+                } else {
+                    Ok(false)
+                }
+            }
+            None => Err(StratisError::Engine(
+                ErrorEnum::NotFound,
+                format!("no filesystem with UUID {}", uuid),
+            )),
+        }
+    }
+
+    /// Set the periodic trim interval of the filesystem with the given UUID.
+    /// Returns true if the interval changed.
+    pub fn set_filesystem_trim_interval(
+        &mut self,
+        uuid: FilesystemUuid,
+        interval: Option<Duration>,
+    ) -> StratisResult<bool> {
+        match self.filesystems.get_mut_by_uuid(uuid) {
+            Some((name, filesystem)) => {
+                if filesystem.set_trim_interval(interval)? {
+                    self.mdv.save_fs(&name, uuid, filesystem)?;
+                    Ok(true)
+                } else {
+                    Ok(false)
+                }
+            }
+            None => Err(StratisError::Engine(
+                ErrorEnum::NotFound,
+                format!("no filesystem with UUID {}", uuid),
+            )),
+        }
+    }
+
+    pub fn state(&self) -> PoolState {
+        self.pool_state
+    }
+
+    pub fn extend_state(&self) -> PoolExtendState {
+        self.pool_extend_state
+    }
+
+    /// The policy for the total size of the filesystems in the pool.
+    pub fn overprovision_policy(&self) -> OverprovisionPolicy {
+        self.overprovision
+    }
+
+    /// Set the policy for the total size of the filesystems in the pool.
+    /// Returns true if the policy changed.
+    /// Returns an error if the policy specifies a ratio of 0.
+    pub fn set_overprovision_policy(&mut self, policy: OverprovisionPolicy) -> StratisResult<bool> {
+        match policy {
+            OverprovisionPolicy::Warn(0) | OverprovisionPolicy::Limit(0) => {
+                Err(StratisError::Engine(
+                    ErrorEnum::Invalid,
+                    "overprovisioning ratio must be greater than 0".into(),
+                ))
+            }
+            _ => {
+                let changed = self.overprovision != policy;
+                self.overprovision = policy;
+                Ok(changed)
+            }
+        }
+    }
+
+    /// Adopt the overprovisioning policy, the reserve, and the filesystem
+    /// and snapshot limits of source.
+    pub fn copy_policies(&mut self, source: &ThinPool) {
+        self.overprovision = source.overprovision;
+        self.reserved_pct = source.reserved_pct;
+        self.filesystem_limit = source.filesystem_limit;
+        self.snapshot_limit = source.snapshot_limit;
+    }
+
+    /// The thin pool's data block size, its chunk size.
+    pub fn data_block_size(&self) -> Sectors {
+        self.thin_pool.data_block_size()
+    }
+
+    /// The percentage of the pool's usable capacity held in reserve.
+    pub fn reserved_pct(&self) -> u8 {
+        self.reserved_pct
+    }
+
+    /// Set the percentage of the pool's usable capacity held in reserve.
+    /// Returns true if the percentage changed.
+    /// Returns an error if the percentage exceeds MAX_RESERVED_PCT.
+    pub fn set_reserved_pct(&mut self, pct: u8) -> StratisResult<bool> {
+        if pct > MAX_RESERVED_PCT {
+            let err_msg = format!(
+                "reserved capacity of {}% exceeds the maximum of {}%",
+                pct, MAX_RESERVED_PCT
+            );
+            return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+        }
+        let changed = self.reserved_pct != pct;
+        self.reserved_pct = pct;
+        Ok(changed)
+    }
+
+    /// The amount of the pool's usable capacity held in reserve.
+    pub fn reserved_size(&self, backstore: &Backstore) -> Sectors {
+        Sectors(*backstore.datatier_usable_size() * u64::from(self.reserved_pct) / 100)
+    }
+
+    /// The space in the backstore into which the thin pool data device may
+    /// grow, which is the available space less whatever the reserve takes.
+    fn available_for_data(&self, backstore: &Backstore) -> Sectors {
+        let available = backstore.available_in_backstore();
+        let reserved = self.reserved_size(backstore);
+        if available > reserved {
+            available - reserved
+        } else {
+            Sectors(0)
+        }
+    }
+
+    /// The largest number of filesystems, snapshots included, that the pool
+    /// may hold. Unless it has been set explicitly, it is one filesystem for
+    /// each META_BLOCKS_PER_FILESYSTEM of the thin pool meta device, so it
+    /// grows as the meta device is extended.
+    pub fn filesystem_limit(&self) -> u64 {
+        self.filesystem_limit.unwrap_or_else(|| {
+            max(
+                1,
+                *self.thin_pool.meta_dev().size().metablocks() / *META_BLOCKS_PER_FILESYSTEM,
+            )
+        })
+    }
+
+    /// Set the largest number of filesystems that the pool may hold. If
+    /// limit is None, the limit is computed from the size of the thin pool
+    /// meta device.
+    /// Returns true if the limit changed.
+    /// Returns an error if the limit is 0 or less than the number of
+    /// filesystems already in the pool.
+    pub fn set_filesystem_limit(&mut self, limit: Option<u64>) -> StratisResult<bool> {
+        if let Some(limit) = limit {
+            let count = self.filesystems.len() as u64;
+            if limit == 0 || limit < count {
+                let err_msg = format!(
+                    "filesystem limit of {} must be at least 1 and no less than the {} filesystems already in the pool",
+                    limit, count
+                );
+                return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+            }
+        }
+        let changed = self.filesystem_limit != limit;
+        self.filesystem_limit = limit;
+        Ok(changed)
+    }
+
+    /// Check that the pool can hold additional filesystems without exceeding
+    /// its filesystem limit.
+    pub fn check_filesystem_limit(&self, additional: usize) -> StratisResult<()> {
+        let limit = self.filesystem_limit();
+        let total = (self.filesystems.len() + additional) as u64;
+        if total > limit {
+            let err_msg = format!(
+                "{} filesystems would exceed the pool's limit of {} filesystems",
+                total, limit
+            );
+            return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+        }
+        Ok(())
+    }
+
+    /// The most snapshots that may be taken of each filesystem that has no
+    /// limit of its own, if any.
+    pub fn snapshot_limit(&self) -> Option<u64> {
+        self.snapshot_limit
+    }
+
+    /// Set the most snapshots that may be taken of each filesystem that has
+    /// no limit of its own. Returns true if the limit changed.
+    pub fn set_snapshot_limit(&mut self, limit: Option<u64>) -> bool {
+        let changed = self.snapshot_limit != limit;
+        self.snapshot_limit = limit;
+        changed
+    }
+
+    /// Check that another snapshot may be taken of the filesystem with the
+    /// given UUID without exceeding its snapshot limit, or, if it has none,
+    /// the pool's.
+    pub fn check_snapshot_limit(&self, origin_uuid: FilesystemUuid) -> StratisResult<()> {
+        let limit = match self.get_filesystem_by_uuid(origin_uuid) {
+            Some((_, origin)) => origin.snapshot_limit().or(self.snapshot_limit),
+            None => None,
+        };
+        if let Some(limit) = limit {
+            let count = self
+                .filesystems
+                .iter()
+                .filter(|(_, _, filesystem)| filesystem.origin() == Some(origin_uuid))
+                .count() as u64;
+            if count >= limit {
+                let err_msg = format!(
+                    "filesystem {} already has {} snapshots, its limit is {}",
+                    origin_uuid, count, limit
+                );
+                return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+            }
+        }
+        Ok(())
+    }
+
+    /// The total size of the filesystems in the pool.
+    fn filesystems_size(&self) -> Sectors {
+        self.filesystems
+            .iter()
+            .map(|(_, _, fs)| fs.thindev_size())
+            .sum()
+    }
+
+    /// The total size of filesystems allowed by the overprovisioning policy
+    /// for a pool with the given capacity, if any.
+    fn overprovision_limit(&self, capacity: Sectors) -> Option<Sectors> {
+        match self.overprovision {
+            OverprovisionPolicy::Unlimited => None,
+            OverprovisionPolicy::Warn(ratio_pct) | OverprovisionPolicy::Limit(ratio_pct) => {
+                Some(Sectors(*capacity * u64::from(ratio_pct) / 100))
+            }
+        }
+    }
+
+    /// Check the total size of the filesystems, increased by additional,
+    /// against the overprovisioning policy for a pool with the given
+    /// capacity. If the total exceeds the ratio, return an error if the
+    /// policy is Limit, and log a warning if it is Warn.
+    pub fn check_overprovision(&self, capacity: Sectors, additional: Sectors) -> StratisResult<()> {
+        let limit = match self.overprovision_limit(capacity) {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        let total = self.filesystems_size() + additional;
+        if total <= limit {
+            return Ok(());
+        }
+
+        let msg = format!(
+            "total size of filesystems, {}, exceeds the overprovisioning ratio of the pool's capacity, {}, of {:?}",
+            total, capacity, self.overprovision
+        );
+        match self.overprovision {
+            OverprovisionPolicy::Limit(_) => Err(StratisError::Engine(ErrorEnum::Invalid, msg)),
+            _ => {
+                warn!("{}", msg);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn free_space_state(&self) -> FreeSpaceState {
+        self.free_space_state
+    }
+
+    /// Rename a filesystem within the thin pool.
+    pub fn rename_filesystem(
+        &mut self,
+        pool_name: &str,
+        uuid: FilesystemUuid,
+        new_name: &str,
+    ) -> StratisResult<RenameAction> {
+        let old_name = rename_filesystem_pre!(self; uuid; new_name);
+        let new_name = Name::new(new_name.to_owned());
+
+        let filesystem = self
+            .filesystems
+            .remove_by_uuid(uuid)
+            .expect("Must succeed since self.filesystems.get_by_uuid() returned a value")
+            .1;
+
+        if let Err(err) = self.mdv.save_fs(&new_name, uuid, &filesystem) {
+            self.filesystems.insert(old_name, uuid, filesystem);
+            Err(err)
+        } else {
+            get_engine_listener_list().notify(&EngineEvent::FilesystemRenamed {
+                dbus_path: filesystem.get_dbus_path(),
+                from: &*old_name,
+                to: &*new_name,
+            });
+            sync_xfs_label(&new_name, &filesystem);
+            self.filesystems.insert(new_name.clone(), uuid, filesystem);
+            devlinks::filesystem_renamed(pool_name, &old_name, &new_name);
+            Ok(RenameAction::Renamed)
+        }
+    }
+
+    /// The names of DM devices belonging to this pool that may generate events
+    pub fn get_eventing_dev_names(&self, pool_uuid: PoolUuid) -> Vec<DmNameBuf> {
         vec![
             format_flex_ids(pool_uuid, FlexRole::ThinMeta).0,
             format_flex_ids(pool_uuid, FlexRole::ThinData).0,
@@ -1035,6 +2143,27 @@ impl ThinPool {
         Ok(())
     }
 
+    /// Flush and suspend all the filesystems, then the thinpool, so that
+    /// nothing is written to the pool's devices until unquiesce() is called.
+    pub fn quiesce(&mut self) -> StratisResult<()> {
+        for (_, _, fs) in &mut self.filesystems {
+            fs.suspend(true)?;
+        }
+        self.suspend()
+    }
+
+    /// Resume the thinpool, then the filesystems, unless the pool is so
+    /// full that the filesystems should remain suspended.
+    pub fn unquiesce(&mut self) -> StratisResult<()> {
+        self.resume()?;
+        if self.free_space_state != FreeSpaceState::Crit {
+            for (_, _, fs) in &mut self.filesystems {
+                fs.resume()?;
+            }
+        }
+        Ok(())
+    }
+
     /// Set the device on all DM devices
     pub fn set_device(&mut self, backstore_device: Device) -> StratisResult<bool> {
         if backstore_device == self.backstore_device {
@@ -1117,6 +2246,7 @@ impl Recordable<FlexDevsSave> for Segments {
             thin_meta_dev: self.meta_segments.to_vec(),
             thin_data_dev: self.data_segments.to_vec(),
             thin_meta_dev_spare: self.meta_spare_segments.to_vec(),
+            thin_meta_dev_retained: self.meta_retained_segments.to_vec(),
         }
     }
 }
@@ -1131,6 +2261,24 @@ impl Recordable<ThinPoolDevSave> for ThinPool {
     fn record(&self) -> ThinPoolDevSave {
         ThinPoolDevSave {
             data_block_size: self.thin_pool.data_block_size(),
+            overprovision: match self.overprovision {
+                OverprovisionPolicy::Unlimited => None,
+                OverprovisionPolicy::Warn(ratio_pct) => Some(OverprovisionSave {
+                    ratio_pct,
+                    enforce: false,
+                }),
+                OverprovisionPolicy::Limit(ratio_pct) => Some(OverprovisionSave {
+                    ratio_pct,
+                    enforce: true,
+                }),
+            },
+            reserved_pct: if self.reserved_pct == 0 {
+                None
+            } else {
+                Some(self.reserved_pct)
+            },
+            filesystem_limit: self.filesystem_limit,
+            snapshot_limit: self.snapshot_limit,
         }
     }
 }
@@ -1162,7 +2310,11 @@ fn setup_metadev(
         // TODO: Refine policy about failure to run thin_check.
         // If, e.g., thin_check is unavailable, that doesn't necessarily
         // mean that data is corrupted.
-        if thin_check(&meta_dev.devnode()).is_err() {
+        if let Err(err) = thin_check(&meta_dev.devnode()) {
+            warn!(
+                "thin_check failed on thin meta device for pool with UUID {}, attempting repair: {}",
+                pool_uuid, err
+            );
             meta_dev = attempt_thin_repair(pool_uuid, meta_dev, device, &spare_segments)?;
             return Ok((meta_dev, spare_segments, meta_segments));
         }
@@ -1174,6 +2326,9 @@ fn setup_metadev(
 /// Attempt a thin repair operation on the meta device.
 /// If the operation succeeds, teardown the old meta device,
 /// and return the new meta device.
+/// If the operation fails, teardown both devices, so that the thinpool
+/// is not activated on metadata that failed its check, and return a
+/// Corrupt error stating that the pool requires repair.
 fn attempt_thin_repair(
     pool_uuid: PoolUuid,
     mut meta_dev: LinearDev,
@@ -1188,7 +2343,20 @@ fn attempt_thin_repair(
         segs_to_table(device, spare_segments),
     )?;
 
-    thin_repair(&meta_dev.devnode(), &new_meta_dev.devnode())?;
+    if let Err(err) = thin_repair(&meta_dev.devnode(), &new_meta_dev.devnode()) {
+        if let Err(e) = new_meta_dev.teardown(get_dm()) {
+            warn!("failed to teardown thin meta spare device: {}", e);
+        }
+        if let Err(e) = meta_dev.teardown(get_dm()) {
+            warn!("failed to teardown thin meta device: {}", e);
+        }
+        let err_msg = format!(
+            "thin metadata for pool with UUID {} failed thin_check and could not be repaired, \
+             not activating pool: {}",
+            pool_uuid, err
+        );
+        return Err(StratisError::Engine(ErrorEnum::Corrupt, err_msg));
+    }
 
     let name = meta_dev.name().to_owned();
     meta_dev.teardown(get_dm())?;
@@ -1197,9 +2365,86 @@ fn attempt_thin_repair(
     Ok(new_meta_dev)
 }
 
+/// Repair the thin metadata of a pool whose thin pool is not set up because
+/// its metadata failed thin_check and could not be repaired into the spare.
+/// thin_repair writes the repaired metadata to a newly allocated device of
+/// the same size, which replaces the thin meta device. The segments of the
+/// damaged device are retained rather than reused, so that the damaged
+/// metadata can be examined later.
+/// Returns the flex devs with the new thin meta device, or an error if no
+/// space can be allocated for it or if thin_repair fails. In either case,
+/// the metadata recorded on the pool's devices is unchanged.
+pub fn repair_thin_metadata(
+    pool_uuid: PoolUuid,
+    flex_devs: &FlexDevsSave,
+    backstore: &mut Backstore,
+) -> StratisResult<FlexDevsSave> {
+    let meta_size = flex_devs.thin_meta_dev.iter().map(|s| s.1).sum::<Sectors>();
+    let new_segments = match backstore.alloc_metadata(pool_uuid, &[meta_size])? {
+        Some(segments) => segments,
+        None => {
+            let err_msg = format!(
+                "insufficient space to allocate {} for the repaired thin metadata of pool with UUID {}",
+                meta_size, pool_uuid
+            );
+            return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+        }
+    };
+
+    let device = backstore
+        .device()
+        .expect("space was just allocated from the backstore, so it must have a cap device");
+
+    let (dm_name, dm_uuid) = format_flex_ids(pool_uuid, FlexRole::ThinMeta);
+    let mut meta_dev = LinearDev::setup(
+        get_dm(),
+        &dm_name,
+        Some(&dm_uuid),
+        segs_to_table(device, &flex_devs.thin_meta_dev),
+    )?;
+
+    let (dm_name, dm_uuid) = format_flex_ids(pool_uuid, FlexRole::ThinMetaSpare);
+    let repaired = LinearDev::setup(
+        get_dm(),
+        &dm_name,
+        Some(&dm_uuid),
+        segs_to_table(device, &new_segments),
+    )
+    .map_err(StratisError::from)
+    .and_then(|mut new_meta_dev| {
+        let result = thin_repair(&meta_dev.devnode(), &new_meta_dev.devnode());
+        if let Err(e) = new_meta_dev.teardown(get_dm()) {
+            warn!("failed to teardown repaired thin meta device: {}", e);
+        }
+        result
+    });
+    if let Err(e) = meta_dev.teardown(get_dm()) {
+        warn!("failed to teardown thin meta device: {}", e);
+    }
+    if let Err(err) = repaired {
+        let err_msg = format!(
+            "thin metadata for pool with UUID {} could not be repaired: {}",
+            pool_uuid, err
+        );
+        return Err(StratisError::Engine(ErrorEnum::Corrupt, err_msg));
+    }
+
+    let mut retained_segments = flex_devs.thin_meta_dev_retained.to_vec();
+    retained_segments.extend_from_slice(&flex_devs.thin_meta_dev);
+    Ok(FlexDevsSave {
+        meta_dev: flex_devs.meta_dev.to_vec(),
+        thin_meta_dev: new_segments,
+        thin_data_dev: flex_devs.thin_data_dev.to_vec(),
+        thin_meta_dev_spare: flex_devs.thin_meta_dev_spare.to_vec(),
+        thin_meta_dev_retained: retained_segments,
+    })
+}
+
 #[cfg(test)]
 mod tests {
-    use std::fs::OpenOptions;
+    #![cfg_attr(not(feature = "device_tests"), allow(dead_code, unused_imports))]
+
+    use std::fs::{self, OpenOptions};
     use std::io::{BufWriter, Read, Write};
     use std::path::Path;
 
@@ -1214,51 +2459,158 @@ mod tests {
     use crate::engine::strat_engine::device::SyncAll;
     use crate::engine::strat_engine::tests::{loopbacked, real};
 
-    use crate::engine::strat_engine::thinpool::filesystem::{fs_usage, FILESYSTEM_LOWATER};
+    use crate::engine::strat_engine::thinpool::filesystem::{
+        fs_usage, trim_pass_len, xfs_label_for, FILESYSTEM_LOWATER,
+    };
 
     use super::*;
 
     const BYTES_PER_WRITE: usize = 2 * IEC::Ki as usize * SECTOR_SIZE as usize;
 
-    /// Test greedy allocation.
-    /// Verify that ThinPool::new() allocates nearly everything available.
-    /// Verify that meta and data devices are roughly in their correct
-    /// proportion.
-    /// FIXME: This is a temporary test; it should be removed when greedy
-    /// allocation is removed.
-    fn test_greedy_allocation(paths: &[&Path]) {
+    /// Set up a thin pool with the default sizes on paths. Returns the
+    /// pool's UUID, its backstore, and the thin pool.
+    fn setup_thin_pool(paths: &[&Path]) -> (PoolUuid, Backstore, ThinPool) {
         let pool_uuid = Uuid::new_v4();
-        devlinks::setup_dev_path().unwrap();
         devlinks::cleanup_devlinks(Vec::new().into_iter());
-
         let mut backstore = Backstore::initialize(pool_uuid, paths, MIN_MDA_SECTORS).unwrap();
-
-        let mut pool = ThinPool::new(
+        let pool = ThinPool::new(
             pool_uuid,
             &ThinPoolSizeParams::default(),
             DATA_BLOCK_SIZE,
             &mut backstore,
         )
         .unwrap();
+        (pool_uuid, backstore, pool)
+    }
 
-        pool.check(pool_uuid, &mut backstore).unwrap();
-
-        assert!(backstore.available_in_backstore() < DATA_BLOCK_SIZE);
+    /// As setup_thin_pool(), but with the data device doubled in size, so
+    /// that a filesystem and its snapshots can be written to without the
+    /// pool running out of data space.
+    fn setup_thin_pool_with_data(paths: &[&Path]) -> (PoolUuid, Backstore, ThinPool) {
+        let (pool_uuid, mut backstore, mut pool) = setup_thin_pool(paths);
+        pool.extend_thin_data_device(
+            pool_uuid,
+            &mut backstore,
+            datablocks_to_sectors(INITIAL_DATA_SIZE, DATA_BLOCK_SIZE),
+        )
+        .unwrap();
+        (pool_uuid, backstore, pool)
+    }
 
-        let meta_size = pool.thin_pool.meta_dev().size();
-        let data_size = pool.thin_pool.data_dev().size();
-        assert!(meta_size * 1500u64 > data_size);
-        assert!(meta_size * 500u64 < data_size || meta_size.metablocks() == INITIAL_META_SIZE);
+    /// Mount the XFS filesystem on devnode at mount_point, write bytes to
+    /// the file at file_path, which is below mount_point, and unmount it.
+    fn write_file(devnode: &Path, mount_point: &Path, file_path: &Path, bytes: &[u8]) {
+        mount(
+            Some(devnode),
+            mount_point,
+            Some("xfs"),
+            MsFlags::empty(),
+            None as Option<&str>,
+        )
+        .unwrap();
+        OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(file_path)
+            .unwrap()
+            .write_all(bytes)
+            .unwrap();
+        umount(mount_point).unwrap();
     }
 
     #[test]
-    pub fn loop_test_greedy_allocation() {
+    /// Verify that invalid space thresholds are rejected and leave the
+    /// current thresholds unchanged.
+    fn test_set_space_thresholds_invalid() {
+        let current = (space_warn_pct(), space_crit_pct());
+        for &(warn_pct, crit_pct) in &[(0, 50), (50, 50), (60, 50), (90, 101)] {
+            assert_matches!(
+                set_space_thresholds(warn_pct, crit_pct),
+                Err(StratisError::Engine(ErrorEnum::Invalid, _))
+            );
+        }
+        assert_eq!((space_warn_pct(), space_crit_pct()), current);
+    }
+
+    #[test]
+    /// Verify that an explicit chunk size is validated and used as given,
+    /// and that a chosen chunk size is raised to the devices' optimal I/O
+    /// size, rounded up to a size that dm-thin accepts.
+    fn test_choose_data_block_size() {
+        for &size in &[Sectors(0), Sectors(64), Sectors(200), Sectors(4 * IEC::Mi)] {
+            assert_matches!(
+                choose_data_block_size(ChunkSize::Fixed(size), None),
+                Err(StratisError::Engine(ErrorEnum::Invalid, _))
+            );
+        }
+        assert_eq!(
+            choose_data_block_size(ChunkSize::Fixed(Sectors(256)), Some(DATA_BLOCK_SIZE)).unwrap(),
+            Sectors(256)
+        );
+
+        assert_eq!(
+            choose_data_block_size(ChunkSize::Auto, None).unwrap(),
+            DATA_BLOCK_SIZE
+        );
+        assert_eq!(
+            choose_data_block_size(ChunkSize::Auto, Some(Sectors(8))).unwrap(),
+            DATA_BLOCK_SIZE
+        );
+        assert_eq!(
+            choose_data_block_size(ChunkSize::AutoSnapshots, None).unwrap(),
+            MIN_DATA_BLOCK_SIZE
+        );
+        assert_eq!(
+            choose_data_block_size(ChunkSize::AutoSnapshots, Some(Sectors(300))).unwrap(),
+            Sectors(384)
+        );
+        assert_eq!(
+            choose_data_block_size(ChunkSize::Auto, Some(Sectors(8 * IEC::Mi))).unwrap(),
+            MAX_DATA_BLOCK_SIZE
+        );
+    }
+
+    /// Test greedy allocation.
+    /// Verify that ThinPool::new() allocates nearly everything available.
+    /// Verify that meta and data devices are roughly in their correct
+    /// proportion.
+    /// FIXME: This is a temporary test; it should be removed when greedy
+    /// allocation is removed.
+    fn test_greedy_allocation(paths: &[&Path]) {
+        let pool_uuid = Uuid::new_v4();
+        devlinks::setup_dev_path().unwrap();
+        devlinks::cleanup_devlinks(Vec::new().into_iter());
+
+        let mut backstore = Backstore::initialize(pool_uuid, paths, MIN_MDA_SECTORS).unwrap();
+
+        let mut pool = ThinPool::new(
+            pool_uuid,
+            &ThinPoolSizeParams::default(),
+            DATA_BLOCK_SIZE,
+            &mut backstore,
+        )
+        .unwrap();
+
+        pool.check(pool_uuid, &mut backstore).unwrap();
+
+        assert!(backstore.available_in_backstore() < DATA_BLOCK_SIZE);
+
+        let meta_size = pool.thin_pool.meta_dev().size();
+        let data_size = pool.thin_pool.data_dev().size();
+        assert!(meta_size * 1500u64 > data_size);
+        assert!(meta_size * 500u64 < data_size || meta_size.metablocks() == INITIAL_META_SIZE);
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_greedy_allocation() {
         loopbacked::test_with_spec(
             &loopbacked::DeviceLimits::Range(2, 3, None),
             test_greedy_allocation,
         );
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn real_test_greedy_allocation() {
         real::test_with_spec(
@@ -1267,6 +2619,120 @@ mod tests {
         );
     }
 
+    /// Verify that when the meta device is extended, the next check extends
+    /// the metadata spare to match it.
+    fn test_meta_spare(paths: &[&Path]) {
+        let pool_uuid = Uuid::new_v4();
+        devlinks::setup_dev_path().unwrap();
+        devlinks::cleanup_devlinks(Vec::new().into_iter());
+
+        let mut backstore = Backstore::initialize(pool_uuid, paths, MIN_MDA_SECTORS).unwrap();
+
+        let mut pool = ThinPool::new(
+            pool_uuid,
+            &ThinPoolSizeParams::default(),
+            DATA_BLOCK_SIZE,
+            &mut backstore,
+        )
+        .unwrap();
+
+        let segments_size = |segments: &[(Sectors, Sectors)]| {
+            segments.iter().map(|&(_, length)| length).sum::<Sectors>()
+        };
+        assert_eq!(
+            segments_size(&pool.segments.meta_spare_segments),
+            segments_size(&pool.segments.meta_segments)
+        );
+
+        let extend_size = pool
+            .extend_thin_meta_device(pool_uuid, &mut backstore, MIN_META_SEGMENT_SIZE.sectors())
+            .unwrap();
+        assert_eq!(extend_size, MIN_META_SEGMENT_SIZE.sectors());
+        assert!(
+            segments_size(&pool.segments.meta_spare_segments)
+                < segments_size(&pool.segments.meta_segments)
+        );
+
+        pool.check(pool_uuid, &mut backstore).unwrap();
+        assert!(
+            segments_size(&pool.segments.meta_spare_segments)
+                >= segments_size(&pool.segments.meta_segments)
+        );
+
+        pool.teardown().unwrap();
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_meta_spare() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Range(1, 3, None),
+            test_meta_spare,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_meta_spare() {
+        real::test_with_spec(&real::DeviceLimits::AtLeast(1, None, None), test_meta_spare);
+    }
+
+    /// Verify that repairing the thin metadata of a torn down thin pool
+    /// moves it to newly allocated segments and retains the old ones, and
+    /// that the thin pool, with its filesystem, can be set up from the
+    /// repaired metadata.
+    fn test_repair_thin_metadata(paths: &[&Path]) {
+        let (pool_uuid, mut backstore, mut pool) = setup_thin_pool(paths);
+
+        let pool_name = "stratis_test_pool";
+        devlinks::pool_added(&pool_name);
+        let fs_uuid = pool
+            .create_filesystem(pool_uuid, pool_name, "fsname", None)
+            .unwrap();
+
+        let thinpooldevsave: ThinPoolDevSave = pool.record();
+        let flex_devs: FlexDevsSave = pool.record();
+        pool.teardown().unwrap();
+
+        let repaired = repair_thin_metadata(pool_uuid, &flex_devs, &mut backstore).unwrap();
+        assert_eq!(repaired.thin_meta_dev_retained, flex_devs.thin_meta_dev);
+        assert!(repaired
+            .thin_meta_dev
+            .iter()
+            .all(|seg| !flex_devs.thin_meta_dev.contains(seg)));
+        assert_eq!(
+            repaired.thin_meta_dev.iter().map(|s| s.1).sum::<Sectors>(),
+            flex_devs.thin_meta_dev.iter().map(|s| s.1).sum::<Sectors>()
+        );
+
+        let mut new_pool =
+            ThinPool::setup(pool_uuid, &thinpooldevsave, &repaired, &backstore).unwrap();
+        assert!(new_pool.get_filesystem_by_uuid(fs_uuid).is_some());
+        assert_eq!(new_pool.missing_filesystems().unwrap(), vec![]);
+        let flex_devs: FlexDevsSave = new_pool.record();
+        assert_eq!(flex_devs, repaired);
+
+        new_pool.teardown().unwrap();
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_repair_thin_metadata() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Range(1, 3, None),
+            test_repair_thin_metadata,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_repair_thin_metadata() {
+        real::test_with_spec(
+            &real::DeviceLimits::AtLeast(1, None, None),
+            test_repair_thin_metadata,
+        );
+    }
+
     /// Verify that a full pool extends properly when additional space is added.
     fn test_full_pool(paths: &[&Path]) {
         let pool_uuid = Uuid::new_v4();
@@ -1362,6 +2828,7 @@ mod tests {
         };
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn loop_test_full_pool() {
         loopbacked::test_with_spec(
@@ -1370,6 +2837,7 @@ mod tests {
         );
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn real_test_full_pool() {
         real::test_with_spec(
@@ -1382,7 +2850,10 @@ mod tests {
         );
     }
 
-    /// Verify a snapshot has the same files and same contents as the origin.
+    /// Verify a snapshot has the same files and same contents as the origin,
+    /// and that it records the origin's UUID in the pool's metadata.
+    /// Verify that a snapshot keeping the origin's XFS UUID can not be
+    /// mounted while the origin is.
     fn test_filesystem_snapshot(paths: &[&Path]) {
         let pool_uuid = Uuid::new_v4();
         devlinks::cleanup_devlinks(Vec::new().into_iter());
@@ -1442,13 +2913,14 @@ mod tests {
         pool.extend_thin_data_device(
             pool_uuid,
             &mut backstore,
-            datablocks_to_sectors(INITIAL_DATA_SIZE),
+            datablocks_to_sectors(INITIAL_DATA_SIZE, DATA_BLOCK_SIZE),
         )
         .unwrap();
 
-        let (_, snapshot_filesystem) = pool
-            .snapshot_filesystem(pool_uuid, pool_name, fs_uuid, "test_snapshot")
+        let (snapshot_uuid, snapshot_filesystem) = pool
+            .snapshot_filesystem(pool_uuid, pool_name, fs_uuid, "test_snapshot", false, false)
             .unwrap();
+        assert_eq!(snapshot_filesystem.origin(), Some(fs_uuid));
         let mut read_buf = [0u8; SECTOR_SIZE];
         let snapshot_tmp_dir = tempfile::Builder::new()
             .prefix("stratis_testing")
@@ -1472,8 +2944,60 @@ mod tests {
                 assert_eq!(read_buf[0..SECTOR_SIZE], write_buf[0..SECTOR_SIZE]);
             }
         }
+
+        assert!(pool
+            .mdv
+            .filesystems()
+            .unwrap()
+            .iter()
+            .any(|fssave| fssave.name == "test_snapshot" && fssave.origin == Some(fs_uuid)));
+
+        // A snapshot which keeps the origin's XFS UUID can not be mounted
+        // alongside the origin.
+        let (_, same_uuid_snapshot) = pool
+            .snapshot_filesystem(pool_uuid, pool_name, fs_uuid, "test_same_uuid", true, false)
+            .unwrap();
+        let same_uuid_tmp_dir = tempfile::Builder::new()
+            .prefix("stratis_testing")
+            .tempdir()
+            .unwrap();
+        assert!(mount(
+            Some(&same_uuid_snapshot.devnode()),
+            same_uuid_tmp_dir.path(),
+            Some("xfs"),
+            MsFlags::empty(),
+            None as Option<&str>,
+        )
+        .is_err());
+
+        // A snapshot of the snapshot records the snapshot as its origin,
+        // until the snapshot is destroyed.
+        umount(snapshot_tmp_dir.path()).unwrap();
+        let (clone_uuid, clone) = pool
+            .snapshot_filesystem(
+                pool_uuid,
+                pool_name,
+                snapshot_uuid,
+                "test_clone",
+                false,
+                false,
+            )
+            .unwrap();
+        assert_eq!(clone.origin(), Some(snapshot_uuid));
+        pool.destroy_filesystem(pool_name, snapshot_uuid).unwrap();
+        assert_eq!(
+            pool.get_filesystem_by_uuid(clone_uuid).unwrap().1.origin(),
+            None
+        );
+        assert!(pool
+            .mdv
+            .filesystems()
+            .unwrap()
+            .iter()
+            .any(|fssave| fssave.name == "test_clone" && fssave.origin.is_none()));
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn loop_test_filesystem_snapshot() {
         loopbacked::test_with_spec(
@@ -1482,6 +3006,7 @@ mod tests {
         );
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn real_test_filesystem_snapshot() {
         real::test_with_spec(
@@ -1490,6 +3015,185 @@ mod tests {
         );
     }
 
+    /// Verify that reverting a filesystem to its snapshot restores the
+    /// contents it had when the snapshot was taken, under the same devnode,
+    /// and that a filesystem that is not the snapshot's origin can not be
+    /// reverted to it.
+    fn test_revert_filesystem(paths: &[&Path]) {
+        let (pool_uuid, _backstore, mut pool) = setup_thin_pool_with_data(paths);
+
+        let pool_name = "stratis_test_pool";
+        devlinks::pool_added(&pool_name);
+        let fs_uuid = pool
+            .create_filesystem(pool_uuid, pool_name, "stratis_test_filesystem", None)
+            .unwrap();
+        let other_uuid = pool
+            .create_filesystem(pool_uuid, pool_name, "stratis_other_filesystem", None)
+            .unwrap();
+        let devnode = pool.get_filesystem_by_uuid(fs_uuid).unwrap().1.devnode();
+
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("stratis_testing")
+            .tempdir()
+            .unwrap();
+        let file_path = tmp_dir.path().join("stratis_test.txt");
+
+        write_file(&devnode, tmp_dir.path(), &file_path, b"original");
+        let (snapshot_uuid, _) = pool
+            .snapshot_filesystem(pool_uuid, pool_name, fs_uuid, "test_snapshot", false, false)
+            .unwrap();
+        write_file(&devnode, tmp_dir.path(), &file_path, b"modified");
+
+        assert_matches!(
+            pool.revert_filesystem(pool_uuid, pool_name, other_uuid, snapshot_uuid),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+        pool.revert_filesystem(pool_uuid, pool_name, fs_uuid, snapshot_uuid)
+            .unwrap();
+        assert_eq!(
+            pool.get_filesystem_by_uuid(fs_uuid).unwrap().1.devnode(),
+            devnode
+        );
+
+        mount(
+            Some(&devnode),
+            tmp_dir.path(),
+            Some("xfs"),
+            MsFlags::empty(),
+            None as Option<&str>,
+        )
+        .unwrap();
+        let mut buf = [0u8; 8];
+        OpenOptions::new()
+            .read(true)
+            .open(&file_path)
+            .unwrap()
+            .read_exact(&mut buf)
+            .unwrap();
+        assert_eq!(&buf, b"original");
+        umount(tmp_dir.path()).unwrap();
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_revert_filesystem() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Range(2, 3, None),
+            test_revert_filesystem,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_revert_filesystem() {
+        real::test_with_spec(
+            &real::DeviceLimits::AtLeast(2, None, None),
+            test_revert_filesystem,
+        );
+    }
+
+    /// Verify that merging a snapshot into its origin gives the origin the
+    /// contents it had when the snapshot was taken, under the same devnode,
+    /// that the snapshot is removed, and that the snapshot's own snapshots
+    /// become snapshots of the origin, also after the pool is set up again.
+    fn test_merge_snapshot(paths: &[&Path]) {
+        let (pool_uuid, backstore, mut pool) = setup_thin_pool_with_data(paths);
+
+        let pool_name = "stratis_test_pool";
+        devlinks::pool_added(&pool_name);
+        let fs_uuid = pool
+            .create_filesystem(pool_uuid, pool_name, "stratis_test_filesystem", None)
+            .unwrap();
+        assert_matches!(
+            pool.merge_snapshot(pool_uuid, pool_name, fs_uuid),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+        let devnode = pool.get_filesystem_by_uuid(fs_uuid).unwrap().1.devnode();
+
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("stratis_testing")
+            .tempdir()
+            .unwrap();
+        let file_path = tmp_dir.path().join("stratis_test.txt");
+
+        write_file(&devnode, tmp_dir.path(), &file_path, b"original");
+        let (snapshot_uuid, _) = pool
+            .snapshot_filesystem(pool_uuid, pool_name, fs_uuid, "test_snapshot", false, false)
+            .unwrap();
+        let (child_uuid, _) = pool
+            .snapshot_filesystem(
+                pool_uuid,
+                pool_name,
+                snapshot_uuid,
+                "test_child",
+                false,
+                false,
+            )
+            .unwrap();
+        write_file(&devnode, tmp_dir.path(), &file_path, b"modified");
+
+        assert_eq!(
+            pool.merge_snapshot(pool_uuid, pool_name, snapshot_uuid)
+                .unwrap(),
+            fs_uuid
+        );
+        assert!(pool.get_filesystem_by_uuid(snapshot_uuid).is_none());
+        assert_eq!(
+            pool.get_filesystem_by_uuid(fs_uuid).unwrap().1.devnode(),
+            devnode
+        );
+        assert_eq!(
+            pool.get_filesystem_by_uuid(child_uuid).unwrap().1.origin(),
+            Some(fs_uuid)
+        );
+
+        mount(
+            Some(&devnode),
+            tmp_dir.path(),
+            Some("xfs"),
+            MsFlags::empty(),
+            None as Option<&str>,
+        )
+        .unwrap();
+        let mut buf = [0u8; 8];
+        OpenOptions::new()
+            .read(true)
+            .open(&file_path)
+            .unwrap()
+            .read_exact(&mut buf)
+            .unwrap();
+        assert_eq!(&buf, b"original");
+        umount(tmp_dir.path()).unwrap();
+
+        let flexdevs: FlexDevsSave = pool.record();
+        let thinpoolsave: ThinPoolDevSave = pool.record();
+        pool.teardown().unwrap();
+        let pool = ThinPool::setup(pool_uuid, &thinpoolsave, &flexdevs, &backstore).unwrap();
+        assert!(pool.get_filesystem_by_uuid(snapshot_uuid).is_none());
+        assert_eq!(
+            pool.get_filesystem_by_uuid(child_uuid).unwrap().1.origin(),
+            Some(fs_uuid)
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_merge_snapshot() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Range(2, 3, None),
+            test_merge_snapshot,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_merge_snapshot() {
+        real::test_with_spec(
+            &real::DeviceLimits::AtLeast(2, None, None),
+            test_merge_snapshot,
+        );
+    }
+
     /// Verify that a filesystem rename causes the filesystem metadata to be
     /// updated.
     fn test_filesystem_rename(paths: &[&Path]) {
@@ -1524,6 +3228,7 @@ mod tests {
         assert_eq!(&*pool.get_filesystem_by_uuid(fs_uuid).unwrap().0, name2);
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn loop_test_filesystem_rename() {
         loopbacked::test_with_spec(
@@ -1532,6 +3237,7 @@ mod tests {
         );
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn real_test_filesystem_rename() {
         real::test_with_spec(
@@ -1540,36 +3246,167 @@ mod tests {
         );
     }
 
-    /// Verify that setting up a pool when the pool has not been previously torn
-    /// down does not fail. Clutter the original pool with a filesystem with
-    /// some data on it.
-    fn test_pool_setup(paths: &[&Path]) {
-        let pool_uuid = Uuid::new_v4();
-        devlinks::cleanup_devlinks(Vec::new().into_iter());
-        let mut backstore = Backstore::initialize(pool_uuid, paths, MIN_MDA_SECTORS).unwrap();
-        let mut pool = ThinPool::new(
-            pool_uuid,
-            &ThinPoolSizeParams::default(),
-            DATA_BLOCK_SIZE,
-            &mut backstore,
-        )
-        .unwrap();
+    #[test]
+    /// Verify that an XFS label is the longest prefix of the name that fits
+    /// and does not split a character.
+    fn test_trim_pass_len() {
+        let pass = trim_pass_len(Bytes(0), Bytes(1 << 60)).unwrap();
+        assert!(pass > Bytes(0));
+        assert_eq!(trim_pass_len(Bytes(0), pass * 2u64), Some(pass));
+        assert_eq!(trim_pass_len(pass, pass * 2u64), None);
+        assert_eq!(trim_pass_len(Bytes(0), pass), None);
+        assert_eq!(trim_pass_len(Bytes(0), Bytes(0)), None);
+    }
+
+    #[test]
+    fn test_xfs_label_for() {
+        assert_eq!(xfs_label_for("fs"), "fs");
+        assert_eq!(xfs_label_for("twelve_bytes"), "twelve_bytes");
+        assert_eq!(xfs_label_for("thirteen_byte"), "thirteen_byt");
+        assert_eq!(xfs_label_for("eleven_byte\u{e9}"), "eleven_byte");
+    }
+
+    /// Verify that the XFS label follows the filesystem's name when it is
+    /// created and when it is renamed, whether mounted or not, and that a
+    /// label changed outside of Stratis is restored when the pool is set up.
+    fn test_xfs_label(paths: &[&Path]) {
+        let (pool_uuid, backstore, mut pool) = setup_thin_pool(paths);
 
         let pool_name = "stratis_test_pool";
         devlinks::pool_added(&pool_name);
         let fs_uuid = pool
-            .create_filesystem(pool_uuid, pool_name, "fsname", None)
+            .create_filesystem(pool_uuid, pool_name, "stratis_test_filesystem", None)
+            .unwrap();
+        let label = |pool: &ThinPool| {
+            pool.get_filesystem_by_uuid(fs_uuid)
+                .unwrap()
+                .1
+                .xfs_label()
+                .unwrap()
+        };
+        assert_eq!(label(&pool), "stratis_test");
+
+        pool.rename_filesystem(pool_name, fs_uuid, "unmounted")
             .unwrap();
+        assert_eq!(label(&pool), "unmounted");
 
         let tmp_dir = tempfile::Builder::new()
             .prefix("stratis_testing")
             .tempdir()
             .unwrap();
-        let new_file = tmp_dir.path().join("stratis_test.txt");
-        {
-            let (_, fs) = pool.get_filesystem_by_uuid(fs_uuid).unwrap();
-            mount(
-                Some(&fs.devnode()),
+        mount(
+            Some(&pool.get_filesystem_by_uuid(fs_uuid).unwrap().1.devnode()),
+            tmp_dir.path(),
+            Some("xfs"),
+            MsFlags::empty(),
+            None as Option<&str>,
+        )
+        .unwrap();
+        pool.rename_filesystem(pool_name, fs_uuid, "mounted")
+            .unwrap();
+        assert_eq!(label(&pool), "mounted");
+        umount(tmp_dir.path()).unwrap();
+
+        cmd::set_label(
+            &pool.get_filesystem_by_uuid(fs_uuid).unwrap().1.devnode(),
+            "other",
+        )
+        .unwrap();
+        assert_eq!(label(&pool), "other");
+        assert_matches!(
+            cmd::set_label(
+                &pool.get_filesystem_by_uuid(fs_uuid).unwrap().1.devnode(),
+                "quote\"d",
+            ),
+            Err(StratisError::Error(_))
+        );
+        assert_eq!(label(&pool), "other");
+
+        let flexdevs: FlexDevsSave = pool.record();
+        let thinpoolsave: ThinPoolDevSave = pool.record();
+        pool.teardown().unwrap();
+        let pool = ThinPool::setup(pool_uuid, &thinpoolsave, &flexdevs, &backstore).unwrap();
+        assert_eq!(label(&pool), "mounted");
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_xfs_label() {
+        loopbacked::test_with_spec(&loopbacked::DeviceLimits::Range(1, 3, None), test_xfs_label);
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_xfs_label() {
+        real::test_with_spec(&real::DeviceLimits::AtLeast(1, None, None), test_xfs_label);
+    }
+
+    /// Verify that a metadata snapshot can be reserved, that reserving it
+    /// again yields the same snapshot, and that it can be released once.
+    fn test_metadata_snapshot(paths: &[&Path]) {
+        let (_, _backstore, mut pool) = setup_thin_pool(paths);
+
+        assert_eq!(pool.held_metadata_root().unwrap(), None);
+        let (devnode, root) = pool.reserve_metadata_snapshot().unwrap();
+        assert_eq!(devnode, pool.thin_pool.meta_dev().devnode());
+        assert_eq!(pool.held_metadata_root().unwrap(), Some(root));
+        assert_eq!(pool.reserve_metadata_snapshot().unwrap(), (devnode, root));
+
+        assert!(pool.release_metadata_snapshot().unwrap());
+        assert_eq!(pool.held_metadata_root().unwrap(), None);
+        assert!(!pool.release_metadata_snapshot().unwrap());
+
+        pool.teardown().unwrap();
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_metadata_snapshot() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Range(1, 3, None),
+            test_metadata_snapshot,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_metadata_snapshot() {
+        real::test_with_spec(
+            &real::DeviceLimits::AtLeast(1, None, None),
+            test_metadata_snapshot,
+        );
+    }
+
+    /// Verify that setting up a pool when the pool has not been previously torn
+    /// down does not fail. Clutter the original pool with a filesystem with
+    /// some data on it.
+    fn test_pool_setup(paths: &[&Path]) {
+        let pool_uuid = Uuid::new_v4();
+        devlinks::cleanup_devlinks(Vec::new().into_iter());
+        let mut backstore = Backstore::initialize(pool_uuid, paths, MIN_MDA_SECTORS).unwrap();
+        let mut pool = ThinPool::new(
+            pool_uuid,
+            &ThinPoolSizeParams::default(),
+            DATA_BLOCK_SIZE,
+            &mut backstore,
+        )
+        .unwrap();
+
+        let pool_name = "stratis_test_pool";
+        devlinks::pool_added(&pool_name);
+        let fs_uuid = pool
+            .create_filesystem(pool_uuid, pool_name, "fsname", None)
+            .unwrap();
+
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("stratis_testing")
+            .tempdir()
+            .unwrap();
+        let new_file = tmp_dir.path().join("stratis_test.txt");
+        {
+            let (_, fs) = pool.get_filesystem_by_uuid(fs_uuid).unwrap();
+            mount(
+                Some(&fs.devnode()),
                 tmp_dir.path(),
                 Some("xfs"),
                 MsFlags::empty(),
@@ -1594,6 +3431,7 @@ mod tests {
         assert!(new_pool.get_filesystem_by_uuid(fs_uuid).is_some());
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn loop_test_pool_setup() {
         loopbacked::test_with_spec(
@@ -1602,11 +3440,13 @@ mod tests {
         );
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn real_test_pool_setup() {
         real::test_with_spec(&real::DeviceLimits::AtLeast(1, None, None), test_pool_setup);
     }
-    /// Verify that destroy_filesystems actually deallocates the space
+    /// Verify that a mounted filesystem can not be destroyed. Verify that
+    /// destroy_filesystems actually deallocates the space
     /// from the thinpool, by attempting to reinstantiate it using the
     /// same thin id and verifying that it fails.
     fn test_thindev_destroy(paths: &[&Path]) {
@@ -1626,6 +3466,26 @@ mod tests {
         let fs_uuid = pool
             .create_filesystem(pool_uuid, pool_name, &fs_name, None)
             .unwrap();
+
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("stratis_testing")
+            .tempdir()
+            .unwrap();
+        mount(
+            Some(&pool.get_filesystem_by_uuid(fs_uuid).unwrap().1.devnode()),
+            tmp_dir.path(),
+            Some("xfs"),
+            MsFlags::empty(),
+            None as Option<&str>,
+        )
+        .unwrap();
+        assert_matches!(
+            pool.destroy_filesystem(pool_name, fs_uuid),
+            Err(StratisError::Engine(ErrorEnum::Busy, _))
+        );
+        assert!(pool.get_filesystem_by_uuid(fs_uuid).is_some());
+        umount(tmp_dir.path()).unwrap();
+
         pool.destroy_filesystem(pool_name, fs_uuid).unwrap();
         let flexdevs: FlexDevsSave = pool.record();
         let thinpooldevsave: ThinPoolDevSave = pool.record();
@@ -1639,6 +3499,7 @@ mod tests {
         assert!(pool.get_filesystem_by_uuid(fs_uuid).is_none());
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn loop_test_thindev_destroy() {
         // This test requires more than 1 GiB.
@@ -1648,6 +3509,7 @@ mod tests {
         );
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn real_test_thindev_destroy() {
         real::test_with_spec(
@@ -1736,6 +3598,7 @@ mod tests {
         assert!(thindev_size > start_thindev_size)
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn loop_test_thindev_expand() {
         loopbacked::test_with_spec(
@@ -1744,6 +3607,7 @@ mod tests {
         );
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn real_test_thindev_expand() {
         real::test_with_spec(
@@ -1752,6 +3616,531 @@ mod tests {
         );
     }
 
+    /// Verify that extending an unmounted filesystem grows the XFS
+    /// filesystem on it, and that the new size is recorded in the MDV.
+    fn test_extend_filesystem(paths: &[&Path]) {
+        let (pool_uuid, backstore, mut pool) = setup_thin_pool(paths);
+
+        let fs_size = FILESYSTEM_LOWATER + Bytes(IEC::Mi).sectors();
+
+        let pool_name = "stratis_test_pool";
+        devlinks::pool_added(&pool_name);
+        let fs_uuid = pool
+            .create_filesystem(
+                pool_uuid,
+                pool_name,
+                "stratis_test_filesystem",
+                Some(fs_size),
+            )
+            .unwrap();
+
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("stratis_testing")
+            .tempdir()
+            .unwrap();
+        let devnode = pool.get_filesystem_by_uuid(fs_uuid).unwrap().1.devnode();
+        let fs_total = || {
+            mount(
+                Some(&devnode),
+                tmp_dir.path(),
+                Some("xfs"),
+                MsFlags::empty(),
+                None as Option<&str>,
+            )
+            .unwrap();
+            let (fs_total_bytes, _) = fs_usage(&tmp_dir.path()).unwrap();
+            umount(tmp_dir.path()).unwrap();
+            fs_total_bytes
+        };
+
+        let orig_fs_total_bytes = fs_total();
+        pool.extend_filesystem(fs_uuid, fs_size).unwrap();
+        assert!(fs_total() > orig_fs_total_bytes);
+
+        let usage = pool
+            .get_filesystem_by_uuid(fs_uuid)
+            .unwrap()
+            .1
+            .usage()
+            .unwrap();
+        assert_eq!(usage.size, fs_size + fs_size);
+        assert!(usage.allocated > Bytes(0));
+        assert_eq!(usage.fs_size, None);
+        assert_eq!(usage.fs_used, None);
+
+        let flexdevs: FlexDevsSave = pool.record();
+        let thinpoolsave: ThinPoolDevSave = pool.record();
+        pool.teardown().unwrap();
+        let pool = ThinPool::setup(pool_uuid, &thinpoolsave, &flexdevs, &backstore).unwrap();
+        assert_eq!(
+            pool.get_filesystem_by_uuid(fs_uuid)
+                .unwrap()
+                .1
+                .thindev_size(),
+            fs_size + fs_size
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_extend_filesystem() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Range(1, 3, None),
+            test_extend_filesystem,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_extend_filesystem() {
+        real::test_with_spec(
+            &real::DeviceLimits::AtLeast(1, None, None),
+            test_extend_filesystem,
+        );
+    }
+
+    /// Verify that a size limit less than the filesystem's size is refused,
+    /// and that a filesystem at its size limit is not extended when it
+    /// fills, and that the limit and user info are recorded in the MDV.
+    fn test_filesystem_size_limit(paths: &[&Path]) {
+        let (pool_uuid, mut backstore, mut pool) = setup_thin_pool(paths);
+
+        let fs_size = FILESYSTEM_LOWATER + Bytes(IEC::Mi).sectors();
+
+        let pool_name = "stratis_test_pool";
+        devlinks::pool_added(&pool_name);
+        let fs_uuid = pool
+            .create_filesystem(
+                pool_uuid,
+                pool_name,
+                "stratis_test_filesystem",
+                Some(fs_size),
+            )
+            .unwrap();
+
+        assert_matches!(
+            pool.set_filesystem_size_limit(fs_uuid, Some(fs_size - Sectors(1))),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(
+            pool.set_filesystem_size_limit(fs_uuid, Some(fs_size)),
+            Ok(true)
+        );
+        assert_matches!(
+            pool.set_filesystem_size_limit(fs_uuid, Some(fs_size)),
+            Ok(false)
+        );
+        assert_matches!(
+            pool.extend_filesystem(fs_uuid, Sectors(1)),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(
+            pool.set_filesystem_user_info(fs_uuid, Some("limited")),
+            Ok(true)
+        );
+
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("stratis_testing")
+            .tempdir()
+            .unwrap();
+        mount(
+            Some(&pool.get_filesystem_by_uuid(fs_uuid).unwrap().1.devnode()),
+            tmp_dir.path(),
+            Some("xfs"),
+            MsFlags::empty(),
+            None as Option<&str>,
+        )
+        .unwrap();
+        // Write 2 MiB of data. The filesystem's free space is now 1 MiB
+        // below FILESYSTEM_LOWATER.
+        let write_size = Bytes(IEC::Mi * 2).sectors();
+        let buf = &[1u8; SECTOR_SIZE];
+        for i in 0..*write_size {
+            let file_path = tmp_dir.path().join(format!("stratis_test{}.txt", i));
+            let mut f = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .open(file_path)
+                .unwrap();
+            if f.write_all(buf).is_err() {
+                break;
+            }
+        }
+        let (orig_fs_total_bytes, _) = fs_usage(&tmp_dir.path()).unwrap();
+        pool.check(pool_uuid, &mut backstore).unwrap();
+        let (fs_total_bytes, _) = fs_usage(&tmp_dir.path()).unwrap();
+        assert_eq!(fs_total_bytes, orig_fs_total_bytes);
+        umount(tmp_dir.path()).unwrap();
+        assert_eq!(
+            pool.get_filesystem_by_uuid(fs_uuid)
+                .unwrap()
+                .1
+                .thindev_size(),
+            fs_size
+        );
+
+        let flexdevs: FlexDevsSave = pool.record();
+        let thinpoolsave: ThinPoolDevSave = pool.record();
+        pool.teardown().unwrap();
+        let pool = ThinPool::setup(pool_uuid, &thinpoolsave, &flexdevs, &backstore).unwrap();
+        assert_eq!(
+            pool.get_filesystem_by_uuid(fs_uuid).unwrap().1.size_limit(),
+            Some(fs_size)
+        );
+        assert_eq!(
+            pool.get_filesystem_by_uuid(fs_uuid).unwrap().1.user_info(),
+            Some("limited")
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_filesystem_size_limit() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Range(1, 3, None),
+            test_filesystem_size_limit,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_filesystem_size_limit() {
+        real::test_with_spec(
+            &real::DeviceLimits::AtLeast(1, None, None),
+            test_filesystem_size_limit,
+        );
+    }
+
+    /// Verify that a filesystem is trimmed only while it is mounted, that
+    /// the space of deleted files is reported as unreclaimed until trimming
+    /// returns it to the thin pool, and
+    /// that its trim interval is recorded in the MDV.
+    fn test_filesystem_trim(paths: &[&Path]) {
+        let (pool_uuid, backstore, mut pool) = setup_thin_pool(paths);
+
+        let pool_name = "stratis_test_pool";
+        devlinks::pool_added(&pool_name);
+        let fs_uuid = pool
+            .create_filesystem(pool_uuid, pool_name, "stratis_test_filesystem", None)
+            .unwrap();
+
+        let interval = Duration::from_secs(3600);
+        assert_matches!(
+            pool.set_filesystem_trim_interval(fs_uuid, Some(interval)),
+            Ok(true)
+        );
+        assert_matches!(
+            pool.get_mut_filesystem_by_uuid(fs_uuid).unwrap().1.trim(),
+            Ok(None)
+        );
+
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("stratis_testing")
+            .tempdir()
+            .unwrap();
+        mount(
+            Some(&pool.get_filesystem_by_uuid(fs_uuid).unwrap().1.devnode()),
+            tmp_dir.path(),
+            Some("xfs"),
+            MsFlags::empty(),
+            None as Option<&str>,
+        )
+        .unwrap();
+        let file_path = tmp_dir.path().join("stratis_test.txt");
+        {
+            let mut f = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .open(&file_path)
+                .unwrap();
+            let buf = &[1u8; SECTOR_SIZE];
+            for _ in 0..*Bytes(8 * IEC::Mi).sectors() {
+                f.write_all(buf).unwrap();
+            }
+            f.sync_all().unwrap();
+        }
+        let written = pool
+            .get_filesystem_by_uuid(fs_uuid)
+            .unwrap()
+            .1
+            .used()
+            .unwrap();
+        fs::remove_file(&file_path).unwrap();
+        let filesystem = pool.get_mut_filesystem_by_uuid(fs_uuid).unwrap().1;
+        let unreclaimed = filesystem.usage().unwrap().unreclaimed().unwrap();
+        assert!(unreclaimed >= Bytes(8 * IEC::Mi));
+        assert_matches!(filesystem.trim(), Ok(Some(_)));
+        assert!(filesystem.used().unwrap() < written);
+        assert!(filesystem.usage().unwrap().unreclaimed().unwrap() < unreclaimed);
+        umount(tmp_dir.path()).unwrap();
+
+        let flexdevs: FlexDevsSave = pool.record();
+        let thinpoolsave: ThinPoolDevSave = pool.record();
+        pool.teardown().unwrap();
+        let pool = ThinPool::setup(pool_uuid, &thinpoolsave, &flexdevs, &backstore).unwrap();
+        let filesystem = pool.get_filesystem_by_uuid(fs_uuid).unwrap().1;
+        assert_eq!(filesystem.trim_interval(), Some(interval));
+        assert!(filesystem.trim_deadline().is_some());
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_filesystem_trim() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Range(1, 3, None),
+            test_filesystem_trim,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_filesystem_trim() {
+        real::test_with_spec(
+            &real::DeviceLimits::AtLeast(1, None, None),
+            test_filesystem_trim,
+        );
+    }
+
+    /// Verify that a snapshot taken with a bounded freeze of the origin
+    /// contains data written to the origin but not yet synced, and that the
+    /// origin is writable again afterwards.
+    fn test_snapshot_freeze(paths: &[&Path]) {
+        let (pool_uuid, _backstore, mut pool) = setup_thin_pool(paths);
+
+        let pool_name = "stratis_test_pool";
+        devlinks::pool_added(&pool_name);
+        let fs_uuid = pool
+            .create_filesystem(pool_uuid, pool_name, "stratis_test_filesystem", None)
+            .unwrap();
+
+        let origin_dir = tempfile::Builder::new()
+            .prefix("stratis_testing")
+            .tempdir()
+            .unwrap();
+        mount(
+            Some(&pool.get_filesystem_by_uuid(fs_uuid).unwrap().1.devnode()),
+            origin_dir.path(),
+            Some("xfs"),
+            MsFlags::empty(),
+            None as Option<&str>,
+        )
+        .unwrap();
+        let write_file = |name: &str| {
+            OpenOptions::new()
+                .create(true)
+                .write(true)
+                .open(origin_dir.path().join(name))
+                .unwrap()
+                .write_all(b"stratis")
+                .unwrap();
+        };
+        write_file("before_snapshot.txt");
+
+        let snapshot_uuid = pool
+            .snapshot_filesystem(pool_uuid, pool_name, fs_uuid, "test_snapshot", false, true)
+            .unwrap()
+            .0;
+        write_file("after_snapshot.txt");
+        umount(origin_dir.path()).unwrap();
+
+        let snapshot_dir = tempfile::Builder::new()
+            .prefix("stratis_testing")
+            .tempdir()
+            .unwrap();
+        mount(
+            Some(
+                &pool
+                    .get_filesystem_by_uuid(snapshot_uuid)
+                    .unwrap()
+                    .1
+                    .devnode(),
+            ),
+            snapshot_dir.path(),
+            Some("xfs"),
+            MsFlags::empty(),
+            None as Option<&str>,
+        )
+        .unwrap();
+        let mut contents = String::new();
+        fs::File::open(snapshot_dir.path().join("before_snapshot.txt"))
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "stratis");
+        assert!(!snapshot_dir.path().join("after_snapshot.txt").exists());
+        umount(snapshot_dir.path()).unwrap();
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_snapshot_freeze() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Range(1, 3, None),
+            test_snapshot_freeze,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_snapshot_freeze() {
+        real::test_with_spec(
+            &real::DeviceLimits::AtLeast(1, None, None),
+            test_snapshot_freeze,
+        );
+    }
+
+    /// Verify that a read-only filesystem can be mounted only read-only and
+    /// is not extended, that a mounted filesystem can not be made read-only,
+    /// and that the flag is recorded in the MDV and applied on setup.
+    fn test_filesystem_read_only(paths: &[&Path]) {
+        let (pool_uuid, backstore, mut pool) = setup_thin_pool(paths);
+
+        let pool_name = "stratis_test_pool";
+        devlinks::pool_added(&pool_name);
+        let fs_uuid = pool
+            .create_filesystem(pool_uuid, pool_name, "stratis_test_filesystem", None)
+            .unwrap();
+        let devnode = pool.get_filesystem_by_uuid(fs_uuid).unwrap().1.devnode();
+
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("stratis_testing")
+            .tempdir()
+            .unwrap();
+        let mount_fs = |flags: MsFlags| {
+            mount(
+                Some(&devnode),
+                tmp_dir.path(),
+                Some("xfs"),
+                flags,
+                None as Option<&str>,
+            )
+        };
+
+        mount_fs(MsFlags::empty()).unwrap();
+        assert_matches!(
+            pool.set_filesystem_read_only(fs_uuid, true),
+            Err(StratisError::Engine(ErrorEnum::Busy, _))
+        );
+        umount(tmp_dir.path()).unwrap();
+
+        assert_matches!(pool.set_filesystem_read_only(fs_uuid, true), Ok(true));
+        assert_matches!(pool.set_filesystem_read_only(fs_uuid, true), Ok(false));
+        assert_matches!(
+            pool.extend_filesystem(fs_uuid, Sectors(1024)),
+            Err(StratisError::Engine(ErrorEnum::Busy, _))
+        );
+
+        let flexdevs: FlexDevsSave = pool.record();
+        let thinpoolsave: ThinPoolDevSave = pool.record();
+        pool.teardown().unwrap();
+        let mut pool = ThinPool::setup(pool_uuid, &thinpoolsave, &flexdevs, &backstore).unwrap();
+        assert!(pool.get_filesystem_by_uuid(fs_uuid).unwrap().1.read_only());
+
+        assert!(mount_fs(MsFlags::empty()).is_err());
+        mount_fs(MsFlags::MS_RDONLY).unwrap();
+        umount(tmp_dir.path()).unwrap();
+
+        assert_matches!(pool.set_filesystem_read_only(fs_uuid, false), Ok(true));
+        mount_fs(MsFlags::empty()).unwrap();
+        umount(tmp_dir.path()).unwrap();
+    }
+
+    /// Verify that a filesystem's minimum size is the size of its XFS
+    /// filesystem, and that its ThinDev can not be shrunk below that, nor
+    /// while it is mounted.
+    fn test_shrink_filesystem(paths: &[&Path]) {
+        let (pool_uuid, _backstore, mut pool) = setup_thin_pool(paths);
+
+        let fs_size = Bytes(512 * IEC::Mi).sectors();
+        let pool_name = "stratis_test_pool";
+        devlinks::pool_added(&pool_name);
+        let fs_uuid = pool
+            .create_filesystem(
+                pool_uuid,
+                pool_name,
+                "stratis_test_filesystem",
+                Some(fs_size),
+            )
+            .unwrap();
+        assert_eq!(
+            pool.get_filesystem_by_uuid(fs_uuid)
+                .unwrap()
+                .1
+                .min_size()
+                .unwrap(),
+            fs_size
+        );
+        assert_matches!(
+            pool.shrink_filesystem(fs_uuid, fs_size),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(
+            pool.shrink_filesystem(fs_uuid, fs_size - Sectors(1)),
+            Err(StratisError::Engine(ErrorEnum::Invalid, _))
+        );
+
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("stratis_testing")
+            .tempdir()
+            .unwrap();
+        mount(
+            Some(&pool.get_filesystem_by_uuid(fs_uuid).unwrap().1.devnode()),
+            tmp_dir.path(),
+            Some("xfs"),
+            MsFlags::empty(),
+            None as Option<&str>,
+        )
+        .unwrap();
+        assert_matches!(
+            pool.shrink_filesystem(fs_uuid, fs_size - Sectors(1)),
+            Err(StratisError::Engine(ErrorEnum::Busy, _))
+        );
+        umount(tmp_dir.path()).unwrap();
+        assert_eq!(
+            pool.get_filesystem_by_uuid(fs_uuid)
+                .unwrap()
+                .1
+                .thindev_size(),
+            fs_size
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_shrink_filesystem() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Range(1, 3, None),
+            test_shrink_filesystem,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_shrink_filesystem() {
+        real::test_with_spec(
+            &real::DeviceLimits::AtLeast(1, None, None),
+            test_shrink_filesystem,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn loop_test_filesystem_read_only() {
+        loopbacked::test_with_spec(
+            &loopbacked::DeviceLimits::Range(1, 3, None),
+            test_filesystem_read_only,
+        );
+    }
+
+    #[cfg(feature = "device_tests")]
+    #[test]
+    pub fn real_test_filesystem_read_only() {
+        real::test_with_spec(
+            &real::DeviceLimits::AtLeast(1, None, None),
+            test_filesystem_read_only,
+        );
+    }
+
     /// Just suspend and resume the device and make sure it doesn't crash.
     /// Suspend twice in succession and then resume twice in succession
     /// to check idempotency.
@@ -1778,6 +4167,7 @@ mod tests {
         pool.resume().unwrap();
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn loop_test_suspend_resume() {
         loopbacked::test_with_spec(
@@ -1786,6 +4176,7 @@ mod tests {
         );
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn real_test_suspend_resume() {
         real::test_with_spec(
@@ -1888,6 +4279,7 @@ mod tests {
         );
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn loop_test_set_device() {
         loopbacked::test_with_spec(
@@ -1896,6 +4288,7 @@ mod tests {
         );
     }
 
+    #[cfg(feature = "device_tests")]
     #[test]
     pub fn real_test_set_device() {
         real::test_with_spec(&real::DeviceLimits::AtLeast(2, None, None), test_set_device);
@@ -3,18 +3,80 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use std::borrow::Borrow;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::ops::Deref;
+use std::path::PathBuf;
 use std::rc::Rc;
+use std::time::Duration;
 
 #[cfg(feature = "dbus_enabled")]
 use dbus;
 use uuid::Uuid;
 
+use devicemapper::{Bytes, Sectors};
+
+use crate::stratis::ErrorEnum;
+
 pub type DevUuid = Uuid;
 pub type FilesystemUuid = Uuid;
 pub type PoolUuid = Uuid;
 
+/// A pool may be referred to either by its UUID or by its name.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PoolId {
+    Uuid(PoolUuid),
+    Name(String),
+}
+
+/// An engine operation which the simulator can be made to fail.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum SimOperation {
+    CreatePool,
+    DestroyPool,
+    RenamePool,
+    AddBlockdevs,
+    CreateFilesystems,
+    DestroyFilesystems,
+    RenameFilesystem,
+    SnapshotFilesystem,
+}
+
+/// How the simulator decides whether an operation fails.
+#[derive(Clone, Debug)]
+pub enum SimFailureModel {
+    /// Fail with a probability of 1/denominator, with ErrorEnum::Error.
+    /// If denominator is 0, never fail.
+    Probability(u32),
+    /// Decide each attempt in turn from the script: None succeeds, and
+    /// Some(err) fails with err. Once the script is used up, every attempt
+    /// succeeds.
+    Script(Vec<Option<ErrorEnum>>),
+}
+
+/// The properties of a simulated device. A device devnode that has not been
+/// given properties of its own has the default properties.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SimDevProperties {
+    /// The size of the device. If None, the device is 1 GiB, but does not
+    /// count toward the size of its pool, which is then practically
+    /// unlimited.
+    pub size: Option<Sectors>,
+    pub rotational: bool,
+    /// How long each operation of the pool that writes to the device takes.
+    pub latency: Duration,
+}
+
+impl Default for SimDevProperties {
+    fn default() -> SimDevProperties {
+        SimDevProperties {
+            size: None,
+            rotational: false,
+            latency: Duration::from_secs(0),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum RenameAction {
     Identity,
@@ -56,6 +118,200 @@ pub enum FreeSpaceState {
     Crit = 3,
 }
 
+/// A pool's policy for the total size of its filesystems, which may exceed
+/// the pool's physical capacity. The ratio is a percentage of the capacity.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OverprovisionPolicy {
+    Unlimited,
+    /// Log a warning when the ratio is exceeded.
+    Warn(u32),
+    /// Refuse to create or extend filesystems beyond the ratio.
+    Limit(u32),
+}
+
+/// How a pool's data tier spreads newly allocated extents over its devices.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AllocationStrategy {
+    /// Use up each device before allocating from the next. A device that
+    /// was added last is the easiest to remove.
+    FillFirst,
+    /// Allocate extents from each device in turn, so that I/O is spread
+    /// across all devices.
+    RoundRobin,
+}
+
+/// How the chunk size of a pool's thin pool, the unit in which space is
+/// allocated to its filesystems and shared between snapshots, is chosen when
+/// the pool is created.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ChunkSize {
+    /// Use the default chunk size, raised if necessary to the optimal I/O
+    /// size of the pool's devices.
+    Auto,
+    /// Use a small chunk size, raised if necessary to the optimal I/O size
+    /// of the pool's devices. Snapshots share data in whole chunks, so small
+    /// chunks make writes to snapshotted filesystems cheaper.
+    AutoSnapshots,
+    /// Use the given chunk size, which must be a multiple of 64 KiB between
+    /// 64 KiB and 1 GiB.
+    Fixed(Sectors),
+}
+
+/// An account of how the space in a pool is used.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PoolSpaceReport {
+    /// The total size of the devices in the data tier.
+    pub raw_size: Sectors,
+    /// The space on those devices taken up by Stratis metadata.
+    pub metadata_overhead: Sectors,
+    /// The usable space in the data tier allocated to the pool's components.
+    pub allocated: Sectors,
+    /// The usable space in the data tier not yet allocated.
+    pub free: Sectors,
+    /// The part of the free space held in reserve, into which the thin
+    /// pool's data device may not grow.
+    pub reserved: Sectors,
+    /// The percentage of the thin pool's data device in use.
+    pub thin_data_used_pct: u8,
+    /// The percentage of the thin pool's metadata device in use.
+    pub thin_meta_used_pct: u8,
+    /// The space allocated to each filesystem.
+    pub filesystems: Vec<(FilesystemUuid, Bytes)>,
+}
+
+/// An account of how the space in a filesystem is used.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FilesystemUsage {
+    /// The size of the filesystem's thin device.
+    pub size: Sectors,
+    /// The space in the thin pool allocated to the filesystem.
+    pub allocated: Bytes,
+    /// The total space reported by the filesystem, if it is mounted.
+    pub fs_size: Option<Bytes>,
+    /// The used space reported by the filesystem, if it is mounted.
+    pub fs_used: Option<Bytes>,
+}
+
+/// The percentage of a filesystem's size which may be allocated to it but
+/// unused before trimming it is recommended.
+const TRIM_RECOMMENDED_PCT: u64 = 10;
+
+impl FilesystemUsage {
+    /// The space allocated to the filesystem beyond what the filesystem
+    /// reports as used. This is mostly space held by blocks that were
+    /// freed, but never discarded. None if the filesystem is not mounted.
+    pub fn unreclaimed(&self) -> Option<Bytes> {
+        self.fs_used.map(|fs_used| {
+            if self.allocated > fs_used {
+                self.allocated - fs_used
+            } else {
+                Bytes(0)
+            }
+        })
+    }
+
+    /// Whether the unreclaimed space has grown to more than
+    /// TRIM_RECOMMENDED_PCT of the filesystem's size, so that trimming the
+    /// filesystem would return a worthwhile amount of space to the pool.
+    pub fn trim_recommended(&self) -> bool {
+        match (self.unreclaimed(), self.fs_size) {
+            (Some(unreclaimed), Some(fs_size)) => {
+                *unreclaimed * 100 > *fs_size * TRIM_RECOMMENDED_PCT
+            }
+            _ => false,
+        }
+    }
+}
+
+/// A filesystem, with the snapshots taken of it, and of those in turn.
+#[derive(Debug, Eq, PartialEq)]
+pub struct FilesystemTree {
+    pub name: Name,
+    pub uuid: FilesystemUuid,
+    pub snapshots: Vec<FilesystemTree>,
+}
+
+impl FilesystemTree {
+    /// Arrange filesystems, given by name, UUID, and origin, into trees
+    /// according to their origins. A filesystem is the root of a tree if it
+    /// has no origin among the filesystems given. Trees and the snapshots
+    /// within them are ordered by name.
+    pub fn build(
+        filesystems: Vec<(Name, FilesystemUuid, Option<FilesystemUuid>)>,
+    ) -> Vec<FilesystemTree> {
+        let uuids: HashSet<FilesystemUuid> = filesystems.iter().map(|&(_, uuid, _)| uuid).collect();
+        let mut snapshots: HashMap<Option<FilesystemUuid>, Vec<(Name, FilesystemUuid)>> =
+            HashMap::new();
+        for (name, uuid, origin) in filesystems {
+            snapshots
+                .entry(origin.filter(|origin| uuids.contains(origin)))
+                .or_default()
+                .push((name, uuid));
+        }
+
+        fn grow(
+            origin: Option<FilesystemUuid>,
+            snapshots: &mut HashMap<Option<FilesystemUuid>, Vec<(Name, FilesystemUuid)>>,
+        ) -> Vec<FilesystemTree> {
+            let mut filesystems = snapshots.remove(&origin).unwrap_or_default();
+            filesystems.sort_by_key(|(name, _)| name.to_owned());
+            filesystems
+                .into_iter()
+                .map(|(name, uuid)| FilesystemTree {
+                    name,
+                    uuid,
+                    snapshots: grow(Some(uuid), snapshots),
+                })
+                .collect()
+        }
+
+        grow(None, &mut snapshots)
+    }
+}
+
+/// The layout a pool would have if it were created from some devices.
+#[derive(Debug, Eq, PartialEq)]
+pub struct PoolPlan {
+    /// The devices that would make up the pool's data tier.
+    pub devices: Vec<PlannedBlockDev>,
+    /// The pool's redundancy.
+    pub redundancy: Redundancy,
+    /// The space, after Stratis metadata on each device, that the devices
+    /// would provide.
+    pub usable_size: Sectors,
+    /// The space that would be allocated to the pool's thin pool and
+    /// metadata volume when the pool is created.
+    pub initial_allocation: Sectors,
+    /// The chunk size that the pool's thin pool would have.
+    pub chunk_size: Sectors,
+}
+
+/// The outcome of repairing the thin metadata of a pool.
+#[derive(Debug, Eq, PartialEq)]
+pub struct ThinRepairSummary {
+    /// The segments of the pool's cap device which held the damaged thin
+    /// metadata. They are not reused, so that it can be examined.
+    pub retained_segments: Vec<(Sectors, Sectors)>,
+    /// The filesystems recorded in the pool's metadata volume that were set
+    /// up from the repaired thin metadata.
+    pub restored_filesystems: Vec<Name>,
+    /// The filesystems recorded in the pool's metadata volume that could
+    /// not be set up from the repaired thin metadata.
+    pub missing_filesystems: Vec<Name>,
+}
+
+/// A device as it would be made part of a pool.
+#[derive(Debug, Eq, PartialEq)]
+pub struct PlannedBlockDev {
+    pub devnode: PathBuf,
+    /// The size of the device.
+    pub size: Sectors,
+    /// The space on the device that would be taken up by Stratis metadata.
+    pub metadata_size: Sectors,
+    /// The device's optimal I/O size, if it reports one.
+    pub optimal_io_size: Option<Sectors>,
+}
+
 /// See Design Doc section 10.2.1 for more details.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum BlockDevState {
@@ -84,7 +340,7 @@ pub enum BlockDevTier {
 }
 
 /// Redundancy classifications which the engine allows for pools.
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 #[allow(non_camel_case_types)]
 /// Redundancy specification for a pool.
 pub enum Redundancy {
@@ -25,6 +25,8 @@ pub enum ErrorEnum {
     Busy,
     Invalid,
     NotFound,
+    // Metadata on the devices is damaged and must be repaired.
+    Corrupt,
 }
 
 #[derive(Debug)]
@@ -32,6 +32,21 @@ SPECS = {
 <arg name="return_code" type="q" direction="out"/>
 <arg name="return_string" type="s" direction="out"/>
 </method>
+<method name="ConfigureSimulatorDevice">
+<arg name="devnode" type="s" direction="in"/>
+<arg name="size" type="(bt)" direction="in"/>
+<arg name="rotational" type="b" direction="in"/>
+<arg name="latency_ms" type="u" direction="in"/>
+<arg name="return_code" type="q" direction="out"/>
+<arg name="return_string" type="s" direction="out"/>
+</method>
+<method name="ConfigureSimulatorOperation">
+<arg name="operation" type="s" direction="in"/>
+<arg name="denominator" type="u" direction="in"/>
+<arg name="script" type="aq" direction="in"/>
+<arg name="return_code" type="q" direction="out"/>
+<arg name="return_string" type="s" direction="out"/>
+</method>
 <method name="CreatePool">
 <arg name="name" type="s" direction="in"/>
 <arg name="redundancy" type="(bq)" direction="in"/>
@@ -40,12 +55,28 @@ SPECS = {
 <arg name="return_code" type="q" direction="out"/>
 <arg name="return_string" type="s" direction="out"/>
 </method>
+<method name="CreatePoolWithRequestId">
+<arg name="name" type="s" direction="in"/>
+<arg name="redundancy" type="(bq)" direction="in"/>
+<arg name="devices" type="as" direction="in"/>
+<arg name="request_id" type="(bs)" direction="in"/>
+<arg name="result" type="(oao)" direction="out"/>
+<arg name="return_code" type="q" direction="out"/>
+<arg name="return_string" type="s" direction="out"/>
+</method>
 <method name="DestroyPool">
 <arg name="pool" type="o" direction="in"/>
 <arg name="action" type="b" direction="out"/>
 <arg name="return_code" type="q" direction="out"/>
 <arg name="return_string" type="s" direction="out"/>
 </method>
+<method name="DestroyPoolWithRequestId">
+<arg name="pool" type="o" direction="in"/>
+<arg name="request_id" type="(bs)" direction="in"/>
+<arg name="action" type="b" direction="out"/>
+<arg name="return_code" type="q" direction="out"/>
+<arg name="return_string" type="s" direction="out"/>
+</method>
 <property name="Version" type="s" access="read">
 <annotation name="org.freedesktop.DBus.Property.EmitsChangedSignal" value="const"/>
 </property>
@@ -161,6 +192,9 @@ SPECS = {
 <property name="InitializationTime" type="t" access="read">
 <annotation name="org.freedesktop.DBus.Property.EmitsChangedSignal" value="const"/>
 </property>
+<property name="PersistentDevnode" type="s" access="read">
+<annotation name="org.freedesktop.DBus.Property.EmitsChangedSignal" value="const"/>
+</property>
 <property name="Pool" type="o" access="read">
 <annotation name="org.freedesktop.DBus.Property.EmitsChangedSignal" value="const"/>
 </property>
@@ -81,4 +81,4 @@ class SetNameTestCase(SimTestCase):
 
         devnode = Filesystem.Properties.Devnode.Get(filesystem)
 
-        self.assertEqual(devnode, "/stratis/deadpool/fs")
+        self.assertEqual(devnode, "/dev/stratis/deadpool/fs")
@@ -144,3 +144,40 @@ class Create3TestCase(SimTestCase):
         self.assertEqual(
             frozenset(x for (x, y) in pools1), frozenset(
                 x for (x, y) in pools2))
+
+
+class Create4TestCase(SimTestCase):
+    """
+    Test 'create' made again with the same request ID.
+    """
+    _POOLNAME = 'deadpool'
+
+    def setUp(self):
+        """
+        Start the stratisd daemon with the simulator.
+        """
+        super().setUp()
+        self._proxy = get_object(TOP_OBJECT)
+
+    def testCreate(self):
+        """
+        The second create should get the result of the first, rather than
+        failing because the pool already exists.
+        """
+        spec = {
+            'name': self._POOLNAME,
+            'redundancy': (True, 0),
+            'devices': _DEVICE_STRATEGY(),
+            'request_id': (True, 'create')
+        }
+        ((poolpath1, _), rc1, _) = Manager.Methods.CreatePoolWithRequestId(
+            self._proxy, spec)
+        ((poolpath2, _), rc2, _) = Manager.Methods.CreatePoolWithRequestId(
+            self._proxy, spec)
+        self.assertEqual(rc1, StratisdErrors.OK)
+        self.assertEqual(rc2, StratisdErrors.OK)
+        self.assertEqual(poolpath1, poolpath2)
+
+        managed_objects = \
+           ObjectManager.Methods.GetManagedObjects(self._proxy, {})
+        self.assertEqual(len(list(pools().search(managed_objects))), 1)